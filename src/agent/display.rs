@@ -10,6 +10,10 @@
 //! The rendering function is pure (no I/O, no colour) so it's
 //! unit-testable. The caller is responsible for ANSI colouring.
 
+use super::{ContextUsage, ReasoningCallback, ToolProgressCallback};
+use crate::llm::retry::RetryNotice;
+use crate::llm::ToolCallProgress;
+use crate::tools::todo::{TodoItem, TodoStatus};
 use serde_json::Value;
 use std::io::{self, IsTerminal, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -85,6 +89,10 @@ pub fn format_tool_call_compact(name: &str, args: &Value) -> String {
         }
         "web_search" => format!("Search({})", truncate_arg(s("query"))),
         "git" => format!("Git({})", truncate_arg(s("command"))),
+        "todo" => {
+            let items = args.get("todos").and_then(|v| v.as_array());
+            format!("Todo({} items)", items.map_or(0, |a| a.len()))
+        }
         // Unknown / MCP-registered tools: keep the tool name and show a
         // condensed JSON of args so the user can still see what was sent.
         _ => {
@@ -297,6 +305,168 @@ pub fn print_colored_diff(text: &str) {
     }
 }
 
+/// One line per todo item: `✓` done, `▶` in progress, `○` pending.
+pub fn format_todo_list(items: &[TodoItem]) -> Vec<String> {
+    items
+        .iter()
+        .map(|t| {
+            let mark = match t.status {
+                TodoStatus::Done => '✓',
+                TodoStatus::InProgress => '▶',
+                TodoStatus::Pending => '○',
+            };
+            format!("{} {}", mark, t.content)
+        })
+        .collect()
+}
+
+/// Print the todo list under its tool-call line: finished items dimmed,
+/// the current one bold.
+pub fn print_todo_list(items: &[TodoItem]) {
+    use colored::Colorize;
+    for (item, line) in items.iter().zip(format_todo_list(items)) {
+        match item.status {
+            TodoStatus::Done => eprintln!("    {}", line.dimmed()),
+            TodoStatus::InProgress => eprintln!("    {}", line.bold()),
+            TodoStatus::Pending => eprintln!("    {}", line),
+        }
+    }
+}
+
+/// Render a backend retry as a single line, e.g.
+/// `Server error: Ollama returned status 503 … — retrying in 2.0s (attempt 2/3)…`.
+/// Only the first line of the error is kept so an HTML error page from a
+/// proxy doesn't flood the terminal.
+pub fn format_retry_notice(notice: &RetryNotice) -> String {
+    let error = notice.error.lines().next().unwrap_or("");
+    format!(
+        "{} — retrying in {:.1}s (attempt {}/{})…",
+        truncate_arg(error),
+        notice.delay.as_secs_f64(),
+        notice.attempt,
+        notice.max_attempts
+    )
+}
+
+/// Print a retry notice on stderr. Unlike the diff preview this is shown
+/// even when stderr isn't a TTY — a stalled run should say why. On a TTY
+/// the spinner line is cleared first so the notice doesn't splice into it.
+pub fn print_retry_notice(notice: &RetryNotice) {
+    use colored::Colorize;
+    if io::stderr().is_terminal() {
+        eprint!("\r\x1b[2K");
+    }
+    eprintln!("  {}", format_retry_notice(notice).yellow());
+}
+
+/// Lines for the `/context` command: message and tool-definition token
+/// counts, the total against the compaction threshold and, when known,
+/// the model's context window.
+pub fn format_context_usage(usage: &ContextUsage, num_ctx: Option<u32>) -> Vec<String> {
+    let total = usage.total();
+    let pct = (total * 100)
+        .checked_div(usage.compaction_threshold)
+        .unwrap_or(0);
+    let mut lines = vec![
+        format!(
+            "Messages:  {} tokens ({} messages)",
+            usage.message_tokens, usage.messages
+        ),
+        format!("Tools:     {} tokens", usage.tool_tokens),
+        format!(
+            "Total:     {} / {} compaction threshold ({}%)",
+            total, usage.compaction_threshold, pct
+        ),
+    ];
+    if let Some(n) = num_ctx {
+        lines.push(format!("num_ctx:   {}", n));
+    }
+    lines.push(format!("Counter:   {}", usage.counter));
+    lines
+}
+
+/// Streaming renderer for the model's thinking trace. Prefixes the first
+/// chunk with `think> ` and ends the block with a newline when the agent
+/// signals the end of the trace (an empty chunk), so the `bot>` line that
+/// follows starts on its own row. Pure — the caller adds colour.
+#[derive(Debug, Default)]
+pub struct ReasoningPrinter {
+    open: bool,
+}
+
+impl ReasoningPrinter {
+    pub fn render(&mut self, chunk: &str) -> Option<String> {
+        if chunk.is_empty() {
+            return std::mem::take(&mut self.open).then(|| "\n".to_string());
+        }
+        if self.open {
+            Some(chunk.to_string())
+        } else {
+            self.open = true;
+            Some(format!("think> {}", chunk))
+        }
+    }
+}
+
+/// Reasoning callback for the REPL: the trace, dimmed, on stderr.
+pub fn reasoning_printer() -> ReasoningCallback {
+    use colored::Colorize;
+    let mut printer = ReasoningPrinter::default();
+    Box::new(move |chunk| {
+        if let Some(text) = printer.render(chunk) {
+            eprint!("{}", text.dimmed());
+            let _ = io::stderr().flush();
+        }
+    })
+}
+
+/// Status line for a tool call whose arguments are still streaming,
+/// e.g. `generating write_file… 12 KB`. Redraws in place only when the
+/// call or its size in whole KB changes, and clears the line once the
+/// agent signals the end (`None`). Pure — the caller adds colour.
+#[derive(Debug, Default)]
+pub struct ToolProgressPrinter {
+    shown: Option<(usize, String, usize)>,
+}
+
+impl ToolProgressPrinter {
+    pub fn render(&mut self, progress: Option<&ToolCallProgress>) -> Option<String> {
+        let Some(p) = progress else {
+            return self.shown.take().map(|_| "\r\x1b[2K".to_string());
+        };
+        let kb = p.bytes / 1024;
+        let key = (p.index, p.name.to_string(), kb);
+        if self.shown.as_ref() == Some(&key) {
+            return None;
+        }
+        self.shown = Some(key);
+        let name = if p.name.is_empty() {
+            "tool call"
+        } else {
+            p.name
+        };
+        let size = if kb == 0 {
+            "<1 KB".to_string()
+        } else {
+            format!("{} KB", kb)
+        };
+        Some(format!("\r\x1b[2K  generating {}… {}", name, size))
+    }
+}
+
+/// Tool-progress callback for the REPL: one dimmed line on stderr,
+/// rewritten as the call grows.
+pub fn tool_progress_printer() -> ToolProgressCallback {
+    use colored::Colorize;
+    let mut printer = ToolProgressPrinter::default();
+    Box::new(move |progress| {
+        if let Some(text) = printer.render(progress) {
+            eprint!("{}", text.dimmed());
+            let _ = io::stderr().flush();
+        }
+    })
+}
+
 /// Truncate `s` to at most `MAX_ARG_LEN` characters, appending `…` when
 /// truncation occurred. Char-boundary safe.
 fn truncate_arg(s: &str) -> String {
@@ -314,6 +484,27 @@ mod tests {
     use super::*;
     use serde_json::json;
 
+    #[test]
+    fn todo_list_lines() {
+        let item = |content: &str, status| TodoItem {
+            content: content.to_string(),
+            status,
+        };
+        let items = [
+            item("Read config", TodoStatus::Done),
+            item("Add flag", TodoStatus::InProgress),
+            item("Update docs", TodoStatus::Pending),
+        ];
+        assert_eq!(
+            format_todo_list(&items),
+            vec!["✓ Read config", "▶ Add flag", "○ Update docs"]
+        );
+        assert_eq!(
+            format_tool_call_compact("todo", &json!({"todos": [{}, {}, {}]})),
+            "Todo(3 items)"
+        );
+    }
+
     #[test]
     fn read_file_compact() {
         assert_eq!(
@@ -678,4 +869,85 @@ mod tests {
     fn unified_diff_excerpt_empty_input() {
         assert_eq!(format_unified_diff_excerpt("", 10), "");
     }
+
+    #[test]
+    fn retry_notice_format() {
+        let notice = RetryNotice {
+            attempt: 2,
+            max_attempts: 3,
+            delay: Duration::from_millis(1500),
+            error: "Server error: Ollama returned status 503".to_string(),
+        };
+        assert_eq!(
+            format_retry_notice(&notice),
+            "Server error: Ollama returned status 503 — retrying in 1.5s (attempt 2/3)…"
+        );
+    }
+
+    #[test]
+    fn retry_notice_keeps_first_error_line_only() {
+        let notice = RetryNotice {
+            attempt: 3,
+            max_attempts: 5,
+            delay: Duration::from_secs(4),
+            error: "Server error: 502\n<html><body>Bad Gateway</body></html>".to_string(),
+        };
+        let line = format_retry_notice(&notice);
+        assert!(!line.contains("<html>"));
+        assert!(line.starts_with("Server error: 502 — retrying in 4.0s"));
+    }
+
+    #[test]
+    fn context_usage_lines() {
+        let usage = ContextUsage {
+            messages: 12,
+            message_tokens: 3000,
+            tool_tokens: 1500,
+            compaction_threshold: 6000,
+            counter: "llama.cpp /tokenize".to_string(),
+        };
+        let lines = format_context_usage(&usage, Some(8192));
+        assert_eq!(lines[0], "Messages:  3000 tokens (12 messages)");
+        assert_eq!(
+            lines[2],
+            "Total:     4500 / 6000 compaction threshold (75%)"
+        );
+        assert_eq!(lines[3], "num_ctx:   8192");
+        assert_eq!(lines[4], "Counter:   llama.cpp /tokenize");
+        assert_eq!(format_context_usage(&usage, None).len(), 4);
+    }
+
+    #[test]
+    fn tool_progress_redraws_per_kb_and_clears() {
+        let mut p = ToolProgressPrinter::default();
+        let at = |bytes| ToolCallProgress {
+            index: 0,
+            name: "write_file",
+            bytes,
+        };
+        assert_eq!(p.render(None), None);
+        assert_eq!(
+            p.render(Some(&at(10))).as_deref(),
+            Some("\r\x1b[2K  generating write_file… <1 KB")
+        );
+        assert_eq!(p.render(Some(&at(900))), None);
+        assert_eq!(
+            p.render(Some(&at(3000))).as_deref(),
+            Some("\r\x1b[2K  generating write_file… 2 KB")
+        );
+        assert_eq!(p.render(None).as_deref(), Some("\r\x1b[2K"));
+        assert_eq!(p.render(None), None);
+    }
+
+    #[test]
+    fn reasoning_printer_prefixes_and_closes_block() {
+        let mut p = ReasoningPrinter::default();
+        assert_eq!(p.render(""), None);
+        assert_eq!(p.render("Let me ").as_deref(), Some("think> Let me "));
+        assert_eq!(p.render("check.").as_deref(), Some("check."));
+        assert_eq!(p.render("").as_deref(), Some("\n"));
+        assert_eq!(p.render(""), None);
+        // A second trace in the same turn (next LLM call) gets a new prefix.
+        assert_eq!(p.render("again").as_deref(), Some("think> again"));
+    }
 }
@@ -3,13 +3,14 @@
 //! A small set of checks that surface the most common reasons Whet
 //! "doesn't work" for a first-time user: ollama not running, configured
 //! model not pulled, config file syntax error, MCP server can't spawn,
-//! `~/.whet` not writable.
+//! `~/.whet` not writable, no backend for the `[sandbox]` profile.
 //!
 //! Each check is pure (or near-pure: takes its IO dependencies as
 //! callbacks) so it can be unit-tested without network or filesystem.
 //! `run_all` is the aggregator the slash command calls.
 
-use crate::config::{Config, McpServerConfig};
+use crate::config::{Config, McpServerConfig, SandboxConfig, SandboxProfile};
+use crate::security::sandbox;
 use std::path::{Path, PathBuf};
 
 /// Verdict for a single check.
@@ -200,6 +201,28 @@ pub fn check_mcp_binaries(servers: &[McpServerConfig], which: impl Fn(&str) -> b
     }
 }
 
+/// Check that the `[sandbox]` profile has a backend to run on. Without
+/// one every shell command is refused, which otherwise looks like the
+/// model failing at random.
+pub fn check_sandbox(cfg: &SandboxConfig, which: impl Fn(&str) -> bool) -> Diagnostic {
+    if cfg.profile == SandboxProfile::Off {
+        return Diagnostic::pass("sandbox", "off (skipped)");
+    }
+    match sandbox::find_backend(cfg.backend, which) {
+        Some(backend) => Diagnostic::pass(
+            "sandbox",
+            format!("{} profile via {}", cfg.profile, backend.program()),
+        ),
+        None => Diagnostic::fail(
+            "sandbox",
+            format!(
+                "{} profile needs bwrap or unshare on PATH; shell commands are refused",
+                cfg.profile
+            ),
+        ),
+    }
+}
+
 // --- Aggregator ---
 
 /// Run all checks against a real environment. The `fetch` argument
@@ -252,6 +275,7 @@ where
     rows.push(check_mcp_binaries(&cfg.mcp.servers, |cmd| {
         which_in_path(cmd)
     }));
+    rows.push(check_sandbox(&cfg.sandbox, which_in_path));
 
     rows
 }
@@ -289,6 +313,13 @@ mod tests {
                 api_key: None,
                 streaming: false,
                 options: LlmOptions::default(),
+                retry: Default::default(),
+                fallbacks: Vec::new(),
+                tool_mode: Default::default(),
+                tool_call_format: Default::default(),
+                models: Default::default(),
+                roles: Default::default(),
+                cassette: None,
             },
             agent: AgentConfig {
                 max_iterations: 10,
@@ -298,12 +329,21 @@ mod tests {
                 compaction_token_threshold_ratio: 0.6,
                 skills_dir: "~/.whet/skills".to_string(),
                 web_enabled: false,
+                show_reasoning: true,
+                keep_reasoning: false,
+                max_turn_tokens: None,
+                max_turn_seconds: None,
+                policies: Default::default(),
+                verify: None,
             },
             memory: MemoryConfig {
                 database_path: ":memory:".to_string(),
                 max_inject_memories: 50,
             },
             mcp: McpConfig { servers: vec![] },
+            permissions: Default::default(),
+            workspace: Default::default(),
+            sandbox: Default::default(),
         }
     }
 
@@ -435,6 +475,27 @@ database_path = "test.db"
         assert!(d.detail.contains("definitely_not_installed_xyz"));
     }
 
+    #[test]
+    fn sandbox_fails_without_a_backend() {
+        let off = SandboxConfig::default();
+        assert_eq!(
+            check_sandbox(&off, |_| false).status,
+            DiagnosticStatus::Pass
+        );
+        let cfg = SandboxConfig {
+            profile: SandboxProfile::Offline,
+            ..Default::default()
+        };
+        let d = check_sandbox(&cfg, |_| false);
+        assert_eq!(d.status, DiagnosticStatus::Fail);
+        assert!(d.detail.contains("offline"));
+        if cfg!(target_os = "linux") {
+            let d = check_sandbox(&cfg, |cmd| cmd == "unshare");
+            assert_eq!(d.status, DiagnosticStatus::Pass);
+            assert!(d.detail.contains("unshare"));
+        }
+    }
+
     // --- format_row + overall_exit_code ---
 
     #[test]
@@ -1,13 +1,32 @@
 pub mod display;
 pub mod doctor;
+pub mod policy;
 pub mod prompt;
-
-use crate::config::{PermissionMode, ToolRiskLevel};
-use crate::llm::{LlmProvider, Message, TokenUsage, ToolCall};
+pub mod review;
+
+use crate::cancel::CancelToken;
+use crate::checkpoint::CheckpointStore;
+use crate::config::{
+    LoopPolicyConfig, PermissionMode, PermissionsConfig, ToolRiskLevel, VerifyConfig,
+};
+use crate::llm::attachment::{self, Attachment};
+use crate::llm::tokens::{HeuristicCounter, TokenCounter};
+use crate::llm::{
+    LlmError, LlmProvider, LlmResponse, Message, Role, Timings, TokenUsage, ToolCall,
+    ToolCallProgress,
+};
+use crate::security::permissions::{Decision, Permissions};
+use crate::security::sandbox::Sandbox;
+use crate::security::workspace::Workspace;
 use crate::skills::Skill;
-use crate::tools::ToolRegistry;
+use crate::tools::todo::{self, TodoItem};
+use crate::tools::{ToolError, ToolRegistry};
 use colored::Colorize;
-use std::collections::HashSet;
+use policy::{LoopPolicy, TurnState, TurnToolCall};
+use review::{FileChange, HunkReview};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
 
 /// Outcome of a single tool dispatch. The agent loop uses this to (a)
 /// route stats correctly and (b) decide whether Pattern 4 (failed-
@@ -53,6 +72,13 @@ pub struct SessionStats {
     pub llm_calls: u64,
     pub prompt_tokens: u64,
     pub completion_tokens: u64,
+    /// Part of `prompt_tokens` the server reused from its prefix cache.
+    /// Only backends that report it (llama.cpp, vLLM) contribute.
+    pub cached_prompt_tokens: u64,
+    /// Summed server timings over the calls that reported them; see
+    /// `llm::Timings`. Rates are derived from these, not from
+    /// `prompt_tokens`, since cached tokens cost no prefill time.
+    pub timings: Timings,
     pub tool_calls_ok: u64,
     pub tool_calls_failed: u64,
     /// Tool dispatches that were short-circuited by the harness
@@ -62,6 +88,9 @@ pub struct SessionStats {
     pub tool_calls_skipped: u64,
     pub text_to_tool_fallbacks: u64,
     pub reprompts: u64,
+    /// LLM calls answered per backend label, populated only when
+    /// `[[llm.fallbacks]]` is configured. Sorted for stable output.
+    pub backend_calls: std::collections::BTreeMap<String, u64>,
 }
 
 impl SessionStats {
@@ -73,6 +102,19 @@ impl SessionStats {
         if let Some(ct) = usage.completion_tokens {
             self.completion_tokens += ct;
         }
+        if let Some(cached) = usage.cached_prompt_tokens {
+            self.cached_prompt_tokens += cached;
+        }
+        if let Some(t) = usage.timings {
+            self.timings.prompt_eval_tokens += t.prompt_eval_tokens;
+            self.timings.prompt_eval_ms += t.prompt_eval_ms;
+            self.timings.eval_tokens += t.eval_tokens;
+            self.timings.eval_ms += t.eval_ms;
+            self.timings.load_ms += t.load_ms;
+        }
+        if let Some(ref backend) = usage.backend {
+            *self.backend_calls.entry(backend.clone()).or_insert(0) += 1;
+        }
     }
 
     pub fn record_tool_call(&mut self, kind: ToolResultKind) {
@@ -87,6 +129,26 @@ impl SessionStats {
         self.prompt_tokens + self.completion_tokens
     }
 
+    /// Share of prompt tokens served from the prefix cache, once the
+    /// backend has reported any cache hits.
+    pub fn prompt_cache_hit_rate(&self) -> Option<f64> {
+        if self.cached_prompt_tokens == 0 || self.prompt_tokens == 0 {
+            None
+        } else {
+            Some(self.cached_prompt_tokens as f64 / self.prompt_tokens as f64 * 100.0)
+        }
+    }
+
+    /// Prompt evaluation (prefill) throughput in tokens per second.
+    pub fn prefill_tokens_per_sec(&self) -> Option<f64> {
+        tokens_per_sec(self.timings.prompt_eval_tokens, self.timings.prompt_eval_ms)
+    }
+
+    /// Generation (decode) throughput in tokens per second.
+    pub fn decode_tokens_per_sec(&self) -> Option<f64> {
+        tokens_per_sec(self.timings.eval_tokens, self.timings.eval_ms)
+    }
+
     /// Total tool dispatches counted toward the success-rate
     /// denominator. Skipped dispatches are excluded — they didn't
     /// run, so they aren't ok-or-fail.
@@ -104,6 +166,14 @@ impl SessionStats {
     }
 }
 
+fn tokens_per_sec(tokens: u64, ms: f64) -> Option<f64> {
+    if tokens == 0 || ms <= 0.0 {
+        None
+    } else {
+        Some(tokens as f64 / (ms / 1000.0))
+    }
+}
+
 const MAX_TOOL_OUTPUT_CHARS: usize = 50_000;
 
 /// Compaction trigger: fires when the conversation memory is estimated
@@ -111,8 +181,9 @@ const MAX_TOOL_OUTPUT_CHARS: usize = 50_000;
 /// `num_ctx = 8192` compacts at ~60 % utilisation — well before the
 /// "lost in the middle" decay zone (~80 % +) that hurts task quality.
 ///
-/// Token count is approximated as `chars / 4`; we don't need the real
-/// BPE count to make a reasonable decision. The previous trigger
+/// Tokens are counted by the agent's `TokenCounter`: the backend's
+/// tokenizer when it has one, else an estimate that counts CJK
+/// characters one each and other text as `chars / 4`. The previous trigger
 /// (`message_count > 40`) failed for tool-heavy tasks where 13 LLM
 /// calls produced ~30 messages but ~6.7 K tokens of state — past the
 /// decay zone yet under the message threshold.
@@ -120,47 +191,124 @@ pub const DEFAULT_COMPACTION_TOKEN_THRESHOLD: usize = 5000;
 
 const SUMMARIZE_KEEP_RECENT: usize = 10;
 
-/// Approximate token count of a slice of messages. Uses `chars / 4`,
-/// the standard rough proxy for English-heavy text on BPE tokenisers.
-/// Safe to over- or under-estimate by ~30 %; the trigger threshold has
-/// plenty of margin baked in.
-pub fn approx_token_count(messages: &[Message]) -> usize {
-    messages.iter().map(|m| m.content.chars().count() / 4).sum()
+/// Prefix of the system message that carries a compaction summary.
+const SUMMARY_PREFIX: &str = "Previous conversation summary: ";
+
+/// Earlier summaries kept verbatim when compacting again. Leaving them
+/// (and the system prompt) untouched keeps the start of the prompt
+/// byte-identical, so servers with prefix caching don't re-evaluate it.
+/// Past this many, everything is folded into a single fresh summary.
+const MAX_PRESERVED_SUMMARIES: usize = 3;
+
+/// Shape of a compaction summary. The file list and open tasks are the
+/// details free-text summaries most often dropped.
+fn compaction_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "summary": {"type": "string"},
+            "files": {"type": "array", "items": {"type": "string"}},
+            "open_tasks": {"type": "array", "items": {"type": "string"}}
+        },
+        "required": ["summary"],
+        "additionalProperties": false
+    })
 }
 
-/// Approximate token count of what the LLM provider will actually see:
-/// messages PLUS tool definitions. The tool definitions are passed
-/// alongside `messages` to `chat()` and count toward Ollama's
-/// `prompt_tokens` — without including them, a 12-tool registry
-/// (~2 K tokens of description + schema) is invisible to the
-/// compaction trigger and we'd never compact when the per-call
-/// budget actually exceeds the threshold.
-///
-/// Each `ToolDefinition` is approximated as
-/// `name + description + parameters_json + per-tool framing slack`,
-/// `/ 4`. Slack accounts for JSON wrapping and field-name overhead
-/// that real BPE tokenisers don't compress as tightly as natural text.
-pub fn approx_token_count_with_tools(
-    messages: &[Message],
+/// The text stored after `SUMMARY_PREFIX` for a reply matching
+/// `compaction_schema`.
+fn render_compaction_summary(value: &serde_json::Value) -> String {
+    let list = |key: &str| -> Vec<&str> {
+        value[key]
+            .as_array()
+            .map(|items| items.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default()
+    };
+    let mut text = value["summary"]
+        .as_str()
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    let files = list("files");
+    if !files.is_empty() {
+        text.push_str(&format!("\nFiles: {}", files.join(", ")));
+    }
+    let tasks = list("open_tasks");
+    if !tasks.is_empty() {
+        text.push_str("\nOpen tasks:");
+        for task in tasks {
+            text.push_str(&format!("\n- {}", task));
+        }
+    }
+    text
+}
+
+/// Prefix of the system message that carries the todo list through a
+/// compaction. It sits after the summaries and is rebuilt each time.
+const TODO_SNAPSHOT_PREFIX: &str = "Current todo list:";
+
+/// Sum of per-message counts. Counting message by message (rather than
+/// the joined transcript) lets a caching counter reuse every unchanged
+/// message across turns.
+pub fn count_message_tokens(counter: &dyn TokenCounter, messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            counter.count(&m.content) + m.attachments.len() * attachment::ATTACHMENT_TOKEN_ESTIMATE
+        })
+        .sum()
+}
+
+/// Each `ToolDefinition` is counted as `name + description +
+/// parameters_json` plus a fixed framing allowance for the JSON
+/// wrapping and field names that real tokenisers don't compress as
+/// tightly as natural text.
+pub fn count_tool_tokens(
+    counter: &dyn TokenCounter,
     tools: &[crate::llm::ToolDefinition],
 ) -> usize {
-    const PER_TOOL_FRAMING_CHARS: usize = 40;
-    let msg_tokens = approx_token_count(messages);
-    let tool_chars: usize = tools
+    const PER_TOOL_FRAMING_TOKENS: usize = 10;
+    tools
         .iter()
         .map(|t| {
-            t.name.len()
-                + t.description.len()
-                + t.parameters.to_string().len()
-                + PER_TOOL_FRAMING_CHARS
+            counter.count(&t.name)
+                + counter.count(&t.description)
+                + counter.count(&t.parameters.to_string())
+                + PER_TOOL_FRAMING_TOKENS
         })
-        .sum();
-    msg_tokens + tool_chars / 4
+        .sum()
+}
+
+/// Snapshot of how much of the context budget the conversation uses.
+/// Rendered by the `/context` REPL command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextUsage {
+    pub messages: usize,
+    pub message_tokens: usize,
+    pub tool_tokens: usize,
+    pub compaction_threshold: usize,
+    /// `TokenCounter::describe()` of the counter that produced the numbers.
+    pub counter: String,
+}
+
+impl ContextUsage {
+    pub fn total(&self) -> usize {
+        self.message_tokens + self.tool_tokens
+    }
 }
 
+/// Tail of a failed `[agent.verify]` run handed back to the model.
+/// Build and test tools print their summary last.
+const MAX_VERIFY_OUTPUT_CHARS: usize = 8000;
+
+/// Worker threads for running one response's read-only tool calls
+/// concurrently. Small: these are mostly file reads and greps.
+const MAX_PARALLEL_TOOLS: usize = 4;
+
 /// Tools that observe the workspace without modifying it. Used by the
 /// premature-exit detector: a turn that only invoked tools from this set
-/// hasn't actually acted on the user's request yet.
+/// hasn't actually acted on the user's request yet. `todo` only updates
+/// the plan, so it belongs here too.
 const READ_ONLY_TOOLS: &[&str] = &[
     "read_file",
     "list_dir",
@@ -168,6 +316,7 @@ const READ_ONLY_TOOLS: &[&str] = &[
     "grep",
     "web_fetch",
     "web_search",
+    "todo",
 ];
 
 fn is_read_only_tool(name: &str) -> bool {
@@ -199,8 +348,51 @@ pub struct Agent {
     /// it via the `remember` tool. None means no memory backend (tests,
     /// or runs where the user disabled persistent memory).
     on_remember: Option<RememberCallback>,
+    /// Set by the Ctrl+C handler. Polled between LLM chunks, between
+    /// tool calls and by long-running tools. Shared with subagent loops
+    /// so one interrupt unwinds the whole turn.
+    cancel: CancelToken,
+    /// Counts tokens for the compaction trigger and `/context`. Defaults
+    /// to the offline heuristic; `main.rs` swaps in the backend's
+    /// tokenizer when the provider has one.
+    token_counter: Box<dyn TokenCounter>,
+    /// Receives thinking-trace text as it streams, separately from the
+    /// answer tokens passed to `on_token`. None drops the trace.
+    on_reasoning: Option<ReasoningCallback>,
+    /// Receives progress of tool calls while their arguments stream in;
+    /// see `Agent::set_on_tool_progress`. None hides it.
+    on_tool_progress: Option<ToolProgressCallback>,
+    /// Decides whether a turn that ran out of budget may go on; see
+    /// `Agent::set_on_budget`. None ends the turn at the limit.
+    on_budget: Option<BudgetCallback>,
+    /// Picks which hunks of an edit to write when the edit needs
+    /// approval; see `Agent::set_on_review`. None: plain yes/no approval.
+    on_review: Option<ReviewCallback>,
+    /// End-of-turn reprompt checks, in the order they are tried.
+    loop_policies: Vec<Box<dyn LoopPolicy>>,
+    /// Providers for roles routed away from `llm` via `[llm.roles]`.
+    /// A role with no entry here uses `llm`.
+    role_llms: HashMap<ModelRole, Box<dyn LlmProvider>>,
+    /// Images for the next user message (`@file` in the REPL).
+    pending_attachments: Vec<Attachment>,
+    /// False when the model is known not to accept images; `read_file`
+    /// on an image then answers in text instead of attaching it.
+    vision: bool,
+    /// The model's plan, maintained through the `todo` tool.
+    todos: Vec<TodoItem>,
+    /// Where files are saved before the agent changes them. None: no
+    /// checkpoints (tests, embedders that don't want them).
+    checkpoints: Option<CheckpointStore>,
+    /// Allow / ask / deny rules, starting from `config.permissions`.
+    /// Shared so the approval prompt can add "always allow" rules while
+    /// a turn is running.
+    permissions: Rc<Permissions>,
 }
 
+/// Tool result for a call a `[[permissions.deny]]` rule blocks.
+const DENIED_BY_RULE: &str =
+    "Tool call blocked by a permission rule. Don't retry it; find another way or ask the user.";
+
 /// Hard cap on subagent nesting. Phase A keeps it at 1 — a subagent
 /// itself cannot spawn further subagents. Lift later if a real workflow
 /// needs deeper delegation.
@@ -211,6 +403,44 @@ pub const MAX_SUBAGENT_DEPTH: usize = 1;
 /// success, or a printable error on failure.
 pub type RememberCallback = Box<dyn Fn(&str) -> Result<i64, String>>;
 
+/// Sink for reasoning tokens; see `Agent::set_on_reasoning`.
+pub type ReasoningCallback = Box<dyn FnMut(&str)>;
+
+/// Sink for streamed tool-call progress. `None` marks the end of the
+/// call, so a live status line can be cleared before tools run.
+pub type ToolProgressCallback = Box<dyn FnMut(Option<&ToolCallProgress>)>;
+
+/// Asked when a turn runs out of `Budget`, with the limit that was hit
+/// (in the budget's unit). Returns how much more to allow, or None to
+/// end the turn.
+pub type BudgetCallback = Box<dyn FnMut(Budget, u64) -> Option<u64>>;
+
+/// Shown each file an edit would change; returns which hunks to keep.
+pub type ReviewCallback = Box<dyn FnMut(&FileChange) -> HunkReview>;
+
+/// Which part of the agent an LLM call serves. Lets `[llm.roles]` put a
+/// small, fast model on summarisation and page extraction while the main
+/// loop keeps the big one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelRole {
+    Main,
+    Subagent,
+    Compaction,
+    Extraction,
+}
+
+impl ModelRole {
+    /// Key used in `[llm.roles]`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModelRole::Main => "main",
+            ModelRole::Subagent => "subagent",
+            ModelRole::Compaction => "compaction",
+            ModelRole::Extraction => "extraction",
+        }
+    }
+}
+
 /// How an agent loop iteration concluded. Surfaced from
 /// `process_message_full` and consumed by `run_subagent` so the parent
 /// can correctly classify a subagent's outcome (success vs. LLM-side
@@ -224,6 +454,13 @@ pub enum ExitReason {
     LlmError(String),
     /// Hit the adaptive iteration cap without a final answer.
     MaxIterations,
+    /// The turn used up `max_turn_tokens` without a final answer.
+    TokenBudget,
+    /// The turn ran past `max_turn_duration` without a final answer.
+    TimeBudget,
+    /// The user pressed Ctrl+C. Memory holds everything up to the
+    /// interrupt, with a stub result for any tool call that never ran.
+    Cancelled,
 }
 
 impl ExitReason {
@@ -233,6 +470,47 @@ impl ExitReason {
     pub fn is_success(&self) -> bool {
         matches!(self, ExitReason::Answered)
     }
+
+    /// Process exit status for single-shot mode, so scripts can tell an
+    /// answer from an error or a budget running out. 2 is left to clap
+    /// for usage errors; 130 is the shell's convention for SIGINT.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Answered => 0,
+            ExitReason::LlmError(_) => 1,
+            ExitReason::MaxIterations => 3,
+            ExitReason::TokenBudget => 4,
+            ExitReason::TimeBudget => 5,
+            ExitReason::Cancelled => 130,
+        }
+    }
+}
+
+/// One of the per-turn limits on the agent loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Iterations,
+    Tokens,
+    Time,
+}
+
+impl Budget {
+    pub fn name(self) -> &'static str {
+        match self {
+            Budget::Iterations => "iteration",
+            Budget::Tokens => "token",
+            Budget::Time => "time",
+        }
+    }
+
+    /// What the budget's amounts count.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Budget::Iterations => "iterations",
+            Budget::Tokens => "tokens",
+            Budget::Time => "seconds",
+        }
+    }
 }
 
 /// RAII guard that swaps the parent's per-conversation state out for a
@@ -241,13 +519,14 @@ impl ExitReason {
 /// guard's lifetime; child loop work goes through `guard.agent.*`.
 ///
 /// Drop runs even on panic unwind, so a panic inside the child loop
-/// doesn't leak corrupted memory / read_paths / resumed / depth state
-/// into the next parent turn.
+/// doesn't leak corrupted memory / read_paths / todos / resumed / depth
+/// state into the next parent turn.
 struct SubagentGuard<'a> {
     agent: &'a mut Agent,
     saved_memory: Vec<Message>,
     saved_read_paths: HashSet<String>,
     saved_searched_queries: HashSet<String>,
+    saved_todos: Vec<TodoItem>,
     saved_resumed: bool,
 }
 
@@ -260,6 +539,9 @@ impl<'a> SubagentGuard<'a> {
         // context, different goal). Restored on Drop so the parent's
         // turn-local set is unaffected.
         let saved_searched_queries = std::mem::take(&mut agent.searched_queries);
+        // The child plans its own task; a `todo` call in it must not
+        // replace the parent's list.
+        let saved_todos = std::mem::take(&mut agent.todos);
         let saved_resumed = agent.resumed;
         agent.resumed = false;
         agent.subagent_depth += 1;
@@ -268,6 +550,7 @@ impl<'a> SubagentGuard<'a> {
             saved_memory,
             saved_read_paths,
             saved_searched_queries,
+            saved_todos,
             saved_resumed,
         }
     }
@@ -278,6 +561,7 @@ impl<'a> Drop for SubagentGuard<'a> {
         self.agent.memory = std::mem::take(&mut self.saved_memory);
         self.agent.read_paths = std::mem::take(&mut self.saved_read_paths);
         self.agent.searched_queries = std::mem::take(&mut self.saved_searched_queries);
+        self.agent.todos = std::mem::take(&mut self.saved_todos);
         self.agent.resumed = self.saved_resumed;
         // Saturating decrement just in case Drop fires twice via some
         // future refactor — we never want to wrap into usize::MAX.
@@ -294,11 +578,32 @@ pub struct AgentConfig {
     pub context_compression: bool,
     /// Token threshold above which the conversation is summarised down
     /// to system prompt + last `SUMMARIZE_KEEP_RECENT` messages. Counted
-    /// via `approx_token_count`. Default: `DEFAULT_COMPACTION_TOKEN_THRESHOLD`.
+    /// with the agent's `TokenCounter`. Default: `DEFAULT_COMPACTION_TOKEN_THRESHOLD`.
     pub compaction_token_threshold: usize,
     /// If set, structured per-event session stats are appended as JSON Lines.
     /// One object per tool call plus a final `session_end` summary line.
     pub stats_jsonl_path: Option<std::path::PathBuf>,
+    /// Keep the model's thinking trace in `memory` as a `<think>` block
+    /// ahead of the answer. Off by default: the trace is usually longer
+    /// than the answer and re-sending it every turn eats the context
+    /// window without helping the next step.
+    pub keep_reasoning: bool,
+    /// Prompt plus completion tokens one turn may spend. None: no limit.
+    pub max_turn_tokens: Option<u64>,
+    /// Wall-clock limit for one turn, checked between model calls, so a
+    /// single slow call can overrun it. None: no limit.
+    pub max_turn_duration: Option<std::time::Duration>,
+    /// `[agent.policies]` overrides, by policy name. A policy with no
+    /// entry runs with its own reprompt text.
+    pub loop_policies: BTreeMap<String, LoopPolicyConfig>,
+    /// Check to run before a turn that changed something may end.
+    pub verify: Option<VerifyConfig>,
+    /// `[permissions]` rules, consulted before `permission_mode`.
+    pub permissions: PermissionsConfig,
+    /// Set when `[workspace] confine` is on: file tools may not leave it.
+    pub workspace: Option<Workspace>,
+    /// `[sandbox]`, for the verify command. `shell` holds its own copy.
+    pub sandbox: Option<Sandbox>,
 }
 
 impl Default for AgentConfig {
@@ -311,13 +616,21 @@ impl Default for AgentConfig {
             context_compression: true,
             compaction_token_threshold: DEFAULT_COMPACTION_TOKEN_THRESHOLD,
             stats_jsonl_path: None,
+            keep_reasoning: false,
+            max_turn_tokens: None,
+            max_turn_duration: None,
+            loop_policies: BTreeMap::new(),
+            verify: None,
+            permissions: PermissionsConfig::default(),
+            workspace: None,
+            sandbox: None,
         }
     }
 }
 
 /// Append a single JSON object as a line to the stats JSONL sink, if enabled.
 /// Failures are deliberately silent — observability must never break the agent.
-fn write_stats_event(path: &Option<std::path::PathBuf>, event: serde_json::Value) {
+pub(crate) fn write_stats_event(path: &Option<std::path::PathBuf>, event: serde_json::Value) {
     if let Some(p) = path {
         if let Ok(mut f) = std::fs::OpenOptions::new()
             .create(true)
@@ -354,11 +667,20 @@ fn emit_session_end(path: &Option<std::path::PathBuf>, stats: &SessionStats, rea
             "llm_calls": stats.llm_calls,
             "prompt_tokens": stats.prompt_tokens,
             "completion_tokens": stats.completion_tokens,
+            "cached_prompt_tokens": stats.cached_prompt_tokens,
+            "prompt_eval_tokens": stats.timings.prompt_eval_tokens,
+            "prompt_eval_ms": stats.timings.prompt_eval_ms,
+            "eval_tokens": stats.timings.eval_tokens,
+            "eval_ms": stats.timings.eval_ms,
+            "load_ms": stats.timings.load_ms,
+            "prefill_tokens_per_sec": stats.prefill_tokens_per_sec(),
+            "decode_tokens_per_sec": stats.decode_tokens_per_sec(),
             "tool_calls_ok": stats.tool_calls_ok,
             "tool_calls_failed": stats.tool_calls_failed,
             "tool_calls_skipped": stats.tool_calls_skipped,
             "text_to_tool_fallbacks": stats.text_to_tool_fallbacks,
             "reprompts": stats.reprompts,
+            "backend_calls": stats.backend_calls,
         }),
     );
 }
@@ -371,7 +693,8 @@ impl Agent {
         skills: &[Skill],
     ) -> Self {
         let memory = vec![Message::system(&prompt::system_prompt(skills))];
-        Self {
+        let permissions = Rc::new(Permissions::new(config.permissions.clone()));
+        let mut agent = Self {
             llm,
             tools,
             memory,
@@ -382,9 +705,231 @@ impl Agent {
             resumed: false,
             subagent_depth: 0,
             on_remember: None,
+            cancel: CancelToken::new(),
+            token_counter: Box::new(HeuristicCounter),
+            on_reasoning: None,
+            on_tool_progress: None,
+            on_budget: None,
+            on_review: None,
+            loop_policies: Vec::new(),
+            role_llms: HashMap::new(),
+            pending_attachments: Vec::new(),
+            vision: true,
+            todos: Vec::new(),
+            checkpoints: None,
+            permissions,
+        };
+        // The built-in policies go through the same path as custom ones.
+        for policy in policy::default_policies() {
+            agent.add_loop_policy(policy);
+        }
+        agent
+    }
+
+    /// Attach images to the next user message.
+    pub fn queue_attachments(&mut self, attachments: Vec<Attachment>) {
+        self.pending_attachments.extend(attachments);
+    }
+
+    /// Record whether the model accepts images. Defaults to true, so an
+    /// unprobed model gets the image and the server decides.
+    pub fn set_vision(&mut self, supported: bool) {
+        self.vision = supported;
+    }
+
+    pub fn supports_vision(&self) -> bool {
+        self.vision
+    }
+
+    pub fn todos(&self) -> &[TodoItem] {
+        &self.todos
+    }
+
+    /// Restore the todo list of a resumed session.
+    pub fn set_todos(&mut self, todos: Vec<TodoItem>) {
+        self.todos = todos;
+    }
+
+    /// Save files to `store` before the agent's file tools change them,
+    /// one checkpoint per turn.
+    pub fn set_checkpoint_store(&mut self, store: CheckpointStore) {
+        self.checkpoints = Some(store);
+    }
+
+    pub fn checkpoints(&self) -> Option<&CheckpointStore> {
+        self.checkpoints.as_ref()
+    }
+
+    pub fn permissions(&self) -> Rc<Permissions> {
+        Rc::clone(&self.permissions)
+    }
+
+    /// Snapshot the files `call` is about to write, if it is a file tool.
+    /// A failed snapshot is reported but doesn't block the edit.
+    fn checkpoint_before(&self, call: &ToolCall) {
+        if self.checkpoints.is_none() {
+            return;
+        }
+        let paths = match call.name.as_str() {
+            "write_file" | "edit_file" => call.arguments["path"]
+                .as_str()
+                .map(|p| vec![p.to_string()])
+                .unwrap_or_default(),
+            "apply_diff" => crate::tools::apply_diff::target_paths(&call.arguments),
+            _ => return,
+        };
+        for path in paths {
+            self.checkpoint_file(&path);
+        }
+    }
+
+    fn checkpoint_file(&self, path: &str) {
+        let Some(store) = self.checkpoints.as_ref() else {
+            return;
+        };
+        if let Err(e) = store.snapshot(std::path::Path::new(path)) {
+            eprintln!(
+                "  {}",
+                format!("[checkpoint: could not save {}: {}]", path, e).yellow()
+            );
+        }
+    }
+
+    /// Run `changes` past the review callback and write the hunks it
+    /// keeps. The result names the applied and rejected hunks per file.
+    fn review_edit(
+        &mut self,
+        tool_call: &ToolCall,
+        changes: Vec<FileChange>,
+    ) -> (String, ToolResultKind) {
+        let mut reports = Vec::new();
+        let mut wrote = false;
+        let mut failed = false;
+        let mut always = false;
+        for change in &changes {
+            if change.hunks.is_empty() {
+                reports.push(format!("No changes to '{}'.", change.path));
+                continue;
+            }
+            let mut review = match self.on_review.as_mut() {
+                Some(cb) => cb(change),
+                None => HunkReview::accept_all(change),
+            };
+            review.accepted.resize(change.hunks.len(), false);
+            always |= review.always;
+            let content = review
+                .edited
+                .clone()
+                .unwrap_or_else(|| change.apply(&review.accepted));
+            let keep_as_is = match &change.old {
+                Some(old) => *old == content,
+                // Don't create a file none of whose lines were kept.
+                None => review.edited.is_none() && !review.accepted.contains(&true),
+            };
+            if !keep_as_is {
+                self.checkpoint_file(&change.path);
+                if let Err(e) = std::fs::write(&change.path, &content) {
+                    reports.push(format!("Failed to write '{}': {}", change.path, e));
+                    failed = true;
+                    continue;
+                }
+                wrote = true;
+            }
+            reports.push(review::describe(change, &review));
+        }
+        if always {
+            self.allow_file_edits(tool_call, changes.len());
+        }
+        let nothing_kept = !wrote && changes.iter().any(|c| !c.hunks.is_empty());
+        let kind = if failed || nothing_kept {
+            ToolResultKind::Failure
+        } else {
+            ToolResultKind::Success
+        };
+        (reports.join("\n"), kind)
+    }
+
+    /// Save an always-allow rule for an edit reviewed with "always". The
+    /// rule names the one file the call touches, so an edit spanning
+    /// several files is only applied this time.
+    fn allow_file_edits(&self, tool_call: &ToolCall, files: usize) {
+        let note = if files != 1 {
+            "(Applied; always-allow needs an edit to a single file)".to_string()
+        } else {
+            match self
+                .permissions
+                .always_allow(&tool_call.name, &tool_call.arguments)
+            {
+                Ok(Some(file)) => format!("(Always allowed; saved to {})", file.display()),
+                Ok(None) => "(Always allowed for this session)".to_string(),
+                Err(e) => format!("(Allowed for this session; could not save rule: {})", e),
+            }
+        };
+        eprintln!("  {}", note.dimmed());
+    }
+
+    /// Serve `role` with `llm` instead of the main provider.
+    pub fn set_role_llm(&mut self, role: ModelRole, llm: Box<dyn LlmProvider>) {
+        self.role_llms.insert(role, llm);
+    }
+
+    /// Provider for `role`: its own entry if one was set, else `llm`.
+    fn llm_for(&self, role: ModelRole) -> &dyn LlmProvider {
+        self.role_llms.get(&role).unwrap_or(&self.llm).as_ref()
+    }
+
+    pub fn set_token_counter(&mut self, counter: Box<dyn TokenCounter>) {
+        self.token_counter = counter;
+    }
+
+    /// Tool definitions offered to the model this turn: the read-only
+    /// subset in plan mode, and never `subagent` inside a subagent.
+    fn turn_tool_definitions(&self) -> Vec<crate::llm::ToolDefinition> {
+        let mut tool_defs: Vec<crate::llm::ToolDefinition> = if self.config.plan_mode {
+            self.tools.safe_definitions().to_vec()
+        } else {
+            self.tools.definitions().to_vec()
+        };
+        // Hide `subagent` from child loops. The child can't usefully spawn
+        // another subagent (depth cap = 1), so exposing it just wastes
+        // ~150 tokens of description on every child LLM call and risks the
+        // model emitting calls that always error out.
+        if self.subagent_depth > 0 {
+            tool_defs.retain(|d| d.name != "subagent");
+        }
+        tool_defs
+    }
+
+    fn prompt_tokens(&self, tool_defs: &[crate::llm::ToolDefinition]) -> usize {
+        count_message_tokens(self.token_counter.as_ref(), &self.memory)
+            + count_tool_tokens(self.token_counter.as_ref(), tool_defs)
+    }
+
+    /// Current context usage as seen by the compaction trigger.
+    pub fn context_usage(&self) -> ContextUsage {
+        let tool_defs = self.turn_tool_definitions();
+        ContextUsage {
+            messages: self.memory.len(),
+            message_tokens: count_message_tokens(self.token_counter.as_ref(), &self.memory),
+            tool_tokens: count_tool_tokens(self.token_counter.as_ref(), &tool_defs),
+            compaction_threshold: self.config.compaction_token_threshold,
+            counter: self.token_counter.describe(),
         }
     }
 
+    /// Share a cancellation token with the caller. `main.rs` hands in
+    /// the token its Ctrl+C handler trips; the loop resets it at the
+    /// start of every top-level turn.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    /// The token that interrupts this agent, for work the caller runs
+    /// on its behalf (e.g. `/test`).
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
     /// Wire a callback that the `remember` tool will invoke. Typical
     /// caller (`main.rs`) closes over an `Arc<Mutex<MemoryStore>>` and
     /// the canonical working directory so the fact is scoped to the
@@ -394,6 +939,192 @@ impl Agent {
         self.on_remember = Some(cb);
     }
 
+    /// Route the model's thinking trace to `cb` as it streams. The REPL
+    /// uses this to print reasoning dimmed; without a callback the trace
+    /// is discarded (and only kept in memory under `keep_reasoning`).
+    pub fn set_on_reasoning(&mut self, cb: ReasoningCallback) {
+        self.on_reasoning = Some(cb);
+    }
+
+    /// Report tool calls to `cb` while the model is still generating
+    /// them, so a long `write_file` doesn't look like a hang.
+    pub fn set_on_tool_progress(&mut self, cb: ToolProgressCallback) {
+        self.on_tool_progress = Some(cb);
+    }
+
+    /// Let `cb` extend a turn's budget when it runs out instead of
+    /// stopping. The REPL asks the user; single-shot runs leave it unset.
+    /// Subagent loops never ask and stop at their limit.
+    pub fn set_on_budget(&mut self, cb: BudgetCallback) {
+        self.on_budget = Some(cb);
+    }
+
+    /// Review edits that need approval hunk by hunk through `cb` instead
+    /// of asking yes/no. The REPL shows the diff against the file on disk
+    /// and lets the user pick hunks or open `$EDITOR`.
+    pub fn set_on_review(&mut self, cb: ReviewCallback) {
+        self.on_review = Some(cb);
+    }
+
+    /// Check `policy` after the built-in ones whenever the model is about
+    /// to end a turn. It is configured like them under
+    /// `[agent.policies.<name>]`.
+    pub fn add_loop_policy(&mut self, policy: Box<dyn LoopPolicy>) {
+        self.loop_policies.push(policy);
+    }
+
+    /// The first enabled policy that objects to `turn` ending, with the
+    /// reprompt to send: its configured text if any, else its own.
+    fn policy_reprompt(&self, turn: &TurnState) -> Option<(&str, String)> {
+        self.loop_policies.iter().find_map(|policy| {
+            let settings = self.config.loop_policies.get(policy.name());
+            if settings.is_some_and(|s| !s.enabled) || !policy.should_reprompt(turn) {
+                return None;
+            }
+            let text = settings
+                .and_then(|s| s.reprompt.clone())
+                .unwrap_or_else(|| policy.reprompt().to_string());
+            Some((policy.notice(), text))
+        })
+    }
+
+    /// How much more of `budget` the turn may use, if any.
+    fn extend_budget(&mut self, budget: Budget, limit: u64) -> Option<u64> {
+        if self.subagent_depth > 0 {
+            return None;
+        }
+        self.on_budget
+            .as_mut()
+            .and_then(|cb| cb(budget, limit))
+            .filter(|more| *more > 0)
+    }
+
+    /// Remove the reasoning callback, returning it. `/reasoning off`
+    /// uses this to hide the trace for the rest of the session.
+    pub fn take_on_reasoning(&mut self) -> Option<ReasoningCallback> {
+        self.on_reasoning.take()
+    }
+
+    /// One streamed LLM call for the agent loop. Answer tokens go to
+    /// `on_token` and are teed into the returned string; reasoning goes to
+    /// the `on_reasoning` callback, bracketed by `on_token("")` before the
+    /// first chunk (so the caller can stop its spinner) and
+    /// `on_reasoning("")` once the trace is over. Tool-call progress goes
+    /// to `on_tool_progress` the same way, closed with `None`.
+    fn stream_llm_call(
+        &mut self,
+        tool_defs: &[crate::llm::ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> (Result<LlmResponse, LlmError>, String) {
+        // Child loops run on the subagent model when one is configured.
+        let role = if self.subagent_depth > 0 {
+            ModelRole::Subagent
+        } else {
+            ModelRole::Main
+        };
+        let Self {
+            llm,
+            role_llms,
+            memory,
+            cancel,
+            on_reasoning,
+            on_tool_progress,
+            ..
+        } = self;
+        let llm = role_llms.get(&role).unwrap_or(llm);
+        let streamed = RefCell::new(String::new());
+        let on_token = RefCell::new(on_token);
+        // None until the first reasoning chunk, then whether the trace is
+        // still open (no answer token seen since).
+        let reasoning_open: Cell<Option<bool>> = Cell::new(None);
+        let close_reasoning = |cb: &mut Option<ReasoningCallback>| {
+            if reasoning_open.get() == Some(true) {
+                reasoning_open.set(Some(false));
+                if let Some(cb) = cb.as_mut() {
+                    cb("");
+                }
+            }
+        };
+        let on_reasoning = RefCell::new(on_reasoning);
+        let progress_shown = Cell::new(false);
+        // Answer text that arrives while a tool call's progress line is
+        // up; shown once that line is cleared so the two don't collide.
+        let held = RefCell::new(String::new());
+        let result = llm.chat_streaming_cancellable(
+            memory,
+            tool_defs,
+            &mut |t| {
+                close_reasoning(&mut on_reasoning.borrow_mut());
+                streamed.borrow_mut().push_str(t);
+                if progress_shown.get() {
+                    held.borrow_mut().push_str(t);
+                } else {
+                    (on_token.borrow_mut())(t);
+                }
+            },
+            &mut |t| {
+                let mut cb = on_reasoning.borrow_mut();
+                if let Some(cb) = cb.as_mut() {
+                    if reasoning_open.get().is_none() {
+                        (on_token.borrow_mut())("");
+                    }
+                    cb(t);
+                }
+                reasoning_open.set(Some(true));
+            },
+            &mut |p| {
+                if let Some(cb) = on_tool_progress.as_mut() {
+                    close_reasoning(&mut on_reasoning.borrow_mut());
+                    if !progress_shown.get() {
+                        (on_token.borrow_mut())("");
+                        progress_shown.set(true);
+                    }
+                    cb(Some(p));
+                }
+            },
+            cancel,
+        );
+        close_reasoning(&mut on_reasoning.borrow_mut());
+        if progress_shown.get() {
+            if let Some(cb) = on_tool_progress.as_mut() {
+                cb(None);
+            }
+            let held = held.into_inner();
+            if !held.is_empty() {
+                (on_token.borrow_mut())(&held);
+            }
+        }
+
+        // Providers that only return the trace whole (the default
+        // non-streaming path) still get it shown.
+        if let Ok(ref resp) = result {
+            let mut cb = on_reasoning.borrow_mut();
+            if let (None, Some(r), Some(cb)) =
+                (reasoning_open.get(), resp.reasoning.as_deref(), cb.as_mut())
+            {
+                (on_token.borrow_mut())("");
+                cb(r);
+                cb("");
+            }
+        }
+        (result, streamed.into_inner())
+    }
+
+    /// Assistant message text as stored in `memory`: the answer alone, or
+    /// prefixed with a `<think>` block when `keep_reasoning` is on.
+    fn assistant_memory_text(&self, reasoning: Option<&str>, content: &str) -> String {
+        match reasoning {
+            Some(r) if self.config.keep_reasoning => {
+                if content.is_empty() {
+                    format!("<think>\n{}\n</think>", r)
+                } else {
+                    format!("<think>\n{}\n</think>\n\n{}", r, content)
+                }
+            }
+            _ => content.to_string(),
+        }
+    }
+
     pub fn set_resumed(&mut self, resumed: bool) {
         self.resumed = resumed;
     }
@@ -424,18 +1155,14 @@ impl Agent {
     /// a fallback per-message head+tail truncation kicks in so a
     /// single oversized message can't keep memory in the decay zone.
     fn compress_context(&mut self, tool_defs: &[crate::llm::ToolDefinition]) {
-        if approx_token_count_with_tools(&self.memory, tool_defs)
-            <= self.config.compaction_token_threshold
-        {
+        if self.prompt_tokens(tool_defs) <= self.config.compaction_token_threshold {
             return;
         }
         self.compress_context_with_instruction(None);
         // Summarisation may have been a no-op (history too short to
         // have anything to summarise). If we're still over budget,
         // shrink any individual oversized middle message in place.
-        if approx_token_count_with_tools(&self.memory, tool_defs)
-            > self.config.compaction_token_threshold
-        {
+        if self.prompt_tokens(tool_defs) > self.config.compaction_token_threshold {
             self.truncate_oversized_messages();
         }
     }
@@ -502,25 +1229,59 @@ impl Agent {
             return;
         }
 
+        // Summaries from earlier compactions sit right after the system
+        // prompt. Keep them as they are and summarize only what follows,
+        // unless there are already enough of them to fold together.
+        let earlier_summaries = self.memory[1..keep_from]
+            .iter()
+            .take_while(|m| m.role == Role::System && m.content.starts_with(SUMMARY_PREFIX))
+            .count();
+        let preserved = if earlier_summaries < MAX_PRESERVED_SUMMARIES {
+            earlier_summaries
+        } else {
+            0
+        };
+        if keep_from <= 1 + preserved {
+            return;
+        }
+
         // Build summarization request by draining old messages (avoids cloning)
         // First, split off the recent messages
-        let recent_messages = self.memory.split_off(keep_from);
+        let mut recent_messages = self.memory.split_off(keep_from);
+        // The todo snapshot is replaced below, never summarized.
+        let is_todo_snapshot =
+            |m: &Message| m.role == Role::System && m.content.starts_with(TODO_SNAPSHOT_PREFIX);
+        self.memory.retain(|m| !is_todo_snapshot(m));
+        recent_messages.retain(|m| !is_todo_snapshot(m));
+        if self.memory.len() <= 1 + preserved {
+            // Nothing but the old snapshot to summarize.
+            self.memory.extend(recent_messages);
+            return;
+        }
         // Now self.memory = [system_prompt, old_messages...]
-        // Drain old messages, keeping system_prompt at index 0
-        let system_prompt = self.memory[0].clone();
+        // Drain old messages, keeping system_prompt and preserved summaries
+        let prefix: Vec<Message> = self.memory[..1 + preserved].to_vec();
         // Use the remaining messages (including system prompt) as summarization input
+        let scope = if preserved > 0 {
+            "since the previous summary"
+        } else {
+            "so far"
+        };
         let summarize_prompt = match instruction {
             Some(inst) => format!(
-                "Summarize the conversation so far concisely, preserving key facts, decisions, and context needed for future turns. Additional instructions: {}",
-                inst
+                "Summarize the conversation {} concisely, preserving key facts, decisions, and context needed for future turns. Additional instructions: {}",
+                scope, inst
             ),
-            None => "Summarize the conversation so far concisely, preserving key facts, decisions, and context needed for future turns:".to_string(),
+            None => format!("Summarize the conversation {} concisely, preserving key facts, decisions, and context needed for future turns:", scope),
         };
         self.memory.push(Message::user(&summarize_prompt));
 
         // Call LLM without tools for summarization
-        let summary = match self.llm.chat(&self.memory, &[]) {
-            Ok(resp) => resp.content.unwrap_or_default(),
+        let summary = match self
+            .llm_for(ModelRole::Compaction)
+            .chat_json(&self.memory, &compaction_schema())
+        {
+            Ok(value) => render_compaction_summary(&value),
             Err(_) => {
                 // Restore memory on error
                 self.memory.pop(); // remove summarize request
@@ -535,13 +1296,17 @@ impl Agent {
             return;
         }
 
-        // Rebuild memory: [system_prompt, summary, recent_messages...]
-        self.memory.clear();
-        self.memory.push(system_prompt);
-        self.memory.push(Message::system(&format!(
-            "Previous conversation summary: {}",
-            summary
-        )));
+        // Rebuild memory: [system_prompt, summaries..., recent_messages...]
+        self.memory = prefix;
+        self.memory
+            .push(Message::system(&format!("{}{}", SUMMARY_PREFIX, summary)));
+        if !self.todos.is_empty() {
+            self.memory.push(Message::system(&format!(
+                "{}\n{}",
+                TODO_SNAPSHOT_PREFIX,
+                todo::render_for_model(&self.todos)
+            )));
+        }
         self.memory.extend(recent_messages);
 
         eprintln!(
@@ -577,7 +1342,19 @@ impl Agent {
         on_token: &mut dyn FnMut(&str),
         on_approve: &mut dyn FnMut(&str, &serde_json::Value) -> bool,
     ) -> (String, ExitReason) {
-        self.memory.push(Message::user(user_input));
+        self.retire_images();
+        let attachments = std::mem::take(&mut self.pending_attachments);
+        self.memory
+            .push(Message::user_with_attachments(user_input, attachments));
+
+        // A new top-level turn starts uncancelled. Child loops share the
+        // parent's token and must not clear an interrupt aimed at it.
+        if self.subagent_depth == 0 {
+            self.cancel.reset();
+            if let Some(store) = &self.checkpoints {
+                store.begin_turn(user_input);
+            }
+        }
 
         // Reset per-turn dedup state. A new user message is a new
         // turn — reasking the same web_search query is legitimate
@@ -595,21 +1372,10 @@ impl Agent {
         // compaction threshold can account for tool-definition size
         // (~2 K tokens for whet's 12-tool registry). Without it, a
         // typical bench prompt of 4-5 K total tokens reads as only
-        // 2-3 K of "messages" by `approx_token_count` and never
+        // 2-3 K of "messages" by `count_message_tokens` and never
         // breaches the threshold even though the model is past the
         // decay zone.
-        let mut tool_defs: Vec<crate::llm::ToolDefinition> = if self.config.plan_mode {
-            self.tools.safe_definitions().to_vec()
-        } else {
-            self.tools.definitions().to_vec()
-        };
-        // Hide `subagent` from child loops. The child can't usefully spawn
-        // another subagent (depth cap = 1), so exposing it just wastes
-        // ~150 tokens of description on every child LLM call and risks the
-        // model emitting calls that always error out.
-        if self.subagent_depth > 0 {
-            tool_defs.retain(|d| d.name != "subagent");
-        }
+        let tool_defs = self.turn_tool_definitions();
 
         // Compress context if enabled and threshold exceeded. Counts
         // both messages AND tool definitions (the LLM provider sees
@@ -640,11 +1406,12 @@ impl Agent {
 
         let mut reprompt_count: usize = 0;
         const MAX_REPROMPTS: usize = 1;
-        // Per-turn tool tracking. The premature-exit detector fires only when
-        // the model has invoked at least one read-only tool but no edit/write
-        // tool — i.e. it explored the workspace and then stopped.
-        let mut has_acted: bool = false;
-        let mut has_read: bool = false;
+        // Every tool call of the turn, for the loop policies.
+        let mut turn_calls: Vec<TurnToolCall> = Vec::new();
+        // `[agent.verify]` runs this turn, and how many of `turn_calls`
+        // the latest run already covered.
+        let mut verify_rounds: usize = 0;
+        let mut verified_calls: usize = 0;
         // Pattern 4 input: did the previous iteration's tool execution
         // include any failures? Set at the END of each iteration that
         // ran tools; cleared when the next iteration runs all-success
@@ -658,23 +1425,68 @@ impl Agent {
         // before forcing a stop. Avoids losing tasks that need a few more
         // turns to land while still bounding runaway loops.
         const MAX_PROGRESS_EXTENSION: usize = 5;
-        let base_cap = self.config.max_iterations;
-        let hard_cap = base_cap.saturating_add(MAX_PROGRESS_EXTENSION);
+        let mut base_cap = self.config.max_iterations;
+        let mut hard_cap = base_cap.saturating_add(MAX_PROGRESS_EXTENSION);
         let mut iteration: usize = 0;
         let mut last_progress_iter: usize = 0;
 
+        // Token and time budgets count from the start of this turn.
+        let turn_start = std::time::Instant::now();
+        let tokens_at_start = self.stats.total_tokens();
+        let mut token_limit = self.config.max_turn_tokens;
+        let mut time_limit = self.config.max_turn_duration;
+
         loop {
             // Stop if we've exhausted both base + extension, OR we're past
-            // the base and recent iterations stopped making progress.
-            if iteration >= hard_cap {
-                break;
+            // the base and recent iterations stopped making progress —
+            // unless the budget callback grants more.
+            if iteration >= hard_cap
+                || (iteration >= base_cap && iteration.saturating_sub(last_progress_iter) >= 2)
+            {
+                match self.extend_budget(Budget::Iterations, base_cap as u64) {
+                    Some(more) => {
+                        base_cap = iteration.saturating_add(more as usize);
+                        hard_cap = base_cap.saturating_add(MAX_PROGRESS_EXTENSION);
+                        last_progress_iter = iteration;
+                    }
+                    None => break,
+                }
+            }
+            if let Some(limit) = token_limit {
+                let spent = self.stats.total_tokens() - tokens_at_start;
+                if spent >= limit {
+                    match self.extend_budget(Budget::Tokens, limit) {
+                        Some(more) => token_limit = Some(spent + more),
+                        None => return self.finish_over_budget(Budget::Tokens, limit),
+                    }
+                }
             }
-            if iteration >= base_cap && iteration.saturating_sub(last_progress_iter) >= 2 {
-                break;
+            if let Some(limit) = time_limit {
+                let elapsed = turn_start.elapsed();
+                if elapsed >= limit {
+                    match self.extend_budget(Budget::Time, limit.as_secs()) {
+                        Some(more) => {
+                            time_limit = Some(elapsed + std::time::Duration::from_secs(more))
+                        }
+                        None => return self.finish_over_budget(Budget::Time, limit.as_secs()),
+                    }
+                }
             }
             iteration += 1;
-            let response = match self.llm.chat_streaming(&self.memory, &tool_defs, on_token) {
+            if self.cancel.is_cancelled() {
+                return self.finish_cancelled();
+            }
+            // Tee streamed text so a cancelled response can still be
+            // recorded up to the point the user interrupted it.
+            let (result, streamed) = self.stream_llm_call(&tool_defs, on_token);
+            let response = match result {
                 Ok(resp) => resp,
+                Err(LlmError::Cancelled) => {
+                    if !streamed.is_empty() {
+                        self.memory.push(Message::assistant(&streamed));
+                    }
+                    return self.finish_cancelled();
+                }
                 Err(e) => {
                     emit_session_end_at_depth(
                         &self.config.stats_jsonl_path,
@@ -688,6 +1500,7 @@ impl Agent {
             };
 
             self.stats.record_llm_call(&response.usage);
+            let reasoning = response.reasoning;
 
             // Try to recover tool calls from text if the model didn't use the API
             let mut effective_tool_calls = response.tool_calls;
@@ -709,103 +1522,89 @@ impl Agent {
                     self.stats.text_to_tool_fallbacks += 1;
                     effective_tool_calls = extracted;
                 }
-                // Pattern 1: Re-prompt if model asked a question instead of acting.
-                // Skipped when the user's input itself was open-ended — in that
-                // case the model SHOULD reply with options + a trailing question,
-                // and chastising it for asking back would defeat the
-                // "OPEN-ENDED QUESTIONS" prompt rule.
-                else if reprompt_count < MAX_REPROMPTS
-                    && !user_asked_open_ended
-                    && looks_like_question(&content)
-                {
-                    eprintln!(
-                        "  {}",
-                        "[re-prompt: model asked instead of acting]".yellow()
-                    );
-                    self.stats.reprompts += 1;
-                    reprompt_count += 1;
-                    self.memory.push(Message::assistant(&content));
-                    self.memory.push(Message::user(
-                        "Don't ask questions. Use your tools to take action directly. \
-                         If you're unsure, start by reading files or exploring the project structure.",
-                    ));
-                    continue;
-                }
-                // Pattern 4: Re-prompt if the previous iteration's tools
-                // failed and the model is now exiting with explanatory
-                // text instead of fixing the failure. Catches the
-                // "pytest reported errors → model writes a long
-                // markdown explanation about how the user should fix
-                // it → exits without retrying" anti-pattern observed
-                // in dog-food runs. Requires:
-                //   (a) at least one tool call in the previous iteration failed,
-                //   (b) the current iteration is exiting (no tool calls),
-                //   (c) the final response has non-empty content
-                //       (an empty content + only-reads case is Pattern 3's job).
-                // Skipped when the user input was open-ended, because
-                // an open-ended exchange may legitimately end on a
-                // tradeoff/options reply rather than a fix.
-                else if reprompt_count < MAX_REPROMPTS
-                    && !user_asked_open_ended
-                    && last_iter_had_failure
-                    && !content.trim().is_empty()
-                {
-                    eprintln!(
-                        "  {}",
-                        "[re-prompt: tool call failed — push to fix instead of explain]".yellow()
-                    );
-                    self.stats.reprompts += 1;
-                    reprompt_count += 1;
-                    self.memory.push(Message::assistant(&content));
-                    self.memory.push(Message::user(
-                        "Your last tool call failed and you stopped before fixing it. \
-                         Don't describe the fix — apply it. Edit the failing code, \
-                         re-run the failing command, and only stop when the command \
-                         succeeds. If a dependency is missing, install it; if a path \
-                         is wrong, correct it; if a test reveals a bug, fix the bug \
-                         in the code under test.",
-                    ));
-                    continue;
-                }
-                // Pattern 3: Re-prompt if model is exiting after only reads.
-                // Requires:
-                //   (a) the model invoked at least one read-only tool this turn,
-                //   (b) no edit/write/shell call ran this turn,
-                //   (c) the final response is empty.
-                // Read-only Q&A ("read file → answer in text") is unaffected
-                // because it produces non-empty content.
-                else if reprompt_count < MAX_REPROMPTS
-                    && has_read
-                    && !has_acted
-                    && content.trim().is_empty()
-                {
-                    eprintln!(
-                        "  {}",
-                        "[re-prompt: model stopped after only reads — pushing it to act]".yellow()
-                    );
-                    self.stats.reprompts += 1;
-                    reprompt_count += 1;
-                    self.memory.push(Message::assistant(&content));
-                    self.memory.push(Message::user(
-                        "You've only read files so far. Use your editing tools \
-                         (edit_file / apply_diff / write_file / shell) to make the \
-                         changes the task requires. If you've finished, state in plain \
-                         text what you did so the user can verify.",
-                    ));
-                    continue;
+                // Patterns 1, 3 and 4: let the loop policies send the
+                // model back to work instead of ending the turn.
+                else if reprompt_count < MAX_REPROMPTS {
+                    let turn = TurnState {
+                        user_input,
+                        reply: &content,
+                        user_asked_open_ended,
+                        tool_calls: &turn_calls,
+                        last_batch_failed: last_iter_had_failure,
+                    };
+                    if let Some((notice, reprompt)) = self.policy_reprompt(&turn) {
+                        eprintln!("  {}", format!("[re-prompt: {}]", notice).yellow());
+                        self.stats.reprompts += 1;
+                        reprompt_count += 1;
+                        self.memory.push(Message::assistant(&content));
+                        self.memory.push(Message::user(&reprompt));
+                        continue;
+                    }
                 }
             }
 
             // If still no tool calls after recovery, return the content
             if effective_tool_calls.is_empty() {
                 let content = response.content.unwrap_or_default();
-                self.memory.push(Message::assistant(&content));
-                emit_session_end_at_depth(
-                    &self.config.stats_jsonl_path,
-                    &self.stats,
-                    "answered",
-                    self.subagent_depth,
-                );
+
+                // Before a turn that changed something ends, run the
+                // configured check; a failure goes back to the model as
+                // the result of a `shell` call. Subagents leave it to
+                // the parent, whose turn covers their edits.
+                let acted_since_verify = turn_calls[verified_calls..]
+                    .iter()
+                    .any(|c| c.result.is_success() && !is_read_only_tool(&c.name));
+                if let Some(verify) = self
+                    .config
+                    .verify
+                    .clone()
+                    .filter(|_| acted_since_verify && self.subagent_depth == 0)
+                {
+                    if verify_rounds < verify.max_rounds {
+                        verify_rounds += 1;
+                        verified_calls = turn_calls.len();
+                        match self.run_verify(&verify, verify_rounds) {
+                            Ok(None) => {}
+                            Ok(Some(failure)) => {
+                                let id = format!("verify_{}", verify_rounds);
+                                let mut call_msg =
+                                    Message::assistant_with_tool_calls(vec![ToolCall {
+                                        id: id.clone(),
+                                        name: "shell".to_string(),
+                                        arguments: serde_json::json!({"command": verify.command}),
+                                    }]);
+                                call_msg.content =
+                                    self.assistant_memory_text(reasoning.as_deref(), &content);
+                                self.memory.push(call_msg);
+                                self.memory.push(Message::tool_result(&id, &failure));
+                                continue;
+                            }
+                            Err(_) if self.cancel.is_cancelled() => {
+                                self.memory.push(Message::assistant(&content));
+                                return self.finish_cancelled();
+                            }
+                            Err(e) => eprintln!("  {}", format!("[verify: {}]", e).yellow()),
+                        }
+                    } else {
+                        eprintln!(
+                            "  {}",
+                            format!(
+                                "[verify: `{}` not re-run: round limit ({}) reached]",
+                                verify.command, verify.max_rounds
+                            )
+                            .yellow()
+                        );
+                    }
+                }
+
+                let stored = self.assistant_memory_text(reasoning.as_deref(), &content);
+                self.memory.push(Message::assistant(&stored));
+                emit_session_end_at_depth(
+                    &self.config.stats_jsonl_path,
+                    &self.stats,
+                    "answered",
+                    self.subagent_depth,
+                );
                 return (content, ExitReason::Answered);
             }
 
@@ -820,8 +1619,9 @@ impl Agent {
             let mut iter_had_failure: bool = false;
 
             // Store tool calls in memory — move instead of clone
-            self.memory
-                .push(Message::assistant_with_tool_calls(tool_calls.clone()));
+            let mut call_msg = Message::assistant_with_tool_calls(tool_calls.clone());
+            call_msg.content = self.assistant_memory_text(reasoning.as_deref(), "");
+            self.memory.push(call_msg);
 
             // Notify the caller's on_token closure that the model has
             // committed to action — this lets a "thinking…" spinner /
@@ -831,7 +1631,24 @@ impl Agent {
             // print for callers that just append text.
             on_token("");
 
-            for tool_call in &tool_calls {
+            // Images read this iteration, sent after the tool results.
+            let mut images: Vec<Attachment> = Vec::new();
+            // Results of read-only calls already run ahead, concurrently
+            // with their neighbours, keyed by call index.
+            let mut prefetched: HashMap<usize, Result<String, ToolError>> = HashMap::new();
+
+            for (call_idx, tool_call) in tool_calls.iter().enumerate() {
+                if self.cancel.is_cancelled() {
+                    // Every tool_call id needs a matching result or the
+                    // next request is malformed for OpenAI-style servers.
+                    for skipped in &tool_calls[call_idx..] {
+                        self.memory.push(Message::tool_result(
+                            &skipped.id,
+                            "Cancelled by user before execution.",
+                        ));
+                    }
+                    return self.finish_cancelled();
+                }
                 eprintln!(
                     "  {}",
                     display::format_tool_call_compact(&tool_call.name, &tool_call.arguments).cyan()
@@ -844,13 +1661,42 @@ impl Agent {
                     }
                 }
 
+                if !prefetched.contains_key(&call_idx) && self.runs_in_parallel(tool_call) {
+                    let run: Vec<(&str, &serde_json::Value)> = tool_calls[call_idx..]
+                        .iter()
+                        .take_while(|c| self.runs_in_parallel(c))
+                        .map(|c| (c.name.as_str(), &c.arguments))
+                        .collect();
+                    if run.len() > 1 {
+                        let results =
+                            self.tools
+                                .execute_parallel(&run, MAX_PARALLEL_TOOLS, &self.cancel);
+                        prefetched.extend(
+                            results
+                                .into_iter()
+                                .enumerate()
+                                .map(|(i, r)| (call_idx + i, r)),
+                        );
+                    }
+                }
+
                 let needs_read_first = !self.resumed
                     && (tool_call.name == "edit_file" || tool_call.name == "apply_diff")
                     && tool_call.arguments["path"].as_str().map_or(true, |p| {
                         !self.read_paths.contains(&Self::normalize_tool_path(p))
                     });
 
-                let (result, result_kind) = if needs_read_first {
+                let outside_workspace = self
+                    .config
+                    .workspace
+                    .as_ref()
+                    .and_then(|w| w.violation(&tool_call.name, &tool_call.arguments));
+
+                // Set when the user already saw (and maybe trimmed) the diff.
+                let mut reviewed = false;
+                let (result, result_kind) = if let Some(message) = outside_workspace {
+                    (message, ToolResultKind::Failure)
+                } else if needs_read_first {
                     let p = tool_call.arguments["path"].as_str().unwrap_or("<unknown>");
                     (
                         format!(
@@ -860,6 +1706,11 @@ impl Agent {
                         ),
                         ToolResultKind::Failure,
                     )
+                } else if let Some(prefetched) = prefetched.remove(&call_idx) {
+                    match prefetched {
+                        Ok(output) => (output, ToolResultKind::Success),
+                        Err(e) => (format!("Tool error: {}", e), ToolResultKind::Failure),
+                    }
                 } else if tool_call.name == "subagent" {
                     // Special-cased — needs agent state Tool::execute can't
                     // reach (memory swap, read-paths reset).
@@ -868,6 +1719,9 @@ impl Agent {
                     // Special-cased — needs the on_remember callback for
                     // SQLite, which Tool::execute can't reach.
                     self.dispatch_remember_call(&tool_call.arguments)
+                } else if tool_call.name == "todo" && self.tools.get("todo").is_some() {
+                    // Special-cased — the list is agent state.
+                    self.dispatch_todo_call(&tool_call.arguments)
                 } else if tool_call.name == "web_fetch"
                     && self.tools.get("web_fetch").is_some()
                     && tool_call
@@ -880,6 +1734,16 @@ impl Agent {
                     // focused-extraction pass. Gated by registry presence
                     // so `web_enabled = false` keeps it off.
                     self.dispatch_web_fetch_extract(&tool_call.arguments)
+                } else if tool_call.name == "read_file"
+                    && self.tools.get("read_file").is_some()
+                    && tool_call.arguments["path"]
+                        .as_str()
+                        .and_then(|p| attachment::image_mime_type(std::path::Path::new(p)))
+                        .is_some()
+                {
+                    // Special-cased — a tool result is text-only, so the
+                    // image rides on a user message after the results.
+                    self.dispatch_read_image(&tool_call.arguments, &mut images)
                 } else if tool_call.name == "web_search" && self.tools.get("web_search").is_some() {
                     // Special-cased — checks the per-turn dedup set
                     // BEFORE actually running the network call. A
@@ -896,6 +1760,11 @@ impl Agent {
                     } else {
                         tool.risk_level()
                     };
+                    let decision = self.permission(
+                        &tool_call.name,
+                        &tool_call.arguments,
+                        effective_risk.clone(),
+                    );
 
                     // In plan mode, block non-safe tools
                     if self.config.plan_mode && effective_risk != ToolRiskLevel::Safe {
@@ -904,20 +1773,37 @@ impl Agent {
                                 .to_string(),
                             ToolResultKind::Failure,
                         )
-                    } else if self.needs_approval(effective_risk) {
-                        if !on_approve(&tool_call.name, &tool_call.arguments) {
+                    } else if decision == Decision::Deny {
+                        (DENIED_BY_RULE.to_string(), ToolResultKind::Failure)
+                    } else if decision == Decision::Ask {
+                        let changes = self
+                            .on_review
+                            .is_some()
+                            .then(|| review::proposed_changes(tool_call))
+                            .flatten();
+                        if let Some(changes) = changes {
+                            reviewed = true;
+                            match changes {
+                                Ok(changes) => self.review_edit(tool_call, changes),
+                                Err(e) => (format!("Tool error: {}", e), ToolResultKind::Failure),
+                            }
+                        } else if !on_approve(&tool_call.name, &tool_call.arguments) {
                             (
                                 "Tool execution denied by user.".to_string(),
                                 ToolResultKind::Failure,
                             )
                         } else {
-                            match tool.execute(tool_call.arguments.clone()) {
+                            self.checkpoint_before(tool_call);
+                            match tool
+                                .execute_cancellable(tool_call.arguments.clone(), &self.cancel)
+                            {
                                 Ok(output) => (output, ToolResultKind::Success),
                                 Err(e) => (format!("Tool error: {}", e), ToolResultKind::Failure),
                             }
                         }
                     } else {
-                        match tool.execute(tool_call.arguments.clone()) {
+                        self.checkpoint_before(tool_call);
+                        match tool.execute_cancellable(tool_call.arguments.clone(), &self.cancel) {
                             Ok(output) => (output, ToolResultKind::Success),
                             Err(e) => (format!("Tool error: {}", e), ToolResultKind::Failure),
                         }
@@ -930,16 +1816,18 @@ impl Agent {
                 };
 
                 self.stats.record_tool_call(result_kind);
+                turn_calls.push(TurnToolCall {
+                    name: tool_call.name.clone(),
+                    arguments: tool_call.arguments.clone(),
+                    result: result_kind,
+                });
                 if result_kind.is_failure() {
                     iter_had_failure = true;
                 }
                 if result_kind.is_success() {
                     last_progress_iter = iteration;
-                    if is_read_only_tool(&tool_call.name) {
-                        has_read = true;
-                    } else {
-                        has_acted = true;
-                    }
+                }
+                if result_kind.is_success() && !reviewed {
                     // UX.9: show what actually changed for edit_file / apply_diff.
                     match tool_call.name.as_str() {
                         "edit_file" => {
@@ -992,6 +1880,13 @@ impl Agent {
                     .push(Message::tool_result(&tool_call.id, &result));
             }
 
+            if !images.is_empty() {
+                let names: Vec<&str> = images.iter().map(|a| a.name.as_str()).collect();
+                let note = format!("[Image from read_file: {}]", names.join(", "));
+                self.memory
+                    .push(Message::user_with_attachments(&note, images));
+            }
+
             // Roll the iteration's failure flag forward so Pattern 4
             // sees it on the *next* iteration.
             last_iter_had_failure = iter_had_failure;
@@ -1013,6 +1908,85 @@ impl Agent {
         )
     }
 
+    /// Close out a turn that ran out of its token or time budget.
+    fn finish_over_budget(&mut self, budget: Budget, limit: u64) -> (String, ExitReason) {
+        let (reason, exit) = match budget {
+            Budget::Time => ("time_budget", ExitReason::TimeBudget),
+            _ => ("token_budget", ExitReason::TokenBudget),
+        };
+        emit_session_end_at_depth(
+            &self.config.stats_jsonl_path,
+            &self.stats,
+            reason,
+            self.subagent_depth,
+        );
+        let mut name = budget.name().to_string();
+        name[..1].make_ascii_uppercase();
+        (
+            format!(
+                "{} budget reached ({} {}). The agent could not complete the task.",
+                name,
+                limit,
+                budget.unit()
+            ),
+            exit,
+        )
+    }
+
+    /// Run the `[agent.verify]` check. `Ok(None)` when it passed, else
+    /// the tool result telling the model what failed.
+    fn run_verify(&self, verify: &VerifyConfig, round: usize) -> Result<Option<String>, ToolError> {
+        eprintln!(
+            "  {}",
+            format!("Verify({}) {}/{}", verify.command, round, verify.max_rounds).cyan()
+        );
+        let output = crate::tools::shell::run_command(
+            &verify.command,
+            None,
+            std::time::Duration::from_secs(verify.timeout_secs),
+            self.config.sandbox.as_ref(),
+            &self.cancel,
+        )?;
+        write_stats_event(
+            &self.config.stats_jsonl_path,
+            serde_json::json!({
+                "event": "verify",
+                "command": verify.command,
+                "ok": output.success(),
+            }),
+        );
+        if output.success() {
+            eprintln!("  {}", "[verify: passed]".green());
+            return Ok(None);
+        }
+        eprintln!("  {}", "[verify: failed]".yellow());
+        let mut text = output.text.as_str();
+        if text.len() > MAX_VERIFY_OUTPUT_CHARS {
+            let mut start = text.len() - MAX_VERIFY_OUTPUT_CHARS;
+            while !text.is_char_boundary(start) {
+                start += 1;
+            }
+            text = &text[start..];
+        }
+        Ok(Some(format!(
+            "The configured check `{}` failed after your changes:\n\n{}\n\n\
+             Fix the cause before finishing; the check runs again when you stop.",
+            verify.command, text
+        )))
+    }
+
+    /// Close out a turn the user interrupted. The caller has already put
+    /// whatever ran into memory; this just records the outcome.
+    fn finish_cancelled(&mut self) -> (String, ExitReason) {
+        emit_session_end_at_depth(
+            &self.config.stats_jsonl_path,
+            &self.stats,
+            "cancelled",
+            self.subagent_depth,
+        );
+        ("Cancelled.".to_string(), ExitReason::Cancelled)
+    }
+
     /// Add a path to the set of files that have been read (for read-before-edit tracking).
     pub fn add_read_path(&mut self, path: &str) {
         self.read_paths.insert(Self::normalize_tool_path(path));
@@ -1097,6 +2071,78 @@ impl Agent {
         }
     }
 
+    /// Dispatch handler for `todo`: replace the list and show it.
+    pub(crate) fn dispatch_todo_call(
+        &mut self,
+        args: &serde_json::Value,
+    ) -> (String, ToolResultKind) {
+        match todo::parse_todos(args) {
+            Ok(items) => {
+                display::print_todo_list(&items);
+                self.todos = items;
+                let text = if self.todos.is_empty() {
+                    "Todo list cleared.".to_string()
+                } else {
+                    format!(
+                        "Todo list updated:\n{}",
+                        todo::render_for_model(&self.todos)
+                    )
+                };
+                (text, ToolResultKind::Success)
+            }
+            Err(e) => (format!("Tool error: {}", e), ToolResultKind::Failure),
+        }
+    }
+
+    /// Swap the images in earlier turns for a one-line placeholder. Every
+    /// request re-sends the whole history, so an image the model has
+    /// already looked at would otherwise cost its full base64 each time.
+    fn retire_images(&mut self) {
+        for m in &mut self.memory {
+            if m.attachments.is_empty() {
+                continue;
+            }
+            let labels: Vec<String> = m.attachments.drain(..).map(|a| a.describe()).collect();
+            m.content.push_str(&format!(
+                "\n[image no longer attached: {}]",
+                labels.join(", ")
+            ));
+        }
+    }
+
+    /// Dispatch handler for `read_file` on an image path. Reading it as
+    /// text would hand the model mojibake; instead the image is pushed to
+    /// `images` for the caller to attach, and the tool result says so.
+    /// Models known to lack vision get a plain explanation instead.
+    pub(crate) fn dispatch_read_image(
+        &self,
+        args: &serde_json::Value,
+        images: &mut Vec<Attachment>,
+    ) -> (String, ToolResultKind) {
+        let path = args["path"].as_str().unwrap_or("");
+        if let Err(e) = crate::tools::read_file::check_read(path, attachment::MAX_ATTACHMENT_BYTES)
+        {
+            return (format!("Tool error: {}", e), ToolResultKind::Failure);
+        }
+        let image = match Attachment::from_path(std::path::Path::new(path)) {
+            Ok(image) => image,
+            Err(e) => return (format!("Tool error: {}", e), ToolResultKind::Failure),
+        };
+        if !self.vision {
+            return (
+                format!(
+                    "'{}' is an image ({}) and the current model cannot view images.",
+                    path,
+                    image.describe()
+                ),
+                ToolResultKind::Failure,
+            );
+        }
+        let result = format!("Image {} is attached below.", image.describe());
+        images.push(image);
+        (result, ToolResultKind::Success)
+    }
+
     /// Dispatch handler for `web_search`. Same-turn dedup: if an
     /// identical normalised query has already been issued in this
     /// `process_message_full` call, skip the network call and return
@@ -1153,11 +2199,15 @@ impl Agent {
                 ToolResultKind::Failure,
             );
         }
-        if self.needs_approval(effective_risk) && !on_approve("web_search", args) {
-            return (
-                "Tool execution denied by user.".to_string(),
-                ToolResultKind::Failure,
-            );
+        match self.permission("web_search", args, effective_risk) {
+            Decision::Deny => return (DENIED_BY_RULE.to_string(), ToolResultKind::Failure),
+            Decision::Ask if !on_approve("web_search", args) => {
+                return (
+                    "Tool execution denied by user.".to_string(),
+                    ToolResultKind::Failure,
+                );
+            }
+            _ => {}
         }
         // Record AFTER mode/approval gates so a denial doesn't poison
         // future iterations (user might un-deny by switching modes).
@@ -1244,7 +2294,7 @@ impl Agent {
         );
         let messages = vec![Message::system(&sys), Message::user(&user)];
 
-        match self.llm.chat(&messages, &[]) {
+        match self.llm_for(ModelRole::Extraction).chat(&messages, &[]) {
             Ok(resp) => {
                 self.stats.record_llm_call(&resp.usage);
                 let answer = resp.content.unwrap_or_default().trim().to_string();
@@ -1336,6 +2386,57 @@ impl Agent {
         normalized.to_string_lossy().to_string()
     }
 
+    /// Whether a call can run concurrently with its neighbours: a plain
+    /// registry tool that is `Safe` and needs no approval. Calls that are
+    /// special-cased in the tool loop always run there, one at a time.
+    fn runs_in_parallel(&self, call: &ToolCall) -> bool {
+        let special = match call.name.as_str() {
+            "subagent" | "remember" | "todo" | "web_search" | "git" => true,
+            "web_fetch" => call.arguments.get("prompt").is_some(),
+            "read_file" => call.arguments["path"]
+                .as_str()
+                .and_then(|p| attachment::image_mime_type(std::path::Path::new(p)))
+                .is_some(),
+            _ => false,
+        };
+        let outside_workspace = self
+            .config
+            .workspace
+            .as_ref()
+            .is_some_and(|w| w.violation(&call.name, &call.arguments).is_some());
+        !special
+            && !outside_workspace
+            && self.tools.get(&call.name).is_some_and(|tool| {
+                tool.risk_level() == ToolRiskLevel::Safe
+                    && self.permission(&call.name, &call.arguments, ToolRiskLevel::Safe)
+                        == Decision::Allow
+            })
+    }
+
+    /// Whether a call may run: the first matching `[permissions]` rule
+    /// decides, else the permission mode. A confined workspace turns any
+    /// write outside its root into a question, whatever either says,
+    /// unless a rule denies it outright.
+    fn permission(&self, tool: &str, args: &serde_json::Value, risk: ToolRiskLevel) -> Decision {
+        let decision = self.permissions.decide(tool, args).unwrap_or_else(|| {
+            if self.needs_approval(risk) {
+                Decision::Ask
+            } else {
+                Decision::Allow
+            }
+        });
+        let writes_outside = self
+            .config
+            .workspace
+            .as_ref()
+            .is_some_and(|w| w.writes_outside_root(tool, args));
+        if writes_outside && decision != Decision::Deny {
+            Decision::Ask
+        } else {
+            decision
+        }
+    }
+
     /// Determine if a tool at the given risk level needs user approval.
     fn needs_approval(&self, risk_level: ToolRiskLevel) -> bool {
         match self.config.permission_mode {
@@ -1563,6 +2664,7 @@ mod tests {
     use crate::tools::default_registry;
     use serde_json::json;
     use std::cell::RefCell;
+    use std::rc::Rc;
 
     /// A mock LLM that returns pre-scripted responses in sequence.
     struct MockLlm {
@@ -1595,6 +2697,7 @@ mod tests {
                     content: Some("(no more scripted responses)".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -1633,6 +2736,7 @@ mod tests {
             content: Some("Hello! I'm here to help.".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let response = agent.process_message("Hi there");
@@ -1645,6 +2749,7 @@ mod tests {
             content: None,
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let response = agent.process_message("Hi");
@@ -1665,11 +2770,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "Cargo.toml"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("The project is named whet.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -1677,6 +2784,130 @@ mod tests {
         assert_eq!(response, "The project is named whet.");
     }
 
+    fn read_image_then_answer(path: &str) -> MockLlm {
+        MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: "call_0".to_string(),
+                    name: "read_file".to_string(),
+                    arguments: serde_json::json!({ "path": path }),
+                }],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            LlmResponse {
+                content: Some("The button overlaps the header.".to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+        ])
+    }
+
+    #[test]
+    fn test_read_file_on_image_attaches_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ui.png");
+        std::fs::write(&path, b"\x89PNG\r\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut agent = make_agent(Box::new(read_image_then_answer(path)));
+        agent.process_message("What's wrong in ui.png?");
+
+        let n = agent.memory.len();
+        let result = &agent.memory[n - 3];
+        assert_eq!(result.role, Role::Tool);
+        assert!(result
+            .content
+            .contains("ui.png (image/png, 1 KB) is attached"));
+        let carrier = &agent.memory[n - 2];
+        assert_eq!(carrier.role, Role::User);
+        assert_eq!(carrier.attachments.len(), 1);
+        assert_eq!(carrier.attachments[0].data, b"\x89PNG\r\n");
+    }
+
+    #[test]
+    fn test_read_file_on_image_without_vision_explains() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ui.png");
+        std::fs::write(&path, b"\x89PNG").unwrap();
+
+        let mut agent = make_agent(Box::new(read_image_then_answer(path.to_str().unwrap())));
+        agent.set_vision(false);
+        agent.process_message("Look at ui.png");
+        assert!(agent.memory.iter().all(|m| m.attachments.is_empty()));
+        assert!(agent
+            .memory
+            .iter()
+            .any(|m| m.content.contains("cannot view images")));
+    }
+
+    #[test]
+    fn test_queued_attachments_ride_on_next_user_message() {
+        let llm = MockLlm::new(vec![]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.queue_attachments(vec![Attachment {
+            name: "shot.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: vec![0; 4],
+        }]);
+        agent.process_message("why is this misaligned? @shot.png");
+        assert_eq!(agent.memory[1].attachments.len(), 1);
+        assert!(
+            count_message_tokens(&HeuristicCounter, &agent.memory[1..2])
+                >= attachment::ATTACHMENT_TOKEN_ESTIMATE
+        );
+        // Consumed: the next turn goes out without it.
+        agent.process_message("and now?");
+        let last_user = agent.memory.iter().rfind(|m| m.role == Role::User).unwrap();
+        assert!(last_user.attachments.is_empty());
+    }
+
+    #[test]
+    fn test_images_from_earlier_turns_become_placeholders() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ui.png");
+        std::fs::write(&path, b"\x89PNG\r\n").unwrap();
+
+        let mut agent = make_agent(Box::new(read_image_then_answer(path.to_str().unwrap())));
+        agent.queue_attachments(vec![Attachment {
+            name: "shot.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: vec![0; 4],
+        }]);
+        agent.process_message("Compare shot.png with ui.png");
+        assert_eq!(
+            agent
+                .memory
+                .iter()
+                .filter(|m| !m.attachments.is_empty())
+                .count(),
+            2
+        );
+
+        agent.process_message("Thanks");
+        assert!(agent.memory.iter().all(|m| m.attachments.is_empty()));
+        assert!(agent.memory[1]
+            .content
+            .ends_with("\n[image no longer attached: shot.png (image/png, 1 KB)]"));
+        assert!(agent
+            .memory
+            .iter()
+            .any(|m| m.content.contains("[image no longer attached: ui.png")));
+    }
+
+    #[test]
+    fn test_read_image_refuses_sensitive_path() {
+        let agent = make_agent(Box::new(MockLlm::new(vec![])));
+        let mut images = Vec::new();
+        let (text, kind) =
+            agent.dispatch_read_image(&json!({"path": "~/.ssh/key.png"}), &mut images);
+        assert_eq!(kind, ToolResultKind::Failure);
+        assert!(text.contains("blocked for security"), "{}", text);
+        assert!(images.is_empty());
+    }
+
     #[test]
     fn test_multiple_tool_calls_in_one_response() {
         let llm = MockLlm::new(vec![
@@ -1695,11 +2926,13 @@ mod tests {
                     },
                 ],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("I found 2 things.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -1711,6 +2944,77 @@ mod tests {
         assert_eq!(agent.memory.len(), 6);
     }
 
+    #[test]
+    fn test_parallel_read_only_calls_keep_result_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt"] {
+            std::fs::write(dir.path().join(name), format!("contents of {}", name)).unwrap();
+        }
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let call = |id: &str, name: &str, args: serde_json::Value| ToolCall {
+            id: id.to_string(),
+            name: name.to_string(),
+            arguments: args,
+        };
+        let llm = MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![
+                    call(
+                        "c0",
+                        "read_file",
+                        serde_json::json!({"path": path("a.txt")}),
+                    ),
+                    call(
+                        "c1",
+                        "read_file",
+                        serde_json::json!({"path": path("gone.txt")}),
+                    ),
+                    call(
+                        "c2",
+                        "read_file",
+                        serde_json::json!({"path": path("b.txt")}),
+                    ),
+                    call(
+                        "c3",
+                        "write_file",
+                        serde_json::json!({"path": path("c.txt"), "content": "new"}),
+                    ),
+                ],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            LlmResponse {
+                content: Some("Done.".to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.config.permission_mode = PermissionMode::Yolo;
+        agent.process_message("Compare a and b, then write c");
+
+        let results: Vec<&Message> = agent
+            .memory
+            .iter()
+            .filter(|m| m.role == Role::Tool)
+            .collect();
+        let ids: Vec<&str> = results
+            .iter()
+            .map(|m| m.tool_call_id.as_deref().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["c0", "c1", "c2", "c3"]);
+        assert!(results[0].content.contains("contents of a.txt"));
+        assert!(results[1].content.starts_with("Tool error:"));
+        assert!(results[2].content.contains("contents of b.txt"));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("c.txt")).unwrap(),
+            "new"
+        );
+        assert_eq!(agent.stats.tool_calls_failed, 1);
+    }
+
     #[test]
     fn test_unknown_tool_handled_gracefully() {
         // Sequence: bad tool call (fails) → explanation (Pattern 4
@@ -1726,16 +3030,19 @@ mod tests {
                     arguments: serde_json::json!({}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Sorry, that tool doesn't exist.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Final answer: tool unavailable.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -1768,16 +3075,19 @@ mod tests {
                     arguments: serde_json::json!({"path": "/nonexistent/file.txt"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("The file doesn't exist.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("OK, no file to read.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -1818,12 +3128,14 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             });
         }
         responses.push(LlmResponse {
             content: Some("All done.".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         });
         let llm = MockLlm::new(responses);
         let config = AgentConfig {
@@ -1855,6 +3167,7 @@ mod tests {
                     arguments: serde_json::json!({}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             });
         }
         let llm = MockLlm::new(responses);
@@ -1890,6 +3203,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             });
         }
         let llm = MockLlm::new(responses);
@@ -1906,6 +3220,98 @@ mod tests {
         );
     }
 
+    /// `n` list_dir calls costing `tokens` each, then a final answer.
+    fn tool_calls_then_answer(n: usize, tokens: u64) -> MockLlm {
+        let mut responses: Vec<LlmResponse> = (0..n)
+            .map(|i| LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: format!("call_{}", i),
+                    name: "list_dir".to_string(),
+                    arguments: serde_json::json!({"path": "."}),
+                }],
+                usage: TokenUsage {
+                    prompt_tokens: Some(tokens),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect();
+        responses.push(LlmResponse {
+            content: Some("Finished.".to_string()),
+            tool_calls: vec![],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        });
+        MockLlm::new(responses)
+    }
+
+    #[test]
+    fn test_token_budget_ends_turn() {
+        let config = AgentConfig {
+            max_turn_tokens: Some(250),
+            ..AgentConfig::default()
+        };
+        let llm = tool_calls_then_answer(5, 100);
+        let mut agent = Agent::new(Box::new(llm), default_registry(), config, &[]);
+        let (text, reason) = agent.process_message_full("go", &mut |_| {}, &mut |_, _| true);
+        assert_eq!(reason, ExitReason::TokenBudget);
+        assert_eq!(
+            text,
+            "Token budget reached (250 tokens). The agent could not complete the task."
+        );
+        assert_eq!(agent.stats.llm_calls, 3);
+        assert_eq!(reason.exit_code(), 4);
+    }
+
+    #[test]
+    fn test_budget_callback_extends_turn() {
+        let config = AgentConfig {
+            max_iterations: 1,
+            max_turn_tokens: Some(150),
+            ..AgentConfig::default()
+        };
+        // Enough calls to run past both the iteration cap (1 + 5
+        // progress extension) and the token budget.
+        let llm = tool_calls_then_answer(8, 100);
+        let mut agent = Agent::new(Box::new(llm), default_registry(), config, &[]);
+        let asked = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let log = std::rc::Rc::clone(&asked);
+        agent.set_on_budget(Box::new(move |budget, limit| {
+            log.borrow_mut().push((budget, limit));
+            Some(match budget {
+                Budget::Tokens => 1000,
+                _ => 10,
+            })
+        }));
+        let (text, reason) = agent.process_message_full("go", &mut |_| {}, &mut |_, _| true);
+        assert_eq!(reason, ExitReason::Answered);
+        assert_eq!(text, "Finished.");
+        assert_eq!(
+            *asked.borrow(),
+            vec![(Budget::Tokens, 150), (Budget::Iterations, 1)]
+        );
+    }
+
+    #[test]
+    fn test_time_budget_and_declined_extension() {
+        let config = AgentConfig {
+            max_turn_duration: Some(std::time::Duration::ZERO),
+            ..AgentConfig::default()
+        };
+        let mut agent = Agent::new(
+            Box::new(tool_calls_then_answer(1, 0)),
+            default_registry(),
+            config,
+            &[],
+        );
+        agent.set_on_budget(Box::new(|_, _| None));
+        let (text, reason) = agent.process_message_full("go", &mut |_| {}, &mut |_, _| true);
+        assert_eq!(reason, ExitReason::TimeBudget);
+        assert!(text.starts_with("Time budget reached (0 seconds)."));
+        assert_eq!(agent.stats.llm_calls, 0);
+    }
+
     #[test]
     fn test_memory_accumulates_across_messages() {
         let llm = MockLlm::new(vec![
@@ -1913,11 +3319,13 @@ mod tests {
                 content: Some("First response".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Second response".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -1937,6 +3345,7 @@ mod tests {
             content: Some("ok".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let agent = make_agent(Box::new(llm));
 
@@ -1959,11 +3368,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "Cargo.toml"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done reading.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2014,6 +3425,7 @@ mod tests {
                     content: Some("(no more responses)".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -2035,6 +3447,7 @@ mod tests {
                     content: Some("(no more responses)".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -2048,6 +3461,7 @@ mod tests {
                 content: Some("Hello world!".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         )]);
 
@@ -2076,6 +3490,7 @@ mod tests {
                 content: Some("token1".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         )]);
 
@@ -2103,6 +3518,7 @@ mod tests {
                         arguments: serde_json::json!({"path": "Cargo.toml"}),
                     }],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 },
             ),
             (
@@ -2111,6 +3527,7 @@ mod tests {
                     content: Some("The project.".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 },
             ),
         ]);
@@ -2159,6 +3576,7 @@ mod tests {
                     content: Some("First".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 },
             ),
             (
@@ -2167,6 +3585,7 @@ mod tests {
                     content: Some("Second".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 },
             ),
         ]);
@@ -2199,11 +3618,13 @@ mod tests {
             content: Some("Same result".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let llm2 = MockLlm::new(vec![LlmResponse {
             content: Some("Same result".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
 
         let mut agent1 = make_agent(Box::new(llm1));
@@ -2256,31 +3677,138 @@ mod tests {
         assert!(!agent.needs_approval(ToolRiskLevel::Safe));
     }
 
+    fn shell_call(id: &str, command: &str) -> LlmResponse {
+        LlmResponse {
+            content: None,
+            tool_calls: vec![ToolCall {
+                id: id.to_string(),
+                name: "shell".to_string(),
+                arguments: serde_json::json!({ "command": command }),
+            }],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        }
+    }
+
     #[test]
-    fn test_tool_denied_by_approval_callback() {
-        // shell tool is Dangerous → needs approval in Default mode.
-        // Denial counts as tool_success=false → Pattern 4 fires, so
-        // we provide a 3rd response for the agent to land on.
+    fn test_permission_rules_override_mode() {
+        use crate::config::PermissionRule;
         let llm = MockLlm::new(vec![
-            LlmResponse {
-                content: None,
-                tool_calls: vec![ToolCall {
-                    id: "call_0".to_string(),
-                    name: "shell".to_string(),
-                    arguments: serde_json::json!({"command": "echo hello"}),
-                }],
-                usage: TokenUsage::default(),
-            },
-            LlmResponse {
-                content: Some("I couldn't execute the command.".to_string()),
-                tool_calls: vec![],
-                usage: TokenUsage::default(),
-            },
-            LlmResponse {
-                content: Some("Stopping — user denied.".to_string()),
-                tool_calls: vec![],
-                usage: TokenUsage::default(),
-            },
+            shell_call("c0", "echo allowed"),
+            shell_call("c1", "rm -rf build"),
+            text_reply("Done."),
+        ]);
+        let rules = PermissionsConfig {
+            allow: vec![PermissionRule {
+                command_prefix: Some("echo".to_string()),
+                ..Default::default()
+            }],
+            deny: vec![PermissionRule {
+                command_prefix: Some("rm".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut agent = Agent::new(
+            Box::new(llm),
+            default_registry(),
+            AgentConfig {
+                permission_mode: PermissionMode::Default,
+                permissions: rules,
+                ..AgentConfig::default()
+            },
+            &[],
+        );
+
+        let mut asked = Vec::new();
+        agent.process_message_with_callbacks("Clean up", &mut |_| {}, &mut |_, args| {
+            asked.push(args["command"].as_str().unwrap_or("").to_string());
+            true
+        });
+        assert!(asked.is_empty(), "allowed and denied calls never prompt");
+        let result = |id: &str| {
+            agent
+                .memory
+                .iter()
+                .find(|m| m.tool_call_id.as_deref() == Some(id))
+                .unwrap()
+                .content
+                .clone()
+        };
+        assert!(result("c0").contains("allowed"));
+        assert_eq!(result("c1"), DENIED_BY_RULE);
+    }
+
+    #[test]
+    fn test_workspace_confines_file_tools_even_in_yolo() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let shared = dir.path().join("shared");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&shared).unwrap();
+        let shared_file = shared.join("notes.md");
+        let outside_file = dir.path().join("elsewhere.txt");
+        let llm = MockLlm::new(vec![
+            write_call("c0", &shared_file),
+            write_call("c1", &outside_file),
+            text_reply("Done."),
+            text_reply("Done."),
+        ]);
+        let mut agent = Agent::new(
+            Box::new(llm),
+            default_registry(),
+            AgentConfig {
+                permission_mode: PermissionMode::Yolo,
+                workspace: Some(Workspace::new(&root, &[shared.display().to_string()])),
+                ..AgentConfig::default()
+            },
+            &[],
+        );
+
+        let mut asked = Vec::new();
+        agent.process_message_with_callbacks("Take notes", &mut |_| {}, &mut |_, args| {
+            asked.push(args["path"].as_str().unwrap_or("").to_string());
+            true
+        });
+        assert_eq!(asked, vec![shared_file.display().to_string()]);
+        assert!(shared_file.exists());
+        assert!(!outside_file.exists());
+        let rejected = agent
+            .memory
+            .iter()
+            .find(|m| m.tool_call_id.as_deref() == Some("c1"))
+            .unwrap();
+        assert!(rejected.content.contains("outside the workspace"));
+    }
+
+    #[test]
+    fn test_tool_denied_by_approval_callback() {
+        // shell tool is Dangerous → needs approval in Default mode.
+        // Denial counts as tool_success=false → Pattern 4 fires, so
+        // we provide a 3rd response for the agent to land on.
+        let llm = MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: "call_0".to_string(),
+                    name: "shell".to_string(),
+                    arguments: serde_json::json!({"command": "echo hello"}),
+                }],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            LlmResponse {
+                content: Some("I couldn't execute the command.".to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            LlmResponse {
+                content: Some("Stopping — user denied.".to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
         ]);
         let mut agent = make_agent_with_mode(Box::new(llm), PermissionMode::Default);
 
@@ -2313,11 +3841,13 @@ mod tests {
                     arguments: serde_json::json!({"command": "echo approved"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Command executed.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent_with_mode(Box::new(llm), PermissionMode::Default);
@@ -2349,11 +3879,13 @@ mod tests {
                     arguments: serde_json::json!({"command": "echo yolo"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent_with_mode(Box::new(llm), PermissionMode::Yolo);
@@ -2384,11 +3916,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "Cargo.toml"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Read the file.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent_with_mode(Box::new(llm), PermissionMode::Default);
@@ -2422,11 +3956,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("File written.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent_with_mode(Box::new(llm), PermissionMode::AcceptEdits);
@@ -2462,16 +3998,19 @@ mod tests {
                     arguments: serde_json::json!({"command": "rm -rf /"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Blocked.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("OK, plan-mode prevents that.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -2509,16 +4048,19 @@ mod tests {
                     arguments: serde_json::json!({"path": "/tmp/test.txt", "content": "x"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Can't write.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Plan mode blocks writes.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -2555,11 +4097,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "Cargo.toml"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Read successfully.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -2599,11 +4143,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "Cargo.toml"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2635,11 +4181,13 @@ mod tests {
                     arguments: serde_json::json!({"path": path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2682,11 +4230,13 @@ mod tests {
                     arguments: serde_json::json!({"path": path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2723,11 +4273,13 @@ mod tests {
                     arguments: serde_json::json!({"path": path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2766,11 +4318,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Warned.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2805,6 +4359,7 @@ mod tests {
                     arguments: serde_json::json!({"path": path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Step 2: edit_file (should succeed now)
             LlmResponse {
@@ -2819,11 +4374,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Edited.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2862,6 +4419,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "./tmp/whet_test_edit_norm.txt"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // edit_file without "./" prefix — should still match
             LlmResponse {
@@ -2876,11 +4434,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2916,11 +4476,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Warned.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2954,11 +4516,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Written.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -2999,11 +4563,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Edited.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3039,11 +4605,13 @@ mod tests {
                     arguments: serde_json::json!({"path": path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Read.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3080,6 +4648,7 @@ mod tests {
         stats.record_llm_call(&TokenUsage {
             prompt_tokens: Some(100),
             completion_tokens: Some(50),
+            ..Default::default()
         });
         assert_eq!(stats.llm_calls, 1);
         assert_eq!(stats.prompt_tokens, 100);
@@ -3089,12 +4658,71 @@ mod tests {
         stats.record_llm_call(&TokenUsage {
             prompt_tokens: Some(200),
             completion_tokens: Some(80),
+            ..Default::default()
         });
         assert_eq!(stats.llm_calls, 2);
         assert_eq!(stats.prompt_tokens, 300);
         assert_eq!(stats.completion_tokens, 130);
     }
 
+    #[test]
+    fn test_session_stats_prompt_cache_hits() {
+        let mut stats = SessionStats::default();
+        stats.record_llm_call(&TokenUsage {
+            prompt_tokens: Some(1000),
+            ..Default::default()
+        });
+        assert!(stats.prompt_cache_hit_rate().is_none());
+        stats.record_llm_call(&TokenUsage {
+            prompt_tokens: Some(1000),
+            cached_prompt_tokens: Some(900),
+            ..Default::default()
+        });
+        assert_eq!(stats.cached_prompt_tokens, 900);
+        assert_eq!(stats.prompt_cache_hit_rate(), Some(45.0));
+    }
+
+    #[test]
+    fn test_session_stats_throughput() {
+        let mut stats = SessionStats::default();
+        stats.record_llm_call(&TokenUsage::default());
+        assert!(stats.prefill_tokens_per_sec().is_none());
+        assert!(stats.decode_tokens_per_sec().is_none());
+        for load_ms in [2000.0, 0.0] {
+            stats.record_llm_call(&TokenUsage {
+                timings: Some(Timings {
+                    prompt_eval_tokens: 500,
+                    prompt_eval_ms: 250.0,
+                    eval_tokens: 20,
+                    eval_ms: 1000.0,
+                    load_ms,
+                }),
+                ..Default::default()
+            });
+        }
+        assert_eq!(stats.prefill_tokens_per_sec(), Some(2000.0));
+        assert_eq!(stats.decode_tokens_per_sec(), Some(20.0));
+        assert_eq!(stats.timings.load_ms, 2000.0);
+    }
+
+    #[test]
+    fn test_session_stats_tallies_backends() {
+        let mut stats = SessionStats::default();
+        let via = |b: Option<&str>| TokenUsage {
+            backend: b.map(String::from),
+            ..Default::default()
+        };
+        stats.record_llm_call(&via(Some("ollama:qwen3:8b")));
+        stats.record_llm_call(&via(Some("llamacpp:qwen3-8b")));
+        stats.record_llm_call(&via(Some("ollama:qwen3:8b")));
+        // Single-backend responses carry no label and aren't tallied
+        stats.record_llm_call(&via(None));
+        assert_eq!(stats.llm_calls, 4);
+        assert_eq!(stats.backend_calls.len(), 2);
+        assert_eq!(stats.backend_calls["ollama:qwen3:8b"], 2);
+        assert_eq!(stats.backend_calls["llamacpp:qwen3-8b"], 1);
+    }
+
     #[test]
     fn test_session_stats_record_llm_call_none_usage() {
         let mut stats = SessionStats::default();
@@ -3149,7 +4777,9 @@ mod tests {
                 usage: TokenUsage {
                     prompt_tokens: Some(100),
                     completion_tokens: Some(50),
+                    ..Default::default()
                 },
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
@@ -3157,7 +4787,9 @@ mod tests {
                 usage: TokenUsage {
                     prompt_tokens: Some(200),
                     completion_tokens: Some(80),
+                    ..Default::default()
                 },
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3186,12 +4818,14 @@ mod tests {
                 ),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // After fallback tool execution, model gives final answer
             LlmResponse {
                 content: Some("Here are the files.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3208,6 +4842,7 @@ mod tests {
             content: Some("{\"name\": \"delete_everything\", \"arguments\": {}}".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let response = agent.process_message("Do something");
@@ -3225,11 +4860,13 @@ mod tests {
                 ),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3247,6 +4884,7 @@ mod tests {
                 content: Some("Do you want me to read the file?".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // After re-prompt, model uses tool
             LlmResponse {
@@ -3257,11 +4895,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Here are the files.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3270,6 +4910,279 @@ mod tests {
         assert_eq!(agent.stats.reprompts, 1);
     }
 
+    fn text_reply(text: &str) -> LlmResponse {
+        LlmResponse {
+            content: Some(text.to_string()),
+            tool_calls: vec![],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        }
+    }
+
+    /// A compaction reply matching `compaction_schema`.
+    fn summary_reply(summary: &str) -> LlmResponse {
+        text_reply(&json!({ "summary": summary }).to_string())
+    }
+
+    #[test]
+    fn test_question_policy_disabled_or_reworded_by_config() {
+        let mut agent = make_agent(Box::new(MockLlm::new(vec![text_reply(
+            "Should I proceed?",
+        )])));
+        agent.config.loop_policies.insert(
+            "question".to_string(),
+            LoopPolicyConfig {
+                enabled: false,
+                reprompt: None,
+            },
+        );
+        assert_eq!(agent.process_message("Fix it"), "Should I proceed?");
+        assert_eq!(agent.stats.reprompts, 0);
+
+        let mut agent = make_agent(Box::new(MockLlm::new(vec![
+            text_reply("Should I proceed?"),
+            text_reply("Done."),
+        ])));
+        agent.config.loop_policies.insert(
+            "question".to_string(),
+            LoopPolicyConfig {
+                enabled: true,
+                reprompt: Some("Just do it.".to_string()),
+            },
+        );
+        assert_eq!(agent.process_message("Fix it"), "Done.");
+        let n = agent.memory.len();
+        assert_eq!(agent.memory[n - 2].content, "Just do it.");
+    }
+
+    fn write_call(id: &str, path: &std::path::Path) -> LlmResponse {
+        LlmResponse {
+            content: None,
+            tool_calls: vec![ToolCall {
+                id: id.to_string(),
+                name: "write_file".to_string(),
+                arguments: serde_json::json!({"path": path.to_str().unwrap(), "content": "x"}),
+            }],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_failure_is_fed_back_until_it_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ok");
+        let llm = MockLlm::new(vec![
+            write_call("c0", &dir.path().join("a.txt")),
+            text_reply("Done."),
+            write_call("c1", &marker),
+            text_reply("Fixed."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.config.verify = Some(VerifyConfig {
+            command: format!(
+                "test -f {} || {{ echo missing marker; false; }}",
+                marker.display()
+            ),
+            max_rounds: 3,
+            timeout_secs: 10,
+        });
+
+        assert_eq!(agent.process_message("Write a.txt"), "Fixed.");
+        let failure = agent
+            .memory
+            .iter()
+            .find(|m| m.tool_call_id.as_deref() == Some("verify_1"))
+            .unwrap();
+        assert!(failure.content.contains("missing marker"));
+        let call = agent
+            .memory
+            .iter()
+            .find(|m| m.tool_calls.iter().any(|c| c.id == "verify_1"))
+            .unwrap();
+        assert_eq!(call.content, "Done.");
+        assert!(agent
+            .memory
+            .iter()
+            .all(|m| m.tool_call_id.as_deref() != Some("verify_2")));
+    }
+
+    #[test]
+    fn test_verify_skipped_without_edits_and_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = VerifyConfig {
+            command: "false".to_string(),
+            max_rounds: 1,
+            timeout_secs: 10,
+        };
+
+        let mut agent = make_agent(Box::new(MockLlm::new(vec![text_reply("Nothing to do.")])));
+        agent.config.verify = Some(config.clone());
+        assert_eq!(agent.process_message("Hi"), "Nothing to do.");
+
+        let llm = MockLlm::new(vec![
+            write_call("c0", &dir.path().join("a.txt")),
+            text_reply("Done."),
+            write_call("c1", &dir.path().join("b.txt")),
+            text_reply("Still done."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.config.verify = Some(config);
+        assert_eq!(agent.process_message("Write files"), "Still done.");
+    }
+
+    #[test]
+    fn test_review_writes_only_accepted_hunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "a\nb\nc\nd\ne\n").unwrap();
+        let llm = MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: "c0".to_string(),
+                    name: "write_file".to_string(),
+                    arguments: serde_json::json!({
+                        "path": path.to_str().unwrap(),
+                        "content": "A\nb\nc\nd\ne\nf\n",
+                    }),
+                }],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            text_reply("Done."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        let seen = Rc::new(Cell::new(0));
+        let seen_in_cb = seen.clone();
+        agent.set_on_review(Box::new(move |change| {
+            seen_in_cb.set(change.hunks.len());
+            HunkReview {
+                accepted: vec![true, false],
+                edited: None,
+                always: false,
+            }
+        }));
+
+        let mut asked = false;
+        agent.process_message_with_callbacks("Update a.txt", &mut |_| {}, &mut |_, _| {
+            asked = true;
+            true
+        });
+        assert!(!asked, "review replaces the yes/no prompt");
+        assert_eq!(seen.get(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "A\nb\nc\nd\ne\n");
+        let result = agent
+            .memory
+            .iter()
+            .find(|m| m.tool_call_id.as_deref() == Some("c0"))
+            .unwrap();
+        assert!(result.content.contains("Applied hunk(s) 1 of 2"));
+        assert!(result.content.contains("+f"));
+    }
+
+    #[test]
+    fn test_review_always_allows_later_edits_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "a\n").unwrap();
+        let write = |id: &str, content: &str| LlmResponse {
+            content: None,
+            tool_calls: vec![ToolCall {
+                id: id.to_string(),
+                name: "write_file".to_string(),
+                arguments: serde_json::json!({"path": path.to_str().unwrap(), "content": content}),
+            }],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        };
+        let llm = MockLlm::new(vec![
+            write("c0", "b\n"),
+            write("c1", "c\n"),
+            text_reply("Done."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        let reviews = Rc::new(Cell::new(0));
+        let reviews_in_cb = reviews.clone();
+        agent.set_on_review(Box::new(move |change| {
+            reviews_in_cb.set(reviews_in_cb.get() + 1);
+            HunkReview {
+                always: true,
+                ..HunkReview::accept_all(change)
+            }
+        }));
+
+        agent.process_message_with_callbacks("Update a.txt", &mut |_| {}, &mut |_, _| false);
+        assert_eq!(
+            reviews.get(),
+            1,
+            "the second edit is allowed by the saved rule"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "c\n");
+    }
+
+    #[test]
+    fn test_file_tools_checkpoint_each_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("a.txt");
+        let created = dir.path().join("b.txt");
+        std::fs::write(&existing, "before").unwrap();
+        let llm = MockLlm::new(vec![
+            write_call("c0", &existing),
+            write_call("c1", &created),
+            text_reply("Done."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.set_checkpoint_store(CheckpointStore::new(dir.path().join("checkpoints")));
+
+        agent.process_message("Write both files");
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "x");
+        let store = agent.checkpoints().unwrap();
+        let undone = store.undo().unwrap().unwrap();
+        assert_eq!(undone.label, "Write both files");
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "before");
+        assert!(!created.exists());
+    }
+
+    /// A team policy: no final answer until a test command has run.
+    struct RequireTests;
+
+    impl LoopPolicy for RequireTests {
+        fn name(&self) -> &str {
+            "require_tests"
+        }
+        fn notice(&self) -> &str {
+            "tests not run"
+        }
+        fn should_reprompt(&self, turn: &TurnState) -> bool {
+            !turn.tool_calls.iter().any(|c| {
+                c.name == "shell"
+                    && c.arguments["command"]
+                        .as_str()
+                        .is_some_and(|cmd| cmd.contains("cargo test"))
+            })
+        }
+        fn reprompt(&self) -> &str {
+            "Run the tests before answering."
+        }
+    }
+
+    #[test]
+    fn test_custom_loop_policy_reprompts() {
+        let mut agent = make_agent(Box::new(MockLlm::new(vec![
+            text_reply("All fixed."),
+            text_reply("Fixed, tests pass."),
+        ])));
+        agent.add_loop_policy(Box::new(RequireTests));
+        assert_eq!(agent.process_message("Fix the bug"), "Fixed, tests pass.");
+        assert_eq!(agent.stats.reprompts, 1);
+        let n = agent.memory.len();
+        assert_eq!(
+            agent.memory[n - 2].content,
+            "Run the tests before answering."
+        );
+    }
+
     #[test]
     fn test_reprompt_limit() {
         // Model asks twice — second question should be returned as final answer
@@ -3278,11 +5191,13 @@ mod tests {
                 content: Some("Should I proceed?".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Are you sure?".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3298,6 +5213,7 @@ mod tests {
             content: Some("The answer is 42.".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let response = agent.process_message("What is the answer?");
@@ -3324,11 +5240,13 @@ mod tests {
                     arguments: serde_json::json!({"path": target_path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent_with_jsonl(Box::new(llm), jsonl_path.clone());
@@ -3359,6 +5277,7 @@ mod tests {
             content: Some("hi".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let _ = agent.process_message("hi");
@@ -3385,12 +5304,14 @@ mod tests {
                     arguments: serde_json::json!({"path": target_path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Empty content + no tool calls: triggers premature-exit detector.
             LlmResponse {
                 content: Some(String::new()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // After re-prompt, model finally writes.
             LlmResponse {
@@ -3405,11 +5326,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3436,11 +5359,13 @@ mod tests {
                     arguments: serde_json::json!({"path": target_path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("There are 2 lines.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3467,6 +5392,7 @@ mod tests {
                     arguments: serde_json::json!({"path": target_path}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -3480,11 +5406,13 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some(String::new()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3622,6 +5550,7 @@ mod tests {
             ),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let response = agent.process_message_with_callbacks(
@@ -3661,6 +5590,7 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some(
@@ -3670,11 +5600,13 @@ mod tests {
                 ),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3704,11 +5636,13 @@ mod tests {
                     arguments: serde_json::json!({"path": "Cargo.toml"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("The project is whet.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3735,11 +5669,13 @@ mod tests {
                 content: Some("Which file has the bug?".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("OK I'll explore.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -3825,6 +5761,7 @@ mod tests {
                     arguments: json!({"query": "rust ownership"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -3834,11 +5771,13 @@ mod tests {
                     arguments: json!({"query": "rust ownership"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, mock_calls) = make_agent_with_recording_web_search(Box::new(llm));
@@ -3877,6 +5816,7 @@ mod tests {
                     arguments: json!({"query": "Rust Ownership"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -3886,11 +5826,13 @@ mod tests {
                     arguments: json!({"query": "  rust   ownership "}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, mock_calls) = make_agent_with_recording_web_search(Box::new(llm));
@@ -3910,6 +5852,7 @@ mod tests {
                     arguments: json!({"query": "rust async"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -3919,11 +5862,13 @@ mod tests {
                     arguments: json!({"query": "go channels"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, mock_calls) = make_agent_with_recording_web_search(Box::new(llm));
@@ -3948,11 +5893,13 @@ mod tests {
                     arguments: json!({"query": "rust"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("turn1 done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // turn 2: same query + text
             LlmResponse {
@@ -3963,11 +5910,13 @@ mod tests {
                     arguments: json!({"query": "rust"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("turn2 done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, mock_calls) = make_agent_with_recording_web_search(Box::new(llm));
@@ -3993,6 +5942,7 @@ mod tests {
                     arguments: json!({"query": "rust", "max_results": 5}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -4002,11 +5952,13 @@ mod tests {
                     arguments: json!({"query": "rust", "max_results": 10}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, mock_calls) = make_agent_with_recording_web_search(Box::new(llm));
@@ -4033,6 +5985,7 @@ mod tests {
                     arguments: json!({"query": "rust"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -4042,6 +5995,7 @@ mod tests {
                     arguments: json!({"query": "rust"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Iter 3: model emits text-only "I'll explain instead"
             // shape that would trigger Pattern 4 IF a prior failure
@@ -4053,6 +6007,7 @@ mod tests {
                 ),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, _) = make_agent_with_recording_web_search(Box::new(llm));
@@ -4135,6 +6090,7 @@ mod tests {
                     arguments: json!({"query": "shared-query"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent iter 2: dispatch subagent
             LlmResponse {
@@ -4145,6 +6101,7 @@ mod tests {
                     arguments: json!({"task": "child task"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child iter 1: search same query — must reach mock
             // because child has its own set.
@@ -4156,18 +6113,21 @@ mod tests {
                     arguments: json!({"query": "shared-query"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child finishes
             LlmResponse {
                 content: Some("child done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent finishes
             LlmResponse {
                 content: Some("parent done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let (mut agent, mock_calls) = make_agent_with_recording_web_search(Box::new(llm));
@@ -4213,16 +6173,19 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Fetch was blocked.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Done — abandoned the fetch.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut registry = default_registry();
@@ -4268,16 +6231,19 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Refused.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Stopping.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut registry = default_registry();
@@ -4320,16 +6286,19 @@ mod tests {
                     }),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Tool wasn't available.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("Stopping.".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         // Default registry — web tools NOT registered.
@@ -4383,6 +6352,7 @@ mod tests {
                     content: Some("(no more scripted responses)".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -4399,6 +6369,7 @@ mod tests {
             content: Some("The answer is 42.".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         // Hold a borrow on the recorder so we can inspect afterwards.
         // We need a 2nd handle to the underlying RefCell — easiest is
@@ -4455,6 +6426,7 @@ mod tests {
                     content: Some("answer".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -4519,6 +6491,7 @@ mod tests {
             content: Some("   ".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let fetch = crate::tools::web_fetch::FetchResult {
@@ -4585,6 +6558,7 @@ mod tests {
             content: Some("subagent finished".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         let (text, reason) = agent
@@ -4606,6 +6580,7 @@ mod tests {
                 content: Some("subagent finished".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4642,6 +6617,7 @@ mod tests {
             content: Some("done".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         agent.add_read_path("src/parent.rs");
@@ -4667,6 +6643,7 @@ mod tests {
             content: Some("noop".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         // Manually force the depth as if we were already inside one.
@@ -4696,18 +6673,21 @@ mod tests {
                     arguments: serde_json::json!({"task": "investigate X"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child turn: subagent finishes with a text response.
             LlmResponse {
                 content: Some("found 3 things".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent turn 2: parent finishes after seeing child's result.
             LlmResponse {
                 content: Some("done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4744,12 +6724,14 @@ mod tests {
                     arguments: serde_json::json!({}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // After the error, parent recovers with a text response.
             LlmResponse {
                 content: Some("recovered".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4790,12 +6772,14 @@ mod tests {
                     arguments: serde_json::json!({"content": "uses pnpm not npm"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent: text response after tool result.
             LlmResponse {
                 content: Some("noted".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4838,11 +6822,13 @@ mod tests {
                     arguments: serde_json::json!({"content": "   "}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4876,11 +6862,13 @@ mod tests {
                     arguments: serde_json::json!({"content": "fact"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4911,11 +6899,13 @@ mod tests {
                     arguments: serde_json::json!({"content": "fact"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -4938,13 +6928,16 @@ mod tests {
     // --- Context budget (token-based compaction trigger) ---
 
     #[test]
-    fn test_approx_token_count_uses_chars_div_4() {
+    fn test_count_message_tokens_uses_chars_div_4() {
         // Empirical sanity: chars/4 approximation. 100-char message → 25 tok.
         let msgs = vec![
             Message::system(&"a".repeat(100)),
             Message::user(&"b".repeat(80)),
         ];
-        assert_eq!(approx_token_count(&msgs), 100 / 4 + 80 / 4);
+        assert_eq!(
+            count_message_tokens(&HeuristicCounter, &msgs),
+            100 / 4 + 80 / 4
+        );
     }
 
     #[test]
@@ -4962,6 +6955,7 @@ mod tests {
             content: Some("ok".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = make_agent(Box::new(llm));
         // Pre-seed 50 short user/assistant turns.
@@ -4997,17 +6991,14 @@ mod tests {
         // Use a small threshold to avoid wasting test cycles on the
         // 5000-token default.
         let llm = MockLlm::new(vec![
-            // Compaction summary call (no tools, just text).
-            LlmResponse {
-                content: Some("Summary: stuff happened".to_string()),
-                tool_calls: vec![],
-                usage: TokenUsage::default(),
-            },
+            // Compaction summary call (no tools, JSON reply).
+            summary_reply("Summary: stuff happened"),
             // Real user-message call.
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -5050,6 +7041,7 @@ mod tests {
             content: Some("ok".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = Agent::new(
             Box::new(llm),
@@ -5079,29 +7071,115 @@ mod tests {
         );
     }
 
+    /// Counter that charges a flat amount per non-empty text, standing in
+    /// for a backend tokenizer that disagrees with `chars / 4`.
+    struct FlatCounter(usize);
+
+    impl TokenCounter for FlatCounter {
+        fn count(&self, text: &str) -> usize {
+            if text.is_empty() {
+                0
+            } else {
+                self.0
+            }
+        }
+
+        fn describe(&self) -> String {
+            "flat".to_string()
+        }
+    }
+
+    fn seeded_compaction_agent(responses: usize) -> Agent {
+        let mut script = vec![summary_reply("Summary: stuff happened")];
+        script.truncate(responses);
+        script.push(LlmResponse {
+            content: Some("ok".to_string()),
+            tool_calls: vec![],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        });
+        let mut agent = make_agent(Box::new(MockLlm::new(script)));
+        for i in 0..15 {
+            agent.memory.push(Message::user(&format!("turn {}", i)));
+        }
+        agent
+    }
+
+    fn has_summary(agent: &Agent) -> bool {
+        agent
+            .memory
+            .iter()
+            .any(|m| m.content.contains("Previous conversation summary"))
+    }
+
+    #[test]
+    fn test_compaction_uses_configured_token_counter() {
+        // Short messages stay far under the 5000-token default with the
+        // heuristic, but a tokenizer reporting 400 tokens each pushes the
+        // 16 messages over it.
+        let mut agent = seeded_compaction_agent(0);
+        let _ = agent.process_message_with_callbacks("hi", &mut |_| {}, &mut |_, _| true);
+        assert!(!has_summary(&agent));
+
+        let mut agent = seeded_compaction_agent(1);
+        agent.set_token_counter(Box::new(FlatCounter(400)));
+        let _ = agent.process_message_with_callbacks("hi", &mut |_| {}, &mut |_, _| true);
+        assert!(has_summary(&agent));
+    }
+
+    #[test]
+    fn test_japanese_history_triggers_compaction_with_heuristic() {
+        // 15 × 300 kana is 4500 characters: ~1125 tokens under the old
+        // chars/4 estimate, ~4500 with CJK counted per character.
+        let mut agent = seeded_compaction_agent(1);
+        for m in agent.memory.iter_mut().skip(1) {
+            m.content = "あ".repeat(300);
+        }
+        let _ = agent.process_message_with_callbacks("hi", &mut |_| {}, &mut |_, _| true);
+        assert!(has_summary(&agent));
+    }
+
+    #[test]
+    fn test_context_usage_reports_counter_and_split() {
+        let mut agent = make_agent(Box::new(MockLlm::new(vec![])));
+        agent.set_token_counter(Box::new(FlatCounter(7)));
+        agent.memory.push(Message::user("hello"));
+        let usage = agent.context_usage();
+        assert_eq!(usage.messages, 2);
+        assert_eq!(usage.message_tokens, 14);
+        assert!(usage.tool_tokens > 0);
+        assert_eq!(usage.total(), usage.message_tokens + usage.tool_tokens);
+        assert_eq!(
+            usage.compaction_threshold,
+            DEFAULT_COMPACTION_TOKEN_THRESHOLD
+        );
+        assert_eq!(usage.counter, "flat");
+    }
+
     #[test]
-    fn test_approx_token_count_with_tools_includes_tool_def_size() {
+    fn test_count_tool_tokens_includes_tool_def_size() {
         // The trigger must account for tool definitions because the
         // LLM provider counts them toward prompt_tokens. A real
         // 12-tool registry adds ~2 K tokens; without including it,
         // the threshold is silently 2 K too high in practice.
         let msgs = vec![Message::user("hi")];
-        let bare = approx_token_count(&msgs);
-        let with_tools = approx_token_count_with_tools(
-            &msgs,
-            &[
-                crate::llm::ToolDefinition {
-                    name: "read_file".to_string(),
-                    description: "Read a file".to_string(),
-                    parameters: serde_json::json!({"type":"object"}),
-                },
-                crate::llm::ToolDefinition {
-                    name: "write_file".to_string(),
-                    description: "Write a file".to_string(),
-                    parameters: serde_json::json!({"type":"object"}),
-                },
-            ],
-        );
+        let bare = count_message_tokens(&HeuristicCounter, &msgs);
+        let with_tools = bare
+            + count_tool_tokens(
+                &HeuristicCounter,
+                &[
+                    crate::llm::ToolDefinition {
+                        name: "read_file".to_string(),
+                        description: "Read a file".to_string(),
+                        parameters: serde_json::json!({"type":"object"}),
+                    },
+                    crate::llm::ToolDefinition {
+                        name: "write_file".to_string(),
+                        description: "Write a file".to_string(),
+                        parameters: serde_json::json!({"type":"object"}),
+                    },
+                ],
+            );
         assert!(
             with_tools > bare,
             "tools-aware count {} should exceed bare {} when tools provided",
@@ -5119,16 +7197,13 @@ mod tests {
         // counted.
         let llm = MockLlm::new(vec![
             // Compaction summary call.
-            LlmResponse {
-                content: Some("summary".to_string()),
-                tool_calls: vec![],
-                usage: TokenUsage::default(),
-            },
+            summary_reply("summary"),
             // Real response.
             LlmResponse {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -5178,6 +7253,7 @@ mod tests {
             content: Some("ok".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = Agent::new(
             Box::new(llm),
@@ -5191,12 +7267,12 @@ mod tests {
         let huge = "x".repeat(60_000); // ~15 K tokens
         agent.memory.push(Message::tool_result("c1", &huge));
 
-        let tokens_before = approx_token_count(&agent.memory);
+        let tokens_before = count_message_tokens(&HeuristicCounter, &agent.memory);
         assert!(tokens_before > 10_000, "setup precondition failed");
 
         let _ = agent.process_message_with_callbacks("hi", &mut |_| {}, &mut |_, _| true);
 
-        let tokens_after = approx_token_count(&agent.memory);
+        let tokens_after = count_message_tokens(&HeuristicCounter, &agent.memory);
         assert!(
             tokens_after < tokens_before / 4,
             "huge message not shrunk: before {} tok, after {} tok",
@@ -5230,6 +7306,7 @@ mod tests {
             content: Some("ok".to_string()),
             tool_calls: vec![],
             usage: TokenUsage::default(),
+            ..Default::default()
         }]);
         let mut agent = Agent::new(
             Box::new(llm),
@@ -5279,11 +7356,13 @@ mod tests {
                 content: Some("ok".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: Some("ok again".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -5350,6 +7429,7 @@ mod tests {
                     content: Some("done".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -5408,6 +7488,7 @@ mod tests {
                     arguments: serde_json::json!({"task": "save a fact"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child emits a remember call.
             LlmResponse {
@@ -5418,18 +7499,21 @@ mod tests {
                     arguments: serde_json::json!({"content": "child-saved fact"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child wraps up.
             LlmResponse {
                 content: Some("noted".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent wraps up.
             LlmResponse {
                 content: Some("done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -5473,6 +7557,7 @@ mod tests {
                     content: Some("done".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -5518,6 +7603,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5527,6 +7613,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5536,6 +7623,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5545,6 +7633,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5554,6 +7643,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5563,6 +7653,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5572,6 +7663,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5581,6 +7673,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -5617,12 +7710,14 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Second turn: real text response.
             LlmResponse {
                 content: Some("done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent(Box::new(llm));
@@ -5660,18 +7755,21 @@ mod tests {
                     arguments: serde_json::json!({"task": "investigate"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child: returns immediately with a final answer.
             LlmResponse {
                 content: Some("subagent done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent: final answer after seeing subagent's result.
             LlmResponse {
                 content: Some("parent done".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = make_agent_with_jsonl(Box::new(llm), jsonl_path.clone());
@@ -5788,6 +7886,7 @@ mod tests {
                     arguments: serde_json::json!({"task": "spin"}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Child: 8 turns of looping nonsense — list_dir each time so
             // every iteration counts as "progress" and base+extension cap
@@ -5800,6 +7899,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5809,6 +7909,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5818,6 +7919,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5827,6 +7929,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5836,6 +7939,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5845,6 +7949,7 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             LlmResponse {
                 content: None,
@@ -5854,12 +7959,14 @@ mod tests {
                     arguments: serde_json::json!({"path": "."}),
                 }],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
             // Parent recovers after seeing failed subagent.
             LlmResponse {
                 content: Some("ok recovered".to_string()),
                 tool_calls: vec![],
                 usage: TokenUsage::default(),
+                ..Default::default()
             },
         ]);
         let mut agent = Agent::new(
@@ -5914,6 +8021,7 @@ mod tests {
                     content: Some("done".to_string()),
                     tool_calls: vec![],
                     usage: TokenUsage::default(),
+                    ..Default::default()
                 })
             }
         }
@@ -5938,4 +8046,635 @@ mod tests {
         // restored parent memory still has its original system message.
         assert_eq!(agent.memory[0].content, parent_system);
     }
+
+    // --- Cancellation ---
+
+    /// Streams a partial answer, then reports the request as cancelled
+    /// (what an HTTP provider does when Ctrl+C lands mid-stream).
+    struct CancelledMidStreamLlm;
+
+    impl LlmProvider for CancelledMidStreamLlm {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            unreachable!("agent loop should use the cancellable entry point")
+        }
+
+        fn chat_streaming_cancellable(
+            &self,
+            _: &[Message],
+            _: &[ToolDefinition],
+            on_token: &mut dyn FnMut(&str),
+            _: &mut dyn FnMut(&str),
+            _: &mut dyn FnMut(&ToolCallProgress),
+            cancel: &CancelToken,
+        ) -> Result<LlmResponse, LlmError> {
+            on_token("Let me start by");
+            cancel.cancel();
+            Err(LlmError::Cancelled)
+        }
+    }
+
+    /// Tool that trips the shared token while "running", standing in for
+    /// a user pressing Ctrl+C during a tool call.
+    struct InterruptingTool(CancelToken);
+
+    impl crate::tools::Tool for InterruptingTool {
+        fn name(&self) -> &str {
+            "interrupting"
+        }
+        fn description(&self) -> &str {
+            "test tool"
+        }
+        fn parameters_schema(&self) -> serde_json::Value {
+            serde_json::json!({"type": "object", "properties": {}})
+        }
+        fn execute(&self, _: serde_json::Value) -> Result<String, crate::tools::ToolError> {
+            self.0.cancel();
+            Ok("partial work".to_string())
+        }
+    }
+
+    #[test]
+    fn test_cancel_mid_stream_keeps_partial_text() {
+        let mut agent = make_agent(Box::new(CancelledMidStreamLlm));
+        let (text, reason) =
+            agent.process_message_full("refactor it", &mut |_| {}, &mut |_, _| true);
+        assert_eq!(reason, ExitReason::Cancelled);
+        assert_eq!(text, "Cancelled.");
+        let last = agent.memory.last().unwrap();
+        assert_eq!(last.role, crate::llm::Role::Assistant);
+        assert_eq!(last.content, "Let me start by");
+    }
+
+    #[test]
+    fn test_cancel_during_tool_stubs_remaining_calls() {
+        let token = CancelToken::new();
+        let llm = MockLlm::new(vec![LlmResponse {
+            content: None,
+            tool_calls: vec![
+                ToolCall {
+                    id: "call_0".to_string(),
+                    name: "interrupting".to_string(),
+                    arguments: serde_json::json!({}),
+                },
+                ToolCall {
+                    id: "call_1".to_string(),
+                    name: "read_file".to_string(),
+                    arguments: serde_json::json!({"path": "Cargo.toml"}),
+                },
+            ],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        }]);
+        let mut agent = make_agent(Box::new(llm));
+        agent
+            .tools
+            .register(Box::new(InterruptingTool(token.clone())));
+        agent.set_cancel_token(token);
+
+        let (_, reason) = agent.process_message_full("go", &mut |_| {}, &mut |_, _| true);
+        assert_eq!(reason, ExitReason::Cancelled);
+        // Only one LLM call: the loop stopped instead of feeding results back
+        assert_eq!(agent.stats.llm_calls, 1);
+
+        let n = agent.memory.len();
+        assert_eq!(agent.memory[n - 2].tool_call_id.as_deref(), Some("call_0"));
+        assert_eq!(agent.memory[n - 2].content, "partial work");
+        assert_eq!(agent.memory[n - 1].tool_call_id.as_deref(), Some("call_1"));
+        assert!(agent.memory[n - 1].content.contains("Cancelled by user"));
+    }
+
+    #[test]
+    fn test_new_turn_clears_previous_cancel() {
+        let token = CancelToken::new();
+        token.cancel();
+        let llm = MockLlm::new(vec![LlmResponse {
+            content: Some("fine".to_string()),
+            tool_calls: vec![],
+            usage: TokenUsage::default(),
+            ..Default::default()
+        }]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.set_cancel_token(token.clone());
+        let (text, reason) = agent.process_message_full("hi", &mut |_| {}, &mut |_, _| true);
+        assert_eq!(reason, ExitReason::Answered);
+        assert_eq!(text, "fine");
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancelled_turn_writes_session_end_reason() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("stats.jsonl");
+        let mut agent = make_agent_with_jsonl(Box::new(CancelledMidStreamLlm), path.clone());
+        agent.process_message_full("x", &mut |_| {}, &mut |_, _| true);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("\"reason\":\"cancelled\""));
+    }
+
+    // --- Reasoning ---
+
+    /// Streams a two-chunk thinking trace, then the answer.
+    struct ThinkingLlm;
+
+    impl LlmProvider for ThinkingLlm {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            unreachable!("agent loop should use the cancellable entry point")
+        }
+
+        fn chat_streaming_cancellable(
+            &self,
+            _: &[Message],
+            _: &[ToolDefinition],
+            on_token: &mut dyn FnMut(&str),
+            on_reasoning: &mut dyn FnMut(&str),
+            _: &mut dyn FnMut(&ToolCallProgress),
+            _: &CancelToken,
+        ) -> Result<LlmResponse, LlmError> {
+            on_reasoning("Let me ");
+            on_reasoning("think.");
+            on_token("Answer");
+            Ok(LlmResponse {
+                content: Some("Answer".to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                reasoning: Some("Let me think.".to_string()),
+            })
+        }
+    }
+
+    type Events = std::rc::Rc<RefCell<Vec<(&'static str, String)>>>;
+
+    /// Says a word, streams a `write_file` call in two pieces with more
+    /// text in between, then answers once the tool has run.
+    struct ToolStreamingLlm {
+        calls: Cell<usize>,
+    }
+
+    impl LlmProvider for ToolStreamingLlm {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            unreachable!("agent loop should use the cancellable entry point")
+        }
+
+        fn chat_streaming_cancellable(
+            &self,
+            _: &[Message],
+            _: &[ToolDefinition],
+            on_token: &mut dyn FnMut(&str),
+            _: &mut dyn FnMut(&str),
+            on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+            _: &CancelToken,
+        ) -> Result<LlmResponse, LlmError> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() > 1 {
+                on_token("Done");
+                return Ok(LlmResponse {
+                    content: Some("Done".to_string()),
+                    tool_calls: vec![],
+                    usage: TokenUsage::default(),
+                    ..Default::default()
+                });
+            }
+            on_token("Writing.");
+            let progress = |bytes| ToolCallProgress {
+                index: 0,
+                name: "write_file",
+                bytes,
+            };
+            on_tool_progress(&progress(10));
+            on_token(" Almost.");
+            on_tool_progress(&progress(2048));
+            Ok(LlmResponse {
+                content: Some("Writing. Almost.".to_string()),
+                tool_calls: vec![ToolCall {
+                    id: "call_0".to_string(),
+                    name: "no_such_tool".to_string(),
+                    arguments: serde_json::json!({}),
+                }],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn test_tool_progress_closed_before_held_text() {
+        let mut agent = make_agent(Box::new(ToolStreamingLlm {
+            calls: Cell::new(0),
+        }));
+        let events: Events = Default::default();
+        let sink = std::rc::Rc::clone(&events);
+        agent.set_on_tool_progress(Box::new(move |p| {
+            let event = p.map_or("end".to_string(), |p| format!("{} {}", p.name, p.bytes));
+            sink.borrow_mut().push(("progress", event))
+        }));
+        let tokens = std::rc::Rc::clone(&events);
+        agent.process_message_full(
+            "q",
+            &mut |t| tokens.borrow_mut().push(("token", t.to_string())),
+            &mut |_, _| true,
+        );
+        let events = events.borrow();
+        let first_turn: Vec<(&str, &str)> = events
+            .iter()
+            .take(6)
+            .map(|(k, v)| (*k, v.as_str()))
+            .collect();
+        assert_eq!(
+            first_turn,
+            vec![
+                ("token", "Writing."),
+                ("token", ""),
+                ("progress", "write_file 10"),
+                ("progress", "write_file 2048"),
+                ("progress", "end"),
+                ("token", " Almost."),
+            ]
+        );
+    }
+
+    fn record_reasoning(agent: &mut Agent) -> Events {
+        let events: Events = Default::default();
+        let sink = std::rc::Rc::clone(&events);
+        agent.set_on_reasoning(Box::new(move |t| {
+            sink.borrow_mut().push(("reasoning", t.to_string()))
+        }));
+        events
+    }
+
+    #[test]
+    fn test_reasoning_streamed_to_callback_and_kept_out_of_memory() {
+        let mut agent = make_agent(Box::new(ThinkingLlm));
+        let events = record_reasoning(&mut agent);
+        let tokens = std::rc::Rc::clone(&events);
+        let (text, _) = agent.process_message_full(
+            "q",
+            &mut |t| tokens.borrow_mut().push(("token", t.to_string())),
+            &mut |_, _| true,
+        );
+        assert_eq!(text, "Answer");
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                ("token", String::new()), // stops the spinner
+                ("reasoning", "Let me ".to_string()),
+                ("reasoning", "think.".to_string()),
+                ("reasoning", String::new()), // end of trace
+                ("token", "Answer".to_string()),
+            ]
+        );
+        assert_eq!(agent.memory.last().unwrap().content, "Answer");
+    }
+
+    #[test]
+    fn test_whole_reasoning_from_non_streaming_provider_is_shown() {
+        let llm = MockLlm::new(vec![LlmResponse {
+            content: Some("Done".to_string()),
+            tool_calls: vec![],
+            usage: TokenUsage::default(),
+            reasoning: Some("because".to_string()),
+        }]);
+        let mut agent = make_agent(Box::new(llm));
+        let events = record_reasoning(&mut agent);
+        agent.process_message("q");
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                ("reasoning", "because".to_string()),
+                ("reasoning", String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_reasoning_stores_think_block() {
+        let llm = MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: "call_0".to_string(),
+                    name: "list_dir".to_string(),
+                    arguments: json!({"path": "."}),
+                }],
+                usage: TokenUsage::default(),
+                reasoning: Some("look around".to_string()),
+            },
+            LlmResponse {
+                content: Some("Done".to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                reasoning: Some("because".to_string()),
+            },
+        ]);
+        let mut agent = Agent::new(
+            Box::new(llm),
+            default_registry(),
+            AgentConfig {
+                keep_reasoning: true,
+                ..AgentConfig::default()
+            },
+            &[],
+        );
+        let text = agent.process_message("q");
+        // The returned answer never carries the trace.
+        assert_eq!(text, "Done");
+        let assistant: Vec<&str> = agent
+            .memory
+            .iter()
+            .filter(|m| m.role == Role::Assistant)
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(
+            assistant,
+            vec![
+                "<think>\nlook around\n</think>",
+                "<think>\nbecause\n</think>\n\nDone"
+            ]
+        );
+    }
+
+    // --- Role routing ---
+
+    /// Answers every call with `tag`, logging the call under that tag.
+    struct TaggedLlm {
+        tag: &'static str,
+        calls: std::rc::Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl LlmProvider for TaggedLlm {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            self.calls.borrow_mut().push(self.tag);
+            Ok(LlmResponse {
+                content: Some(self.tag.to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            })
+        }
+
+        fn chat_with_schema(
+            &self,
+            _: &[Message],
+            _: &serde_json::Value,
+        ) -> Result<LlmResponse, LlmError> {
+            self.calls.borrow_mut().push(self.tag);
+            Ok(summary_reply(self.tag))
+        }
+    }
+
+    /// Agent whose main model and each routed role answer with their
+    /// own name, plus the shared call log.
+    fn routed_agent() -> (Agent, std::rc::Rc<RefCell<Vec<&'static str>>>) {
+        let calls = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let tagged = |tag| {
+            Box::new(TaggedLlm {
+                tag,
+                calls: std::rc::Rc::clone(&calls),
+            })
+        };
+        let mut agent = make_agent(tagged("main"));
+        agent.set_role_llm(ModelRole::Subagent, tagged("subagent"));
+        agent.set_role_llm(ModelRole::Compaction, tagged("compaction"));
+        agent.set_role_llm(ModelRole::Extraction, tagged("extraction"));
+        (agent, calls)
+    }
+
+    #[test]
+    fn test_roles_route_each_call_site() {
+        let (mut agent, calls) = routed_agent();
+        assert_eq!(agent.process_message("hi"), "main");
+
+        let (text, _) = agent
+            .run_subagent("look around", &mut |_| {}, &mut |_, _| true)
+            .unwrap();
+        assert_eq!(text, "subagent");
+
+        let fetch = crate::tools::web_fetch::FetchResult {
+            markdown: "body".to_string(),
+            final_url: "https://example.com/".to_string(),
+            content_type: "text/html".to_string(),
+            status: 200,
+            truncated: false,
+            original_chars: 4,
+        };
+        let (text, _) = agent.extract_from_page(&fetch, "q");
+        assert!(text.starts_with("extraction"));
+
+        for i in 0..SUMMARIZE_KEEP_RECENT + 2 {
+            agent.memory.push(Message::user(&format!("m{}", i)));
+        }
+        agent.compact(None);
+        assert!(agent
+            .memory
+            .iter()
+            .any(|m| m.content == "Previous conversation summary: compaction"));
+
+        assert_eq!(
+            *calls.borrow(),
+            vec!["main", "subagent", "extraction", "compaction"]
+        );
+    }
+
+    #[test]
+    fn test_unrouted_roles_fall_back_to_main() {
+        let llm = MockLlm::new(vec![summary_reply("summary")]);
+        let mut agent = make_agent(Box::new(llm));
+        for i in 0..SUMMARIZE_KEEP_RECENT + 2 {
+            agent.memory.push(Message::user(&format!("m{}", i)));
+        }
+        agent.compact(None);
+        assert_eq!(
+            agent.memory[1].content,
+            "Previous conversation summary: summary"
+        );
+    }
+
+    #[test]
+    fn test_compaction_keeps_earlier_summaries_as_prefix() {
+        let llm = MockLlm::new(vec![
+            summary_reply("first"),
+            summary_reply("second"),
+            summary_reply("third"),
+            summary_reply("folded"),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        let fill = |agent: &mut Agent, round: usize| {
+            for i in 0..SUMMARIZE_KEEP_RECENT + 2 {
+                agent
+                    .memory
+                    .push(Message::user(&format!("r{}m{}", round, i)));
+            }
+        };
+
+        fill(&mut agent, 0);
+        agent.compact(None);
+        let prefix: Vec<String> = agent.memory[..2]
+            .iter()
+            .map(|m| m.content.clone())
+            .collect();
+        fill(&mut agent, 1);
+        agent.compact(None);
+        // The system prompt and first summary are untouched; the new
+        // summary follows them.
+        assert_eq!(agent.memory[0].content, prefix[0]);
+        assert_eq!(agent.memory[1].content, prefix[1]);
+        assert_eq!(
+            agent.memory[2].content,
+            "Previous conversation summary: second"
+        );
+        assert_eq!(agent.memory[3].content, "r1m2");
+
+        fill(&mut agent, 2);
+        agent.compact(None);
+        assert_eq!(
+            agent.memory[3].content,
+            "Previous conversation summary: third"
+        );
+
+        // Once the preserved summaries hit the cap they are folded into one.
+        fill(&mut agent, 3);
+        agent.compact(None);
+        let summaries: Vec<&str> = agent
+            .memory
+            .iter()
+            .filter(|m| m.content.starts_with(SUMMARY_PREFIX))
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(summaries, vec!["Previous conversation summary: folded"]);
+        assert_eq!(agent.memory.len(), 2 + SUMMARIZE_KEEP_RECENT);
+    }
+
+    fn todo_item(content: &str, status: todo::TodoStatus) -> TodoItem {
+        TodoItem {
+            content: content.to_string(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_todo_call_replaces_agent_list() {
+        let llm = MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: "t0".to_string(),
+                    name: "todo".to_string(),
+                    arguments: serde_json::json!({"todos": [
+                        {"content": "Read config", "status": "done"},
+                        {"content": "Add flag", "status": "in_progress"}
+                    ]}),
+                }],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            text_reply("Working on it."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.set_todos(vec![todo_item("Old plan", todo::TodoStatus::Pending)]);
+        agent.process_message("Add a --verbose flag");
+
+        assert_eq!(
+            agent.todos(),
+            &[
+                todo_item("Read config", todo::TodoStatus::Done),
+                todo_item("Add flag", todo::TodoStatus::InProgress),
+            ]
+        );
+        let result = agent
+            .memory
+            .iter()
+            .find(|m| m.tool_call_id.as_deref() == Some("t0"))
+            .unwrap();
+        assert_eq!(
+            result.content,
+            "Todo list updated:\n[x] Read config\n[~] Add flag"
+        );
+    }
+
+    #[test]
+    fn test_subagent_todo_call_keeps_parent_list() {
+        let llm = MockLlm::new(vec![
+            LlmResponse {
+                content: None,
+                tool_calls: vec![ToolCall {
+                    id: "t0".to_string(),
+                    name: "todo".to_string(),
+                    arguments: serde_json::json!({"todos": [
+                        {"content": "Child step", "status": "in_progress"}
+                    ]}),
+                }],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            },
+            text_reply("Child done."),
+        ]);
+        let mut agent = make_agent(Box::new(llm));
+        let parent = vec![todo_item("Parent step", todo::TodoStatus::Pending)];
+        agent.set_todos(parent.clone());
+
+        let (answer, _) = agent
+            .run_subagent("Investigate", &mut |_| {}, &mut |_, _| true)
+            .unwrap();
+        assert_eq!(answer, "Child done.");
+        assert_eq!(agent.todos(), parent.as_slice());
+    }
+
+    #[test]
+    fn test_compaction_summary_lists_files_and_open_tasks() {
+        let llm = MockLlm::new(vec![text_reply(
+            &json!({
+                "summary": "Added the --dry-run flag.",
+                "files": ["src/main.rs", "src/config.rs"],
+                "open_tasks": ["Document the flag"]
+            })
+            .to_string(),
+        )]);
+        let mut agent = make_agent(Box::new(llm));
+        for i in 0..SUMMARIZE_KEEP_RECENT + 2 {
+            agent.memory.push(Message::user(&format!("m{}", i)));
+        }
+        agent.compact(None);
+        assert_eq!(
+            agent.memory[1].content,
+            "Previous conversation summary: Added the --dry-run flag.\n\
+             Files: src/main.rs, src/config.rs\n\
+             Open tasks:\n- Document the flag"
+        );
+    }
+
+    #[test]
+    fn test_compaction_carries_todo_list_verbatim() {
+        let llm = MockLlm::new(vec![summary_reply("first"), summary_reply("second")]);
+        let mut agent = make_agent(Box::new(llm));
+        agent.set_todos(vec![
+            todo_item("Read config", todo::TodoStatus::Done),
+            todo_item("Add flag", todo::TodoStatus::Pending),
+        ]);
+        let fill = |agent: &mut Agent| {
+            for i in 0..SUMMARIZE_KEEP_RECENT + 2 {
+                agent.memory.push(Message::user(&format!("m{}", i)));
+            }
+        };
+
+        fill(&mut agent);
+        agent.compact(None);
+        assert_eq!(
+            agent.memory[2].content,
+            "Current todo list:\n[x] Read config\n[ ] Add flag"
+        );
+
+        agent.todos[1].status = todo::TodoStatus::Done;
+        fill(&mut agent);
+        agent.compact(None);
+        let snapshots: Vec<usize> = agent
+            .memory
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.content.starts_with(TODO_SNAPSHOT_PREFIX))
+            .map(|(i, _)| i)
+            .collect();
+        // One fresh snapshot, right after both summaries.
+        assert_eq!(snapshots, vec![3]);
+        assert!(agent.memory[2].content.ends_with("second"));
+        assert!(agent.memory[3].content.ends_with("[x] Add flag"));
+    }
 }
@@ -0,0 +1,197 @@
+//! Loop policies: checks run when the model is about to end a turn with
+//! a text reply, each able to send it back to work with a reprompt.
+//!
+//! The built-in policies catch the common ways small models stop early:
+//! asking permission instead of acting, explaining a failed tool call
+//! instead of fixing it, and stopping silently after only reading. Each
+//! can be switched off or reworded under `[agent.policies.<name>]`, and
+//! embedders can add their own with `Agent::add_loop_policy`.
+//!
+//! At most one reprompt is sent per turn; the first policy that fires,
+//! in registration order, supplies it.
+
+use super::ToolResultKind;
+
+/// One tool call made during the current turn.
+#[derive(Debug, Clone)]
+pub struct TurnToolCall {
+    pub name: String,
+    #[allow(dead_code)]
+    pub arguments: serde_json::Value,
+    pub result: ToolResultKind,
+}
+
+/// What a policy gets to look at when the turn is about to end.
+#[derive(Debug)]
+pub struct TurnState<'a> {
+    #[allow(dead_code)]
+    pub user_input: &'a str,
+    /// The reply that would end the turn.
+    pub reply: &'a str,
+    /// The user asked for options or advice rather than an action, so a
+    /// reply that ends on a question is legitimate.
+    pub user_asked_open_ended: bool,
+    /// Tool calls made so far this turn, in order.
+    pub tool_calls: &'a [TurnToolCall],
+    /// A tool call in the latest batch failed.
+    pub last_batch_failed: bool,
+}
+
+impl TurnState<'_> {
+    /// A read-only tool ran successfully this turn.
+    pub fn has_read(&self) -> bool {
+        self.succeeded()
+            .any(|call| super::is_read_only_tool(&call.name))
+    }
+
+    /// A tool that changes something (edit, write, shell) ran successfully.
+    pub fn has_acted(&self) -> bool {
+        self.succeeded()
+            .any(|call| !super::is_read_only_tool(&call.name))
+    }
+
+    fn succeeded(&self) -> impl Iterator<Item = &TurnToolCall> {
+        self.tool_calls.iter().filter(|c| c.result.is_success())
+    }
+}
+
+pub trait LoopPolicy {
+    /// Key under `[agent.policies]`.
+    fn name(&self) -> &str;
+    /// Shown as `[re-prompt: ...]` when the policy fires.
+    fn notice(&self) -> &str;
+    /// Whether the reply should be sent back instead of ending the turn.
+    fn should_reprompt(&self, turn: &TurnState) -> bool;
+    /// The user message that sends the model back to work.
+    fn reprompt(&self) -> &str;
+}
+
+/// The model asked a question ("Should I proceed?") instead of acting.
+pub struct AskedInsteadOfActing;
+
+impl LoopPolicy for AskedInsteadOfActing {
+    fn name(&self) -> &str {
+        "question"
+    }
+    fn notice(&self) -> &str {
+        "model asked instead of acting"
+    }
+    fn should_reprompt(&self, turn: &TurnState) -> bool {
+        !turn.user_asked_open_ended && super::looks_like_question(turn.reply)
+    }
+    fn reprompt(&self) -> &str {
+        "Don't ask questions. Use your tools to take action directly. \
+         If you're unsure, start by reading files or exploring the project structure."
+    }
+}
+
+/// A tool call failed and the model is answering with an explanation
+/// instead of fixing it, e.g. describing how the user could repair a
+/// failing test run. Empty replies are `StoppedAfterReads`' business.
+pub struct ExplainedFailure;
+
+impl LoopPolicy for ExplainedFailure {
+    fn name(&self) -> &str {
+        "failed_tool"
+    }
+    fn notice(&self) -> &str {
+        "tool call failed — push to fix instead of explain"
+    }
+    fn should_reprompt(&self, turn: &TurnState) -> bool {
+        !turn.user_asked_open_ended && turn.last_batch_failed && !turn.reply.trim().is_empty()
+    }
+    fn reprompt(&self) -> &str {
+        "Your last tool call failed and you stopped before fixing it. \
+         Don't describe the fix — apply it. Edit the failing code, \
+         re-run the failing command, and only stop when the command \
+         succeeds. If a dependency is missing, install it; if a path \
+         is wrong, correct it; if a test reveals a bug, fix the bug \
+         in the code under test."
+    }
+}
+
+/// The model explored with read-only tools, changed nothing and ended
+/// with an empty reply. Read-then-answer Q&A has text, so it passes.
+pub struct StoppedAfterReads;
+
+impl LoopPolicy for StoppedAfterReads {
+    fn name(&self) -> &str {
+        "only_reads"
+    }
+    fn notice(&self) -> &str {
+        "model stopped after only reads — pushing it to act"
+    }
+    fn should_reprompt(&self, turn: &TurnState) -> bool {
+        turn.has_read() && !turn.has_acted() && turn.reply.trim().is_empty()
+    }
+    fn reprompt(&self) -> &str {
+        "You've only read files so far. Use your editing tools \
+         (edit_file / apply_diff / write_file / shell) to make the \
+         changes the task requires. If you've finished, state in plain \
+         text what you did so the user can verify."
+    }
+}
+
+/// The built-in policies, in the order they are checked.
+pub fn default_policies() -> Vec<Box<dyn LoopPolicy>> {
+    vec![
+        Box::new(AskedInsteadOfActing),
+        Box::new(ExplainedFailure),
+        Box::new(StoppedAfterReads),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, result: ToolResultKind) -> TurnToolCall {
+        TurnToolCall {
+            name: name.to_string(),
+            arguments: serde_json::json!({}),
+            result,
+        }
+    }
+
+    fn turn<'a>(reply: &'a str, tool_calls: &'a [TurnToolCall]) -> TurnState<'a> {
+        TurnState {
+            user_input: "fix the build",
+            reply,
+            user_asked_open_ended: false,
+            tool_calls,
+            last_batch_failed: false,
+        }
+    }
+
+    #[test]
+    fn test_read_and_act_ignore_failed_calls() {
+        let calls = [
+            call("read_file", ToolResultKind::Success),
+            call("edit_file", ToolResultKind::Failure),
+        ];
+        let state = turn("", &calls);
+        assert!(state.has_read());
+        assert!(!state.has_acted());
+        assert!(StoppedAfterReads.should_reprompt(&state));
+    }
+
+    #[test]
+    fn test_open_ended_input_silences_question_and_failure_policies() {
+        let mut state = turn("Which option do you prefer?", &[]);
+        state.last_batch_failed = true;
+        assert!(AskedInsteadOfActing.should_reprompt(&state));
+        assert!(ExplainedFailure.should_reprompt(&state));
+        state.user_asked_open_ended = true;
+        assert!(!AskedInsteadOfActing.should_reprompt(&state));
+        assert!(!ExplainedFailure.should_reprompt(&state));
+    }
+
+    #[test]
+    fn test_default_policies_in_check_order() {
+        let names: Vec<String> = default_policies()
+            .iter()
+            .map(|p| p.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["question", "failed_tool", "only_reads"]);
+    }
+}
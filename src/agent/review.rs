@@ -0,0 +1,400 @@
+//! Hunk-by-hunk review of file edits before they are written.
+//!
+//! When an edit needs approval and `Agent::set_on_review` is set, the
+//! agent works out what `write_file`, `edit_file` or `apply_diff` would
+//! leave on disk, diffs that against the file as it is now and hands
+//! the callback one `FileChange` per file. The callback keeps or drops
+//! each hunk, or returns the whole file rewritten by the user. The agent
+//! writes the result itself and tells the model exactly what landed.
+
+use crate::llm::ToolCall;
+use crate::tools::ToolError;
+
+/// Keep the DP table for the line diff under this many cells; beyond it
+/// the changed middle of the file becomes a single hunk.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Unchanged lines shown around a hunk.
+pub const CONTEXT_LINES: usize = 3;
+
+/// Longest rejected-hunk or user-edit diff quoted back to the model.
+const MAX_REPORT_CHARS: usize = 4000;
+
+/// One run of changed lines. Lines keep their `\n`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// Index of the first replaced line in the old file.
+    pub old_start: usize,
+    pub removed: Vec<String>,
+    pub added: Vec<String>,
+}
+
+/// A file an edit would change.
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub path: String,
+    /// None when the edit creates the file.
+    pub old: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+/// The reviewer's verdict on one `FileChange`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HunkReview {
+    /// One entry per hunk, in order.
+    pub accepted: Vec<bool>,
+    /// The whole file as the user rewrote it. Written instead of the
+    /// accepted hunks.
+    pub edited: Option<String>,
+    /// Allow edits to this file without review from now on.
+    pub always: bool,
+}
+
+impl HunkReview {
+    pub fn accept_all(change: &FileChange) -> Self {
+        Self {
+            accepted: vec![true; change.hunks.len()],
+            edited: None,
+            always: false,
+        }
+    }
+}
+
+impl FileChange {
+    pub fn new(path: String, old: Option<String>, new: String) -> Self {
+        let hunks = diff_hunks(old.as_deref().unwrap_or(""), &new);
+        Self { path, old, hunks }
+    }
+
+    /// The file with only the hunks marked in `accepted` applied.
+    pub fn apply(&self, accepted: &[bool]) -> String {
+        let old_lines = split_lines(self.old.as_deref().unwrap_or(""));
+        let mut out = String::new();
+        let mut next = 0;
+        for (hunk, keep) in self.hunks.iter().zip(accepted) {
+            out.extend(old_lines[next..hunk.old_start].iter().copied());
+            let lines = if *keep { &hunk.added } else { &hunk.removed };
+            out.extend(lines.iter().map(String::as_str));
+            next = hunk.old_start + hunk.removed.len();
+        }
+        out.extend(old_lines[next..].iter().copied());
+        out
+    }
+
+    /// Hunk `index` as a unified-diff fragment with a `@@` header and
+    /// up to `CONTEXT_LINES` of surrounding context.
+    pub fn render_hunk(&self, index: usize) -> String {
+        let old_lines = split_lines(self.old.as_deref().unwrap_or(""));
+        render(&old_lines, &self.hunks, index)
+    }
+}
+
+/// The files `call` would change, or None when it isn't a file edit. An
+/// edit that would fail anyway (bad arguments, a diff that doesn't
+/// apply, a write the tool would refuse) gives the tool's error.
+pub fn proposed_changes(call: &ToolCall) -> Option<Result<Vec<FileChange>, ToolError>> {
+    let files: Result<Vec<(String, Option<String>, String)>, ToolError> = match call.name.as_str() {
+        "write_file" => write_file_change(&call.arguments).map(|f| vec![f]),
+        "edit_file" => crate::tools::edit_file::proposed_content(&call.arguments)
+            .map(|(path, old, new)| vec![(path, Some(old), new)]),
+        "apply_diff" => crate::tools::apply_diff::proposed_contents(&call.arguments).map(|files| {
+            files
+                .into_iter()
+                .map(|(path, old, new)| (path, Some(old), new))
+                .collect()
+        }),
+        _ => return None,
+    };
+    Some(files.map(|files| {
+        files
+            .into_iter()
+            .map(|(path, old, new)| FileChange::new(path, old, new))
+            .collect()
+    }))
+}
+
+fn write_file_change(
+    args: &serde_json::Value,
+) -> Result<(String, Option<String>, String), ToolError> {
+    let (Some(path), Some(content)) = (args["path"].as_str(), args["content"].as_str()) else {
+        return Err(ToolError::InvalidArguments(
+            "missing 'path' or 'content' argument".to_string(),
+        ));
+    };
+    crate::tools::write_file::check_write(path, content)?;
+    let old = match std::fs::read_to_string(path) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(ToolError::ExecutionFailed(e.to_string())),
+    };
+    Ok((path.to_string(), old, content.to_string()))
+}
+
+/// Tell the model what happened to `change` under `review`.
+pub fn describe(change: &FileChange, review: &HunkReview) -> String {
+    let total = change.hunks.len();
+    if let Some(edited) = &review.edited {
+        let selected = FileChange::new(
+            change.path.clone(),
+            Some(change.apply(&review.accepted)),
+            edited.clone(),
+        );
+        if selected.hunks.is_empty() {
+            // Opened in the editor but saved unchanged.
+            return describe(
+                change,
+                &HunkReview {
+                    edited: None,
+                    ..review.clone()
+                },
+            );
+        }
+        let diff: String = (0..selected.hunks.len())
+            .map(|i| selected.render_hunk(i))
+            .collect();
+        return format!(
+            "The user edited your change to '{}' before it was written. \
+             Their changes on top of yours:\n{}Re-read the file before editing it again.",
+            change.path,
+            clip(&diff)
+        );
+    }
+
+    let applied: Vec<usize> = (1..=total).filter(|n| review.accepted[n - 1]).collect();
+    if applied.len() == total {
+        return format!(
+            "Successfully applied all {} hunk(s) to '{}'.",
+            total, change.path
+        );
+    }
+    if applied.is_empty() {
+        return format!(
+            "The user rejected all {} hunk(s) for '{}'; the file is unchanged.",
+            total, change.path
+        );
+    }
+    let rejected: Vec<usize> = (1..=total).filter(|n| !review.accepted[n - 1]).collect();
+    let quoted: String = rejected.iter().map(|n| change.render_hunk(n - 1)).collect();
+    format!(
+        "Applied hunk(s) {} of {} to '{}'. The user rejected hunk(s) {}, \
+         which were NOT applied:\n{}",
+        join_numbers(&applied),
+        total,
+        change.path,
+        join_numbers(&rejected),
+        clip(&quoted)
+    )
+}
+
+fn join_numbers(numbers: &[usize]) -> String {
+    numbers
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn clip(text: &str) -> String {
+    if text.len() <= MAX_REPORT_CHARS {
+        return text.to_string();
+    }
+    let mut end = MAX_REPORT_CHARS;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n…[diff truncated]\n", &text[..end])
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// Line diff of `old` against `new`, grouped into runs of changes.
+pub fn diff_hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let a = split_lines(old);
+    let b = split_lines(new);
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    // Which lines of each side are unchanged, via LCS on the middle.
+    let mut a_kept = vec![false; a_mid.len()];
+    let mut b_kept = vec![false; b_mid.len()];
+    if (a_mid.len() + 1) * (b_mid.len() + 1) <= MAX_DIFF_CELLS {
+        let width = b_mid.len() + 1;
+        let mut lcs = vec![0u32; (a_mid.len() + 1) * width];
+        for i in (0..a_mid.len()).rev() {
+            for j in (0..b_mid.len()).rev() {
+                lcs[i * width + j] = if a_mid[i] == b_mid[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a_mid.len() && j < b_mid.len() {
+            if a_mid[i] == b_mid[j] {
+                a_kept[i] = true;
+                b_kept[j] = true;
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a_mid.len() || j < b_mid.len() {
+        if i < a_mid.len() && j < b_mid.len() && a_kept[i] && b_kept[j] {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let mut hunk = Hunk {
+            old_start: prefix + i,
+            removed: Vec::new(),
+            added: Vec::new(),
+        };
+        while i < a_mid.len() && !a_kept[i] {
+            hunk.removed.push(a_mid[i].to_string());
+            i += 1;
+        }
+        while j < b_mid.len() && !b_kept[j] {
+            hunk.added.push(b_mid[j].to_string());
+            j += 1;
+        }
+        hunks.push(hunk);
+    }
+    hunks
+}
+
+fn render(old_lines: &[&str], hunks: &[Hunk], index: usize) -> String {
+    let hunk = &hunks[index];
+    let shift: isize = hunks[..index]
+        .iter()
+        .map(|h| h.added.len() as isize - h.removed.len() as isize)
+        .sum();
+    let before_start = hunk.old_start.saturating_sub(CONTEXT_LINES);
+    let after_start = hunk.old_start + hunk.removed.len();
+    let after_end = (after_start + CONTEXT_LINES).min(old_lines.len());
+    let before = &old_lines[before_start..hunk.old_start];
+    let after = &old_lines[after_start..after_end];
+
+    let old_count = before.len() + hunk.removed.len() + after.len();
+    let new_count = before.len() + hunk.added.len() + after.len();
+    let new_start = before_start as isize + shift;
+    let mut out = format!(
+        "@@ -{},{} +{},{} @@\n",
+        before_start + 1,
+        old_count,
+        new_start + 1,
+        new_count
+    );
+    let mut push = |mark: char, line: &str| {
+        out.push(mark);
+        out.push_str(line.strip_suffix('\n').unwrap_or(line));
+        out.push('\n');
+    };
+    before.iter().for_each(|l| push(' ', l));
+    hunk.removed.iter().for_each(|l| push('-', l));
+    hunk.added.iter().for_each(|l| push('+', l));
+    after.iter().for_each(|l| push(' ', l));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(old: &str, new: &str) -> FileChange {
+        FileChange::new("f.txt".to_string(), Some(old.to_string()), new.to_string())
+    }
+
+    #[test]
+    fn test_separate_edits_become_separate_hunks() {
+        let c = change("a\nb\nc\nd\ne\n", "A\nb\nc\nd\ne\nf\n");
+        assert_eq!(c.hunks.len(), 2);
+        assert_eq!(c.hunks[0].removed, vec!["a\n"]);
+        assert_eq!(c.hunks[0].added, vec!["A\n"]);
+        assert_eq!(c.hunks[1].old_start, 5);
+        assert_eq!(c.hunks[1].added, vec!["f\n"]);
+
+        assert_eq!(c.apply(&[true, true]), "A\nb\nc\nd\ne\nf\n");
+        assert_eq!(c.apply(&[false, false]), "a\nb\nc\nd\ne\n");
+        assert_eq!(c.apply(&[false, true]), "a\nb\nc\nd\ne\nf\n");
+        assert_eq!(c.render_hunk(1), "@@ -3,3 +3,4 @@\n c\n d\n e\n+f\n");
+    }
+
+    #[test]
+    fn test_new_file_and_no_trailing_newline() {
+        let c = FileChange::new("n.txt".to_string(), None, "x\ny".to_string());
+        assert_eq!(c.hunks.len(), 1);
+        assert_eq!(c.apply(&[true]), "x\ny");
+        assert_eq!(c.apply(&[false]), "");
+        assert!(change("same\n", "same\n").hunks.is_empty());
+    }
+
+    #[test]
+    fn test_describe_names_applied_and_rejected_hunks() {
+        let c = change("a\nb\nc\nd\ne\n", "A\nb\nc\nd\ne\nf\n");
+        let partial = HunkReview {
+            accepted: vec![true, false],
+            edited: None,
+            always: false,
+        };
+        let text = describe(&c, &partial);
+        assert!(text.starts_with("Applied hunk(s) 1 of 2 to 'f.txt'"));
+        assert!(text.contains("rejected hunk(s) 2"));
+        assert!(text.contains("+f"));
+        assert!(describe(&c, &HunkReview::accept_all(&c)).contains("all 2 hunk(s)"));
+
+        let edited = HunkReview {
+            accepted: vec![true, true],
+            edited: Some("A\nb\nc\nd\ne\ng\n".to_string()),
+            always: false,
+        };
+        let text = describe(&c, &edited);
+        assert!(text.contains("The user edited"));
+        assert!(text.contains("-f\n+g\n"));
+    }
+
+    #[test]
+    fn test_proposed_changes_reports_refused_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "keep me\n").unwrap();
+        let call = |name: &str, arguments: serde_json::Value| ToolCall {
+            id: "c0".to_string(),
+            name: name.to_string(),
+            arguments,
+        };
+        let path = path.to_str().unwrap();
+
+        let empty = call(
+            "write_file",
+            serde_json::json!({"path": path, "content": ""}),
+        );
+        assert!(matches!(
+            proposed_changes(&empty),
+            Some(Err(ToolError::PermissionDenied(_)))
+        ));
+        let missing = call(
+            "edit_file",
+            serde_json::json!({"path": path, "old_text": "absent", "new_text": "x"}),
+        );
+        assert!(matches!(proposed_changes(&missing), Some(Err(_))));
+        assert!(proposed_changes(&call("shell", serde_json::json!({}))).is_none());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "keep me\n");
+    }
+}
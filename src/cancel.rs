@@ -0,0 +1,60 @@
+//! Cooperative cancellation for the agent loop.
+//!
+//! A `CancelToken` is a shared flag: the Ctrl+C handler sets it, and the
+//! long-running pieces of a turn (streaming LLM requests, shell commands)
+//! poll it and bail out early. Cloning is cheap and every clone observes
+//! the same flag.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation. Idempotent.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Clear the flag so the token can be reused for the next turn.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_token_is_not_cancelled() {
+        assert!(!CancelToken::new().is_cancelled());
+    }
+
+    #[test]
+    fn test_clones_share_state() {
+        let token = CancelToken::new();
+        let handle = token.clone();
+        handle.cancel();
+        assert!(token.is_cancelled());
+        token.reset();
+        assert!(!handle.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_from_another_thread() {
+        let token = CancelToken::new();
+        let handle = token.clone();
+        std::thread::spawn(move || handle.cancel()).join().unwrap();
+        assert!(token.is_cancelled());
+    }
+}
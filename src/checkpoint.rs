@@ -0,0 +1,272 @@
+//! File checkpoints for undoing the agent's edits.
+//!
+//! Before `write_file`, `edit_file` or `apply_diff` touches a file, the
+//! agent copies its current bytes into a checkpoint (or records that the
+//! file did not exist yet). One checkpoint covers one user turn and holds
+//! each file as it was before that turn's first change to it, so
+//! restoring it puts the whole turn back. No git needed.
+//!
+//! Layout, under `.whet/checkpoints/` in the project:
+//!
+//! ```text
+//! 7/manifest.json   id, time, label, and the files in order
+//! 7/0, 7/1, ...     saved contents, by position in the manifest
+//! ```
+//!
+//! Changes made through `shell` are not tracked.
+
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the REPL keeps checkpoints, relative to the project.
+pub const DEFAULT_DIR: &str = ".whet/checkpoints";
+
+/// Checkpoints kept; older ones are deleted when a new one starts.
+const MAX_CHECKPOINTS: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub id: u64,
+    /// RFC 3339.
+    pub created_at: String,
+    /// What the turn was about, from the user's message.
+    pub label: String,
+    pub files: Vec<SavedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedFile {
+    /// Absolute.
+    pub path: PathBuf,
+    /// False for files the turn created; restoring deletes them.
+    pub existed: bool,
+}
+
+pub struct CheckpointStore {
+    root: PathBuf,
+    /// Label for the turn in progress, set by `begin_turn`.
+    label: RefCell<String>,
+    /// The turn's checkpoint, created at its first snapshot.
+    current: RefCell<Option<Checkpoint>>,
+}
+
+impl CheckpointStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            label: RefCell::new(String::new()),
+            current: RefCell::new(None),
+        }
+    }
+
+    /// Start a new turn; its first snapshot opens a fresh checkpoint.
+    pub fn begin_turn(&self, label: &str) {
+        let first_line = label.lines().next().unwrap_or("").trim();
+        *self.label.borrow_mut() = first_line.chars().take(60).collect();
+        *self.current.borrow_mut() = None;
+    }
+
+    /// Save `path` as it is now, unless this turn already saved it.
+    pub fn snapshot(&self, path: &Path) -> io::Result<()> {
+        let path = std::path::absolute(path)?;
+        let mut current = self.current.borrow_mut();
+        if current
+            .as_ref()
+            .is_some_and(|c| c.files.iter().any(|f| f.path == path))
+        {
+            return Ok(());
+        }
+        if current.is_none() {
+            *current = Some(self.create()?);
+        }
+        let checkpoint = current.as_mut().expect("created above");
+        let dir = self.dir(checkpoint.id);
+        let existed = match std::fs::read(&path) {
+            Ok(bytes) => {
+                std::fs::write(dir.join(checkpoint.files.len().to_string()), bytes)?;
+                true
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        checkpoint.files.push(SavedFile { path, existed });
+        write_manifest(&dir, checkpoint)
+    }
+
+    /// All checkpoints, oldest first.
+    pub fn list(&self) -> Vec<Checkpoint> {
+        let mut checkpoints: Vec<Checkpoint> = self
+            .ids()
+            .into_iter()
+            .filter_map(|id| {
+                let text = std::fs::read_to_string(self.dir(id).join("manifest.json")).ok()?;
+                serde_json::from_str(&text).ok()
+            })
+            .collect();
+        checkpoints.sort_by_key(|c| c.id);
+        checkpoints
+    }
+
+    /// Put back the files of the newest checkpoint and drop it, so a
+    /// second undo goes one turn further back.
+    pub fn undo(&self) -> io::Result<Option<Checkpoint>> {
+        let Some(latest) = self.list().pop() else {
+            return Ok(None);
+        };
+        self.restore(latest.id)?;
+        Ok(Some(latest))
+    }
+
+    /// Return the files to how they were before turn `id`, undoing it
+    /// and every later checkpoint, newest first. Returns the paths put
+    /// back or removed.
+    pub fn restore(&self, id: u64) -> io::Result<Vec<PathBuf>> {
+        let later: Vec<Checkpoint> = self.list().into_iter().filter(|c| c.id >= id).collect();
+        if later.first().map(|c| c.id) != Some(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no checkpoint {}", id),
+            ));
+        }
+        let mut touched = Vec::new();
+        for checkpoint in later.iter().rev() {
+            let dir = self.dir(checkpoint.id);
+            for (i, file) in checkpoint.files.iter().enumerate() {
+                if file.existed {
+                    if let Some(parent) = file.path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::copy(dir.join(i.to_string()), &file.path)?;
+                } else {
+                    match std::fs::remove_file(&file.path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                if !touched.contains(&file.path) {
+                    touched.push(file.path.clone());
+                }
+            }
+            std::fs::remove_dir_all(&dir)?;
+        }
+        // The turn's open checkpoint may be among those just removed.
+        *self.current.borrow_mut() = None;
+        Ok(touched)
+    }
+
+    fn create(&self) -> io::Result<Checkpoint> {
+        let mut ids = self.ids();
+        ids.sort_unstable();
+        let id = ids.last().map_or(1, |last| last + 1);
+        while ids.len() >= MAX_CHECKPOINTS {
+            let _ = std::fs::remove_dir_all(self.dir(ids.remove(0)));
+        }
+        std::fs::create_dir_all(self.dir(id))?;
+        Ok(Checkpoint {
+            id,
+            created_at: chrono::Local::now().to_rfc3339(),
+            label: self.label.borrow().clone(),
+            files: Vec::new(),
+        })
+    }
+
+    fn ids(&self) -> Vec<u64> {
+        let Ok(entries) = std::fs::read_dir(&self.root) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect()
+    }
+
+    fn dir(&self, id: u64) -> PathBuf {
+        self.root.join(id.to_string())
+    }
+}
+
+fn write_manifest(dir: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(checkpoint).map_err(io::Error::other)?;
+    std::fs::write(dir.join("manifest.json"), json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &Path) -> CheckpointStore {
+        CheckpointStore::new(dir.join(".whet/checkpoints"))
+    }
+
+    #[test]
+    fn test_undo_restores_edits_and_removes_created_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let edited = dir.path().join("main.rs");
+        let created = dir.path().join("new/mod.rs");
+        std::fs::write(&edited, "original").unwrap();
+        let store = store(dir.path());
+
+        store.begin_turn("refactor main\nmore detail");
+        store.snapshot(&edited).unwrap();
+        std::fs::write(&edited, "first edit").unwrap();
+        // Only the state before the turn's first change is kept.
+        store.snapshot(&edited).unwrap();
+        std::fs::write(&edited, "second edit").unwrap();
+        store.snapshot(&created).unwrap();
+        std::fs::create_dir_all(created.parent().unwrap()).unwrap();
+        std::fs::write(&created, "new").unwrap();
+
+        let undone = store.undo().unwrap().unwrap();
+        assert_eq!(undone.label, "refactor main");
+        assert_eq!(undone.files.len(), 2);
+        assert_eq!(std::fs::read_to_string(&edited).unwrap(), "original");
+        assert!(!created.exists());
+        assert!(store.list().is_empty());
+        assert!(store.undo().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restore_goes_back_through_later_turns() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        std::fs::write(&a, "a0").unwrap();
+        let store = store(dir.path());
+
+        store.begin_turn("one");
+        store.snapshot(&a).unwrap();
+        std::fs::write(&a, "a1").unwrap();
+        store.begin_turn("two");
+        store.snapshot(&a).unwrap();
+        std::fs::write(&a, "a2").unwrap();
+        store.snapshot(&b).unwrap();
+        std::fs::write(&b, "b2").unwrap();
+        store.begin_turn("no edits");
+
+        let ids: Vec<u64> = store.list().iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(store.restore(7).is_err());
+
+        let touched = store.restore(1).unwrap();
+        assert_eq!(touched.len(), 2);
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a0");
+        assert!(!b.exists());
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn test_oldest_checkpoints_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("f");
+        let store = store(dir.path());
+        for turn in 0..MAX_CHECKPOINTS + 2 {
+            store.begin_turn(&format!("turn {}", turn));
+            store.snapshot(&file).unwrap();
+        }
+        let list = store.list();
+        assert_eq!(list.len(), MAX_CHECKPOINTS);
+        assert_eq!(list[0].id, 3);
+        assert_eq!(list.last().unwrap().label, "turn 51");
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -8,6 +9,12 @@ pub struct Config {
     pub memory: MemoryConfig,
     #[serde(default)]
     pub mcp: McpConfig,
+    #[serde(default)]
+    pub permissions: PermissionsConfig,
+    #[serde(default)]
+    pub workspace: WorkspaceConfig,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
 }
 
 /// Permission mode controlling when user approval is required for tool execution.
@@ -55,6 +62,249 @@ pub struct LlmConfig {
     pub streaming: bool,
     #[serde(default)]
     pub options: LlmOptions,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Backup backends tried in order when the primary is unreachable or
+    /// doesn't have the model. Empty by default (no failover).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<LlmBackendConfig>,
+    /// How tool calls are exchanged with the model. `auto` uses native
+    /// tool calling unless the capability probe says the model lacks it.
+    #[serde(default)]
+    pub tool_mode: ToolMode,
+    /// Markup the model is asked to use in text tool mode.
+    #[serde(default)]
+    pub tool_call_format: ToolCallFormat,
+    /// Per-model overrides, keyed by model name:
+    /// `[llm.models."gemma2:9b"]`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub models: BTreeMap<String, ModelOverrides>,
+    /// Other models for parts of the agent: `[llm.roles.compaction]` etc.
+    #[serde(default, skip_serializing_if = "LlmRolesConfig::is_empty")]
+    pub roles: LlmRolesConfig,
+    /// Record/replay file for LLM calls. Read by `provider = "replay"`;
+    /// written when `record = true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cassette: Option<CassetteConfig>,
+}
+
+/// `[llm.cassette]` — see `llm::cassette`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CassetteConfig {
+    /// JSONL cassette, relative to the working directory.
+    #[serde(default = "default_cassette_path")]
+    pub path: String,
+    /// Record this session's calls to `path`, replacing its contents.
+    /// Ignored with `provider = "replay"`.
+    #[serde(default)]
+    pub record: bool,
+}
+
+fn default_cassette_path() -> String {
+    ".whet/cassette.jsonl".to_string()
+}
+
+impl Default for CassetteConfig {
+    fn default() -> Self {
+        Self {
+            path: default_cassette_path(),
+            record: false,
+        }
+    }
+}
+
+impl LlmConfig {
+    /// The cassette to record to, if this session records.
+    pub fn recording_cassette(&self) -> Option<&str> {
+        self.cassette
+            .as_ref()
+            .filter(|c| c.record && self.provider != "replay")
+            .map(|c| c.path.as_str())
+    }
+}
+
+impl LlmConfig {
+    /// Tool mode and text-mode format for `model`, after applying any
+    /// `[llm.models."<model>"]` override.
+    pub fn tool_protocol_for(&self, model: &str) -> (ToolMode, ToolCallFormat) {
+        let overrides = self.models.get(model);
+        (
+            overrides
+                .and_then(|o| o.tool_mode.clone())
+                .unwrap_or_else(|| self.tool_mode.clone()),
+            overrides
+                .and_then(|o| o.tool_call_format.clone())
+                .unwrap_or_else(|| self.tool_call_format.clone()),
+        )
+    }
+
+    /// This section with `role`'s fields laid over it. Everything the
+    /// role leaves out — retry policy, tool mode — is shared, except the
+    /// fallbacks: the role gets only its own.
+    pub fn for_role(&self, role: &LlmRoleConfig) -> LlmConfig {
+        let mut llm = self.clone();
+        if let Some(ref provider) = role.provider {
+            llm.provider = provider.clone();
+        }
+        if let Some(ref model) = role.model {
+            llm.model = model.clone();
+        }
+        if let Some(ref base_url) = role.base_url {
+            llm.base_url = base_url.clone();
+        }
+        if role.api_key.is_some() {
+            llm.api_key = role.api_key.clone();
+        }
+        if let Some(ref options) = role.options {
+            llm.options = options.clone();
+        }
+        llm.fallbacks = role.fallbacks.clone();
+        llm
+    }
+}
+
+/// `[llm.roles]` — which backend serves each part of the agent. A role
+/// left unset uses the main `[llm]` model, so e.g. only `compaction` and
+/// `extraction` need listing to move summarisation onto a small model.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LlmRolesConfig {
+    /// The agent loop itself. `-m` and `/model` still override the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub main: Option<LlmRoleConfig>,
+    /// Child loops started by `/agent` or the `subagent` tool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subagent: Option<LlmRoleConfig>,
+    /// Conversation summarisation (`/compact` and automatic compaction).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compaction: Option<LlmRoleConfig>,
+    /// The focused-extraction call behind `web_fetch` with a `prompt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extraction: Option<LlmRoleConfig>,
+}
+
+impl LlmRolesConfig {
+    fn is_empty(&self) -> bool {
+        self.main.is_none()
+            && self.subagent.is_none()
+            && self.compaction.is_none()
+            && self.extraction.is_none()
+    }
+}
+
+/// One `[llm.roles.<role>]` table. Every field but `fallbacks` is
+/// optional and inherits from `[llm]`, so a smaller model on the same
+/// server only needs `model`. The `[[llm.fallbacks]]` list backs up the
+/// `[llm]` backend, not the role's, so a role has no failover unless it
+/// lists its own `[[llm.roles.<role>.fallbacks]]`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LlmRoleConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<LlmOptions>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<LlmBackendConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolMode {
+    /// Native tool calling unless the probe reports no support
+    #[default]
+    Auto,
+    /// Always send tool definitions through the provider's `tools` field
+    Native,
+    /// Describe tools in the system prompt and parse calls from the reply text
+    Text,
+}
+
+/// Markup for tool calls in text tool mode.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolCallFormat {
+    /// `<tool_call>{"name": ..., "arguments": {...}}</tool_call>`
+    #[default]
+    Xml,
+    /// A fenced block: ```` ```tool_call ```` … ```` ``` ````
+    Json,
+}
+
+/// One `[llm.models."<name>"]` table. Unset fields fall back to `[llm]`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ModelOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_mode: Option<ToolMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_format: Option<ToolCallFormat>,
+}
+
+/// One `[[llm.fallbacks]]` entry. `model`, `api_key` and `options`
+/// inherit from the primary `[llm]` section when omitted, so a second
+/// machine serving the same model only needs `provider` and `base_url`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LlmBackendConfig {
+    pub provider: String,
+    pub base_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<LlmOptions>,
+}
+
+/// `[llm.retry]` — how transient backend errors are retried before the
+/// turn gives up. Applied per backend, so with `[[llm.fallbacks]]` each
+/// backend gets its own attempts before failover moves on.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryConfig {
+    /// Total attempts per LLM call, including the first. 1 disables retry.
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubles on each further attempt.
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// Upper bound on the delay between attempts.
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// `LlmError` kinds worth retrying: `server_error`, `connection_error`,
+    /// `request_error`, `parse_error`, `model_not_found`. Default retries
+    /// only 5xx responses — the "model still loading" case.
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<String>,
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    8000
+}
+
+fn default_retry_on() -> Vec<String> {
+    vec!["server_error".to_string()]
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff_ms: default_retry_initial_backoff_ms(),
+            max_backoff_ms: default_retry_max_backoff_ms(),
+            retry_on: default_retry_on(),
+        }
+    }
 }
 
 /// Per-request inference knobs passed to local providers (Ollama, OpenAI-compatible).
@@ -84,6 +334,16 @@ pub struct LlmOptions {
     /// use the model's default.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub think: Option<bool>,
+    /// Reuse the KV cache for the prompt prefix shared with the previous
+    /// request (llama.cpp `cache_prompt`). Each agent iteration resends
+    /// the whole conversation, so this skips re-evaluating all but the
+    /// new messages. OpenAI-compatible and llama.cpp providers only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_prompt: Option<bool>,
+    /// Pin requests to one llama-server slot (`id_slot`), so with
+    /// `--parallel` > 1 the cached prefix isn't lost to another slot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot_id: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -121,6 +381,67 @@ pub struct AgentConfig {
     pub compaction_token_threshold_ratio: f32,
     #[serde(default = "default_skills_dir")]
     pub skills_dir: String,
+    /// Print the model's thinking trace (dimmed) in the REPL. Toggle at
+    /// runtime with `/reasoning`. Only has something to show when the
+    /// backend returns a trace, e.g. Ollama with `think = true`.
+    #[serde(default = "default_show_reasoning")]
+    pub show_reasoning: bool,
+    /// Keep the thinking trace in the conversation history sent back to
+    /// the model. Off by default to save context.
+    #[serde(default)]
+    pub keep_reasoning: bool,
+    /// Prompt plus completion tokens a single turn may spend. Unset means
+    /// no limit. The REPL offers to continue when it runs out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_turn_tokens: Option<u64>,
+    /// Wall-clock seconds a single turn may take, checked between model
+    /// calls. Unset means no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_turn_seconds: Option<u64>,
+    /// Per-policy overrides for the end-of-turn reprompt checks, keyed
+    /// by policy name (`question`, `failed_tool`, `only_reads`, or one an
+    /// embedder registered).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub policies: BTreeMap<String, LoopPolicyConfig>,
+    /// Check run after turns that changed something; see `VerifyConfig`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify: Option<VerifyConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LoopPolicyConfig {
+    #[serde(default = "default_policy_enabled")]
+    pub enabled: bool,
+    /// Replaces the policy's built-in reprompt text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reprompt: Option<String>,
+}
+
+fn default_policy_enabled() -> bool {
+    true
+}
+
+/// `[agent.verify]`: a command such as `cargo check` that the agent runs
+/// itself when the model ends a turn in which it edited files or ran
+/// commands. A failing check goes back to the model instead of ending
+/// the turn.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VerifyConfig {
+    pub command: String,
+    /// Times the check may run in one turn. Once used up, the turn ends
+    /// even if the last run failed.
+    #[serde(default = "default_verify_max_rounds")]
+    pub max_rounds: usize,
+    #[serde(default = "default_verify_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_verify_max_rounds() -> usize {
+    3
+}
+
+fn default_verify_timeout_secs() -> u64 {
+    300
 }
 
 fn default_context_compression() -> bool {
@@ -135,6 +456,10 @@ fn default_compaction_token_threshold_ratio() -> f32 {
     0.85
 }
 
+fn default_show_reasoning() -> bool {
+    true
+}
+
 fn default_skills_dir() -> String {
     "~/.whet/skills".to_string()
 }
@@ -155,6 +480,115 @@ fn default_max_inject_memories() -> usize {
     50
 }
 
+/// `[[permissions.allow]]`, `[[permissions.deny]]` and `[[permissions.ask]]`
+/// rules. They take precedence over `permission_mode`: a matching `deny`
+/// blocks the call, then `ask` prompts, then `allow` runs it unasked.
+/// Calls no rule matches fall back to the mode.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PermissionsConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<PermissionRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<PermissionRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ask: Vec<PermissionRule>,
+}
+
+/// One permission rule. Every field that is set must match; a rule with
+/// none set matches every call.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PermissionRule {
+    /// Tool name, e.g. `shell` or `write_file`. `*` matches any tool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+    /// The exact `shell` command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// A `shell` command starting with these words, e.g. `cargo test`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_prefix: Option<String>,
+    /// A `shell` command matching this glob; `*` matches anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_glob: Option<String>,
+    /// The `git` tool's subcommand, e.g. `push`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    /// The `git` tool's exact arguments after the subcommand, e.g.
+    /// `origin main`. An empty string means no arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_args: Option<String>,
+    /// Glob for the file a tool reads or writes, e.g. `src/**` or
+    /// `**/.env`. `*` stays within one directory, `**` crosses them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// `[workspace]`: keep the file tools inside the project.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct WorkspaceConfig {
+    /// Reject file-tool paths that resolve outside the directory whet
+    /// was started in and `allowed_dirs`.
+    #[serde(default)]
+    pub confine: bool,
+    /// Extra directories the file tools may use when confined. Writes
+    /// to them still ask for approval, even in yolo mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_dirs: Vec<String>,
+}
+
+/// `[sandbox]`: run `shell`, `/test` and the verify command inside Linux
+/// namespaces instead of with the user's full privileges.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SandboxConfig {
+    #[serde(default)]
+    pub profile: SandboxProfile,
+    #[serde(default)]
+    pub backend: SandboxBackend,
+    /// Directories besides the project and the temp dir that commands
+    /// may write to, e.g. `~/.cargo/registry`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub writable_dirs: Vec<String>,
+    /// Address-space limit per process.
+    pub memory_mb: Option<u64>,
+    /// CPU time limit per process.
+    pub cpu_seconds: Option<u64>,
+    /// Limit on processes for the user, counted across the whole system.
+    pub max_processes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxProfile {
+    /// Commands run unconfined.
+    #[default]
+    Off,
+    /// Read-only filesystem except the project, the temp dir and
+    /// `writable_dirs`; network allowed.
+    Workspace,
+    /// `Workspace` without network access.
+    Offline,
+}
+
+impl fmt::Display for SandboxProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxProfile::Off => write!(f, "off"),
+            SandboxProfile::Workspace => write!(f, "workspace"),
+            SandboxProfile::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxBackend {
+    /// `bwrap` if it is on `PATH`, else `unshare`.
+    #[default]
+    Auto,
+    Bubblewrap,
+    Unshare,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct McpConfig {
     #[serde(default)]
@@ -179,6 +613,13 @@ impl Default for Config {
                 api_key: None,
                 streaming: false,
                 options: LlmOptions::default(),
+                retry: RetryConfig::default(),
+                fallbacks: Vec::new(),
+                tool_mode: Default::default(),
+                tool_call_format: Default::default(),
+                models: Default::default(),
+                roles: Default::default(),
+                cassette: None,
             },
             agent: AgentConfig {
                 max_iterations: 10,
@@ -188,12 +629,21 @@ impl Default for Config {
                 compaction_token_threshold: default_compaction_token_threshold(),
                 compaction_token_threshold_ratio: default_compaction_token_threshold_ratio(),
                 skills_dir: "~/.whet/skills".to_string(),
+                show_reasoning: default_show_reasoning(),
+                keep_reasoning: false,
+                max_turn_tokens: None,
+                max_turn_seconds: None,
+                policies: BTreeMap::new(),
+                verify: None,
             },
             memory: MemoryConfig {
                 database_path: "~/.whet/memory.db".to_string(),
                 max_inject_memories: default_max_inject_memories(),
             },
             mcp: McpConfig::default(),
+            permissions: PermissionsConfig::default(),
+            workspace: WorkspaceConfig::default(),
+            sandbox: SandboxConfig::default(),
         }
     }
 }
@@ -217,10 +667,7 @@ impl Config {
     /// running multiple isolated profiles); tests should call `load_from`
     /// directly with a tempdir to avoid env-var races under parallel runs.
     pub fn load() -> Self {
-        let home = std::env::var_os("WHET_CONFIG_HOME")
-            .map(std::path::PathBuf::from)
-            .or_else(dirs::home_dir);
-        match home {
+        match config_home() {
             Some(h) => Self::load_from(&h),
             None => Self::default(),
         }
@@ -256,6 +703,14 @@ impl Config {
     }
 }
 
+/// Directory that holds `.whet/`: `WHET_CONFIG_HOME` when set, else the
+/// user's home directory.
+pub fn config_home() -> Option<std::path::PathBuf> {
+    std::env::var_os("WHET_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(dirs::home_dir)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -697,4 +1152,400 @@ database_path = "test.db"
         let parsed = Config::from_toml(&toml_str).unwrap();
         assert_eq!(parsed.agent.permission_mode, PermissionMode::Yolo);
     }
+
+    #[test]
+    fn test_config_without_fallbacks_defaults_empty() {
+        let config = Config::default();
+        assert!(config.llm.fallbacks.is_empty());
+        // Empty list is not serialized, keeping generated configs minimal
+        assert!(!config.to_toml().unwrap().contains("fallbacks"));
+    }
+
+    #[test]
+    fn test_config_with_fallbacks() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://workstation:11434"
+
+[[llm.fallbacks]]
+provider = "llamacpp"
+base_url = "http://backup:8080"
+model = "qwen3-8b-q4"
+
+[[llm.fallbacks]]
+provider = "ollama"
+base_url = "http://localhost:11434"
+
+[llm.fallbacks.options]
+num_ctx = 8192
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "test.db"
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        assert_eq!(config.llm.fallbacks.len(), 2);
+        let first = &config.llm.fallbacks[0];
+        assert_eq!(first.provider, "llamacpp");
+        assert_eq!(first.base_url, "http://backup:8080");
+        assert_eq!(first.model.as_deref(), Some("qwen3-8b-q4"));
+        assert!(first.options.is_none());
+        let second = &config.llm.fallbacks[1];
+        assert!(second.model.is_none());
+        assert_eq!(second.options.as_ref().unwrap().num_ctx, Some(8192));
+
+        let parsed = Config::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.llm.fallbacks.len(), 2);
+    }
+
+    #[test]
+    fn test_retry_defaults_when_section_missing() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "test.db"
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        assert_eq!(config.llm.retry.max_attempts, 3);
+        assert_eq!(config.llm.retry.initial_backoff_ms, 1000);
+        assert_eq!(config.llm.retry.max_backoff_ms, 8000);
+        assert_eq!(config.llm.retry.retry_on, vec!["server_error"]);
+    }
+
+    #[test]
+    fn test_retry_partial_section() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[llm.retry]
+max_attempts = 5
+retry_on = ["server_error", "connection_error"]
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "test.db"
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        assert_eq!(config.llm.retry.max_attempts, 5);
+        // Unset keys keep their defaults
+        assert_eq!(config.llm.retry.initial_backoff_ms, 1000);
+        assert_eq!(
+            config.llm.retry.retry_on,
+            vec!["server_error", "connection_error"]
+        );
+    }
+
+    #[test]
+    fn test_tool_mode_defaults_to_auto_xml() {
+        let config = Config::default();
+        assert_eq!(
+            config.llm.tool_protocol_for("qwen3:8b"),
+            (ToolMode::Auto, ToolCallFormat::Xml)
+        );
+        assert!(!config.to_toml().unwrap().contains("[llm.models"));
+    }
+
+    #[test]
+    fn test_tool_mode_per_model_override() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+tool_mode = "native"
+
+[llm.models."gemma2:9b"]
+tool_mode = "text"
+tool_call_format = "json"
+
+[llm.models."phi3:mini"]
+tool_mode = "text"
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "test.db"
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        assert_eq!(
+            config.llm.tool_protocol_for("qwen3:8b"),
+            (ToolMode::Native, ToolCallFormat::Xml)
+        );
+        assert_eq!(
+            config.llm.tool_protocol_for("gemma2:9b"),
+            (ToolMode::Text, ToolCallFormat::Json)
+        );
+        assert_eq!(
+            config.llm.tool_protocol_for("phi3:mini"),
+            (ToolMode::Text, ToolCallFormat::Xml)
+        );
+    }
+
+    #[test]
+    fn test_cassette_section() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[llm.cassette]
+record = true
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "test.db"
+"#;
+        let mut config: Config = toml::from_str(toml_str).unwrap();
+        let cassette = config.llm.cassette.clone().unwrap();
+        assert_eq!(cassette.path, ".whet/cassette.jsonl");
+        assert_eq!(
+            config.llm.recording_cassette(),
+            Some(".whet/cassette.jsonl")
+        );
+        // Replaying never overwrites the cassette it reads.
+        config.llm.provider = "replay".to_string();
+        assert_eq!(config.llm.recording_cassette(), None);
+    }
+
+    #[test]
+    fn test_turn_budgets() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+max_turn_tokens = 200000
+max_turn_seconds = 600
+
+[memory]
+database_path = "test.db"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.agent.max_turn_tokens, Some(200_000));
+        assert_eq!(config.agent.max_turn_seconds, Some(600));
+        assert_eq!(Config::default().agent.max_turn_tokens, None);
+    }
+
+    #[test]
+    fn test_loop_policy_overrides() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+
+[agent.policies.question]
+enabled = false
+
+[agent.policies.only_reads]
+reprompt = "Keep going until the change is made."
+
+[memory]
+database_path = "test.db"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let policies = &config.agent.policies;
+        assert!(!policies["question"].enabled);
+        assert!(policies["only_reads"].enabled);
+        assert_eq!(
+            policies["only_reads"].reprompt.as_deref(),
+            Some("Keep going until the change is made.")
+        );
+        assert!(Config::default().agent.policies.is_empty());
+    }
+
+    #[test]
+    fn test_permission_rules_parse() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "~/.whet/memory.db"
+
+[[permissions.allow]]
+tool = "shell"
+command_prefix = "cargo test"
+
+[[permissions.deny]]
+path = "**/.env"
+
+[[permissions.ask]]
+tool = "git"
+git = "push"
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        let rules = &config.permissions;
+        assert_eq!(rules.allow[0].command_prefix.as_deref(), Some("cargo test"));
+        assert_eq!(rules.deny[0].tool, None);
+        assert_eq!(rules.deny[0].path.as_deref(), Some("**/.env"));
+        assert_eq!(rules.ask[0].git.as_deref(), Some("push"));
+        assert_eq!(Config::default().permissions, PermissionsConfig::default());
+    }
+
+    #[test]
+    fn test_sandbox_section() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "~/.whet/memory.db"
+
+[sandbox]
+profile = "offline"
+backend = "unshare"
+writable_dirs = ["~/.cargo/registry"]
+memory_mb = 4096
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        assert_eq!(config.sandbox.profile, SandboxProfile::Offline);
+        assert_eq!(config.sandbox.backend, SandboxBackend::Unshare);
+        assert_eq!(config.sandbox.memory_mb, Some(4096));
+        assert_eq!(config.sandbox.cpu_seconds, None);
+        assert_eq!(Config::default().sandbox.profile, SandboxProfile::Off);
+    }
+
+    #[test]
+    fn test_workspace_section() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "~/.whet/memory.db"
+
+[workspace]
+confine = true
+allowed_dirs = ["~/notes"]
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        assert!(config.workspace.confine);
+        assert_eq!(config.workspace.allowed_dirs, vec!["~/notes"]);
+        assert!(!Config::default().workspace.confine);
+    }
+
+    #[test]
+    fn test_verify_defaults() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:8b"
+base_url = "http://localhost:11434"
+
+[agent]
+max_iterations = 10
+
+[agent.verify]
+command = "cargo check"
+
+[memory]
+database_path = "test.db"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let verify = config.agent.verify.unwrap();
+        assert_eq!(verify.command, "cargo check");
+        assert_eq!(verify.max_rounds, 3);
+        assert_eq!(verify.timeout_secs, 300);
+        assert!(Config::default().agent.verify.is_none());
+    }
+
+    #[test]
+    fn test_roles_inherit_from_llm_section() {
+        let toml_str = r#"
+[llm]
+provider = "ollama"
+model = "qwen3:32b"
+base_url = "http://localhost:11434"
+
+[llm.options]
+num_ctx = 32768
+
+[[llm.fallbacks]]
+provider = "ollama"
+base_url = "http://backup:11434"
+
+[llm.roles.compaction]
+model = "qwen2.5:1.5b"
+
+[llm.roles.extraction]
+provider = "llamacpp"
+base_url = "http://localhost:8080"
+model = "gemma3-4b"
+
+[llm.roles.extraction.options]
+num_ctx = 8192
+
+[[llm.roles.extraction.fallbacks]]
+provider = "llamacpp"
+base_url = "http://backup:8080"
+
+[agent]
+max_iterations = 10
+
+[memory]
+database_path = "test.db"
+"#;
+        let config = Config::from_toml(toml_str).unwrap();
+        let roles = &config.llm.roles;
+        assert!(roles.main.is_none() && roles.subagent.is_none());
+
+        let compaction = config.llm.for_role(roles.compaction.as_ref().unwrap());
+        assert_eq!(compaction.model, "qwen2.5:1.5b");
+        assert_eq!(compaction.provider, "ollama");
+        assert_eq!(compaction.base_url, "http://localhost:11434");
+        assert_eq!(compaction.options.num_ctx, Some(32768));
+        // The main backend's fallbacks don't carry over to a role.
+        assert!(compaction.fallbacks.is_empty());
+
+        let extraction = config.llm.for_role(roles.extraction.as_ref().unwrap());
+        assert_eq!(extraction.provider, "llamacpp");
+        assert_eq!(extraction.base_url, "http://localhost:8080");
+        assert_eq!(extraction.options.num_ctx, Some(8192));
+        assert_eq!(extraction.fallbacks.len(), 1);
+        assert_eq!(extraction.fallbacks[0].base_url, "http://backup:8080");
+
+        assert!(!Config::default().to_toml().unwrap().contains("roles"));
+    }
 }
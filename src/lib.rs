@@ -1,4 +1,6 @@
 pub mod agent;
+pub mod cancel;
+pub mod checkpoint;
 pub mod config;
 pub mod llm;
 pub mod mcp;
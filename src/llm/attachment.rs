@@ -0,0 +1,125 @@
+//! Images carried alongside message text, for vision models.
+//!
+//! Each provider encodes them its own way: Ollama takes bare base64 in
+//! the message's `images` list, OpenAI-compatible servers take `image_url`
+//! content parts with a `data:` URL. Only formats that the common vision
+//! models accept are recognised; anything else stays a text file.
+
+use base64::Engine;
+use std::path::Path;
+
+/// Largest image accepted. Vision encoders downscale anyway, and every
+/// attached image is re-sent with each request of the session.
+pub const MAX_ATTACHMENT_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Rough prompt cost of one image for context budgeting. Real costs vary
+/// by model and resolution (a few hundred to ~1.5K tokens); this keeps
+/// compaction from ignoring images entirely.
+pub const ATTACHMENT_TOKEN_ESTIMATE: usize = 768;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    /// File name shown to the user and mentioned to text-only models.
+    pub name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    /// Load an image file. Fails for unrecognised extensions, so callers
+    /// can try it on any path and fall back to reading text.
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let mime_type = image_mime_type(path)
+            .ok_or_else(|| format!("'{}' is not a supported image type", path.display()))?;
+        let size = std::fs::metadata(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?
+            .len();
+        if size > MAX_ATTACHMENT_BYTES {
+            return Err(format!(
+                "Image '{}' is too large ({} bytes, max {} bytes)",
+                path.display(),
+                size,
+                MAX_ATTACHMENT_BYTES
+            ));
+        }
+        let data = std::fs::read(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        Ok(Self {
+            name,
+            mime_type: mime_type.to_string(),
+            data,
+        })
+    }
+
+    pub fn base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.data)
+    }
+
+    /// `data:<mime>;base64,...`, as OpenAI-style `image_url` parts expect.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.base64())
+    }
+
+    /// Short label, e.g. `screenshot.png (image/png, 42 KB)`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({}, {} KB)",
+            self.name,
+            self.mime_type,
+            self.data.len().div_ceil(1024)
+        )
+    }
+}
+
+/// MIME type for image extensions vision models accept, `None` otherwise.
+pub fn image_mime_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_mime_type_by_extension() {
+        assert_eq!(image_mime_type(Path::new("a.PNG")), Some("image/png"));
+        assert_eq!(image_mime_type(Path::new("dir/b.jpeg")), Some("image/jpeg"));
+        assert_eq!(image_mime_type(Path::new("c.webp")), Some("image/webp"));
+        assert_eq!(image_mime_type(Path::new("main.rs")), None);
+        assert_eq!(image_mime_type(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn test_from_path_encodes_data_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shot.png");
+        std::fs::write(&path, b"\x89PNG").unwrap();
+        let image = Attachment::from_path(&path).unwrap();
+        assert_eq!(image.name, "shot.png");
+        assert_eq!(image.base64(), "iVBORw==");
+        assert_eq!(image.data_url(), "data:image/png;base64,iVBORw==");
+        assert_eq!(image.describe(), "shot.png (image/png, 1 KB)");
+    }
+
+    #[test]
+    fn test_from_path_rejects_non_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "hi").unwrap();
+        assert!(Attachment::from_path(&path)
+            .unwrap_err()
+            .contains("not a supported image type"));
+        assert!(Attachment::from_path(&dir.path().join("missing.png")).is_err());
+    }
+}
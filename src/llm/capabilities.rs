@@ -0,0 +1,395 @@
+//! Model capability probing.
+//!
+//! Asks the backend what the configured model can do — context window,
+//! native tool calling, thinking — so whet doesn't have to assume every
+//! model takes `tools` and has whatever `num_ctx` the config says:
+//!
+//! - Ollama: `POST /api/show` (`capabilities`, `model_info`, `parameters`)
+//! - llama.cpp: `GET /props` (`default_generation_settings.n_ctx`)
+//! - OpenAI-compatible: `GET /v1/models` metadata (`max_model_len`, …)
+//!
+//! Results are cached per backend+model in `~/.whet/capabilities.json`
+//! so startup doesn't pay a round trip every time. Anything the backend
+//! doesn't report stays `None` ("unknown"), and callers treat unknown as
+//! "keep the current behaviour".
+
+use super::LlmError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    /// Context window in tokens: the server's configured window when it
+    /// reports one, otherwise the model's trained maximum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<u32>,
+    /// Whether the chat template accepts native `tools`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<bool>,
+    /// Whether the model accepts the `think` option.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<bool>,
+    /// Whether the model accepts images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vision: Option<bool>,
+}
+
+/// Probe `model` on the backend. `provider` uses the same names as
+/// `[llm].provider`; anything unrecognised is treated as Ollama.
+pub fn probe(
+    provider: &str,
+    base_url: &str,
+    model: &str,
+    api_key: Option<&str>,
+) -> Result<ModelCapabilities, LlmError> {
+    let base_url = base_url.trim_end_matches('/');
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap_or_else(|_| reqwest::blocking::Client::new());
+    let get = |path: &str| -> Result<Value, LlmError> {
+        let mut request = client.get(format!("{}{}", base_url, path));
+        if let Some(key) = api_key {
+            request = request.bearer_auth(key);
+        }
+        read_json(request.send())
+    };
+    match provider {
+        "openai_compat" => Ok(parse_openai_models(&get("/v1/models")?, model)),
+        "llamacpp" => match get("/props") {
+            Ok(props) => Ok(parse_llamacpp_props(&props)),
+            Err(_) => Ok(parse_openai_models(&get("/v1/models")?, model)),
+        },
+        _ => {
+            let response = client
+                .post(format!("{}/api/show", base_url))
+                .json(&serde_json::json!({ "model": model }))
+                .send();
+            Ok(parse_ollama_show(&read_json(response)?))
+        }
+    }
+}
+
+fn read_json(response: reqwest::Result<reqwest::blocking::Response>) -> Result<Value, LlmError> {
+    let response = response.map_err(|e| LlmError::ConnectionError(e.to_string()))?;
+    let status = response.status();
+    if status.as_u16() == 404 {
+        return Err(LlmError::ModelNotFound(status.to_string()));
+    }
+    if !status.is_success() {
+        return Err(LlmError::RequestError(format!("status {}", status)));
+    }
+    response
+        .json()
+        .map_err(|e| LlmError::ParseError(e.to_string()))
+}
+
+pub fn parse_ollama_show(show: &Value) -> ModelCapabilities {
+    // A `num_ctx` baked into the Modelfile is what Ollama actually runs
+    // with; the architecture's context_length is only the ceiling.
+    let modelfile_ctx = show["parameters"].as_str().and_then(|params| {
+        params.lines().find_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("num_ctx"), Some(n)) => n.parse().ok(),
+                _ => None,
+            }
+        })
+    });
+    let trained_ctx = show["model_info"].as_object().and_then(|info| {
+        info.iter()
+            .find(|(k, _)| k.ends_with(".context_length"))
+            .and_then(|(_, v)| v.as_u64())
+            .map(|n| n.min(u32::MAX as u64) as u32)
+    });
+
+    let listed = show["capabilities"]
+        .as_array()
+        .map(|caps| caps.iter().filter_map(|c| c.as_str()).collect::<Vec<_>>());
+    // Ollama < 0.6 has no `capabilities` list; a template that never
+    // mentions `.Tools` can't render tool definitions either way.
+    let tools = match (&listed, show["template"].as_str()) {
+        (Some(caps), _) => Some(caps.contains(&"tools")),
+        (None, Some(template)) => Some(template.contains(".Tools")),
+        (None, None) => None,
+    };
+    let thinking = listed.as_ref().map(|caps| caps.contains(&"thinking"));
+    let vision = listed.as_ref().map(|caps| caps.contains(&"vision"));
+
+    ModelCapabilities {
+        context_length: modelfile_ctx.or(trained_ctx),
+        tools,
+        thinking,
+        vision,
+    }
+}
+
+pub fn parse_llamacpp_props(props: &Value) -> ModelCapabilities {
+    ModelCapabilities {
+        context_length: props["default_generation_settings"]["n_ctx"]
+            .as_u64()
+            .map(|n| n as u32),
+        ..ModelCapabilities::default()
+    }
+}
+
+pub fn parse_openai_models(models: &Value, model: &str) -> ModelCapabilities {
+    let Some(entries) = models["data"].as_array() else {
+        return ModelCapabilities::default();
+    };
+    // Single-model servers (llama-server, vLLM with one model) often
+    // report an id that doesn't match the name the user configured.
+    let entry = entries
+        .iter()
+        .find(|e| e["id"].as_str() == Some(model))
+        .or_else(|| entries.first().filter(|_| entries.len() == 1));
+    let Some(entry) = entry else {
+        return ModelCapabilities::default();
+    };
+    let context_length = [
+        &entry["max_model_len"],
+        &entry["context_length"],
+        &entry["max_context_length"],
+        &entry["meta"]["n_ctx_train"],
+    ]
+    .into_iter()
+    .find_map(|v| v.as_u64())
+    .map(|n| n.min(u32::MAX as u64) as u32);
+    ModelCapabilities {
+        context_length,
+        ..ModelCapabilities::default()
+    }
+}
+
+/// How long a cached probe is trusted. Re-pulling a model under the same
+/// tag can change its template, so entries don't live forever.
+const CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    capabilities: ModelCapabilities,
+    probed_at: u64,
+}
+
+/// JSON file mapping `"<provider> <base_url> <model>"` to the last probe
+/// result. Read and written whole; it holds one small entry per model.
+pub struct CapabilityCache {
+    path: PathBuf,
+}
+
+impl CapabilityCache {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn key(provider: &str, base_url: &str, model: &str) -> String {
+        format!("{} {} {}", provider, base_url.trim_end_matches('/'), model)
+    }
+
+    fn load(&self) -> BTreeMap<String, CacheEntry> {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<ModelCapabilities> {
+        let entry = self.load().remove(key)?;
+        let age = now_secs().saturating_sub(entry.probed_at);
+        (age < CACHE_TTL.as_secs()).then_some(entry.capabilities)
+    }
+
+    /// Best effort: a read-only home directory just means re-probing
+    /// next time.
+    pub fn put(&self, key: &str, capabilities: &ModelCapabilities) {
+        let mut entries = self.load();
+        entries.insert(
+            key.to_string(),
+            CacheEntry {
+                capabilities: capabilities.clone(),
+                probed_at: now_secs(),
+            },
+        );
+        if let Some(parent) = self.path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        if let Ok(s) = serde_json::to_string_pretty(&entries) {
+            let _ = std::fs::write(&self.path, s);
+        }
+    }
+
+    /// Cached capabilities for `key`, probing (and caching) on a miss.
+    /// A failed probe is not cached, so an offline server is retried on
+    /// the next start.
+    pub fn get_or_probe(
+        &self,
+        key: &str,
+        probe: impl FnOnce() -> Result<ModelCapabilities, LlmError>,
+    ) -> Option<ModelCapabilities> {
+        if let Some(caps) = self.get(key) {
+            return Some(caps);
+        }
+        let caps = probe().ok()?;
+        self.put(key, &caps);
+        Some(caps)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::StubServer;
+    use serde_json::json;
+
+    #[test]
+    fn test_ollama_show_with_capabilities_list() {
+        let caps = parse_ollama_show(&json!({
+            "capabilities": ["completion", "tools", "thinking", "vision"],
+            "model_info": {"general.architecture": "qwen3", "qwen3.context_length": 40960},
+            "template": "{{ .Prompt }}"
+        }));
+        assert_eq!(caps.context_length, Some(40960));
+        assert_eq!(caps.tools, Some(true));
+        assert_eq!(caps.thinking, Some(true));
+        assert_eq!(caps.vision, Some(true));
+    }
+
+    #[test]
+    fn test_ollama_modelfile_num_ctx_wins_over_trained_length() {
+        let caps = parse_ollama_show(&json!({
+            "capabilities": ["completion"],
+            "parameters": "stop \"<|im_end|>\"\nnum_ctx 16384",
+            "model_info": {"llama.context_length": 131072}
+        }));
+        assert_eq!(caps.context_length, Some(16384));
+        assert_eq!(caps.tools, Some(false));
+        assert_eq!(caps.thinking, Some(false));
+        assert_eq!(caps.vision, Some(false));
+    }
+
+    #[test]
+    fn test_old_ollama_falls_back_to_template_inspection() {
+        let with_tools = parse_ollama_show(&json!({"template": "{{ if .Tools }}...{{ end }}"}));
+        assert_eq!(with_tools.tools, Some(true));
+        assert_eq!(with_tools.thinking, None);
+        let without = parse_ollama_show(&json!({"template": "{{ .Prompt }}"}));
+        assert_eq!(without.tools, Some(false));
+        assert_eq!(parse_ollama_show(&json!({})), ModelCapabilities::default());
+    }
+
+    #[test]
+    fn test_openai_models_metadata() {
+        let models = json!({"data": [
+            {"id": "a", "max_model_len": 8192},
+            {"id": "b", "meta": {"n_ctx_train": 32768}}
+        ]});
+        assert_eq!(parse_openai_models(&models, "a").context_length, Some(8192));
+        assert_eq!(
+            parse_openai_models(&models, "b").context_length,
+            Some(32768)
+        );
+        assert_eq!(parse_openai_models(&models, "c").context_length, None);
+        // A lone entry is used even when its id doesn't match.
+        let single = json!({"data": [{"id": "/models/q.gguf", "context_length": 4096}]});
+        assert_eq!(
+            parse_openai_models(&single, "qwen").context_length,
+            Some(4096)
+        );
+    }
+
+    #[test]
+    fn test_llamacpp_props() {
+        let caps = parse_llamacpp_props(&json!({"default_generation_settings": {"n_ctx": 12288}}));
+        assert_eq!(caps.context_length, Some(12288));
+        assert_eq!(caps.tools, None);
+    }
+
+    #[test]
+    fn test_probe_ollama_posts_model_name() {
+        let server = StubServer::json(vec![json!({
+            "capabilities": ["completion"],
+            "model_info": {"gemma.context_length": 8192}
+        })]);
+        let caps = probe("ollama", &server.url, "gemma:2b", None).unwrap();
+        assert_eq!(caps.tools, Some(false));
+        let req = &server.requests()[0];
+        assert_eq!(req.path, "/api/show");
+        assert_eq!(req.json()["model"], "gemma:2b");
+    }
+
+    #[test]
+    fn test_probe_llamacpp_falls_back_to_models_list() {
+        let server = StubServer::start(vec![
+            (404, "text/plain", "not found".to_string()),
+            (
+                200,
+                "application/json",
+                json!({"data": [{"id": "m", "meta": {"n_ctx_train": 2048}}]}).to_string(),
+            ),
+        ]);
+        let caps = probe("llamacpp", &server.url, "m", None).unwrap();
+        assert_eq!(caps.context_length, Some(2048));
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec!["/props", "/v1/models"]);
+    }
+
+    #[test]
+    fn test_cache_roundtrip_and_probe_only_on_miss() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CapabilityCache::new(dir.path().join(".whet").join("capabilities.json"));
+        let key = CapabilityCache::key("ollama", "http://localhost:11434/", "qwen3:8b");
+        assert_eq!(key, "ollama http://localhost:11434 qwen3:8b");
+
+        let caps = ModelCapabilities {
+            context_length: Some(40960),
+            tools: Some(true),
+            thinking: Some(true),
+            vision: None,
+        };
+        let mut probes = 0;
+        let got = cache.get_or_probe(&key, || {
+            probes += 1;
+            Ok(caps.clone())
+        });
+        assert_eq!(got.as_ref(), Some(&caps));
+        let again = cache.get_or_probe(&key, || {
+            probes += 1;
+            Ok(ModelCapabilities::default())
+        });
+        assert_eq!(again, Some(caps));
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn test_failed_probe_is_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CapabilityCache::new(dir.path().join("capabilities.json"));
+        let got = cache.get_or_probe("k", || Err(LlmError::ConnectionError("down".into())));
+        assert_eq!(got, None);
+        assert_eq!(cache.get("k"), None);
+    }
+
+    #[test]
+    fn test_expired_entry_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capabilities.json");
+        std::fs::write(
+            &path,
+            json!({"k": {"context_length": 4096, "probed_at": 0}}).to_string(),
+        )
+        .unwrap();
+        let cache = CapabilityCache::new(path);
+        assert_eq!(cache.get("k"), None);
+    }
+}
@@ -0,0 +1,546 @@
+//! Record/replay of LLM calls for deterministic runs.
+//!
+//! `RecordingProvider` wraps a live provider and appends every call it
+//! serves to a cassette — a JSONL file with one request/response pair per
+//! line. `ReplayProvider` (`provider = "replay"`) answers from that file
+//! instead of a model, so agent behaviour can be regression-tested
+//! offline and in CI.
+//!
+//! Requests are matched by a hash of the conversation without its system
+//! messages (those embed the working directory and `WHET.md`, which
+//! differ between machines), plus the tool names and, for structured
+//! calls, the schema. A request seen several times is answered with its
+//! recorded responses in order, the last one repeating.
+
+use super::{
+    LlmError, LlmProvider, LlmResponse, Message, Role, TokenUsage, ToolCall, ToolCallProgress,
+    ToolDefinition,
+};
+use crate::cancel::CancelToken;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// One cassette line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    key: String,
+    /// The hashed request, kept so a cassette can be read and diffed.
+    request: Value,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prompt_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completion_tokens: Option<u64>,
+}
+
+impl RecordedResponse {
+    fn from_response(resp: &LlmResponse) -> Self {
+        Self {
+            content: resp.content.clone(),
+            tool_calls: resp.tool_calls.clone(),
+            reasoning: resp.reasoning.clone(),
+            prompt_tokens: resp.usage.prompt_tokens,
+            completion_tokens: resp.usage.completion_tokens,
+        }
+    }
+
+    fn to_response(&self) -> LlmResponse {
+        LlmResponse {
+            content: self.content.clone(),
+            tool_calls: self.tool_calls.clone(),
+            usage: TokenUsage {
+                prompt_tokens: self.prompt_tokens,
+                completion_tokens: self.completion_tokens,
+                ..Default::default()
+            },
+            reasoning: self.reasoning.clone(),
+        }
+    }
+}
+
+/// The part of a request that identifies it: everything but system
+/// messages, tool names in order, and the schema of a structured call.
+fn request_value(messages: &[Message], tools: &[ToolDefinition], schema: Option<&Value>) -> Value {
+    let messages: Vec<Value> = messages
+        .iter()
+        .filter(|m| m.role != Role::System)
+        .map(|m| {
+            let mut entry = json!({"role": m.role.to_string(), "content": m.content});
+            if let Some(ref id) = m.tool_call_id {
+                entry["tool_call_id"] = json!(id);
+            }
+            if !m.tool_calls.is_empty() {
+                entry["tool_calls"] = json!(m.tool_calls);
+            }
+            if !m.attachments.is_empty() {
+                let images: Vec<String> = m
+                    .attachments
+                    .iter()
+                    .map(|a| format!("{} {:016x}", a.name, fnv1a(&a.data)))
+                    .collect();
+                entry["attachments"] = json!(images);
+            }
+            entry
+        })
+        .collect();
+    let tools: Vec<&str> = tools.iter().map(|t| t.name.as_str()).collect();
+    let mut request = json!({"messages": messages, "tools": tools});
+    if let Some(schema) = schema {
+        request["schema"] = schema.clone();
+    }
+    request
+}
+
+/// FNV-1a, 64-bit. Unlike `DefaultHasher` its output is fixed, so keys
+/// stay valid across Rust versions and platforms.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Cassette key for a request. `serde_json` sorts object keys, so the
+/// serialised form is canonical.
+fn request_key(request: &Value) -> String {
+    format!("{:016x}", fnv1a(request.to_string().as_bytes()))
+}
+
+/// Empty the cassette at `path` (creating parent directories) so a new
+/// recording doesn't append to an old session.
+pub fn start_recording(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::File::create(path).map(|_| ())
+}
+
+/// Passes calls through to `inner` and appends each successful one to
+/// the cassette. Errors are not recorded.
+pub struct RecordingProvider {
+    inner: Box<dyn LlmProvider>,
+    path: PathBuf,
+}
+
+impl RecordingProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, path: &Path) -> Self {
+        Self {
+            inner,
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Append `response` for `request`. A cassette that can't be written
+    /// is reported but doesn't fail the call — the session is live.
+    fn record(
+        &self,
+        request: Value,
+        response: Result<LlmResponse, LlmError>,
+    ) -> Result<LlmResponse, LlmError> {
+        if let Ok(ref resp) = response {
+            let line = Interaction {
+                key: request_key(&request),
+                request,
+                response: RecordedResponse::from_response(resp),
+            };
+            let written = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .and_then(|mut f| {
+                    let json = serde_json::to_string(&line).map_err(std::io::Error::other)?;
+                    writeln!(f, "{}", json)
+                });
+            if let Err(e) = written {
+                eprintln!(
+                    "Warning: failed to write cassette {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+        response
+    }
+}
+
+impl LlmProvider for RecordingProvider {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        let request = request_value(messages, tools, None);
+        self.record(request, self.inner.chat(messages, tools))
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &Value,
+    ) -> Result<LlmResponse, LlmError> {
+        let request = request_value(messages, &[], Some(schema));
+        self.record(request, self.inner.chat_with_schema(messages, schema))
+    }
+
+    fn chat_streaming(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        let request = request_value(messages, tools, None);
+        self.record(
+            request,
+            self.inner.chat_streaming(messages, tools, on_token),
+        )
+    }
+
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        let request = request_value(messages, tools, None);
+        let response = self.inner.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            on_reasoning,
+            on_tool_progress,
+            cancel,
+        );
+        self.record(request, response)
+    }
+}
+
+/// Serves recorded responses; see the module docs for matching.
+pub struct ReplayProvider {
+    path: PathBuf,
+    responses: HashMap<String, Vec<RecordedResponse>>,
+    /// How many times each key has been served so far.
+    served: RefCell<HashMap<String, usize>>,
+    /// Set when the cassette couldn't be read; every call fails with it.
+    load_error: Option<String>,
+}
+
+impl ReplayProvider {
+    /// Load the cassette at `path`. A missing or malformed file doesn't
+    /// fail here: the error is returned from every call instead, like an
+    /// unreachable server.
+    pub fn open(path: &Path) -> Self {
+        let mut provider = Self {
+            path: path.to_path_buf(),
+            responses: HashMap::new(),
+            served: RefCell::new(HashMap::new()),
+            load_error: None,
+        };
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                for (i, line) in text.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Interaction>(line) {
+                        Ok(entry) => provider
+                            .responses
+                            .entry(entry.key)
+                            .or_default()
+                            .push(entry.response),
+                        Err(e) => {
+                            provider.load_error = Some(format!(
+                                "Cassette {} line {} is malformed: {}",
+                                path.display(),
+                                i + 1,
+                                e
+                            ));
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                provider.load_error =
+                    Some(format!("Cannot read cassette {}: {}", path.display(), e));
+            }
+        }
+        provider
+    }
+
+    fn replay(&self, request: Value) -> Result<LlmResponse, LlmError> {
+        if let Some(ref e) = self.load_error {
+            return Err(LlmError::ConnectionError(e.clone()));
+        }
+        let key = request_key(&request);
+        let Some(recorded) = self.responses.get(&key) else {
+            return Err(LlmError::RequestError(format!(
+                "no recorded response in {} for this request (key {}); \
+                 re-record the cassette if the prompt, tools or files changed",
+                self.path.display(),
+                key
+            )));
+        };
+        let mut served = self.served.borrow_mut();
+        let n = served.entry(key).or_insert(0);
+        let response = recorded[(*n).min(recorded.len() - 1)].to_response();
+        *n += 1;
+        Ok(response)
+    }
+}
+
+impl LlmProvider for ReplayProvider {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        self.replay(request_value(messages, tools, None))
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.replay(request_value(messages, &[], Some(schema)))
+    }
+
+    fn chat_streaming(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            &mut |_| {},
+            &mut |_| {},
+            &CancelToken::new(),
+        )
+    }
+
+    /// The recorded reasoning and answer are each emitted as one chunk.
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        _on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        _cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        let response = self.replay(request_value(messages, tools, None))?;
+        if let Some(ref reasoning) = response.reasoning {
+            on_reasoning(reasoning);
+        }
+        if let Some(ref content) = response.content {
+            on_token(content);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Answers "reply N" for the Nth call, with a tool call on the first.
+    struct Counting(Cell<usize>);
+
+    impl LlmProvider for Counting {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            let n = self.0.get() + 1;
+            self.0.set(n);
+            Ok(LlmResponse {
+                content: Some(format!("reply {}", n)),
+                tool_calls: if n == 1 {
+                    vec![ToolCall {
+                        id: "call_0".to_string(),
+                        name: "read_file".to_string(),
+                        arguments: json!({"path": "src/main.rs"}),
+                    }]
+                } else {
+                    vec![]
+                },
+                usage: TokenUsage {
+                    prompt_tokens: Some(10 * n as u64),
+                    completion_tokens: Some(n as u64),
+                    ..Default::default()
+                },
+                reasoning: Some("hmm".to_string()),
+            })
+        }
+    }
+
+    fn tool(name: &str) -> ToolDefinition {
+        ToolDefinition {
+            name: name.to_string(),
+            description: String::new(),
+            parameters: json!({}),
+        }
+    }
+
+    fn record(path: &Path, calls: &[(Vec<Message>, Vec<ToolDefinition>)]) {
+        start_recording(path).unwrap();
+        let recorder = RecordingProvider::new(Box::new(Counting(Cell::new(0))), path);
+        for (messages, tools) in calls {
+            recorder.chat(messages, tools).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replay_returns_recorded_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassettes").join("s.jsonl");
+        let first = vec![Message::system("sys"), Message::user("read main")];
+        let mut second = first.clone();
+        second.push(Message::tool_result("call_0", "fn main() {}"));
+        record(
+            &path,
+            &[
+                (first.clone(), vec![tool("read_file")]),
+                (second.clone(), vec![tool("read_file")]),
+            ],
+        );
+
+        let replay = ReplayProvider::open(&path);
+        let resp = replay.chat(&first, &[tool("read_file")]).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("reply 1"));
+        assert_eq!(resp.tool_calls[0].arguments["path"], "src/main.rs");
+        assert_eq!(resp.usage.prompt_tokens, Some(10));
+
+        let mut tokens = String::new();
+        let mut thoughts = String::new();
+        let resp = replay
+            .chat_streaming_cancellable(
+                &second,
+                &[tool("read_file")],
+                &mut |t| tokens.push_str(t),
+                &mut |r| thoughts.push_str(r),
+                &mut |_| {},
+                &CancelToken::new(),
+            )
+            .unwrap();
+        assert_eq!(resp.content.as_deref(), Some("reply 2"));
+        assert_eq!((tokens.as_str(), thoughts.as_str()), ("reply 2", "hmm"));
+    }
+
+    #[test]
+    fn test_system_prompt_is_not_part_of_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s.jsonl");
+        record(
+            &path,
+            &[(
+                vec![Message::system("cwd: /home/a"), Message::user("hi")],
+                vec![],
+            )],
+        );
+        let replay = ReplayProvider::open(&path);
+        let other_machine = [Message::system("cwd: /runner/work"), Message::user("hi")];
+        assert!(replay.chat(&other_machine, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_repeated_request_replays_in_order_then_repeats_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s.jsonl");
+        let ask = vec![Message::user("again")];
+        record(&path, &[(ask.clone(), vec![]), (ask.clone(), vec![])]);
+        let replay = ReplayProvider::open(&path);
+        let contents: Vec<String> = (0..3)
+            .map(|_| replay.chat(&ask, &[]).unwrap().content.unwrap())
+            .collect();
+        assert_eq!(contents, ["reply 1", "reply 2", "reply 2"]);
+    }
+
+    #[test]
+    fn test_unrecorded_request_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s.jsonl");
+        record(&path, &[(vec![Message::user("hi")], vec![tool("shell")])]);
+        let replay = ReplayProvider::open(&path);
+        // Same text, different tool set: a different request.
+        let err = replay.chat(&[Message::user("hi")], &[]).unwrap_err();
+        assert!(matches!(err, LlmError::RequestError(_)));
+        assert!(err.to_string().contains("no recorded response"));
+    }
+
+    #[test]
+    fn test_unreadable_cassette_fails_every_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = ReplayProvider::open(&dir.path().join("none.jsonl"));
+        assert!(matches!(
+            missing.chat(&[Message::user("hi")], &[]),
+            Err(LlmError::ConnectionError(_))
+        ));
+
+        let bad = dir.path().join("bad.jsonl");
+        std::fs::write(&bad, "{not json\n").unwrap();
+        let err = ReplayProvider::open(&bad)
+            .chat(&[Message::user("hi")], &[])
+            .unwrap_err();
+        assert!(err.to_string().contains("line 1 is malformed"));
+    }
+
+    #[test]
+    fn test_agent_session_replays_offline() {
+        use crate::agent::{Agent, AgentConfig};
+        use crate::tools::default_registry;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.jsonl");
+        start_recording(&path).unwrap();
+        let live = RecordingProvider::new(Box::new(Counting(Cell::new(0))), &path);
+        let mut agent = Agent::new(
+            Box::new(live),
+            default_registry(),
+            AgentConfig::default(),
+            &[],
+        );
+        let recorded = agent.process_message("What does main do?");
+        let recorded_memory = agent.memory.len();
+
+        let mut agent = Agent::new(
+            Box::new(ReplayProvider::open(&path)),
+            default_registry(),
+            AgentConfig::default(),
+            &[],
+        );
+        assert_eq!(agent.process_message("What does main do?"), recorded);
+        assert_eq!(agent.memory.len(), recorded_memory);
+        assert_eq!(agent.stats.llm_calls, 2);
+    }
+
+    #[test]
+    fn test_key_is_stable() {
+        // Cassettes are committed to repositories; the key format must
+        // not drift between builds.
+        let request = request_value(&[Message::user("hi")], &[tool("read_file")], None);
+        assert_eq!(
+            request.to_string(),
+            r#"{"messages":[{"content":"hi","role":"user"}],"tools":["read_file"]}"#
+        );
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
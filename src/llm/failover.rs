@@ -0,0 +1,269 @@
+//! Composite provider that falls through an ordered list of backends.
+//!
+//! Only errors that mean "this backend can't serve the request at all"
+//! trigger failover — a refused connection or a missing model. Request
+//! and parse errors come from a backend that *did* answer, so retrying
+//! them elsewhere would mask a real problem and are returned as-is.
+//!
+//! Every call starts from the primary again, so the session moves back
+//! to it as soon as it is reachable.
+
+use super::{LlmError, LlmProvider, LlmResponse, Message, ToolCallProgress, ToolDefinition};
+use crate::cancel::CancelToken;
+
+/// Emitted each time a backend is skipped in favour of the next one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailoverEvent {
+    pub from: String,
+    pub to: String,
+    pub error: String,
+}
+
+pub type FailoverCallback = Box<dyn Fn(&FailoverEvent)>;
+
+pub struct FailoverProvider {
+    backends: Vec<(String, Box<dyn LlmProvider>)>,
+    on_failover: Option<FailoverCallback>,
+}
+
+fn should_fail_over(err: &LlmError) -> bool {
+    matches!(
+        err,
+        LlmError::ConnectionError(_) | LlmError::ModelNotFound(_)
+    )
+}
+
+impl FailoverProvider {
+    /// `backends` is `(label, provider)` in priority order; the label is
+    /// what shows up in `SessionStats::backend_calls` and JSONL events.
+    pub fn new(backends: Vec<(String, Box<dyn LlmProvider>)>) -> Self {
+        Self {
+            backends,
+            on_failover: None,
+        }
+    }
+
+    pub fn set_on_failover(&mut self, cb: FailoverCallback) {
+        self.on_failover = Some(cb);
+    }
+
+    fn run(
+        &self,
+        mut call: impl FnMut(&dyn LlmProvider) -> Result<LlmResponse, LlmError>,
+    ) -> Result<LlmResponse, LlmError> {
+        let mut last_err = None;
+        for (i, (label, backend)) in self.backends.iter().enumerate() {
+            match call(backend.as_ref()) {
+                Ok(mut resp) => {
+                    resp.usage.backend = Some(label.clone());
+                    return Ok(resp);
+                }
+                Err(e) if should_fail_over(&e) => {
+                    if let (Some(cb), Some((next, _))) =
+                        (&self.on_failover, self.backends.get(i + 1))
+                    {
+                        cb(&FailoverEvent {
+                            from: label.clone(),
+                            to: next.clone(),
+                            error: e.to_string(),
+                        });
+                    }
+                    last_err = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| LlmError::ConnectionError("No LLM backends configured".into())))
+    }
+}
+
+impl LlmProvider for FailoverProvider {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(|b| b.chat(messages, tools))
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(|b| b.chat_with_schema(messages, schema))
+    }
+
+    fn chat_streaming(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(|b| b.chat_streaming(messages, tools, on_token))
+    }
+
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(|b| {
+            b.chat_streaming_cancellable(
+                messages,
+                tools,
+                on_token,
+                on_reasoning,
+                on_tool_progress,
+                cancel,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::TokenUsage;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// Backend that always fails with a fixed error kind.
+    struct Down(fn(String) -> LlmError, Rc<Cell<usize>>);
+
+    impl LlmProvider for Down {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            self.1.set(self.1.get() + 1);
+            Err((self.0)("down".to_string()))
+        }
+    }
+
+    struct Up(&'static str);
+
+    impl LlmProvider for Up {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            Ok(LlmResponse {
+                content: Some(self.0.to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            })
+        }
+
+        fn chat_streaming(
+            &self,
+            messages: &[Message],
+            tools: &[ToolDefinition],
+            on_token: &mut dyn FnMut(&str),
+        ) -> Result<LlmResponse, LlmError> {
+            on_token(self.0);
+            self.chat(messages, tools)
+        }
+    }
+
+    fn down(kind: fn(String) -> LlmError) -> (Box<dyn LlmProvider>, Rc<Cell<usize>>) {
+        let hits = Rc::new(Cell::new(0));
+        (Box::new(Down(kind, Rc::clone(&hits))), hits)
+    }
+
+    #[test]
+    fn test_primary_answers_without_failover() {
+        let provider = FailoverProvider::new(vec![
+            ("primary".into(), Box::new(Up("from primary"))),
+            ("backup".into(), Box::new(Up("from backup"))),
+        ]);
+        let resp = provider.chat(&[Message::user("hi")], &[]).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("from primary"));
+        assert_eq!(resp.usage.backend.as_deref(), Some("primary"));
+    }
+
+    #[test]
+    fn test_connection_error_falls_through_and_notifies() {
+        let (primary, hits) = down(LlmError::ConnectionError);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&events);
+        let mut provider = FailoverProvider::new(vec![
+            ("qwen3@http://workstation:11434".into(), primary),
+            (
+                "qwen3-8b@http://backup-box:8080".into(),
+                Box::new(Up("from backup")),
+            ),
+        ]);
+        provider.set_on_failover(Box::new(move |e| seen.borrow_mut().push(e.clone())));
+
+        let mut streamed = String::new();
+        let resp = provider
+            .chat_streaming(&[Message::user("hi")], &[], &mut |t| streamed.push_str(t))
+            .unwrap();
+        assert_eq!(resp.content.as_deref(), Some("from backup"));
+        assert_eq!(
+            resp.usage.backend.as_deref(),
+            Some("qwen3-8b@http://backup-box:8080")
+        );
+        assert_eq!(streamed, "from backup");
+        assert_eq!(hits.get(), 1);
+
+        let events = events.borrow();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].from, "qwen3@http://workstation:11434");
+        assert_eq!(events[0].to, "qwen3-8b@http://backup-box:8080");
+        assert!(events[0].error.contains("Connection error"));
+    }
+
+    #[test]
+    fn test_model_not_found_falls_through() {
+        let (primary, _) = down(LlmError::ModelNotFound);
+        let provider = FailoverProvider::new(vec![
+            ("a".into(), primary),
+            ("b".into(), Box::new(Up("ok"))),
+        ]);
+        let resp = provider.chat(&[], &[]).unwrap();
+        assert_eq!(resp.usage.backend.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_request_error_is_not_failed_over() {
+        // A backend that answered with a bad status is healthy enough to
+        // have produced a real error — surface it, don't mask it.
+        let (primary, _) = down(LlmError::RequestError);
+        let provider = FailoverProvider::new(vec![
+            ("a".into(), primary),
+            ("b".into(), Box::new(Up("ok"))),
+        ]);
+        let err = provider.chat(&[], &[]).unwrap_err();
+        assert!(matches!(err, LlmError::RequestError(_)));
+    }
+
+    #[test]
+    fn test_all_backends_down_returns_last_error() {
+        let (a, a_hits) = down(LlmError::ConnectionError);
+        let (b, b_hits) = down(LlmError::ModelNotFound);
+        let events = Rc::new(Cell::new(0));
+        let count = Rc::clone(&events);
+        let mut provider = FailoverProvider::new(vec![("a".into(), a), ("b".into(), b)]);
+        provider.set_on_failover(Box::new(move |_| count.set(count.get() + 1)));
+
+        let err = provider.chat(&[], &[]).unwrap_err();
+        assert!(matches!(err, LlmError::ModelNotFound(_)));
+        assert_eq!((a_hits.get(), b_hits.get()), (1, 1));
+        // Only a→b is a failover; there is nothing after b to fail over to
+        assert_eq!(events.get(), 1);
+    }
+
+    #[test]
+    fn test_each_call_starts_from_primary() {
+        let (primary, hits) = down(LlmError::ConnectionError);
+        let provider = FailoverProvider::new(vec![
+            ("a".into(), primary),
+            ("b".into(), Box::new(Up("ok"))),
+        ]);
+        provider.chat(&[], &[]).unwrap();
+        provider.chat(&[], &[]).unwrap();
+        assert_eq!(hits.get(), 2);
+    }
+}
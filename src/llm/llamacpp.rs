@@ -0,0 +1,701 @@
+//! Native llama.cpp server provider.
+//!
+//! llama.cpp speaks the OpenAI chat-completions protocol, but its real
+//! advantage for small local models is constrained decoding: the server
+//! compiles a JSON schema into a GBNF grammar and masks every token that
+//! would violate it. Instead of passing `tools` and hoping the model emits
+//! well-formed tool-call JSON, this provider describes the tools in the
+//! system prompt and constrains the reply to a schema that only admits
+//! either a plain answer or a list of calls whose `arguments` match the
+//! tool's own `parameters` schema.
+//!
+//! Turns without tools are plain chat and go through the regular
+//! OpenAI-compatible client.
+
+use super::openai_compat::{cached_prompt_tokens, server_timings, OpenAiCompatClient};
+use super::{
+    send_cancellable, LlmError, LlmProvider, LlmResponse, Message, Role, TokenUsage, ToolCall,
+    ToolCallProgress, ToolDefinition,
+};
+use crate::cancel::CancelToken;
+use crate::config::LlmOptions;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub struct LlamaCppClient {
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    options: LlmOptions,
+    inner: OpenAiCompatClient,
+    client: reqwest::blocking::Client,
+}
+
+// --- Request/response types for the constrained path ---
+
+#[derive(Serialize)]
+struct ConstrainedRequest {
+    model: String,
+    messages: Vec<PlainMessage>,
+    stream: bool,
+    response_format: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_prompt: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_slot: Option<i32>,
+}
+
+/// Role + text only. The constrained path never sends `tools` or
+/// `tool_calls`, so the chat template sees an ordinary conversation.
+#[derive(Serialize, Debug, PartialEq)]
+struct PlainMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+    #[serde(default)]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    message: CompletionMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionMessage {
+    #[serde(default)]
+    content: Option<String>,
+    /// Set when llama-server runs with `--reasoning-format deepseek`.
+    #[serde(default)]
+    reasoning_content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+/// Shape of the constrained reply. Exactly one of the two fields is
+/// present when the server honoured the schema.
+#[derive(Deserialize, Debug)]
+struct Envelope {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<EnvelopeCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EnvelopeCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+// --- Schema / prompt construction ---
+
+/// Build the JSON schema the reply is constrained to. Each tool becomes a
+/// `oneOf` branch pinning `name` to a constant and `arguments` to that
+/// tool's parameters schema, so the grammar rejects unknown tools and
+/// malformed arguments at sampling time.
+pub fn tool_call_schema(tools: &[ToolDefinition]) -> Value {
+    let calls: Vec<Value> = tools
+        .iter()
+        .map(|t| {
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": { "const": t.name },
+                    "arguments": t.parameters,
+                },
+                "required": ["name", "arguments"],
+                "additionalProperties": false,
+            })
+        })
+        .collect();
+
+    json!({
+        "oneOf": [
+            {
+                "type": "object",
+                "properties": {
+                    "tool_calls": {
+                        "type": "array",
+                        "minItems": 1,
+                        "items": { "oneOf": calls },
+                    },
+                },
+                "required": ["tool_calls"],
+                "additionalProperties": false,
+            },
+            {
+                "type": "object",
+                "properties": {
+                    "content": { "type": "string" },
+                },
+                "required": ["content"],
+                "additionalProperties": false,
+            },
+        ]
+    })
+}
+
+/// Tool catalogue appended to the system prompt. The grammar guarantees
+/// the shape; this text tells the model what the tools are for.
+fn tools_prompt(tools: &[ToolDefinition]) -> String {
+    let mut out = String::from(
+        "\n\n# Tools\n\nReply with a single JSON object. To call tools, reply \
+         {\"tool_calls\": [{\"name\": ..., \"arguments\": {...}}]}. To answer the \
+         user directly, reply {\"content\": \"...\"}.\n\nAvailable tools:\n",
+    );
+    for t in tools {
+        out.push_str(&format!(
+            "\n## {}\n{}\nParameters: {}\n",
+            t.name, t.description, t.parameters
+        ));
+    }
+    out
+}
+
+impl LlamaCppClient {
+    #[allow(dead_code)]
+    pub fn new(base_url: &str, model: &str, api_key: Option<String>) -> Self {
+        Self::with_options(base_url, model, api_key, LlmOptions::default())
+    }
+
+    pub fn with_options(
+        base_url: &str,
+        model: &str,
+        api_key: Option<String>,
+        options: LlmOptions,
+    ) -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(std::time::Duration::from_secs(300))
+            .build()
+            .unwrap_or_else(|_| reqwest::blocking::Client::new());
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key: api_key.clone(),
+            inner: OpenAiCompatClient::with_options(base_url, model, api_key, options.clone()),
+            options,
+            client,
+        }
+    }
+
+    /// Flatten the conversation for the constrained path. Assistant tool
+    /// calls are replayed in the same envelope the model is asked to
+    /// produce, and tool results become user turns, so templates that know
+    /// nothing about tool roles still render a coherent history.
+    fn convert_messages(messages: &[Message], tools: &[ToolDefinition]) -> Vec<PlainMessage> {
+        let mut out: Vec<PlainMessage> = Vec::with_capacity(messages.len() + 1);
+        let catalogue = tools_prompt(tools);
+        let mut injected = false;
+
+        for m in messages {
+            let msg = match m.role {
+                Role::System => {
+                    let mut content = m.content.clone();
+                    if !injected {
+                        content.push_str(&catalogue);
+                        injected = true;
+                    }
+                    PlainMessage {
+                        role: "system".to_string(),
+                        content,
+                    }
+                }
+                Role::User => PlainMessage {
+                    role: "user".to_string(),
+                    content: m.content.clone(),
+                },
+                Role::Assistant if !m.tool_calls.is_empty() => {
+                    let calls: Vec<Value> = m
+                        .tool_calls
+                        .iter()
+                        .map(|tc| json!({"name": tc.name, "arguments": tc.arguments}))
+                        .collect();
+                    PlainMessage {
+                        role: "assistant".to_string(),
+                        content: json!({ "tool_calls": calls }).to_string(),
+                    }
+                }
+                Role::Assistant => PlainMessage {
+                    role: "assistant".to_string(),
+                    content: json!({ "content": m.content }).to_string(),
+                },
+                Role::Tool => PlainMessage {
+                    role: "user".to_string(),
+                    content: format!(
+                        "[tool result {}]\n{}",
+                        m.tool_call_id.as_deref().unwrap_or(""),
+                        m.content
+                    ),
+                },
+            };
+            out.push(msg);
+        }
+
+        if !injected {
+            out.insert(
+                0,
+                PlainMessage {
+                    role: "system".to_string(),
+                    content: catalogue.trim_start().to_string(),
+                },
+            );
+        }
+        out
+    }
+
+    /// Decode the constrained reply. If the server ignored the schema (old
+    /// build, unsupported keyword) the raw text is returned as content so
+    /// the agent's text-extraction fallback still gets a chance.
+    fn parse_envelope(raw: &str) -> (Option<String>, Vec<ToolCall>) {
+        match serde_json::from_str::<Envelope>(raw.trim()) {
+            Ok(env) if !env.tool_calls.is_empty() => {
+                let calls = env
+                    .tool_calls
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, c)| ToolCall {
+                        id: format!("call_{}", i),
+                        name: c.name,
+                        arguments: if c.arguments.is_object() {
+                            c.arguments
+                        } else {
+                            Value::Object(serde_json::Map::new())
+                        },
+                    })
+                    .collect();
+                (None, calls)
+            }
+            Ok(Envelope {
+                content: Some(text),
+                ..
+            }) => (Some(text).filter(|t| !t.is_empty()), Vec::new()),
+            _ => (Some(raw.to_string()).filter(|t| !t.is_empty()), Vec::new()),
+        }
+    }
+
+    fn chat_constrained(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        let url = format!("{}/v1/chat/completions", self.base_url);
+        let request = ConstrainedRequest {
+            model: self.model.clone(),
+            messages: Self::convert_messages(messages, tools),
+            stream: false,
+            response_format: json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "whet_turn",
+                    "strict": true,
+                    "schema": tool_call_schema(tools),
+                },
+            }),
+            temperature: self.options.temperature,
+            top_p: self.options.top_p,
+            max_tokens: self.options.num_predict,
+            seed: self.options.seed,
+            cache_prompt: self.options.cache_prompt,
+            id_slot: self.options.slot_id,
+        };
+
+        let mut req_builder = self.client.post(&url).json(&request);
+        if let Some(ref key) = self.api_key {
+            req_builder = req_builder.header("Authorization", format!("Bearer {}", key));
+        }
+
+        let response = send_cancellable(req_builder, cancel)
+            .ok_or(LlmError::Cancelled)?
+            .map_err(|e| {
+                if e.is_connect() {
+                    LlmError::ConnectionError(format!(
+                        "Cannot connect to llama.cpp server at {}. Is llama-server running?",
+                        self.base_url
+                    ))
+                } else if e.is_timeout() {
+                    LlmError::RequestError("Request timed out".to_string())
+                } else {
+                    LlmError::RequestError(e.to_string())
+                }
+            })?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(LlmError::ModelNotFound(format!(
+                "Model '{}' not found on llama.cpp server at {}",
+                self.model, self.base_url
+            )));
+        }
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(LlmError::RequestError(
+                "Authentication failed. Check your api_key in config.".to_string(),
+            ));
+        }
+        if !status.is_success() {
+            let body = response.text().unwrap_or_default();
+            let msg = format!("llama.cpp returned status {}: {}", status, body);
+            return Err(if status.is_server_error() {
+                LlmError::ServerError(msg)
+            } else {
+                LlmError::RequestError(msg)
+            });
+        }
+
+        let body: Value = response
+            .json()
+            .map_err(|e| LlmError::ParseError(format!("Failed to parse response: {}", e)))?;
+        let cached = cached_prompt_tokens(&body);
+        let timings = server_timings(&body);
+        let resp_body: CompletionResponse = serde_json::from_value(body)
+            .map_err(|e| LlmError::ParseError(format!("Failed to parse response: {}", e)))?;
+
+        let usage = TokenUsage {
+            prompt_tokens: resp_body.usage.as_ref().and_then(|u| u.prompt_tokens),
+            completion_tokens: resp_body.usage.as_ref().and_then(|u| u.completion_tokens),
+            cached_prompt_tokens: cached,
+            timings,
+            ..TokenUsage::default()
+        };
+
+        let choice = resp_body
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| LlmError::ParseError("No choices in response".to_string()))?;
+
+        let (content, tool_calls) =
+            Self::parse_envelope(choice.message.content.as_deref().unwrap_or(""));
+
+        Ok(LlmResponse {
+            content,
+            tool_calls,
+            usage,
+            reasoning: choice.message.reasoning_content.filter(|r| !r.is_empty()),
+        })
+    }
+}
+
+/// The constrained request is text-only, so a conversation carrying
+/// images goes through the plain chat endpoint (llama-server's
+/// multimodal path) with native tool calling for that call.
+fn has_images(messages: &[Message]) -> bool {
+    messages.iter().any(|m| !m.attachments.is_empty())
+}
+
+impl LlmProvider for LlamaCppClient {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        if tools.is_empty() || has_images(messages) {
+            return self.inner.chat(messages, tools);
+        }
+        self.chat_constrained(messages, tools, &CancelToken::new())
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.inner.chat_with_schema(messages, schema)
+    }
+
+    fn chat_streaming(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            &mut |_| {},
+            &mut |_| {},
+            &CancelToken::new(),
+        )
+    }
+
+    /// Constrained replies are a JSON envelope, which is useless to show
+    /// token by token, so tool turns are fetched whole and the answer text
+    /// (if any) is emitted once. Tool-less turns stream normally.
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        if tools.is_empty() || has_images(messages) {
+            return self.inner.chat_streaming_cancellable(
+                messages,
+                tools,
+                on_token,
+                on_reasoning,
+                on_tool_progress,
+                cancel,
+            );
+        }
+        let response = self.chat_constrained(messages, tools, cancel)?;
+        if let Some(ref reasoning) = response.reasoning {
+            on_reasoning(reasoning);
+        }
+        if let Some(ref text) = response.content {
+            on_token(text);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::StubServer;
+
+    fn read_file_def() -> ToolDefinition {
+        ToolDefinition {
+            name: "read_file".to_string(),
+            description: "Read a file".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {"path": {"type": "string"}},
+                "required": ["path"]
+            }),
+        }
+    }
+
+    fn shell_def() -> ToolDefinition {
+        ToolDefinition {
+            name: "shell".to_string(),
+            description: "Run a command".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {"command": {"type": "string"}},
+                "required": ["command"]
+            }),
+        }
+    }
+
+    fn completion(content: &str) -> Value {
+        json!({
+            "choices": [{"message": {"role": "assistant", "content": content}}],
+            "usage": {"prompt_tokens": 12, "completion_tokens": 7}
+        })
+    }
+
+    #[test]
+    fn test_client_trims_trailing_slash() {
+        let client = LlamaCppClient::new("http://localhost:8080/", "m", None);
+        assert_eq!(client.base_url, "http://localhost:8080");
+        assert_eq!(client.model, "m");
+    }
+
+    #[test]
+    fn test_schema_has_one_branch_per_tool() {
+        let schema = tool_call_schema(&[read_file_def(), shell_def()]);
+        let branches = &schema["oneOf"][0]["properties"]["tool_calls"]["items"]["oneOf"];
+        assert_eq!(branches.as_array().unwrap().len(), 2);
+        assert_eq!(branches[0]["properties"]["name"]["const"], "read_file");
+        assert_eq!(branches[1]["properties"]["name"]["const"], "shell");
+        // The tool's own parameters schema is embedded verbatim
+        assert_eq!(
+            branches[0]["properties"]["arguments"],
+            read_file_def().parameters
+        );
+    }
+
+    #[test]
+    fn test_schema_allows_plain_answer() {
+        let schema = tool_call_schema(&[read_file_def()]);
+        assert_eq!(schema["oneOf"][1]["required"], json!(["content"]));
+        assert_eq!(
+            schema["oneOf"][1]["properties"]["content"]["type"],
+            "string"
+        );
+    }
+
+    #[test]
+    fn test_convert_injects_tools_into_existing_system_prompt() {
+        let msgs = vec![Message::system("You are whet."), Message::user("hi")];
+        let converted = LlamaCppClient::convert_messages(&msgs, &[read_file_def()]);
+        assert_eq!(converted.len(), 2);
+        assert!(converted[0].content.starts_with("You are whet."));
+        assert!(converted[0].content.contains("## read_file"));
+        assert_eq!(converted[1].role, "user");
+    }
+
+    #[test]
+    fn test_convert_adds_system_prompt_when_missing() {
+        let msgs = vec![Message::user("hi")];
+        let converted = LlamaCppClient::convert_messages(&msgs, &[read_file_def()]);
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].role, "system");
+        assert!(converted[0].content.starts_with("# Tools"));
+    }
+
+    #[test]
+    fn test_convert_replays_tool_calls_and_results() {
+        let msgs = vec![
+            Message::system("sys"),
+            Message::assistant_with_tool_calls(vec![ToolCall {
+                id: "call_0".to_string(),
+                name: "read_file".to_string(),
+                arguments: json!({"path": "a.rs"}),
+            }]),
+            Message::tool_result("call_0", "fn main() {}"),
+            Message::assistant("done"),
+        ];
+        let converted = LlamaCppClient::convert_messages(&msgs, &[read_file_def()]);
+        let replay: Value = serde_json::from_str(&converted[1].content).unwrap();
+        assert_eq!(replay["tool_calls"][0]["name"], "read_file");
+        assert_eq!(replay["tool_calls"][0]["arguments"]["path"], "a.rs");
+        assert_eq!(converted[2].role, "user");
+        assert!(converted[2].content.contains("[tool result call_0]"));
+        assert!(converted[2].content.contains("fn main() {}"));
+        let answer: Value = serde_json::from_str(&converted[3].content).unwrap();
+        assert_eq!(answer["content"], "done");
+    }
+
+    #[test]
+    fn test_parse_envelope_tool_calls() {
+        let (content, calls) = LlamaCppClient::parse_envelope(
+            r#"{"tool_calls":[{"name":"read_file","arguments":{"path":"x"}},{"name":"shell","arguments":{"command":"ls"}}]}"#,
+        );
+        assert!(content.is_none());
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_0");
+        assert_eq!(calls[1].id, "call_1");
+        assert_eq!(calls[1].arguments["command"], "ls");
+    }
+
+    #[test]
+    fn test_parse_envelope_content() {
+        let (content, calls) = LlamaCppClient::parse_envelope(r#"{"content":"All good."}"#);
+        assert_eq!(content.as_deref(), Some("All good."));
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn test_parse_envelope_falls_back_to_raw_text() {
+        // A server that ignored response_format returns free text; keep it
+        // so the agent's text-extraction fallback can still run.
+        let (content, calls) = LlamaCppClient::parse_envelope("I'll read the file.");
+        assert_eq!(content.as_deref(), Some("I'll read the file."));
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn test_chat_with_tools_sends_schema_and_parses_calls() {
+        let server = StubServer::json(vec![completion(
+            r#"{"tool_calls":[{"name":"read_file","arguments":{"path":"src/main.rs"}}]}"#,
+        )]);
+        let client = LlamaCppClient::new(&server.url, "qwen", None);
+        let resp = client
+            .chat(
+                &[Message::system("sys"), Message::user("read main")],
+                &[read_file_def()],
+            )
+            .unwrap();
+
+        assert!(resp.content.is_none());
+        assert_eq!(resp.tool_calls.len(), 1);
+        assert_eq!(resp.tool_calls[0].name, "read_file");
+        assert_eq!(resp.tool_calls[0].arguments["path"], "src/main.rs");
+        assert_eq!(resp.usage.prompt_tokens, Some(12));
+        assert_eq!(resp.usage.completion_tokens, Some(7));
+
+        let reqs = server.requests();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].path, "/v1/chat/completions");
+        let body = reqs[0].json();
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(
+            body["response_format"]["json_schema"]["schema"],
+            tool_call_schema(&[read_file_def()])
+        );
+        // Tools travel via the grammar, never as native `tools`
+        assert!(body.get("tools").is_none());
+    }
+
+    #[test]
+    fn test_chat_without_tools_is_unconstrained() {
+        let server = StubServer::json(vec![completion("Hello!")]);
+        let client = LlamaCppClient::new(&server.url, "qwen", None);
+        let resp = client.chat(&[Message::user("hi")], &[]).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("Hello!"));
+        assert!(server.requests()[0].json().get("response_format").is_none());
+    }
+
+    #[test]
+    fn test_images_bypass_constrained_path() {
+        let server = StubServer::json(vec![completion("A red button.")]);
+        let client = LlamaCppClient::new(&server.url, "qwen-vl", None);
+        let image = crate::llm::attachment::Attachment {
+            name: "ui.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: vec![1, 2, 3],
+        };
+        let msgs = [Message::user_with_attachments("what is this?", vec![image])];
+        client.chat(&msgs, &[read_file_def()]).unwrap();
+        let body = server.requests()[0].json();
+        assert!(body.get("response_format").is_none());
+        assert_eq!(body["messages"][0]["content"][1]["type"], "image_url");
+    }
+
+    #[test]
+    fn test_streaming_with_tools_emits_answer_once() {
+        let server = StubServer::json(vec![completion(r#"{"content":"Nothing to do."}"#)]);
+        let client = LlamaCppClient::new(&server.url, "qwen", None);
+        let mut tokens = Vec::new();
+        let resp = client
+            .chat_streaming(&[Message::user("hi")], &[read_file_def()], &mut |t| {
+                tokens.push(t.to_string())
+            })
+            .unwrap();
+        assert_eq!(resp.content.as_deref(), Some("Nothing to do."));
+        assert_eq!(tokens, vec!["Nothing to do.".to_string()]);
+        assert_eq!(server.requests()[0].json()["stream"], false);
+    }
+
+    #[test]
+    fn test_chat_maps_404_to_model_not_found() {
+        let server = StubServer::start(vec![(404, "text/plain", "no".to_string())]);
+        let client = LlamaCppClient::new(&server.url, "missing", None);
+        let err = client
+            .chat(&[Message::user("hi")], &[read_file_def()])
+            .unwrap_err();
+        assert!(matches!(err, LlmError::ModelNotFound(_)));
+    }
+
+    #[test]
+    fn test_chat_connection_error() {
+        let client = LlamaCppClient::new("http://127.0.0.1:1", "m", None);
+        let err = client
+            .chat(&[Message::user("hi")], &[read_file_def()])
+            .unwrap_err();
+        assert!(matches!(err, LlmError::ConnectionError(_)));
+    }
+}
@@ -1,7 +1,21 @@
+pub mod attachment;
+pub mod capabilities;
+pub mod cassette;
+pub mod failover;
+pub mod llamacpp;
 pub mod ollama;
 pub mod openai_compat;
+pub mod retry;
+pub mod structured;
+#[cfg(test)]
+pub(crate) mod stub_server;
+pub mod text_tools;
+pub mod tokens;
 
+use crate::cancel::CancelToken;
+use attachment::Attachment;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -9,6 +23,9 @@ pub struct Message {
     pub content: String,
     pub tool_call_id: Option<String>,
     pub tool_calls: Vec<ToolCall>,
+    /// Images shown to vision models with this message. Only user
+    /// messages carry them; see `attachment`.
+    pub attachments: Vec<Attachment>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -34,13 +51,51 @@ impl fmt::Display for Role {
 pub struct TokenUsage {
     pub prompt_tokens: Option<u64>,
     pub completion_tokens: Option<u64>,
+    /// Which backend produced the response, when more than one is
+    /// configured. Set by `failover::FailoverProvider`; `None` otherwise.
+    pub backend: Option<String>,
+    /// Prompt tokens the server reused from its prefix cache instead of
+    /// evaluating, when it reports them (llama.cpp, vLLM).
+    pub cached_prompt_tokens: Option<u64>,
+    /// Server-side timings for the call, when the backend reports them
+    /// (Ollama `*_duration`, llama-server `timings`).
+    pub timings: Option<Timings>,
 }
 
-#[derive(Debug, Clone)]
+/// Where the time of one call went on the server. Token counts here are
+/// the ones actually evaluated, so prefill excludes cached prompt tokens
+/// on backends that reuse them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    pub prompt_eval_tokens: u64,
+    pub prompt_eval_ms: f64,
+    pub eval_tokens: u64,
+    pub eval_ms: f64,
+    /// Time spent loading the model before the call could start
+    /// (Ollama only; 0 when it was already resident).
+    pub load_ms: f64,
+}
+
+/// A tool call still being generated: which one, and how many bytes of
+/// its arguments have arrived. Lets the display show that a long
+/// `write_file` is progressing instead of looking frozen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToolCallProgress<'a> {
+    /// Position of the call within the response.
+    pub index: usize,
+    /// Tool name; empty until the server has sent it.
+    pub name: &'a str,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct LlmResponse {
     pub content: Option<String>,
     pub tool_calls: Vec<ToolCall>,
     pub usage: TokenUsage,
+    /// Thinking trace, when the backend returns one separately from the
+    /// answer (Ollama `thinking`, OpenAI-compatible `reasoning_content`).
+    pub reasoning: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -63,6 +118,26 @@ pub enum LlmError {
     RequestError(String),
     ParseError(String),
     ModelNotFound(String),
+    /// The server answered with a 5xx status — typically transient (a
+    /// model still loading into VRAM, an overloaded backend).
+    ServerError(String),
+    /// The user interrupted the request (Ctrl+C).
+    Cancelled,
+}
+
+impl LlmError {
+    /// Stable snake_case name of the variant, as used by
+    /// `[llm.retry].retry_on` in the config.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LlmError::ConnectionError(_) => "connection_error",
+            LlmError::RequestError(_) => "request_error",
+            LlmError::ParseError(_) => "parse_error",
+            LlmError::ModelNotFound(_) => "model_not_found",
+            LlmError::ServerError(_) => "server_error",
+            LlmError::Cancelled => "cancelled",
+        }
+    }
 }
 
 impl fmt::Display for LlmError {
@@ -72,6 +147,8 @@ impl fmt::Display for LlmError {
             LlmError::RequestError(msg) => write!(f, "Request error: {}", msg),
             LlmError::ParseError(msg) => write!(f, "Parse error: {}", msg),
             LlmError::ModelNotFound(model) => write!(f, "Model not found: {}", model),
+            LlmError::ServerError(msg) => write!(f, "Server error: {}", msg),
+            LlmError::Cancelled => write!(f, "Cancelled by user"),
         }
     }
 }
@@ -82,6 +159,28 @@ pub trait LlmProvider {
     fn chat(&self, messages: &[Message], tools: &[ToolDefinition])
         -> Result<LlmResponse, LlmError>;
 
+    /// Tool-less call whose reply should be JSON matching `schema`.
+    /// Default implementation relies on the prompt alone; providers with
+    /// constrained decoding override it to pass the schema to the server.
+    /// Callers normally want `chat_json`, which also validates.
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        _schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat(messages, &[])
+    }
+
+    /// A JSON value matching `schema`, checked locally and retried once
+    /// with the validation error on a mismatch. See `structured`.
+    fn chat_json(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<serde_json::Value, LlmError> {
+        structured::chat_json(self, messages, schema)
+    }
+
     /// Streaming variant that calls on_token for each token as it arrives.
     /// Default implementation falls back to non-streaming chat.
     fn chat_streaming(
@@ -92,4 +191,56 @@ pub trait LlmProvider {
     ) -> Result<LlmResponse, LlmError> {
         self.chat(messages, tools)
     }
+
+    /// `chat_streaming` that gives up as soon as `cancel` fires, returning
+    /// `LlmError::Cancelled`, and routes thinking-trace tokens to
+    /// `on_reasoning` instead of `on_token`. Providers that stream tool
+    /// calls piecewise report each growing call to `on_tool_progress`.
+    /// Default implementation ignores the token and the reasoning and
+    /// progress sinks; HTTP providers override it to abort the in-flight
+    /// request and stream the trace.
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        _on_reasoning: &mut dyn FnMut(&str),
+        _on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        _cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming(messages, tools, on_token)
+    }
+}
+
+/// How often a cancellable request re-checks its token while waiting.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Send `request` on a worker thread and wait for the response headers,
+/// polling `cancel` in between. Local servers often take tens of seconds
+/// to process a long prompt before the first byte arrives, so a check
+/// between stream chunks alone would leave Ctrl+C unresponsive exactly
+/// when it's most needed.
+///
+/// Returns `None` on cancellation. The worker is abandoned; it drops the
+/// connection (which makes Ollama / llama.cpp stop generating) as soon as
+/// the server answers.
+pub(crate) fn send_cancellable(
+    request: reqwest::blocking::RequestBuilder,
+    cancel: &CancelToken,
+) -> Option<reqwest::Result<reqwest::blocking::Response>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(request.send());
+    });
+    loop {
+        match rx.recv_timeout(CANCEL_POLL_INTERVAL) {
+            Ok(result) => return Some(result),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                if cancel.is_cancelled() {
+                    return None;
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return None,
+        }
+    }
 }
@@ -1,6 +1,9 @@
+use super::attachment::Attachment;
 use super::{
-    LlmError, LlmProvider, LlmResponse, Message, Role, TokenUsage, ToolCall, ToolDefinition,
+    send_cancellable, LlmError, LlmProvider, LlmResponse, Message, Role, Timings, TokenUsage,
+    ToolCall, ToolCallProgress, ToolDefinition,
 };
+use crate::cancel::CancelToken;
 use crate::config::LlmOptions;
 use serde::{Deserialize, Serialize};
 
@@ -29,6 +32,9 @@ struct OllamaChatRequest<'a> {
     /// `[llm.options] think = ...` in config.
     #[serde(skip_serializing_if = "Option::is_none")]
     think: Option<bool>,
+    /// JSON schema the reply must follow (structured outputs).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a serde_json::Value>,
 }
 
 fn is_options_empty(o: &&LlmOptions) -> bool {
@@ -45,6 +51,12 @@ struct OllamaMessage {
     content: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_calls: Option<Vec<OllamaToolCall>>,
+    /// Thinking trace, sent back when the request had `think: true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+    /// Base64-encoded images for vision models.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -81,6 +93,38 @@ struct OllamaChatResponse {
     prompt_eval_count: Option<u64>,
     #[serde(default)]
     eval_count: Option<u64>,
+    #[serde(default)]
+    prompt_eval_duration: Option<u64>,
+    #[serde(default)]
+    eval_duration: Option<u64>,
+    #[serde(default)]
+    load_duration: Option<u64>,
+}
+
+impl OllamaChatResponse {
+    fn usage(&self) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: self.prompt_eval_count,
+            completion_tokens: self.eval_count,
+            timings: self.timings(),
+            ..TokenUsage::default()
+        }
+    }
+
+    /// Durations are reported in nanoseconds on the final response only.
+    fn timings(&self) -> Option<Timings> {
+        if self.prompt_eval_duration.is_none() && self.eval_duration.is_none() {
+            return None;
+        }
+        let ms = |ns: Option<u64>| ns.unwrap_or(0) as f64 / 1_000_000.0;
+        Some(Timings {
+            prompt_eval_tokens: self.prompt_eval_count.unwrap_or(0),
+            prompt_eval_ms: ms(self.prompt_eval_duration),
+            eval_tokens: self.eval_count.unwrap_or(0),
+            eval_ms: ms(self.eval_duration),
+            load_ms: ms(self.load_duration),
+        })
+    }
 }
 
 // --- Implementation ---
@@ -100,6 +144,10 @@ impl OllamaClient {
         if options.num_predict.is_none() {
             options.num_predict = Some(DEFAULT_NUM_PREDICT);
         }
+        // Ollama keeps the loaded model's prompt cache on its own and
+        // would log these as unknown options.
+        options.cache_prompt = None;
+        options.slot_id = None;
 
         let client = reqwest::blocking::Client::builder()
             .timeout(std::time::Duration::from_secs(300))
@@ -121,6 +169,8 @@ impl OllamaClient {
                     role: m.role.to_string(),
                     content: m.content.clone(),
                     tool_calls: None,
+                    thinking: None,
+                    images: m.attachments.iter().map(Attachment::base64).collect(),
                 };
 
                 if !m.tool_calls.is_empty() {
@@ -157,11 +207,14 @@ impl OllamaClient {
     }
 }
 
-impl LlmProvider for OllamaClient {
-    fn chat(
+impl OllamaClient {
+    /// Non-streaming `/api/chat`, optionally constrained to a JSON schema
+    /// through Ollama's `format` field.
+    fn chat_with_format(
         &self,
         messages: &[Message],
         tools: &[ToolDefinition],
+        format: Option<&serde_json::Value>,
     ) -> Result<LlmResponse, LlmError> {
         let url = format!("{}/api/chat", self.base_url);
 
@@ -172,6 +225,7 @@ impl LlmProvider for OllamaClient {
             tools: Self::convert_tools(tools),
             options: &self.options,
             think: self.options.think,
+            format,
         };
 
         let response = self.client.post(&url).json(&request).send().map_err(|e| {
@@ -195,15 +249,18 @@ impl LlmProvider for OllamaClient {
         }
         if !status.is_success() {
             let body = response.text().unwrap_or_default();
-            return Err(LlmError::RequestError(format!(
-                "Ollama returned status {}: {}",
-                status, body
-            )));
+            let msg = format!("Ollama returned status {}: {}", status, body);
+            return Err(if status.is_server_error() {
+                LlmError::ServerError(msg)
+            } else {
+                LlmError::RequestError(msg)
+            });
         }
 
         let resp_body: OllamaChatResponse = response
             .json()
             .map_err(|e| LlmError::ParseError(format!("Failed to parse Ollama response: {}", e)))?;
+        let usage = resp_body.usage();
 
         let tool_calls = resp_body
             .message
@@ -223,22 +280,58 @@ impl LlmProvider for OllamaClient {
         } else {
             Some(resp_body.message.content)
         };
+        let reasoning = resp_body.message.thinking.filter(|t| !t.is_empty());
 
         Ok(LlmResponse {
             content,
             tool_calls,
-            usage: TokenUsage {
-                prompt_tokens: resp_body.prompt_eval_count,
-                completion_tokens: resp_body.eval_count,
-            },
+            usage,
+            reasoning,
         })
     }
+}
+
+impl LlmProvider for OllamaClient {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_with_format(messages, tools, None)
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_with_format(messages, &[], Some(schema))
+    }
 
     fn chat_streaming(
         &self,
         messages: &[Message],
         tools: &[ToolDefinition],
         on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            &mut |_| {},
+            &mut |_| {},
+            &CancelToken::new(),
+        )
+    }
+
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        _on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
     ) -> Result<LlmResponse, LlmError> {
         let url = format!("{}/api/chat", self.base_url);
 
@@ -249,19 +342,23 @@ impl LlmProvider for OllamaClient {
             tools: Self::convert_tools(tools),
             options: &self.options,
             think: self.options.think,
+            format: None,
         };
 
-        let response = self.client.post(&url).json(&request).send().map_err(|e| {
-            if e.is_connect() {
-                LlmError::ConnectionError(
-                    "Cannot connect to Ollama. Is it running? Start with: ollama serve".to_string(),
-                )
-            } else if e.is_timeout() {
-                LlmError::RequestError("Request timed out".to_string())
-            } else {
-                LlmError::RequestError(e.to_string())
-            }
-        })?;
+        let response = send_cancellable(self.client.post(&url).json(&request), cancel)
+            .ok_or(LlmError::Cancelled)?
+            .map_err(|e| {
+                if e.is_connect() {
+                    LlmError::ConnectionError(
+                        "Cannot connect to Ollama. Is it running? Start with: ollama serve"
+                            .to_string(),
+                    )
+                } else if e.is_timeout() {
+                    LlmError::RequestError("Request timed out".to_string())
+                } else {
+                    LlmError::RequestError(e.to_string())
+                }
+            })?;
 
         let status = response.status();
         if status == reqwest::StatusCode::NOT_FOUND {
@@ -272,19 +369,25 @@ impl LlmProvider for OllamaClient {
         }
         if !status.is_success() {
             let body = response.text().unwrap_or_default();
-            return Err(LlmError::RequestError(format!(
-                "Ollama returned status {}: {}",
-                status, body
-            )));
+            let msg = format!("Ollama returned status {}: {}", status, body);
+            return Err(if status.is_server_error() {
+                LlmError::ServerError(msg)
+            } else {
+                LlmError::RequestError(msg)
+            });
         }
 
         let reader = std::io::BufReader::new(response);
         let mut accumulated_content = String::with_capacity(1024);
+        let mut accumulated_reasoning = String::new();
         let mut tool_calls = Vec::new();
         let mut usage = TokenUsage::default();
 
         use std::io::BufRead;
         for line_result in reader.lines() {
+            if cancel.is_cancelled() {
+                return Err(LlmError::Cancelled);
+            }
             let line = line_result
                 .map_err(|e| LlmError::ParseError(format!("Failed to read stream: {}", e)))?;
 
@@ -296,6 +399,14 @@ impl LlmProvider for OllamaClient {
                 LlmError::ParseError(format!("Failed to parse streaming chunk: {}", e))
             })?;
 
+            // Thinking chunks arrive before the answer when `think` is on
+            if let Some(ref thinking) = chunk.message.thinking {
+                if !thinking.is_empty() {
+                    on_reasoning(thinking);
+                    accumulated_reasoning.push_str(thinking);
+                }
+            }
+
             // Emit content tokens
             if !chunk.message.content.is_empty() {
                 on_token(&chunk.message.content);
@@ -303,6 +414,10 @@ impl LlmProvider for OllamaClient {
             }
 
             // Collect tool calls from the final chunk
+            let done = chunk.done.unwrap_or(false);
+            if done {
+                usage = chunk.usage();
+            }
             if let Some(tcs) = chunk.message.tool_calls {
                 let base_idx = tool_calls.len();
                 for (i, tc) in tcs.into_iter().enumerate() {
@@ -314,12 +429,8 @@ impl LlmProvider for OllamaClient {
                 }
             }
 
-            // Check if streaming is done — capture usage from final chunk
-            if chunk.done.unwrap_or(false) {
-                usage = TokenUsage {
-                    prompt_tokens: chunk.prompt_eval_count,
-                    completion_tokens: chunk.eval_count,
-                };
+            // Usage was captured above; the final chunk ends the stream
+            if done {
                 break;
             }
         }
@@ -334,6 +445,7 @@ impl LlmProvider for OllamaClient {
             content,
             tool_calls,
             usage,
+            reasoning: Some(accumulated_reasoning).filter(|r| !r.is_empty()),
         })
     }
 }
@@ -345,6 +457,7 @@ impl Message {
             content: content.to_string(),
             tool_call_id: None,
             tool_calls: vec![],
+            attachments: vec![],
         }
     }
 
@@ -354,6 +467,15 @@ impl Message {
             content: content.to_string(),
             tool_call_id: None,
             tool_calls: vec![],
+            attachments: vec![],
+        }
+    }
+
+    /// A user turn with images attached.
+    pub fn user_with_attachments(content: &str, attachments: Vec<Attachment>) -> Self {
+        Self {
+            attachments,
+            ..Self::user(content)
         }
     }
 
@@ -363,6 +485,7 @@ impl Message {
             content: content.to_string(),
             tool_call_id: None,
             tool_calls: vec![],
+            attachments: vec![],
         }
     }
 
@@ -372,6 +495,7 @@ impl Message {
             content: String::new(),
             tool_call_id: None,
             tool_calls,
+            attachments: vec![],
         }
     }
 
@@ -381,6 +505,7 @@ impl Message {
             content: content.to_string(),
             tool_call_id: Some(tool_call_id.to_string()),
             tool_calls: vec![],
+            attachments: vec![],
         }
     }
 }
@@ -402,6 +527,24 @@ mod tests {
         assert!(converted[0].tool_calls.is_none());
     }
 
+    #[test]
+    fn test_convert_user_message_with_image() {
+        let image = Attachment {
+            name: "shot.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: b"\x89PNG".to_vec(),
+        };
+        let messages = vec![
+            Message::user_with_attachments("What is broken here?", vec![image]),
+            Message::user("plain"),
+        ];
+        let converted = OllamaClient::convert_messages(&messages);
+        let json = serde_json::to_value(&converted).unwrap();
+        assert_eq!(json[0]["content"], "What is broken here?");
+        assert_eq!(json[0]["images"], json!(["iVBORw=="]));
+        assert!(json[1].get("images").is_none());
+    }
+
     #[test]
     fn test_convert_system_message() {
         let messages = vec![Message::system("You are helpful.")];
@@ -500,11 +643,14 @@ mod tests {
                 role: "user".to_string(),
                 content: "Hello".to_string(),
                 tool_calls: None,
+                thinking: None,
+                images: vec![],
             }],
             stream: false,
             tools: vec![],
             options: &opts,
             think: None,
+            format: None,
         };
         let json_str = serde_json::to_string(&request).unwrap();
         let parsed: serde_json::Value = serde_json::from_str(&json_str).unwrap();
@@ -536,6 +682,7 @@ mod tests {
             }],
             options: &opts,
             think: None,
+            format: None,
         };
         let json_str = serde_json::to_string(&request).unwrap();
         let parsed: serde_json::Value = serde_json::from_str(&json_str).unwrap();
@@ -553,6 +700,8 @@ mod tests {
             top_p: None,
             seed: Some(42),
             think: Some(false),
+            cache_prompt: None,
+            slot_id: None,
         };
         let request = OllamaChatRequest {
             model: "qwen3:14b".to_string(),
@@ -561,6 +710,7 @@ mod tests {
             tools: vec![],
             options: &opts,
             think: opts.think,
+            format: None,
         };
         let json_str = serde_json::to_string(&request).unwrap();
         let parsed: serde_json::Value = serde_json::from_str(&json_str).unwrap();
@@ -891,6 +1041,36 @@ mod tests {
         assert_eq!(resp.eval_count, Some(128));
     }
 
+    #[test]
+    fn test_parse_response_timings() {
+        let json = serde_json::json!({
+            "message": {"role": "assistant", "content": "hi"},
+            "done": true,
+            "prompt_eval_count": 400,
+            "eval_count": 50,
+            "prompt_eval_duration": 200_000_000u64,
+            "eval_duration": 1_000_000_000u64,
+            "load_duration": 3_500_000_000u64
+        });
+        let resp: OllamaChatResponse = serde_json::from_value(json).unwrap();
+        let usage = resp.usage();
+        assert_eq!(usage.prompt_tokens, Some(400));
+        assert_eq!(
+            usage.timings,
+            Some(Timings {
+                prompt_eval_tokens: 400,
+                prompt_eval_ms: 200.0,
+                eval_tokens: 50,
+                eval_ms: 1000.0,
+                load_ms: 3500.0,
+            })
+        );
+
+        let json = serde_json::json!({"message": {"role": "assistant", "content": ""}});
+        let resp: OllamaChatResponse = serde_json::from_value(json).unwrap();
+        assert!(resp.usage().timings.is_none());
+    }
+
     #[test]
     fn test_response_parse_without_usage() {
         let json = json!({
@@ -917,4 +1097,83 @@ mod tests {
         assert_eq!(chunk.prompt_eval_count, Some(100));
         assert_eq!(chunk.eval_count, Some(50));
     }
+
+    #[test]
+    fn test_streaming_cancel_while_waiting_for_first_byte() {
+        // A server that accepts the connection but never answers — the
+        // "long prompt still being processed" case. Cancellation must not
+        // wait for it.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let _hold = std::thread::spawn(move || {
+            let conn = listener.accept();
+            std::thread::sleep(std::time::Duration::from_secs(10));
+            drop(conn);
+        });
+
+        let client = OllamaClient::new(&url, "qwen3:8b");
+        let cancel = CancelToken::new();
+        let trigger = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(150));
+            trigger.cancel();
+        });
+        let start = std::time::Instant::now();
+        let result = client.chat_streaming_cancellable(
+            &[Message::user("hi")],
+            &[],
+            &mut |_| {},
+            &mut |_| {},
+            &mut |_| {},
+            &cancel,
+        );
+        assert!(matches!(result, Err(LlmError::Cancelled)));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_thinking_streamed_separately_from_answer() {
+        let body = [
+            json!({"message": {"role": "assistant", "content": "", "thinking": "Let me "}, "done": false}),
+            json!({"message": {"role": "assistant", "content": "", "thinking": "check."}, "done": false}),
+            json!({"message": {"role": "assistant", "content": "42"}, "done": true}),
+        ]
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+        let server =
+            crate::llm::stub_server::StubServer::start(vec![(200, "application/x-ndjson", body)]);
+        let client = OllamaClient::new(&server.url, "qwen3:8b");
+        let (mut answer, mut thought) = (String::new(), String::new());
+        let resp = client
+            .chat_streaming_cancellable(
+                &[Message::user("hi")],
+                &[],
+                &mut |t| answer.push_str(t),
+                &mut |t| thought.push_str(t),
+                &mut |_| {},
+                &CancelToken::new(),
+            )
+            .unwrap();
+        assert_eq!(answer, "42");
+        assert_eq!(thought, "Let me check.");
+        assert_eq!(resp.content.as_deref(), Some("42"));
+        assert_eq!(resp.reasoning.as_deref(), Some("Let me check."));
+    }
+
+    #[test]
+    fn test_non_streaming_thinking_parsed() {
+        let server = crate::llm::stub_server::StubServer::json(vec![json!({
+            "message": {"role": "assistant", "content": "hi", "thinking": "greet back"},
+            "done": true
+        })]);
+        let client = OllamaClient::new(&server.url, "qwen3:8b");
+        let resp = client.chat(&[Message::user("hello")], &[]).unwrap();
+        assert_eq!(resp.reasoning.as_deref(), Some("greet back"));
+        // The trace is never echoed back in the request history.
+        assert!(server.requests()[0].json()["messages"][0]
+            .get("thinking")
+            .is_none());
+    }
 }
@@ -1,4 +1,8 @@
-use super::{LlmError, LlmProvider, LlmResponse, Message, TokenUsage, ToolCall, ToolDefinition};
+use super::{
+    send_cancellable, LlmError, LlmProvider, LlmResponse, Message, Timings, TokenUsage, ToolCall,
+    ToolCallProgress, ToolDefinition,
+};
+use crate::cancel::CancelToken;
 use crate::config::LlmOptions;
 use serde::{Deserialize, Serialize};
 
@@ -28,6 +32,14 @@ struct ChatRequest {
     max_tokens: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     seed: Option<i64>,
+    /// `{"type": "json_schema", ...}` for structured outputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+    /// llama.cpp prompt-prefix reuse; see `LlmOptions::cache_prompt`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_prompt: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_slot: Option<i32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,10 +47,43 @@ struct ChatMessage {
     role: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
+    /// Text and `image_url` parts, sent as `content` in place of the plain
+    /// string when the message has images. Request-only; `content` is
+    /// `None` whenever this is set.
+    #[serde(
+        rename = "content",
+        skip_serializing_if = "Option::is_none",
+        skip_deserializing
+    )]
+    content_parts: Option<Vec<serde_json::Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_calls: Option<Vec<ChatToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_call_id: Option<String>,
+    /// Thinking trace from vLLM, llama-server and DeepSeek-style servers.
+    /// Response-only: never sent back in the history.
+    #[serde(default, skip_serializing)]
+    reasoning_content: Option<String>,
+    /// Same trace under the name OpenRouter and Ollama's `/v1` use.
+    #[serde(default, skip_serializing)]
+    reasoning: Option<String>,
+}
+
+impl ChatMessage {
+    fn take_reasoning(&mut self) -> Option<String> {
+        self.reasoning_content
+            .take()
+            .filter(|r| !r.is_empty())
+            .or_else(|| self.reasoning.take().filter(|r| !r.is_empty()))
+    }
+}
+
+/// Reasoning text in a streaming delta, under either field name.
+fn delta_reasoning(delta: &serde_json::Value) -> Option<&str> {
+    ["reasoning_content", "reasoning"]
+        .iter()
+        .filter_map(|k| delta.get(k).and_then(|v| v.as_str()))
+        .find(|r| !r.is_empty())
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -82,11 +127,124 @@ struct ChatResponse {
     usage: Option<ChatResponseUsage>,
 }
 
+/// Prompt tokens served from the server's prefix cache, from a response
+/// body or a final stream chunk: OpenAI's `prompt_tokens_details`
+/// (vLLM, recent llama-server) or llama-server's `timings.cache_n`.
+pub(super) fn cached_prompt_tokens(body: &serde_json::Value) -> Option<u64> {
+    body["usage"]["prompt_tokens_details"]["cached_tokens"]
+        .as_u64()
+        .or_else(|| body["timings"]["cache_n"].as_u64())
+}
+
+/// llama-server's `timings` object, sent on a response body or the final
+/// stream chunk. Other OpenAI-compatible servers don't report timings.
+pub(super) fn server_timings(body: &serde_json::Value) -> Option<Timings> {
+    let t = body.get("timings")?;
+    Some(Timings {
+        prompt_eval_tokens: t["prompt_n"].as_u64().unwrap_or(0),
+        prompt_eval_ms: t["prompt_ms"].as_f64().unwrap_or(0.0),
+        eval_tokens: t["predicted_n"].as_u64().unwrap_or(0),
+        eval_ms: t["predicted_ms"].as_f64().unwrap_or(0.0),
+        load_ms: 0.0,
+    })
+}
+
 #[derive(Deserialize, Debug)]
 struct ChatChoice {
     message: ChatMessage,
 }
 
+/// Builds tool calls from streamed `tool_calls` deltas. Servers differ
+/// in how they split them: most tag every fragment with an `index`, some
+/// omit it and open each call with a fresh `id`, and a few send
+/// `arguments` as a whole object rather than string pieces. Calls come
+/// out in index order whatever order the fragments arrived in.
+#[derive(Debug, Default)]
+struct ToolCallAssembler {
+    calls: Vec<PartialToolCall>,
+}
+
+#[derive(Debug)]
+struct PartialToolCall {
+    index: usize,
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl ToolCallAssembler {
+    /// Fold one delta in and report the call it extended.
+    fn push(&mut self, delta: &serde_json::Value) -> ToolCallProgress<'_> {
+        let id = delta["id"].as_str().filter(|id| !id.is_empty());
+        let slot = match delta["index"].as_u64() {
+            Some(index) => self.slot_for_index(index as usize),
+            None => {
+                let starts_new = match (self.calls.last(), id) {
+                    (None, _) => true,
+                    (Some(last), Some(id)) => !last.id.is_empty() && last.id != id,
+                    (Some(_), None) => false,
+                };
+                if starts_new {
+                    let index = self.calls.iter().map(|c| c.index + 1).max().unwrap_or(0);
+                    self.slot_for_index(index)
+                } else {
+                    self.calls.len() - 1
+                }
+            }
+        };
+        let call = &mut self.calls[slot];
+        if let Some(id) = id {
+            if call.id.is_empty() {
+                call.id = id.to_string();
+            }
+        }
+        let function = &delta["function"];
+        if let Some(name) = function["name"].as_str().filter(|n| !n.is_empty()) {
+            call.name = name.to_string();
+        }
+        match &function["arguments"] {
+            serde_json::Value::String(piece) => call.arguments.push_str(piece),
+            serde_json::Value::Null => {}
+            whole => call.arguments = whole.to_string(),
+        }
+        ToolCallProgress {
+            index: call.index,
+            name: &call.name,
+            bytes: call.arguments.len(),
+        }
+    }
+
+    fn slot_for_index(&mut self, index: usize) -> usize {
+        if let Some(pos) = self.calls.iter().position(|c| c.index == index) {
+            return pos;
+        }
+        self.calls.push(PartialToolCall {
+            index,
+            id: String::new(),
+            name: String::new(),
+            arguments: String::new(),
+        });
+        self.calls.len() - 1
+    }
+
+    fn finish(mut self) -> Vec<ToolCall> {
+        self.calls.sort_by_key(|c| c.index);
+        self.calls
+            .into_iter()
+            .map(|c| ToolCall {
+                id: if c.id.is_empty() {
+                    format!("call_{}", c.index)
+                } else {
+                    c.id
+                },
+                name: c.name,
+                arguments: serde_json::from_str(&c.arguments)
+                    .unwrap_or_else(|_| serde_json::Value::Object(serde_json::Map::new())),
+            })
+            .collect()
+    }
+}
+
 // --- Implementation ---
 
 impl OpenAiCompatClient {
@@ -125,10 +283,25 @@ impl OpenAiCompatClient {
                     } else {
                         Some(m.content.clone())
                     },
+                    content_parts: None,
                     tool_calls: None,
                     tool_call_id: m.tool_call_id.clone(),
+                    reasoning_content: None,
+                    reasoning: None,
                 };
 
+                if !m.attachments.is_empty() {
+                    let mut parts = vec![serde_json::json!({"type": "text", "text": m.content})];
+                    parts.extend(m.attachments.iter().map(|a| {
+                        serde_json::json!({
+                            "type": "image_url",
+                            "image_url": {"url": a.data_url()}
+                        })
+                    }));
+                    msg.content = None;
+                    msg.content_parts = Some(parts);
+                }
+
                 if !m.tool_calls.is_empty() {
                     msg.tool_calls = Some(
                         m.tool_calls
@@ -165,11 +338,14 @@ impl OpenAiCompatClient {
     }
 }
 
-impl LlmProvider for OpenAiCompatClient {
-    fn chat(
+impl OpenAiCompatClient {
+    /// Non-streaming chat completion. `response_format` carries a JSON
+    /// schema for structured outputs.
+    fn chat_with_format(
         &self,
         messages: &[Message],
         tools: &[ToolDefinition],
+        response_format: Option<serde_json::Value>,
     ) -> Result<LlmResponse, LlmError> {
         let url = format!("{}/v1/chat/completions", self.base_url);
 
@@ -182,6 +358,9 @@ impl LlmProvider for OpenAiCompatClient {
             top_p: self.options.top_p,
             max_tokens: self.options.num_predict,
             seed: self.options.seed,
+            cache_prompt: self.options.cache_prompt,
+            id_slot: self.options.slot_id,
+            response_format,
         };
 
         let mut req_builder = self.client.post(&url).json(&request);
@@ -217,26 +396,36 @@ impl LlmProvider for OpenAiCompatClient {
         }
         if !status.is_success() {
             let body = response.text().unwrap_or_default();
-            return Err(LlmError::RequestError(format!(
-                "Server returned status {}: {}",
-                status, body
-            )));
+            let msg = format!("Server returned status {}: {}", status, body);
+            return Err(if status.is_server_error() {
+                LlmError::ServerError(msg)
+            } else {
+                LlmError::RequestError(msg)
+            });
         }
 
-        let resp_body: ChatResponse = response
+        let body: serde_json::Value = response
             .json()
             .map_err(|e| LlmError::ParseError(format!("Failed to parse response: {}", e)))?;
+        let cached = cached_prompt_tokens(&body);
+        let timings = server_timings(&body);
+        let resp_body: ChatResponse = serde_json::from_value(body)
+            .map_err(|e| LlmError::ParseError(format!("Failed to parse response: {}", e)))?;
 
         let usage = TokenUsage {
             prompt_tokens: resp_body.usage.as_ref().and_then(|u| u.prompt_tokens),
             completion_tokens: resp_body.usage.as_ref().and_then(|u| u.completion_tokens),
+            cached_prompt_tokens: cached,
+            timings,
+            ..TokenUsage::default()
         };
 
-        let choice = resp_body
+        let mut choice = resp_body
             .choices
             .into_iter()
             .next()
             .ok_or_else(|| LlmError::ParseError("No choices in response".to_string()))?;
+        let reasoning = choice.message.take_reasoning();
 
         let tool_calls = choice
             .message
@@ -261,14 +450,56 @@ impl LlmProvider for OpenAiCompatClient {
             content,
             tool_calls,
             usage,
+            reasoning,
         })
     }
+}
+
+impl LlmProvider for OpenAiCompatClient {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_with_format(messages, tools, None)
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        let response_format = serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {"name": "response", "strict": true, "schema": schema},
+        });
+        self.chat_with_format(messages, &[], Some(response_format))
+    }
 
     fn chat_streaming(
         &self,
         messages: &[Message],
         tools: &[ToolDefinition],
         on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            &mut |_| {},
+            &mut |_| {},
+            &CancelToken::new(),
+        )
+    }
+
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
     ) -> Result<LlmResponse, LlmError> {
         let url = format!("{}/v1/chat/completions", self.base_url);
 
@@ -281,6 +512,9 @@ impl LlmProvider for OpenAiCompatClient {
             top_p: self.options.top_p,
             max_tokens: self.options.num_predict,
             seed: self.options.seed,
+            cache_prompt: self.options.cache_prompt,
+            id_slot: self.options.slot_id,
+            response_format: None,
         };
 
         let mut req_builder = self.client.post(&url).json(&request);
@@ -289,38 +523,44 @@ impl LlmProvider for OpenAiCompatClient {
             req_builder = req_builder.header("Authorization", format!("Bearer {}", key));
         }
 
-        let response = req_builder.send().map_err(|e| {
-            if e.is_connect() {
-                LlmError::ConnectionError(format!(
-                    "Cannot connect to OpenAI-compatible server at {}. Is it running?",
-                    self.base_url
-                ))
-            } else if e.is_timeout() {
-                LlmError::RequestError("Request timed out".to_string())
-            } else {
-                LlmError::RequestError(e.to_string())
-            }
-        })?;
+        let response = send_cancellable(req_builder, cancel)
+            .ok_or(LlmError::Cancelled)?
+            .map_err(|e| {
+                if e.is_connect() {
+                    LlmError::ConnectionError(format!(
+                        "Cannot connect to OpenAI-compatible server at {}. Is it running?",
+                        self.base_url
+                    ))
+                } else if e.is_timeout() {
+                    LlmError::RequestError("Request timed out".to_string())
+                } else {
+                    LlmError::RequestError(e.to_string())
+                }
+            })?;
 
         let status = response.status();
         if !status.is_success() {
             let body = response.text().unwrap_or_default();
-            return Err(LlmError::RequestError(format!(
-                "Server returned status {}: {}",
-                status, body
-            )));
+            let msg = format!("Server returned status {}: {}", status, body);
+            return Err(if status.is_server_error() {
+                LlmError::ServerError(msg)
+            } else {
+                LlmError::RequestError(msg)
+            });
         }
 
         let reader = std::io::BufReader::new(response);
         let mut accumulated_content = String::with_capacity(1024);
-        // Track tool calls by index for incremental assembly
-        let mut tool_call_map: std::collections::HashMap<usize, (String, String, String)> =
-            std::collections::HashMap::new();
+        let mut accumulated_reasoning = String::new();
+        let mut tool_call_parts = ToolCallAssembler::default();
         // Track usage from the final chunk
         let mut stream_usage = TokenUsage::default();
 
         use std::io::BufRead;
         for line_result in reader.lines() {
+            if cancel.is_cancelled() {
+                return Err(LlmError::Cancelled);
+            }
             let line = line_result
                 .map_err(|e| LlmError::ParseError(format!("Failed to read stream: {}", e)))?;
 
@@ -342,6 +582,12 @@ impl LlmProvider for OpenAiCompatClient {
             };
 
             // Extract usage from chunk (typically in the final chunk)
+            if let Some(cached) = cached_prompt_tokens(&chunk) {
+                stream_usage.cached_prompt_tokens = Some(cached);
+            }
+            if let Some(timings) = server_timings(&chunk) {
+                stream_usage.timings = Some(timings);
+            }
             if let Some(usage) = chunk.get("usage") {
                 if let Some(pt) = usage.get("prompt_tokens").and_then(|v| v.as_u64()) {
                     stream_usage.prompt_tokens = Some(pt);
@@ -355,6 +601,11 @@ impl LlmProvider for OpenAiCompatClient {
                 if let Some(choice) = choices.first() {
                     let delta = &choice["delta"];
 
+                    if let Some(reasoning) = delta_reasoning(delta) {
+                        on_reasoning(reasoning);
+                        accumulated_reasoning.push_str(reasoning);
+                    }
+
                     // Content delta
                     if let Some(content) = delta.get("content").and_then(|c| c.as_str()) {
                         if !content.is_empty() {
@@ -363,54 +614,17 @@ impl LlmProvider for OpenAiCompatClient {
                         }
                     }
 
-                    // Tool call deltas
+                    // Tool call deltas, possibly interleaved with content
                     if let Some(tool_calls) = delta.get("tool_calls").and_then(|tc| tc.as_array()) {
                         for tc in tool_calls {
-                            let index =
-                                tc.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
-                            let entry = tool_call_map
-                                .entry(index)
-                                .or_insert_with(|| (String::new(), String::new(), String::new()));
-
-                            if let Some(id) = tc.get("id").and_then(|i| i.as_str()) {
-                                entry.0 = id.to_string();
-                            }
-                            if let Some(func) = tc.get("function") {
-                                if let Some(name) = func.get("name").and_then(|n| n.as_str()) {
-                                    entry.1 = name.to_string();
-                                }
-                                if let Some(args) = func.get("arguments").and_then(|a| a.as_str()) {
-                                    entry.2.push_str(args);
-                                }
-                            }
+                            on_tool_progress(&tool_call_parts.push(tc));
                         }
                     }
                 }
             }
         }
 
-        // Assemble final tool calls
-        let mut tool_calls: Vec<ToolCall> = tool_call_map
-            .into_iter()
-            .map(|(_, (id, name, args_str))| {
-                let arguments: serde_json::Value = serde_json::from_str(&args_str)
-                    .unwrap_or_else(|_| serde_json::Value::Object(serde_json::Map::new()));
-                ToolCall {
-                    id,
-                    name,
-                    arguments,
-                }
-            })
-            .collect();
-        tool_calls.sort_by(|a, b| {
-            // Extract numeric suffix for natural ordering (e.g., "call_2" < "call_10")
-            let num_a = a.id.rsplit('_').next().and_then(|s| s.parse::<u64>().ok());
-            let num_b = b.id.rsplit('_').next().and_then(|s| s.parse::<u64>().ok());
-            match (num_a, num_b) {
-                (Some(na), Some(nb)) => na.cmp(&nb),
-                _ => a.id.cmp(&b.id),
-            }
-        });
+        let tool_calls = tool_call_parts.finish();
 
         let content = if accumulated_content.is_empty() {
             None
@@ -422,6 +636,7 @@ impl LlmProvider for OpenAiCompatClient {
             content,
             tool_calls,
             usage: stream_usage,
+            reasoning: Some(accumulated_reasoning).filter(|r| !r.is_empty()),
         })
     }
 }
@@ -429,6 +644,7 @@ impl LlmProvider for OpenAiCompatClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::attachment::Attachment;
     use serde_json::json;
 
     #[test]
@@ -465,6 +681,24 @@ mod tests {
         assert!(converted[0].tool_calls.is_none());
     }
 
+    #[test]
+    fn test_convert_user_message_with_image() {
+        let image = Attachment {
+            name: "shot.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: b"\x89PNG".to_vec(),
+        };
+        let messages = vec![Message::user_with_attachments("Look", vec![image])];
+        let json = serde_json::to_value(OpenAiCompatClient::convert_messages(&messages)).unwrap();
+        assert_eq!(
+            json[0]["content"],
+            json!([
+                {"type": "text", "text": "Look"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw=="}}
+            ])
+        );
+    }
+
     #[test]
     fn test_convert_system_message() {
         let messages = vec![Message::system("You are helpful.")];
@@ -578,8 +812,11 @@ mod tests {
             messages: vec![ChatMessage {
                 role: "user".to_string(),
                 content: Some("Hello".to_string()),
+                content_parts: None,
                 tool_calls: None,
                 tool_call_id: None,
+                reasoning_content: None,
+                reasoning: None,
             }],
             tools: vec![],
             stream: false,
@@ -1038,4 +1275,222 @@ mod tests {
         assert_eq!(stream_usage.prompt_tokens, Some(100));
         assert_eq!(stream_usage.completion_tokens, Some(5));
     }
+
+    #[test]
+    fn test_response_parse_reasoning_content() {
+        let json_val = json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": "4", "reasoning_content": "2+2"}
+            }]
+        });
+        let mut resp: ChatResponse = serde_json::from_value(json_val).unwrap();
+        assert_eq!(
+            resp.choices[0].message.take_reasoning().as_deref(),
+            Some("2+2")
+        );
+
+        // OpenRouter spells it `reasoning`
+        let json_val = json!({
+            "choices": [{"message": {"role": "assistant", "content": "4", "reasoning": "sum"}}]
+        });
+        let mut resp: ChatResponse = serde_json::from_value(json_val).unwrap();
+        assert_eq!(
+            resp.choices[0].message.take_reasoning().as_deref(),
+            Some("sum")
+        );
+    }
+
+    #[test]
+    fn test_streaming_reasoning_deltas_routed_separately() {
+        let body = [
+            r#"data: {"choices":[{"delta":{"reasoning_content":"hmm, "}}]}"#,
+            r#"data: {"choices":[{"delta":{"reasoning_content":"four"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":"4"}}]}"#,
+            "data: [DONE]",
+        ]
+        .join("\n\n");
+        let server =
+            crate::llm::stub_server::StubServer::start(vec![(200, "text/event-stream", body)]);
+        let client = OpenAiCompatClient::new(&server.url, "m", None);
+        let (mut answer, mut thought) = (String::new(), String::new());
+        let resp = client
+            .chat_streaming_cancellable(
+                &[Message::user("2+2?")],
+                &[],
+                &mut |t| answer.push_str(t),
+                &mut |t| thought.push_str(t),
+                &mut |_| {},
+                &CancelToken::new(),
+            )
+            .unwrap();
+        assert_eq!(answer, "4");
+        assert_eq!(thought, "hmm, four");
+        assert_eq!(resp.reasoning.as_deref(), Some("hmm, four"));
+        // Reasoning fields are response-only.
+        let sent = server.requests()[0].json();
+        assert!(sent["messages"][0].get("reasoning_content").is_none());
+    }
+
+    #[test]
+    fn test_prompt_cache_options_sent_and_hits_reported() {
+        let server = crate::llm::stub_server::StubServer::json(vec![json!({
+            "choices": [{"message": {"role": "assistant", "content": "ok"}}],
+            "usage": {
+                "prompt_tokens": 1200,
+                "completion_tokens": 5,
+                "prompt_tokens_details": {"cached_tokens": 1024}
+            }
+        })]);
+        let options = LlmOptions {
+            cache_prompt: Some(true),
+            slot_id: Some(0),
+            ..Default::default()
+        };
+        let client = OpenAiCompatClient::with_options(&server.url, "m", None, options);
+        let resp = client.chat(&[Message::user("hi")], &[]).unwrap();
+        assert_eq!(resp.usage.prompt_tokens, Some(1200));
+        assert_eq!(resp.usage.cached_prompt_tokens, Some(1024));
+        let sent = server.requests()[0].json();
+        assert_eq!(sent["cache_prompt"], true);
+        assert_eq!(sent["id_slot"], 0);
+    }
+
+    #[test]
+    fn test_cached_prompt_tokens_sources() {
+        // vLLM / OpenAI usage details
+        let body = json!({"usage": {"prompt_tokens_details": {"cached_tokens": 64}}});
+        assert_eq!(cached_prompt_tokens(&body), Some(64));
+        // llama-server timings
+        let body = json!({"timings": {"cache_n": 512, "prompt_n": 20}});
+        assert_eq!(cached_prompt_tokens(&body), Some(512));
+        assert_eq!(cached_prompt_tokens(&json!({"usage": {}})), None);
+        assert_eq!(server_timings(&body).unwrap().prompt_eval_tokens, 20);
+        // Unset options stay out of the request entirely.
+        let server = crate::llm::stub_server::StubServer::json(vec![json!({
+            "choices": [{"message": {"role": "assistant", "content": "ok"}}]
+        })]);
+        OpenAiCompatClient::new(&server.url, "m", None)
+            .chat(&[Message::user("hi")], &[])
+            .unwrap();
+        let sent = server.requests()[0].json();
+        assert!(sent.get("cache_prompt").is_none());
+        assert!(sent.get("id_slot").is_none());
+    }
+
+    #[test]
+    fn test_llama_server_timings_parsed() {
+        let server = crate::llm::stub_server::StubServer::json(vec![json!({
+            "choices": [{"message": {"role": "assistant", "content": "ok"}}],
+            "timings": {
+                "cache_n": 100,
+                "prompt_n": 300,
+                "prompt_ms": 150.0,
+                "predicted_n": 40,
+                "predicted_ms": 800.5
+            }
+        })]);
+        let client = OpenAiCompatClient::new(&server.url, "m", None);
+        let resp = client.chat(&[Message::user("hi")], &[]).unwrap();
+        assert_eq!(
+            resp.usage.timings,
+            Some(Timings {
+                prompt_eval_tokens: 300,
+                prompt_eval_ms: 150.0,
+                eval_tokens: 40,
+                eval_ms: 800.5,
+                load_ms: 0.0,
+            })
+        );
+        assert!(server_timings(&json!({"usage": {}})).is_none());
+    }
+
+    #[test]
+    fn test_tool_call_assembler_orders_by_index() {
+        let mut parts = ToolCallAssembler::default();
+        parts.push(&json!({"index": 1, "id": "b", "function": {"name": "grep", "arguments": "{\"pattern\""}}));
+        parts.push(
+            &json!({"index": 0, "id": "a", "function": {"name": "read_file", "arguments": "{}"}}),
+        );
+        let progress = parts.push(&json!({"index": 1, "function": {"arguments": ": \"x\"}"}}));
+        assert_eq!(
+            progress,
+            ToolCallProgress {
+                index: 1,
+                name: "grep",
+                bytes: 16
+            }
+        );
+        let calls = parts.finish();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            (calls[0].id.as_str(), calls[0].name.as_str()),
+            ("a", "read_file")
+        );
+        assert_eq!(calls[1].arguments, json!({"pattern": "x"}));
+    }
+
+    #[test]
+    fn test_tool_call_assembler_without_index() {
+        // No `index`: a new `id` opens the next call, fragments without
+        // one continue the current call. Object arguments are taken whole.
+        let mut parts = ToolCallAssembler::default();
+        parts.push(
+            &json!({"id": "x1", "function": {"name": "read_file", "arguments": "{\"path\":"}}),
+        );
+        parts.push(&json!({"function": {"arguments": "\"a.rs\"}"}}));
+        parts.push(
+            &json!({"id": "x2", "function": {"name": "list_dir", "arguments": {"path": "src"}}}),
+        );
+        parts.push(&json!({"function": {"name": "", "arguments": ""}}));
+        let calls = parts.finish();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].arguments, json!({"path": "a.rs"}));
+        assert_eq!(calls[1].id, "x2");
+        assert_eq!(calls[1].name, "list_dir");
+        assert_eq!(calls[1].arguments, json!({"path": "src"}));
+
+        // No ids either: one call, with a generated id.
+        let mut parts = ToolCallAssembler::default();
+        parts.push(&json!({"function": {"name": "git", "arguments": "{}"}}));
+        assert_eq!(parts.finish()[0].id, "call_0");
+    }
+
+    #[test]
+    fn test_streaming_reports_tool_progress_between_content() {
+        let body = [
+            r#"data: {"choices":[{"delta":{"content":"Writing"}}]}"#,
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"write_file","arguments":"{\"path\":\"a.txt\","}}]}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":" now"}}]}"#,
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"content\":\"hi\"}"}}]}}]}"#,
+            "data: [DONE]",
+        ]
+        .join("\n\n");
+        let server =
+            crate::llm::stub_server::StubServer::start(vec![(200, "text/event-stream", body)]);
+        let client = OpenAiCompatClient::new(&server.url, "m", None);
+        let mut progress = Vec::new();
+        let resp = client
+            .chat_streaming_cancellable(
+                &[Message::user("write")],
+                &[],
+                &mut |_| {},
+                &mut |_| {},
+                &mut |p| progress.push((p.name.to_string(), p.bytes)),
+                &CancelToken::new(),
+            )
+            .unwrap();
+        assert_eq!(
+            progress,
+            vec![
+                ("write_file".to_string(), 16),
+                ("write_file".to_string(), 31)
+            ]
+        );
+        assert_eq!(resp.content.as_deref(), Some("Writing now"));
+        assert_eq!(resp.tool_calls.len(), 1);
+        assert_eq!(
+            resp.tool_calls[0].arguments,
+            json!({"path": "a.txt", "content": "hi"})
+        );
+    }
 }
@@ -0,0 +1,369 @@
+//! Retry wrapper for transient backend errors.
+//!
+//! Ollama answers 500/503 for a few seconds while a model is being loaded
+//! into VRAM; without a retry the whole agent turn dies on the first call.
+//! `RetryingProvider` wraps any `LlmProvider` and re-issues the call with
+//! exponential backoff when the error's `kind()` is listed in
+//! `[llm.retry].retry_on`.
+//!
+//! Streaming calls are only retried while nothing has been shown yet —
+//! once a token has reached the terminal, replaying the response would
+//! print it twice.
+
+use super::{LlmError, LlmProvider, LlmResponse, Message, ToolCallProgress, ToolDefinition};
+use crate::cancel::CancelToken;
+use crate::config::RetryConfig;
+use std::cell::Cell;
+use std::time::Duration;
+
+/// Passed to the notice callback right before sleeping for the next attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryNotice {
+    /// The attempt about to be made (2 for the first retry).
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay: Duration,
+    pub error: String,
+}
+
+pub type RetryCallback = Box<dyn Fn(&RetryNotice)>;
+
+pub struct RetryingProvider {
+    inner: Box<dyn LlmProvider>,
+    policy: RetryConfig,
+    on_retry: Option<RetryCallback>,
+    sleep: fn(Duration),
+}
+
+impl RetryingProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, policy: RetryConfig) -> Self {
+        Self {
+            inner,
+            policy,
+            on_retry: None,
+            sleep: std::thread::sleep,
+        }
+    }
+
+    pub fn set_on_retry(&mut self, cb: RetryCallback) {
+        self.on_retry = Some(cb);
+    }
+
+    fn is_retryable(&self, err: &LlmError) -> bool {
+        // A user interrupt is never transient, whatever retry_on says.
+        !matches!(err, LlmError::Cancelled) && self.policy.retry_on.iter().any(|k| k == err.kind())
+    }
+
+    /// Delay before `attempt` (2-based): initial, 2×initial, 4×initial, …
+    /// capped at `max_backoff_ms`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(2).min(16);
+        let ms = self
+            .policy
+            .initial_backoff_ms
+            .saturating_mul(1u64 << exp)
+            .min(self.policy.max_backoff_ms);
+        Duration::from_millis(ms)
+    }
+
+    fn run(
+        &self,
+        cancel: &CancelToken,
+        mut call: impl FnMut() -> (Result<LlmResponse, LlmError>, bool),
+    ) -> Result<LlmResponse, LlmError> {
+        let max_attempts = self.policy.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let (result, emitted) = call();
+            match result {
+                Err(e) if !emitted && attempt < max_attempts && self.is_retryable(&e) => {
+                    attempt += 1;
+                    let delay = self.backoff(attempt);
+                    if let Some(ref cb) = self.on_retry {
+                        cb(&RetryNotice {
+                            attempt,
+                            max_attempts,
+                            delay,
+                            error: e.to_string(),
+                        });
+                    }
+                    (self.sleep)(delay);
+                    if cancel.is_cancelled() {
+                        return Err(LlmError::Cancelled);
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+impl LlmProvider for RetryingProvider {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(&CancelToken::new(), || {
+            (self.inner.chat(messages, tools), false)
+        })
+    }
+
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(&CancelToken::new(), || {
+            (self.inner.chat_with_schema(messages, schema), false)
+        })
+    }
+
+    fn chat_streaming(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            &mut |_| {},
+            &mut |_| {},
+            &CancelToken::new(),
+        )
+    }
+
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        self.run(cancel, || {
+            let emitted = Cell::new(false);
+            let result = self.inner.chat_streaming_cancellable(
+                messages,
+                tools,
+                &mut |t| {
+                    emitted.set(true);
+                    on_token(t);
+                },
+                &mut |t| {
+                    emitted.set(true);
+                    on_reasoning(t);
+                },
+                &mut |p| {
+                    emitted.set(true);
+                    on_tool_progress(p);
+                },
+                cancel,
+            );
+            (result, emitted.get())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::StubServer;
+    use crate::llm::TokenUsage;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn policy(max_attempts: u32, retry_on: &[&str]) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 250,
+            retry_on: retry_on.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Fails with the queued errors in order, then answers "ok".
+    struct Flaky {
+        errors: RefCell<Vec<LlmError>>,
+        calls: Rc<RefCell<u32>>,
+        stream_before_error: bool,
+    }
+
+    impl Flaky {
+        fn new(errors: Vec<LlmError>) -> (Self, Rc<RefCell<u32>>) {
+            let calls = Rc::new(RefCell::new(0));
+            let mut errors = errors;
+            errors.reverse();
+            (
+                Self {
+                    errors: RefCell::new(errors),
+                    calls: Rc::clone(&calls),
+                    stream_before_error: false,
+                },
+                calls,
+            )
+        }
+    }
+
+    impl LlmProvider for Flaky {
+        fn chat(&self, _: &[Message], _: &[ToolDefinition]) -> Result<LlmResponse, LlmError> {
+            *self.calls.borrow_mut() += 1;
+            match self.errors.borrow_mut().pop() {
+                Some(e) => Err(e),
+                None => Ok(LlmResponse {
+                    content: Some("ok".to_string()),
+                    tool_calls: vec![],
+                    usage: TokenUsage::default(),
+                    ..Default::default()
+                }),
+            }
+        }
+
+        fn chat_streaming(
+            &self,
+            messages: &[Message],
+            tools: &[ToolDefinition],
+            on_token: &mut dyn FnMut(&str),
+        ) -> Result<LlmResponse, LlmError> {
+            if self.stream_before_error {
+                on_token("partial");
+            }
+            self.chat(messages, tools)
+        }
+    }
+
+    fn no_sleep(provider: &mut RetryingProvider) {
+        provider.sleep = |_| {};
+    }
+
+    #[test]
+    fn test_retries_server_error_until_success() {
+        let (flaky, calls) = Flaky::new(vec![
+            LlmError::ServerError("loading".into()),
+            LlmError::ServerError("loading".into()),
+        ]);
+        let notices = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&notices);
+        let mut provider = RetryingProvider::new(Box::new(flaky), policy(3, &["server_error"]));
+        no_sleep(&mut provider);
+        provider.set_on_retry(Box::new(move |n| seen.borrow_mut().push(n.clone())));
+
+        let resp = provider.chat(&[], &[]).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("ok"));
+        assert_eq!(*calls.borrow(), 3);
+
+        let notices = notices.borrow();
+        assert_eq!(notices.len(), 2);
+        assert_eq!(notices[0].attempt, 2);
+        assert_eq!(notices[0].max_attempts, 3);
+        assert_eq!(notices[0].delay, Duration::from_millis(100));
+        assert_eq!(notices[1].attempt, 3);
+        assert_eq!(notices[1].delay, Duration::from_millis(200));
+        assert!(notices[0].error.contains("loading"));
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let (flaky, calls) = Flaky::new(vec![
+            LlmError::ServerError("a".into()),
+            LlmError::ServerError("b".into()),
+            LlmError::ServerError("c".into()),
+        ]);
+        let mut provider = RetryingProvider::new(Box::new(flaky), policy(2, &["server_error"]));
+        no_sleep(&mut provider);
+        let err = provider.chat(&[], &[]).unwrap_err();
+        assert_eq!(*calls.borrow(), 2);
+        assert!(err.to_string().contains("b"));
+    }
+
+    #[test]
+    fn test_non_retryable_kind_returns_immediately() {
+        let (flaky, calls) = Flaky::new(vec![LlmError::ConnectionError("refused".into())]);
+        let mut provider = RetryingProvider::new(Box::new(flaky), policy(3, &["server_error"]));
+        no_sleep(&mut provider);
+        assert!(matches!(
+            provider.chat(&[], &[]),
+            Err(LlmError::ConnectionError(_))
+        ));
+        assert_eq!(*calls.borrow(), 1);
+    }
+
+    #[test]
+    fn test_max_attempts_one_disables_retry() {
+        let (flaky, calls) = Flaky::new(vec![LlmError::ServerError("x".into())]);
+        let mut provider = RetryingProvider::new(Box::new(flaky), policy(1, &["server_error"]));
+        no_sleep(&mut provider);
+        assert!(provider.chat(&[], &[]).is_err());
+        assert_eq!(*calls.borrow(), 1);
+    }
+
+    #[test]
+    fn test_streaming_retried_before_first_token() {
+        let (flaky, calls) = Flaky::new(vec![LlmError::ServerError("loading".into())]);
+        let mut provider = RetryingProvider::new(Box::new(flaky), policy(3, &["server_error"]));
+        no_sleep(&mut provider);
+        let resp = provider.chat_streaming(&[], &[], &mut |_| {}).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("ok"));
+        assert_eq!(*calls.borrow(), 2);
+    }
+
+    #[test]
+    fn test_streaming_not_retried_after_tokens_emitted() {
+        let (mut flaky, calls) = Flaky::new(vec![LlmError::ServerError("mid-stream".into())]);
+        flaky.stream_before_error = true;
+        let mut provider = RetryingProvider::new(Box::new(flaky), policy(3, &["server_error"]));
+        no_sleep(&mut provider);
+        let mut shown = String::new();
+        let result = provider.chat_streaming(&[], &[], &mut |t| shown.push_str(t));
+        assert!(result.is_err());
+        assert_eq!(*calls.borrow(), 1);
+        assert_eq!(shown, "partial");
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let provider = RetryingProvider::new(
+            Box::new(Flaky::new(vec![]).0),
+            policy(10, &["server_error"]),
+        );
+        assert_eq!(provider.backoff(2), Duration::from_millis(100));
+        assert_eq!(provider.backoff(3), Duration::from_millis(200));
+        assert_eq!(provider.backoff(4), Duration::from_millis(250));
+        assert_eq!(provider.backoff(40), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_ollama_503_is_retried_end_to_end() {
+        // First request hits a model that is still loading; second succeeds.
+        let server = StubServer::start(vec![
+            (503, "text/plain", "model is loading".to_string()),
+            (
+                200,
+                "application/json",
+                serde_json::json!({
+                    "message": {"role": "assistant", "content": "ready"},
+                    "done": true
+                })
+                .to_string(),
+            ),
+        ]);
+        let client = crate::llm::ollama::OllamaClient::new(&server.url, "qwen3:8b");
+        let mut provider = RetryingProvider::new(Box::new(client), policy(3, &["server_error"]));
+        no_sleep(&mut provider);
+        let resp = provider.chat(&[Message::user("hi")], &[]).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("ready"));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_openai_compat_500_maps_to_server_error() {
+        let server = StubServer::start(vec![(500, "text/plain", "boom".to_string())]);
+        let client = crate::llm::openai_compat::OpenAiCompatClient::new(&server.url, "m", None);
+        let err = client.chat(&[Message::user("hi")], &[]).unwrap_err();
+        assert!(matches!(err, LlmError::ServerError(_)));
+        assert_eq!(err.kind(), "server_error");
+    }
+}
@@ -0,0 +1,336 @@
+//! JSON-schema structured outputs.
+//!
+//! `LlmProvider::chat_json` asks for a reply shaped by a schema, using the
+//! backend's own constraint when it has one (Ollama `format`, OpenAI-style
+//! `response_format`) and the prompt otherwise. Servers differ in how much
+//! of a schema they actually enforce, so the reply is always checked here;
+//! on a mismatch the model gets one more try with the validation error.
+//!
+//! The validator covers the subset of JSON Schema that tool parameters and
+//! small internal schemas use: `type` (single or list), `properties`,
+//! `required`, `additionalProperties: false`, `items`, `enum` and `const`.
+//! Other keywords are ignored.
+
+use super::{LlmError, LlmProvider, Message, Role};
+use serde_json::Value;
+
+/// Ask for a JSON value matching `schema`; see the module docs.
+pub fn chat_json<P: LlmProvider + ?Sized>(
+    provider: &P,
+    messages: &[Message],
+    schema: &Value,
+) -> Result<Value, LlmError> {
+    let mut messages = with_schema_instruction(messages, schema);
+    let first = provider.chat_with_schema(&messages, schema)?;
+    let reply = first.content.unwrap_or_default();
+    let error = match parse_and_validate(&reply, schema) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+
+    messages.push(Message::assistant(&reply));
+    messages.push(Message::user(&format!(
+        "That reply does not match the schema: {}. Reply again with only the corrected JSON.",
+        error
+    )));
+    let second = provider.chat_with_schema(&messages, schema)?;
+    parse_and_validate(&second.content.unwrap_or_default(), schema).map_err(|e| {
+        LlmError::ParseError(format!(
+            "Structured output does not match the schema: {}",
+            e
+        ))
+    })
+}
+
+/// `messages` with the schema spelled out for the model. Constrained
+/// decoding alone tends to produce valid-but-empty objects when the model
+/// doesn't know what the fields mean. Appended to the last user turn
+/// rather than sent as a late system message, which several chat
+/// templates reject.
+fn with_schema_instruction(messages: &[Message], schema: &Value) -> Vec<Message> {
+    let instruction = format!(
+        "Respond with only a JSON value matching this JSON schema, with no other text:\n{}",
+        serde_json::to_string_pretty(schema).unwrap_or_default()
+    );
+    let mut messages = messages.to_vec();
+    match messages.last_mut() {
+        Some(last) if last.role == Role::User => {
+            last.content = format!("{}\n\n{}", last.content, instruction);
+        }
+        _ => messages.push(Message::user(&instruction)),
+    }
+    messages
+}
+
+/// Parse the reply, tolerating a surrounding ```json fence, and check it.
+pub fn parse_and_validate(reply: &str, schema: &Value) -> Result<Value, String> {
+    let text = strip_code_fence(reply.trim());
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| format!("reply is not valid JSON ({})", e))?;
+    validate(&value, schema, "$")?;
+    Ok(value)
+}
+
+fn strip_code_fence(text: &str) -> &str {
+    let Some(rest) = text.strip_prefix("```") else {
+        return text;
+    };
+    let body = rest.split_once('\n').map_or("", |(_, body)| body);
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+/// Check `value` against `schema`; the error names the offending path,
+/// e.g. `$.files[2].path: expected string, got number`.
+pub fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(format!("{}: expected {}", path, expected));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            let list: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+            return Err(format!("{}: expected one of {}", path, list.join(", ")));
+        }
+    }
+    if let Some(types) = schema.get("type") {
+        let allowed: Vec<&str> = match types {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| has_type(value, t)) {
+            return Err(format!(
+                "{}: expected {}, got {}",
+                path,
+                allowed.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+
+    if let Value::Object(map) = value {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for key in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !map.contains_key(key) {
+                return Err(format!("{}: missing required field \"{}\"", path, key));
+            }
+        }
+        for (key, field) in map {
+            match properties.and_then(|p| p.get(key)) {
+                Some(sub) => validate(field, sub, &format!("{}.{}", path, key))?,
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{}: unexpected field \"{}\"", path, key));
+                }
+                None => {}
+            }
+        }
+    }
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate(item, item_schema, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::StubServer;
+    use crate::llm::{LlmResponse, TokenUsage, ToolDefinition};
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    fn summary_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "summary": {"type": "string"},
+                "files": {"type": "array", "items": {"type": "string"}},
+                "status": {"enum": ["done", "blocked"]}
+            },
+            "required": ["summary", "status"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn test_validate_accepts_matching_value() {
+        let value = json!({"summary": "ok", "files": ["a.rs"], "status": "done"});
+        assert_eq!(validate(&value, &summary_schema(), "$"), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_reports_path_of_first_mismatch() {
+        let schema = summary_schema();
+        let cases = [
+            (
+                json!({"status": "done"}),
+                "$: missing required field \"summary\"",
+            ),
+            (
+                json!({"summary": "x", "status": "done", "files": ["a", 3]}),
+                "$.files[1]: expected string, got number",
+            ),
+            (
+                json!({"summary": "x", "status": "maybe"}),
+                "$.status: expected one of \"done\", \"blocked\"",
+            ),
+            (
+                json!({"summary": "x", "status": "done", "extra": 1}),
+                "$: unexpected field \"extra\"",
+            ),
+            (json!([1]), "$: expected object, got array"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(validate(&value, &schema, "$").unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn test_integer_and_type_lists() {
+        let schema = json!({"type": ["integer", "null"]});
+        assert!(validate(&json!(3), &schema, "$").is_ok());
+        assert!(validate(&Value::Null, &schema, "$").is_ok());
+        assert!(validate(&json!(2.5), &schema, "$").is_err());
+    }
+
+    #[test]
+    fn test_parse_strips_code_fence() {
+        let reply = "```json\n{\"summary\": \"s\", \"status\": \"done\"}\n```";
+        let value = parse_and_validate(reply, &summary_schema()).unwrap();
+        assert_eq!(value["summary"], "s");
+        assert!(parse_and_validate("not json", &summary_schema())
+            .unwrap_err()
+            .contains("not valid JSON"));
+    }
+
+    /// Replies with the scripted texts in order, recording each request.
+    struct Scripted {
+        replies: RefCell<Vec<&'static str>>,
+        seen: RefCell<Vec<Vec<Message>>>,
+    }
+
+    impl LlmProvider for Scripted {
+        fn chat(
+            &self,
+            messages: &[Message],
+            _: &[ToolDefinition],
+        ) -> Result<LlmResponse, LlmError> {
+            self.seen.borrow_mut().push(messages.to_vec());
+            Ok(LlmResponse {
+                content: Some(self.replies.borrow_mut().remove(0).to_string()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn scripted(replies: Vec<&'static str>) -> Scripted {
+        Scripted {
+            replies: RefCell::new(replies),
+            seen: RefCell::new(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn test_chat_json_retries_once_with_validation_error() {
+        let llm = scripted(vec![
+            r#"{"summary": "s"}"#,
+            r#"{"summary": "s", "status": "done"}"#,
+        ]);
+        let value = llm
+            .chat_json(&[Message::user("Summarise")], &summary_schema())
+            .unwrap();
+        assert_eq!(value["status"], "done");
+
+        let seen = llm.seen.borrow();
+        assert_eq!(seen.len(), 2);
+        // The schema rides along on the user's own turn...
+        assert!(seen[0][0].content.starts_with("Summarise\n\n"));
+        assert!(seen[0][0].content.contains("\"additionalProperties\""));
+        // ...and the retry quotes the bad reply and what was wrong with it.
+        let retry = &seen[1];
+        assert_eq!(retry[1].content, r#"{"summary": "s"}"#);
+        assert!(retry[2]
+            .content
+            .contains("missing required field \"status\""));
+    }
+
+    #[test]
+    fn test_chat_json_gives_up_after_second_mismatch() {
+        let llm = scripted(vec!["{}", "still wrong"]);
+        let err = llm
+            .chat_json(&[Message::user("Summarise")], &summary_schema())
+            .unwrap_err();
+        assert!(matches!(err, LlmError::ParseError(_)));
+        assert!(err.to_string().contains("not valid JSON"));
+    }
+
+    #[test]
+    fn test_ollama_sends_schema_as_format() {
+        let server = StubServer::json(vec![json!({
+            "message": {"role": "assistant", "content": "{\"summary\": \"s\", \"status\": \"done\"}"},
+            "done": true
+        })]);
+        let client = crate::llm::ollama::OllamaClient::new(&server.url, "qwen3:8b");
+        let value = client
+            .chat_json(&[Message::user("Summarise")], &summary_schema())
+            .unwrap();
+        assert_eq!(value["summary"], "s");
+        let body = server.requests()[0].json();
+        assert_eq!(body["format"], summary_schema());
+        assert_eq!(body["stream"], false);
+        assert!(body.get("tools").is_none());
+    }
+
+    #[test]
+    fn test_openai_compat_sends_response_format() {
+        let server = StubServer::json(vec![json!({
+            "choices": [{"message": {"role": "assistant", "content": "{\"summary\": \"s\", \"status\": \"blocked\"}"}}]
+        })]);
+        let client = crate::llm::openai_compat::OpenAiCompatClient::new(&server.url, "m", None);
+        let value = client
+            .chat_json(&[Message::user("Summarise")], &summary_schema())
+            .unwrap();
+        assert_eq!(value["status"], "blocked");
+        let body = server.requests()[0].json();
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(
+            body["response_format"]["json_schema"]["schema"],
+            summary_schema()
+        );
+    }
+}
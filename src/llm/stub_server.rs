@@ -0,0 +1,130 @@
+//! Minimal single-threaded HTTP server for provider tests.
+//!
+//! Each stub serves a fixed queue of canned responses, one per incoming
+//! connection, and records the request bodies it saw so tests can assert
+//! on what the provider actually sent over the wire.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+pub struct StubServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StubRequest {
+    pub path: String,
+    pub body: String,
+}
+
+impl StubRequest {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).expect("request body should be JSON")
+    }
+}
+
+impl StubServer {
+    /// Start a server that answers each connection with the next
+    /// `(status, content_type, body)` tuple, then stops accepting.
+    pub fn start(responses: Vec<(u16, &str, String)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind stub server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let responses: Vec<(u16, String, String)> = responses
+            .into_iter()
+            .map(|(s, ct, b)| (s, ct.to_string(), b))
+            .collect();
+
+        let handle = std::thread::spawn(move || {
+            for (status, content_type, body) in responses {
+                let Ok((mut stream, _)) = listener.accept() else {
+                    return;
+                };
+                let request = read_request(&mut stream);
+                seen.lock().unwrap().push(request);
+                let reply = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(reply.as_bytes());
+                let _ = stream.flush();
+            }
+        });
+
+        Self {
+            url,
+            requests,
+            handle: Some(handle),
+        }
+    }
+
+    /// Convenience: every response is a 200 with a JSON body.
+    pub fn json(bodies: Vec<serde_json::Value>) -> Self {
+        Self::start(
+            bodies
+                .into_iter()
+                .map(|b| (200, "application/json", b.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Requests received so far, in arrival order.
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        // Only join if every queued response was consumed; otherwise the
+        // thread is still parked in accept() and joining would hang.
+        if let Some(handle) = self.handle.take() {
+            if handle.is_finished() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+fn read_request(stream: &mut std::net::TcpStream) -> StubRequest {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line);
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("")
+        .to_string();
+
+    let mut content_length = 0usize;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0u8; content_length];
+    let _ = reader.read_exact(&mut body);
+    StubRequest {
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }
+}
@@ -0,0 +1,663 @@
+//! Text tool-call protocol for models whose chat template has no native
+//! tools.
+//!
+//! `TextToolProvider` wraps any provider and moves tool calling into the
+//! conversation text. Tool definitions are rendered into the system
+//! prompt and the model writes each call as a block in one of two
+//! documented formats (`[llm].tool_call_format`):
+//!
+//! ```text
+//! xml:   <tool_call>
+//!        {"name": "read_file", "arguments": {"path": "src/main.rs"}}
+//!        </tool_call>
+//!
+//! json:  ```tool_call
+//!        {"name": "read_file", "arguments": {"path": "src/main.rs"}}
+//!        ```
+//! ```
+//!
+//! Blocks are parsed back into `ToolCall`s, in order, before the agent
+//! sees the response; while streaming, the block markup is held back so
+//! only the prose reaches the terminal. The inner provider is always
+//! called with an empty tool list, so the backend never rejects the
+//! request for lacking tool support.
+//!
+//! History is rewritten the same way: earlier assistant tool calls are
+//! replayed as blocks and each tool result becomes a user turn wrapped in
+//! `<tool_result name="...">`, since templates without tools usually
+//! have no `tool` role either.
+
+use super::{
+    LlmError, LlmProvider, LlmResponse, Message, Role, ToolCall, ToolCallProgress, ToolDefinition,
+};
+use crate::cancel::CancelToken;
+use crate::config::ToolCallFormat;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// A model that keeps going after its call sometimes invents the result
+/// too. Anything from this marker on is dropped from the prose.
+const RESULT_OPEN: &str = "<tool_result";
+
+/// Opening and closing delimiters of a call block.
+fn markers(format: &ToolCallFormat) -> (&'static str, &'static str) {
+    match format {
+        ToolCallFormat::Xml => ("<tool_call>", "</tool_call>"),
+        ToolCallFormat::Json => ("```tool_call", "```"),
+    }
+}
+
+/// Where the body ends and the whole block ends in `after_open`, the
+/// text following an opening marker, or None while it is unterminated.
+/// A body that starts as a JSON object runs at least to the brace that
+/// balances it, so a closing marker inside a string argument (a bare
+/// fence in a markdown file, say) doesn't cut the call short.
+fn block_end(after_open: &str, close: &str) -> Option<(usize, usize)> {
+    let from = if after_open.trim_start().starts_with('{') {
+        json_object_end(after_open)?
+    } else {
+        0
+    };
+    let len = from + after_open[from..].find(close)?;
+    Some((len, len + close.len()))
+}
+
+/// Byte offset just past the `}` closing the first JSON object in `text`,
+/// skipping braces inside strings.
+fn json_object_end(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, ch) in text.char_indices() {
+        if in_string {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match ch {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+pub struct TextToolProvider {
+    inner: Box<dyn LlmProvider>,
+    format: ToolCallFormat,
+}
+
+impl TextToolProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, format: ToolCallFormat) -> Self {
+        Self { inner, format }
+    }
+}
+
+/// Catalogue appended to the system prompt.
+pub fn tools_prompt(tools: &[ToolDefinition], format: &ToolCallFormat) -> String {
+    let example = render_call(
+        &ToolCall {
+            id: String::new(),
+            name: "tool_name".to_string(),
+            arguments: json!({"arg": "value"}),
+        },
+        format,
+    );
+    let mut out = format!(
+        "\n\n# Tools\n\nYou can call tools. To call one, write a block exactly like \
+         this and then stop; the result comes back in the next message:\n\n{}\n\n\
+         Write one block per call; several blocks in one reply run in order. \
+         Reply without a block to answer the user.\n\nAvailable tools:\n",
+        example
+    );
+    for t in tools {
+        out.push_str(&format!(
+            "\n## {}\n{}\nParameters: {}\n",
+            t.name, t.description, t.parameters
+        ));
+    }
+    out
+}
+
+fn render_call(tc: &ToolCall, format: &ToolCallFormat) -> String {
+    let (open, close) = markers(format);
+    // Written by hand so `name` comes first, as in the documented format;
+    // `json!` would sort the keys.
+    format!(
+        "{}\n{{\"name\": {}, \"arguments\": {}}}\n{}",
+        open,
+        Value::String(tc.name.clone()),
+        tc.arguments,
+        close
+    )
+}
+
+/// Rewrite `messages` for a backend that only understands plain
+/// system/user/assistant turns.
+pub fn convert_messages(
+    messages: &[Message],
+    tools: &[ToolDefinition],
+    format: &ToolCallFormat,
+) -> Vec<Message> {
+    let catalogue = tools_prompt(tools, format);
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    let mut out: Vec<Message> = Vec::with_capacity(messages.len() + 1);
+    let mut injected = false;
+
+    for m in messages {
+        let msg = match m.role {
+            Role::System if !injected => {
+                injected = true;
+                Message::system(&format!("{}{}", m.content, catalogue))
+            }
+            Role::Assistant if !m.tool_calls.is_empty() => {
+                for tc in &m.tool_calls {
+                    names.insert(tc.id.as_str(), tc.name.as_str());
+                }
+                let mut text = m.content.clone();
+                for tc in &m.tool_calls {
+                    if !text.is_empty() {
+                        text.push('\n');
+                    }
+                    text.push_str(&render_call(tc, format));
+                }
+                Message::assistant(&text)
+            }
+            Role::Tool => {
+                let id = m.tool_call_id.as_deref().unwrap_or("");
+                let name = names.get(id).copied().unwrap_or("tool");
+                Message::user(&format!(
+                    "<tool_result name=\"{}\">\n{}\n</tool_result>",
+                    name, m.content
+                ))
+            }
+            _ => Message {
+                tool_call_id: None,
+                tool_calls: vec![],
+                ..m.clone()
+            },
+        };
+        out.push(msg);
+    }
+    if !injected {
+        out.insert(0, Message::system(catalogue.trim_start()));
+    }
+    out
+}
+
+/// Split a reply into its prose and the call blocks it contains, in
+/// order. Blocks whose body isn't `{"name", "arguments"}` JSON, and an
+/// unterminated trailing block, are left in the prose so the model's
+/// output is never silently dropped.
+pub fn parse_tool_calls(text: &str, format: &ToolCallFormat) -> (String, Vec<ToolCall>) {
+    let (open, close) = markers(format);
+    let mut prose = String::new();
+    let mut calls = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(open) {
+        let body_start = start + open.len();
+        let Some((len, block_len)) = block_end(&rest[body_start..], close) else {
+            break;
+        };
+        let body = rest[body_start..body_start + len].trim();
+        let block_end = body_start + block_len;
+        prose.push_str(&rest[..start]);
+        match parse_call_body(body, calls.len()) {
+            Some(tc) => calls.push(tc),
+            None => prose.push_str(&rest[start..block_end]),
+        }
+        rest = &rest[block_end..];
+    }
+    prose.push_str(rest);
+    if !calls.is_empty() {
+        if let Some(i) = prose.find(RESULT_OPEN) {
+            prose.truncate(i);
+        }
+    }
+    (prose.trim().to_string(), calls)
+}
+
+fn parse_call_body(body: &str, index: usize) -> Option<ToolCall> {
+    let val: Value = serde_json::from_str(body).ok()?;
+    let name = val.get("name")?.as_str()?.to_string();
+    let arguments = val.get("arguments").cloned().unwrap_or_else(|| json!({}));
+    Some(ToolCall {
+        id: format!("text_call_{}", index),
+        name,
+        arguments,
+    })
+}
+
+/// Incremental counterpart of `parse_tool_calls` for display: passes
+/// prose through as it streams and swallows call blocks. Text that could
+/// be the start of an opening marker is held back until the next token
+/// settles it.
+pub struct StreamFilter {
+    open: &'static str,
+    close: &'static str,
+    buf: String,
+    in_call: bool,
+}
+
+impl StreamFilter {
+    pub fn new(format: &ToolCallFormat) -> Self {
+        let (open, close) = markers(format);
+        Self {
+            open,
+            close,
+            buf: String::new(),
+            in_call: false,
+        }
+    }
+
+    pub fn push(&mut self, token: &str, emit: &mut dyn FnMut(&str)) {
+        self.buf.push_str(token);
+        loop {
+            if self.in_call {
+                // The opening marker stays in `buf` so `finish` can show an
+                // unterminated block as-is.
+                let Some((_, end)) = block_end(&self.buf[self.open.len()..], self.close) else {
+                    return;
+                };
+                self.buf.drain(..self.open.len() + end);
+                self.in_call = false;
+                continue;
+            }
+            if let Some(start) = self.buf.find(self.open) {
+                if start > 0 {
+                    emit(&self.buf[..start]);
+                }
+                self.buf.drain(..start);
+                self.in_call = true;
+                continue;
+            }
+            let keep = (1..self.open.len())
+                .rev()
+                .find(|&k| self.buf.ends_with(&self.open[..k]))
+                .unwrap_or(0);
+            let cut = self.buf.len() - keep;
+            if cut > 0 {
+                emit(&self.buf[..cut]);
+                self.buf.drain(..cut);
+            }
+            return;
+        }
+    }
+
+    /// Flush whatever is still held back at the end of the stream.
+    pub fn finish(&mut self, emit: &mut dyn FnMut(&str)) {
+        if !self.buf.is_empty() {
+            emit(&self.buf);
+            self.buf.clear();
+        }
+        self.in_call = false;
+    }
+}
+
+impl TextToolProvider {
+    fn extract_calls(&self, mut resp: LlmResponse) -> LlmResponse {
+        let (prose, calls) = parse_tool_calls(resp.content.as_deref().unwrap_or(""), &self.format);
+        if !calls.is_empty() {
+            resp.content = (!prose.is_empty()).then_some(prose);
+            resp.tool_calls = calls;
+        }
+        resp
+    }
+}
+
+impl LlmProvider for TextToolProvider {
+    fn chat(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<LlmResponse, LlmError> {
+        if tools.is_empty() {
+            return self.inner.chat(messages, tools);
+        }
+        let converted = convert_messages(messages, tools, &self.format);
+        self.inner
+            .chat(&converted, &[])
+            .map(|r| self.extract_calls(r))
+    }
+
+    /// No tools are involved, so the backend's own schema support applies.
+    fn chat_with_schema(
+        &self,
+        messages: &[Message],
+        schema: &serde_json::Value,
+    ) -> Result<LlmResponse, LlmError> {
+        self.inner.chat_with_schema(messages, schema)
+    }
+
+    fn chat_streaming(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<LlmResponse, LlmError> {
+        self.chat_streaming_cancellable(
+            messages,
+            tools,
+            on_token,
+            &mut |_| {},
+            &mut |_| {},
+            &CancelToken::new(),
+        )
+    }
+
+    fn chat_streaming_cancellable(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        on_token: &mut dyn FnMut(&str),
+        on_reasoning: &mut dyn FnMut(&str),
+        on_tool_progress: &mut dyn FnMut(&ToolCallProgress),
+        cancel: &CancelToken,
+    ) -> Result<LlmResponse, LlmError> {
+        if tools.is_empty() {
+            return self.inner.chat_streaming_cancellable(
+                messages,
+                tools,
+                on_token,
+                on_reasoning,
+                on_tool_progress,
+                cancel,
+            );
+        }
+        let converted = convert_messages(messages, tools, &self.format);
+        let mut filter = StreamFilter::new(&self.format);
+        let result = self.inner.chat_streaming_cancellable(
+            &converted,
+            &[],
+            &mut |t| filter.push(t, on_token),
+            on_reasoning,
+            on_tool_progress,
+            cancel,
+        );
+        filter.finish(on_token);
+        result.map(|r| self.extract_calls(r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::TokenUsage;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn read_file_tool() -> ToolDefinition {
+        ToolDefinition {
+            name: "read_file".to_string(),
+            description: "Read a file".to_string(),
+            parameters: json!({"type": "object", "properties": {"path": {"type": "string"}}}),
+        }
+    }
+
+    /// `(messages, tool count)` of every request the inner provider saw.
+    type Seen = Rc<RefCell<Vec<(Vec<Message>, usize)>>>;
+
+    /// Records what it was sent and answers with a fixed text.
+    struct Echo {
+        reply: String,
+        seen: Seen,
+    }
+
+    impl LlmProvider for Echo {
+        fn chat_streaming(
+            &self,
+            messages: &[Message],
+            tools: &[ToolDefinition],
+            on_token: &mut dyn FnMut(&str),
+        ) -> Result<LlmResponse, LlmError> {
+            // Stream in small, marker-splitting chunks.
+            let chars: Vec<char> = self.reply.chars().collect();
+            for chunk in chars.chunks(3) {
+                on_token(&chunk.iter().collect::<String>());
+            }
+            self.chat(messages, tools)
+        }
+
+        fn chat(
+            &self,
+            messages: &[Message],
+            tools: &[ToolDefinition],
+        ) -> Result<LlmResponse, LlmError> {
+            self.seen
+                .borrow_mut()
+                .push((messages.to_vec(), tools.len()));
+            Ok(LlmResponse {
+                content: Some(self.reply.clone()),
+                tool_calls: vec![],
+                usage: TokenUsage::default(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn echo_with(reply: &str, format: ToolCallFormat) -> (TextToolProvider, Seen) {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let provider = TextToolProvider::new(
+            Box::new(Echo {
+                reply: reply.to_string(),
+                seen: Rc::clone(&seen),
+            }),
+            format,
+        );
+        (provider, seen)
+    }
+
+    fn echo(reply: &str) -> (TextToolProvider, Seen) {
+        echo_with(reply, ToolCallFormat::Xml)
+    }
+
+    fn filtered(tokens: &[&str], format: ToolCallFormat) -> String {
+        let mut shown = String::new();
+        let mut filter = StreamFilter::new(&format);
+        for t in tokens {
+            filter.push(t, &mut |s| shown.push_str(s));
+        }
+        filter.finish(&mut |s| shown.push_str(s));
+        shown
+    }
+
+    #[test]
+    fn test_parse_single_call_with_prose() {
+        let (prose, calls) = parse_tool_calls(
+            "Let me look.\n<tool_call>\n{\"name\": \"read_file\", \"arguments\": {\"path\": \"a.rs\"}}\n</tool_call>",
+            &ToolCallFormat::Xml,
+        );
+        assert_eq!(prose, "Let me look.");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(calls[0].arguments["path"], "a.rs");
+        assert_eq!(calls[0].id, "text_call_0");
+    }
+
+    #[test]
+    fn test_parse_multiple_calls_in_order() {
+        let text = "<tool_call>{\"name\": \"a\", \"arguments\": {}}</tool_call>\n\
+                    <tool_call>{\"name\": \"b\"}</tool_call>";
+        let (prose, calls) = parse_tool_calls(text, &ToolCallFormat::Xml);
+        assert!(prose.is_empty());
+        let names: Vec<&str> = calls.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(calls[1].arguments, json!({}));
+        assert_eq!(calls[1].id, "text_call_1");
+    }
+
+    #[test]
+    fn test_malformed_block_stays_in_prose() {
+        let text = "<tool_call>not json</tool_call> and <tool_call>{\"name\": \"x\"";
+        let (prose, calls) = parse_tool_calls(text, &ToolCallFormat::Xml);
+        assert!(calls.is_empty());
+        assert_eq!(prose, text);
+    }
+
+    #[test]
+    fn test_history_is_flattened_for_plain_templates() {
+        let call = ToolCall {
+            id: "c1".to_string(),
+            name: "read_file".to_string(),
+            arguments: json!({"path": "a.rs"}),
+        };
+        let messages = vec![
+            Message::system("base prompt"),
+            Message::user("read a.rs"),
+            Message::assistant_with_tool_calls(vec![call]),
+            Message::tool_result("c1", "fn main() {}"),
+        ];
+        let out = convert_messages(&messages, &[read_file_tool()], &ToolCallFormat::Xml);
+        assert_eq!(out.len(), 4);
+        assert!(out[0].content.starts_with("base prompt"));
+        assert!(out[0].content.contains("## read_file"));
+        assert!(out[2].tool_calls.is_empty());
+        assert!(out[2].content.contains("<tool_call>"));
+        assert_eq!(out[3].role, Role::User);
+        assert_eq!(
+            out[3].content,
+            "<tool_result name=\"read_file\">\nfn main() {}\n</tool_result>"
+        );
+    }
+
+    #[test]
+    fn test_provider_strips_tools_and_returns_parsed_calls() {
+        let (provider, seen) = echo(
+            "<tool_call>{\"name\": \"read_file\", \"arguments\": {\"path\": \"x\"}}</tool_call>",
+        );
+        let resp = provider
+            .chat(&[Message::user("hi")], &[read_file_tool()])
+            .unwrap();
+        assert_eq!(resp.content, None);
+        assert_eq!(resp.tool_calls.len(), 1);
+
+        let seen = seen.borrow();
+        let (sent, tool_count) = &seen[0];
+        assert_eq!(
+            *tool_count, 0,
+            "inner provider must not receive native tools"
+        );
+        assert_eq!(sent[0].role, Role::System);
+    }
+
+    #[test]
+    fn test_without_tools_passes_through_untouched() {
+        let (provider, seen) = echo("plain answer");
+        let resp = provider.chat(&[Message::user("hi")], &[]).unwrap();
+        assert_eq!(resp.content.as_deref(), Some("plain answer"));
+        assert_eq!(seen.borrow()[0].0.len(), 1);
+    }
+
+    #[test]
+    fn test_fenced_json_format() {
+        let text =
+            "Reading both.\n```tool_call\n{\"name\": \"a\", \"arguments\": {\"x\": 1}}\n```\n\
+                    ```tool_call\n{\"name\": \"b\", \"arguments\": {}}\n```";
+        let (prose, calls) = parse_tool_calls(text, &ToolCallFormat::Json);
+        assert_eq!(prose, "Reading both.");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].arguments["x"], 1);
+        // A fence inside a string argument doesn't close the block.
+        let text = "```tool_call\n{\"name\": \"write_file\", \"arguments\": \
+                    {\"content\": \"```rust\\nfn x() {}\\n```\\n\"}}\n```\nDone.";
+        let (prose, calls) = parse_tool_calls(text, &ToolCallFormat::Json);
+        assert_eq!(prose, "Done.");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].arguments["content"], "```rust\nfn x() {}\n```\n");
+        // Ordinary code fences are not calls.
+        let (prose, calls) = parse_tool_calls("```rust\nfn x() {}\n```", &ToolCallFormat::Json);
+        assert!(calls.is_empty());
+        assert!(prose.contains("fn x()"));
+    }
+
+    #[test]
+    fn test_prompt_documents_the_configured_format() {
+        let xml = tools_prompt(&[read_file_tool()], &ToolCallFormat::Xml);
+        assert!(xml.contains("<tool_call>\n{\"name\": \"tool_name\", \"arguments\": {\"arg\":\"value\"}}\n</tool_call>"));
+        let fenced = tools_prompt(&[read_file_tool()], &ToolCallFormat::Json);
+        assert!(fenced.contains("```tool_call\n"));
+        assert!(!fenced.contains("<tool_call>"));
+    }
+
+    #[test]
+    fn test_invented_tool_result_is_dropped() {
+        let text = "<tool_call>{\"name\": \"a\"}</tool_call>\n<tool_result name=\"a\">made up</tool_result>";
+        let (prose, calls) = parse_tool_calls(text, &ToolCallFormat::Xml);
+        assert_eq!(calls.len(), 1);
+        assert!(prose.is_empty());
+    }
+
+    #[test]
+    fn test_stream_filter_hides_blocks_split_across_tokens() {
+        let shown = filtered(
+            &[
+                "Let me ",
+                "<tool",
+                "_call>{\"name\"",
+                ": \"a\"}</tool_",
+                "call> done",
+            ],
+            ToolCallFormat::Xml,
+        );
+        assert_eq!(shown, "Let me  done");
+    }
+
+    #[test]
+    fn test_stream_filter_releases_false_marker_prefix() {
+        assert_eq!(
+            filtered(&["a <", "b> c <"], ToolCallFormat::Xml),
+            "a <b> c <"
+        );
+        assert_eq!(
+            filtered(&["``", "`rust\nx\n```"], ToolCallFormat::Json),
+            "```rust\nx\n```"
+        );
+    }
+
+    #[test]
+    fn test_stream_filter_waits_for_balanced_json_body() {
+        let shown = filtered(
+            &[
+                "Writing.\n```tool_call\n{\"name\": \"w\", \"arguments\": {\"content\": \"a\\n```",
+                "\\nb\"}}\n```",
+                "\nDone.",
+            ],
+            ToolCallFormat::Json,
+        );
+        assert_eq!(shown, "Writing.\n\nDone.");
+    }
+
+    #[test]
+    fn test_stream_filter_shows_unterminated_block() {
+        let shown = filtered(&["x <tool_call>{\"na"], ToolCallFormat::Xml);
+        assert_eq!(shown, "x <tool_call>{\"na");
+    }
+
+    #[test]
+    fn test_streaming_shows_prose_and_returns_calls() {
+        let (provider, _) = echo_with(
+            "Checking.\n```tool_call\n{\"name\": \"read_file\", \"arguments\": {\"path\": \"x\"}}\n```",
+            ToolCallFormat::Json,
+        );
+        let mut shown = String::new();
+        let resp = provider
+            .chat_streaming(&[Message::user("hi")], &[read_file_tool()], &mut |t| {
+                shown.push_str(t)
+            })
+            .unwrap();
+        assert_eq!(shown.trim(), "Checking.");
+        assert_eq!(resp.content.as_deref(), Some("Checking."));
+        assert_eq!(resp.tool_calls[0].name, "read_file");
+    }
+}
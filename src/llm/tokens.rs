@@ -0,0 +1,311 @@
+//! Token counting for context-budget decisions.
+//!
+//! `chars / 4` is a fair proxy for English prose but badly undercounts
+//! Japanese and Chinese text — most BPE vocabularies spend roughly one
+//! token per CJK character — and dense code. When the backend exposes
+//! its tokenizer we ask it directly and cache the answer per text;
+//! otherwise, or while the endpoint is missing or failing, we fall
+//! back to a CJK-aware heuristic.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+pub trait TokenCounter {
+    /// Number of tokens `text` occupies in the model's prompt.
+    fn count(&self, text: &str) -> usize;
+
+    /// Short label for `/context`, e.g. `heuristic` or `llama.cpp /tokenize`.
+    fn describe(&self) -> String;
+}
+
+/// Offline estimate: CJK characters count one token each, everything
+/// else `chars / 4`. Pure ASCII input gives exactly the old `chars / 4`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicCounter;
+
+impl TokenCounter for HeuristicCounter {
+    fn count(&self, text: &str) -> usize {
+        heuristic_token_count(text)
+    }
+
+    fn describe(&self) -> String {
+        "heuristic".to_string()
+    }
+}
+
+pub fn heuristic_token_count(text: &str) -> usize {
+    let (mut narrow, mut wide) = (0usize, 0usize);
+    for c in text.chars() {
+        if is_cjk(c) {
+            wide += 1;
+        } else {
+            narrow += 1;
+        }
+    }
+    narrow / 4 + wide
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3000..=0x30FF     // CJK punctuation, hiragana, katakana
+            | 0x3400..=0x4DBF // CJK extension A
+            | 0x4E00..=0x9FFF // CJK unified ideographs
+            | 0xAC00..=0xD7AF // Hangul syllables
+            | 0xF900..=0xFAFF // CJK compatibility ideographs
+            | 0xFF00..=0xFFEF // half/full-width forms
+    )
+}
+
+/// Which tokenize endpoint the backend speaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenizeEndpoint {
+    /// `POST /api/tokenize {model, content}` → `{tokens: [...]}`
+    Ollama,
+    /// llama-server's `POST /tokenize {content}` → `{tokens: [...]}`
+    LlamaCpp,
+}
+
+/// Entries kept before the cache is dropped wholesale. Message bodies
+/// rarely change once written, so a session's working set is small.
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// How long to count heuristically after a transient tokenize failure
+/// (timeout, refused connection, 5xx) before asking the server again.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Why a tokenize request produced no count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenizeFailure {
+    /// The server has no such endpoint (404, 405, 501).
+    Missing,
+    /// Anything else; the endpoint may work on a later try.
+    Transient,
+}
+
+/// Asks the backend's tokenizer, caching counts by content hash. A
+/// server that answers 404/405/501 is marked unavailable for the rest
+/// of the session, so a backend without `/tokenize` costs one round
+/// trip, not one per message. Other failures only pause exact counting
+/// for `RETRY_AFTER`, so a restart or a slow reply doesn't leave the
+/// session on the heuristic for good.
+pub struct RemoteTokenCounter {
+    endpoint: TokenizeEndpoint,
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+    client: reqwest::blocking::Client,
+    cache: RefCell<HashMap<u64, usize>>,
+    unavailable: Cell<bool>,
+    /// Set after a transient failure; no requests until it passes.
+    retry_at: Cell<Option<Instant>>,
+    retry_after: Duration,
+}
+
+impl RemoteTokenCounter {
+    pub fn new(
+        endpoint: TokenizeEndpoint,
+        base_url: &str,
+        model: &str,
+        api_key: Option<String>,
+    ) -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap_or_else(|_| reqwest::blocking::Client::new());
+        Self {
+            endpoint,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key,
+            client,
+            cache: RefCell::new(HashMap::new()),
+            unavailable: Cell::new(false),
+            retry_at: Cell::new(None),
+            retry_after: RETRY_AFTER,
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> Result<usize, TokenizeFailure> {
+        let (url, body) = match self.endpoint {
+            TokenizeEndpoint::Ollama => (
+                format!("{}/api/tokenize", self.base_url),
+                serde_json::json!({"model": self.model, "content": text}),
+            ),
+            TokenizeEndpoint::LlamaCpp => (
+                format!("{}/tokenize", self.base_url),
+                serde_json::json!({"content": text}),
+            ),
+        };
+        let mut request = self.client.post(&url).json(&body);
+        if let Some(ref key) = self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().map_err(|_| TokenizeFailure::Transient)?;
+        match response.status().as_u16() {
+            404 | 405 | 501 => return Err(TokenizeFailure::Missing),
+            status if !(200..300).contains(&status) => return Err(TokenizeFailure::Transient),
+            _ => {}
+        }
+        let json: serde_json::Value = response.json().map_err(|_| TokenizeFailure::Transient)?;
+        json["tokens"]
+            .as_array()
+            .map(|t| t.len())
+            .ok_or(TokenizeFailure::Transient)
+    }
+
+    fn cooling_down(&self) -> bool {
+        match self.retry_at.get() {
+            Some(at) if Instant::now() < at => true,
+            Some(_) => {
+                self.retry_at.set(None);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+fn content_hash(text: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TokenCounter for RemoteTokenCounter {
+    fn count(&self, text: &str) -> usize {
+        if text.is_empty() {
+            return 0;
+        }
+        if self.unavailable.get() {
+            return heuristic_token_count(text);
+        }
+        let key = content_hash(text);
+        if let Some(&n) = self.cache.borrow().get(&key) {
+            return n;
+        }
+        if self.cooling_down() {
+            return heuristic_token_count(text);
+        }
+        match self.tokenize(text) {
+            Ok(n) => {
+                let mut cache = self.cache.borrow_mut();
+                if cache.len() >= MAX_CACHE_ENTRIES {
+                    cache.clear();
+                }
+                cache.insert(key, n);
+                n
+            }
+            Err(TokenizeFailure::Missing) => {
+                self.unavailable.set(true);
+                heuristic_token_count(text)
+            }
+            Err(TokenizeFailure::Transient) => {
+                self.retry_at.set(Some(Instant::now() + self.retry_after));
+                heuristic_token_count(text)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        let source = match self.endpoint {
+            TokenizeEndpoint::Ollama => "ollama /api/tokenize",
+            TokenizeEndpoint::LlamaCpp => "llama.cpp /tokenize",
+        };
+        if self.unavailable.get() {
+            format!("heuristic ({} unavailable)", source)
+        } else if self.retry_at.get().is_some() {
+            format!("heuristic ({} failing, will retry)", source)
+        } else {
+            source.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::StubServer;
+
+    #[test]
+    fn test_heuristic_matches_chars_div_4_for_ascii() {
+        assert_eq!(heuristic_token_count(&"a".repeat(100)), 25);
+        assert_eq!(heuristic_token_count(""), 0);
+    }
+
+    #[test]
+    fn test_heuristic_counts_cjk_per_character() {
+        // 10 kana/kanji + 8 ASCII chars → 10 + 2
+        let text = "日本語のテキストです hello!!";
+        assert_eq!(heuristic_token_count(text), 10 + 8 / 4);
+        assert!(heuristic_token_count("こんにちは") > "こんにちは".chars().count() / 4);
+    }
+
+    #[test]
+    fn test_llamacpp_tokenize_request_and_cache() {
+        let server = StubServer::json(vec![serde_json::json!({"tokens": [1, 2, 3, 4, 5, 6, 7]})]);
+        let counter = RemoteTokenCounter::new(TokenizeEndpoint::LlamaCpp, &server.url, "m", None);
+        assert_eq!(counter.count("fn main() {}"), 7);
+        // Second call is served from the cache — the stub only had one reply.
+        assert_eq!(counter.count("fn main() {}"), 7);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/tokenize");
+        assert_eq!(requests[0].json()["content"], "fn main() {}");
+        assert_eq!(counter.describe(), "llama.cpp /tokenize");
+    }
+
+    #[test]
+    fn test_ollama_tokenize_sends_model() {
+        let server = StubServer::json(vec![serde_json::json!({"tokens": [9, 9]})]);
+        let counter =
+            RemoteTokenCounter::new(TokenizeEndpoint::Ollama, &server.url, "qwen3:8b", None);
+        assert_eq!(counter.count("hi"), 2);
+        let req = &server.requests()[0];
+        assert_eq!(req.path, "/api/tokenize");
+        assert_eq!(req.json()["model"], "qwen3:8b");
+    }
+
+    #[test]
+    fn test_missing_endpoint_falls_back_to_heuristic_once() {
+        let server = StubServer::start(vec![(404, "text/plain", "not found".to_string())]);
+        let counter = RemoteTokenCounter::new(TokenizeEndpoint::Ollama, &server.url, "m", None);
+        assert_eq!(counter.count(&"x".repeat(40)), 10);
+        // No further requests once the endpoint is known to be missing.
+        assert_eq!(counter.count("日本語"), 3);
+        assert_eq!(server.requests().len(), 1);
+        assert!(counter.describe().starts_with("heuristic"));
+    }
+
+    #[test]
+    fn test_transient_failure_retries_after_cooldown() {
+        let server = StubServer::start(vec![
+            (500, "text/plain", "loading model".to_string()),
+            (200, "application/json", r#"{"tokens": [1, 2]}"#.to_string()),
+        ]);
+        let mut counter =
+            RemoteTokenCounter::new(TokenizeEndpoint::LlamaCpp, &server.url, "m", None);
+        assert_eq!(counter.count(&"x".repeat(40)), 10);
+        assert!(counter.describe().contains("will retry"));
+        // Within the cooldown nothing is sent.
+        assert_eq!(counter.count("hello"), 1);
+        assert_eq!(server.requests().len(), 1);
+
+        counter.retry_after = Duration::ZERO;
+        counter.retry_at.set(Some(Instant::now()));
+        assert_eq!(counter.count("hello"), 2);
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(counter.describe(), "llama.cpp /tokenize");
+    }
+
+    #[test]
+    fn test_empty_text_skips_request() {
+        let counter =
+            RemoteTokenCounter::new(TokenizeEndpoint::LlamaCpp, "http://127.0.0.1:1", "m", None);
+        assert_eq!(counter.count(""), 0);
+        assert_eq!(counter.describe(), "llama.cpp /tokenize");
+    }
+}
@@ -2,6 +2,8 @@ use clap::{Parser, Subcommand};
 use colored::Colorize;
 
 mod agent;
+mod cancel;
+mod checkpoint;
 mod config;
 mod llm;
 mod mcp;
@@ -10,29 +12,257 @@ mod security;
 mod skills;
 mod tools;
 
-use agent::{Agent, AgentConfig, SessionStats};
+use agent::{Agent, AgentConfig, ModelRole, SessionStats};
 use config::Config;
+use llm::attachment::{self, Attachment};
+use llm::capabilities::{self, CapabilityCache, ModelCapabilities};
+use llm::text_tools::TextToolProvider;
+use llm::tokens::{HeuristicCounter, RemoteTokenCounter, TokenCounter, TokenizeEndpoint};
 use llm::LlmProvider;
 use memory::store::MemoryStore;
+use security::sandbox::Sandbox;
+use security::workspace::Workspace;
 use skills::Skill;
 use tools::default_registry;
 
-fn create_provider(cfg: &Config, model: &str) -> Box<dyn LlmProvider> {
-    match cfg.llm.provider.as_str() {
+fn create_backend(
+    provider: &str,
+    base_url: &str,
+    model: &str,
+    api_key: Option<String>,
+    options: config::LlmOptions,
+    retry: &config::RetryConfig,
+) -> Box<dyn LlmProvider> {
+    let client: Box<dyn LlmProvider> = match provider {
         "openai_compat" => Box::new(llm::openai_compat::OpenAiCompatClient::with_options(
-            &cfg.llm.base_url,
-            model,
-            cfg.llm.api_key.clone(),
-            cfg.llm.options.clone(),
+            base_url, model, api_key, options,
+        )),
+        "llamacpp" => Box::new(llm::llamacpp::LlamaCppClient::with_options(
+            base_url, model, api_key, options,
         )),
         _ => Box::new(llm::ollama::OllamaClient::with_options(
+            base_url, model, options,
+        )),
+    };
+    if retry.max_attempts <= 1 {
+        return client;
+    }
+    let mut retrying = llm::retry::RetryingProvider::new(client, retry.clone());
+    retrying.set_on_retry(Box::new(agent::display::print_retry_notice));
+    Box::new(retrying)
+}
+
+/// Token counter for the primary backend: the server's own tokenizer
+/// where the provider exposes one, the offline heuristic otherwise.
+fn create_token_counter(cfg: &Config, model: &str) -> Box<dyn TokenCounter> {
+    let endpoint = match cfg.llm.provider.as_str() {
+        "openai_compat" | "replay" => return Box::new(HeuristicCounter),
+        "llamacpp" => TokenizeEndpoint::LlamaCpp,
+        _ => TokenizeEndpoint::Ollama,
+    };
+    Box::new(RemoteTokenCounter::new(
+        endpoint,
+        &cfg.llm.base_url,
+        model,
+        cfg.llm.api_key.clone(),
+    ))
+}
+
+/// Largest `num_ctx` filled in from a capability probe. Models advertise
+/// 128 K+ windows, but Ollama allocates the KV cache for the whole window
+/// up front; beyond this, ask for it explicitly in `[llm.options]`.
+const AUTO_NUM_CTX_CAP: u32 = 32_768;
+
+/// What `model` supports on the primary backend, from the
+/// `~/.whet/capabilities.json` cache or a fresh probe. Unknown (all
+/// `None`) when the backend can't be reached.
+fn model_capabilities(cfg: &Config, model: &str) -> ModelCapabilities {
+    if cfg.llm.provider == "replay" {
+        return ModelCapabilities::default();
+    }
+    let probe = || {
+        capabilities::probe(
+            &cfg.llm.provider,
             &cfg.llm.base_url,
             model,
-            cfg.llm.options.clone(),
+            cfg.llm.api_key.as_deref(),
+        )
+    };
+    let caps = match config::config_home() {
+        Some(home) => {
+            let cache = CapabilityCache::new(home.join(".whet").join("capabilities.json"));
+            cache.get_or_probe(
+                &CapabilityCache::key(&cfg.llm.provider, &cfg.llm.base_url, model),
+                probe,
+            )
+        }
+        None => probe().ok(),
+    };
+    caps.unwrap_or_default()
+}
+
+/// `cfg` adjusted to what the model supports: `num_ctx` filled in when
+/// unset (so the compaction ratio applies) and `think` dropped for
+/// models that reject it. Only the primary backend was probed, so
+/// fallbacks without their own `options` keep the configured ones.
+fn with_capabilities(cfg: &Config, caps: &ModelCapabilities) -> Config {
+    let mut cfg = cfg.clone();
+    for fb in &mut cfg.llm.fallbacks {
+        if fb.options.is_none() {
+            fb.options = Some(cfg.llm.options.clone());
+        }
+    }
+    let options = &mut cfg.llm.options;
+    if options.num_ctx.is_none() {
+        options.num_ctx = caps.context_length.map(|n| n.min(AUTO_NUM_CTX_CAP));
+    }
+    if caps.thinking == Some(false) {
+        options.think = None;
+    }
+    cfg
+}
+
+/// Probe `model`, then build its provider and the config it runs with.
+/// The text tool-call protocol is used when `tool_mode` asks for it, or
+/// in `auto` mode when the model has no native tool calling.
+fn provider_for_model(cfg: &Config, model: &str) -> (Box<dyn LlmProvider>, Config) {
+    let caps = model_capabilities(cfg, model);
+    let effective = with_capabilities(cfg, &caps);
+    let provider = create_provider(&effective, model);
+    let (mode, format) = cfg.llm.tool_protocol_for(model);
+    let text_mode = match mode {
+        config::ToolMode::Native => false,
+        config::ToolMode::Text => true,
+        config::ToolMode::Auto => {
+            let unsupported = caps.tools == Some(false);
+            if unsupported {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "{} has no native tool calling; using the text tool-call protocol.",
+                        model
+                    )
+                    .dimmed()
+                );
+            }
+            unsupported
+        }
+    };
+    if text_mode {
+        return (Box::new(TextToolProvider::new(provider, format)), effective);
+    }
+    (provider, effective)
+}
+
+/// The provider for `model`: the cassette for `provider = "replay"`,
+/// otherwise the live backends, recorded when `[llm.cassette]` asks.
+fn create_provider(cfg: &Config, model: &str) -> Box<dyn LlmProvider> {
+    if cfg.llm.provider == "replay" {
+        let cassette = cfg.llm.cassette.clone().unwrap_or_default();
+        return Box::new(llm::cassette::ReplayProvider::open(std::path::Path::new(
+            &cassette.path,
+        )));
+    }
+    let provider = create_live_provider(cfg, model);
+    match cfg.llm.recording_cassette() {
+        Some(path) => Box::new(llm::cassette::RecordingProvider::new(
+            provider,
+            std::path::Path::new(path),
         )),
+        None => provider,
+    }
+}
+
+/// Build the configured backend. With `[[llm.fallbacks]]` present the
+/// primary is wrapped in a `FailoverProvider`; each failover is reported
+/// on stderr and written to the stats JSONL sink.
+fn create_live_provider(cfg: &Config, model: &str) -> Box<dyn LlmProvider> {
+    let primary = create_backend(
+        &cfg.llm.provider,
+        &cfg.llm.base_url,
+        model,
+        cfg.llm.api_key.clone(),
+        cfg.llm.options.clone(),
+        &cfg.llm.retry,
+    );
+    if cfg.llm.fallbacks.is_empty() {
+        return primary;
+    }
+
+    let mut backends = vec![(format!("{}@{}", model, cfg.llm.base_url), primary)];
+    for fb in &cfg.llm.fallbacks {
+        let fb_model = fb.model.as_deref().unwrap_or(model);
+        let options = fb
+            .options
+            .clone()
+            .unwrap_or_else(|| cfg.llm.options.clone());
+        backends.push((
+            format!("{}@{}", fb_model, fb.base_url),
+            create_backend(
+                &fb.provider,
+                &fb.base_url,
+                fb_model,
+                fb.api_key.clone().or_else(|| cfg.llm.api_key.clone()),
+                options,
+                &cfg.llm.retry,
+            ),
+        ));
+    }
+
+    let mut failover = llm::failover::FailoverProvider::new(backends);
+    let stats_path = stats_jsonl_path();
+    failover.set_on_failover(Box::new(move |event| {
+        eprintln!(
+            "{}",
+            format!(
+                "  {} unavailable ({}), falling back to {}",
+                event.from, event.error, event.to
+            )
+            .yellow()
+        );
+        agent::write_stats_event(
+            &stats_path,
+            serde_json::json!({
+                "event": "failover",
+                "from": event.from,
+                "to": event.to,
+                "error": event.error,
+            }),
+        );
+    }));
+    Box::new(failover)
+}
+
+/// Route Ctrl+C to `token` instead of killing the process. The first
+/// press cancels the in-flight turn; a second one before the turn has
+/// unwound exits as before. At the REPL prompt rustyline reads Ctrl+C
+/// as a key, so this only fires while a turn is running.
+fn install_interrupt_handler(token: cancel::CancelToken) {
+    let result = ctrlc::set_handler(move || {
+        if token.is_cancelled() {
+            eprintln!();
+            std::process::exit(130);
+        }
+        token.cancel();
+        eprintln!(
+            "\n{}",
+            "Interrupted — cancelling (press Ctrl+C again to quit)".yellow()
+        );
+    });
+    if let Err(e) = result {
+        eprintln!(
+            "{} Ctrl+C handler not installed: {}",
+            "Warning:".yellow(),
+            e
+        );
     }
 }
 
+/// `WHET_STATS_JSONL` sink path, if the user enabled it.
+fn stats_jsonl_path() -> Option<std::path::PathBuf> {
+    std::env::var_os("WHET_STATS_JSONL").map(std::path::PathBuf::from)
+}
+
 #[derive(Parser)]
 #[command(name = "whet")]
 #[command(version)]
@@ -73,6 +303,24 @@ struct Cli {
     /// extension) is too tight. Has no persistent effect.
     #[arg(long = "max-iterations", value_name = "N")]
     max_iterations: Option<usize>,
+
+    /// Limit each turn to N prompt + completion tokens, overriding
+    /// `[agent].max_turn_tokens`.
+    #[arg(long = "max-turn-tokens", value_name = "N")]
+    max_turn_tokens: Option<u64>,
+
+    /// Limit each turn to SECS seconds of wall-clock time, overriding
+    /// `[agent].max_turn_seconds`.
+    #[arg(long = "max-turn-seconds", value_name = "SECS")]
+    max_turn_seconds: Option<u64>,
+}
+
+/// Per-invocation budget overrides from the command line.
+#[derive(Debug, Clone, Copy, Default)]
+struct BudgetFlags {
+    max_iterations: Option<usize>,
+    max_turn_tokens: Option<u64>,
+    max_turn_seconds: Option<u64>,
 }
 
 #[derive(Subcommand)]
@@ -83,7 +331,43 @@ enum Commands {
     Config,
 }
 
-fn ask_approval(tool_name: &str, args: &serde_json::Value) -> bool {
+/// Offer to continue a turn that ran out of `budget`. Enter or `y` grants
+/// another `limit`, a number grants that many, anything else stops.
+fn ask_budget_extension(budget: agent::Budget, limit: u64) -> Option<u64> {
+    use std::io::{self, Write};
+    eprint!(
+        "\n  {} {}",
+        format!(
+            "Reached the {} budget ({} {}). Continue for {} more?",
+            budget.name(),
+            limit,
+            budget.unit(),
+            limit
+        )
+        .yellow(),
+        "[Y/n/<number>] ".dimmed()
+    );
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+        return None;
+    }
+    parse_budget_answer(&answer, limit)
+}
+
+/// `ask_budget_extension`'s reading of the user's answer.
+fn parse_budget_answer(answer: &str, limit: u64) -> Option<u64> {
+    match answer.trim().to_lowercase().as_str() {
+        "" | "y" | "yes" => Some(limit),
+        other => other.parse::<u64>().ok().filter(|n| *n > 0),
+    }
+}
+
+fn ask_approval(
+    tool_name: &str,
+    args: &serde_json::Value,
+    permissions: &security::permissions::Permissions,
+) -> bool {
     use std::io::{self, Write};
     eprintln!(
         "\n{}",
@@ -151,8 +435,13 @@ fn ask_approval(tool_name: &str, args: &serde_json::Value) -> bool {
         match answer.as_str() {
             "y" | "yes" => true,
             "a" | "always" => {
-                eprintln!("  {}", "(Switching to yolo mode for this session)".dimmed());
-                true // The caller will handle mode switch via return value
+                let note = match permissions.always_allow(tool_name, args) {
+                    Ok(Some(file)) => format!("(Always allowed; saved to {})", file.display()),
+                    Ok(None) => "(Always allowed for this session)".to_string(),
+                    Err(e) => format!("(Allowed for this session; could not save rule: {})", e),
+                };
+                eprintln!("  {}", note.dimmed());
+                true
             }
             _ => {
                 eprintln!("  {}", "Denied.".red());
@@ -164,14 +453,159 @@ fn ask_approval(tool_name: &str, args: &serde_json::Value) -> bool {
     }
 }
 
+/// An answer at the per-hunk prompt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HunkAnswer {
+    Apply,
+    Skip,
+    ApplyRest,
+    SkipRest,
+    Edit,
+    Always,
+}
+
+fn parse_hunk_answer(answer: &str) -> Option<HunkAnswer> {
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Some(HunkAnswer::Apply),
+        "n" | "no" | "" => Some(HunkAnswer::Skip),
+        "a" | "all" => Some(HunkAnswer::ApplyRest),
+        "d" | "q" => Some(HunkAnswer::SkipRest),
+        "e" | "edit" => Some(HunkAnswer::Edit),
+        "!" | "always" => Some(HunkAnswer::Always),
+        _ => None,
+    }
+}
+
+/// Show an edit's diff against the file on disk one hunk at a time and
+/// ask which to apply. `e` opens the result, with every hunk not yet
+/// skipped, in `$EDITOR`; what the user saves is written as is. `!`
+/// applies the rest and allows edits to the file from then on.
+fn review_hunks(change: &agent::review::FileChange) -> agent::review::HunkReview {
+    use agent::review::HunkReview;
+    use std::io::{self, Write};
+    let total = change.hunks.len();
+    eprintln!(
+        "\n{}",
+        format!(
+            "  Edit wants to {} {} ({} hunk{}):",
+            if change.old.is_some() {
+                "change"
+            } else {
+                "create"
+            },
+            change.path,
+            total,
+            if total == 1 { "" } else { "s" }
+        )
+        .yellow()
+    );
+    let mut accepted: Vec<bool> = Vec::with_capacity(total);
+    let mut always = false;
+    while accepted.len() < total {
+        let index = accepted.len();
+        agent::display::print_colored_diff(&change.render_hunk(index));
+        eprint!(
+            "  {} ",
+            format!("Apply hunk {}/{}? [y/N/a/d/e/!/?]", index + 1, total).bright_yellow()
+        );
+        io::stderr().flush().ok();
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).unwrap_or(0) == 0 {
+            accepted.resize(total, false);
+            break;
+        }
+        match parse_hunk_answer(&input) {
+            Some(HunkAnswer::Apply) => accepted.push(true),
+            Some(HunkAnswer::Skip) => accepted.push(false),
+            Some(HunkAnswer::ApplyRest) => accepted.resize(total, true),
+            Some(HunkAnswer::SkipRest) => accepted.resize(total, false),
+            Some(HunkAnswer::Always) => {
+                accepted.resize(total, true);
+                always = true;
+            }
+            Some(HunkAnswer::Edit) => {
+                let mut proposed = accepted.clone();
+                proposed.resize(total, true);
+                match edit_in_editor(&change.path, &change.apply(&proposed)) {
+                    Ok(text) => {
+                        return HunkReview {
+                            accepted: proposed,
+                            edited: Some(text),
+                            always: false,
+                        }
+                    }
+                    Err(e) => eprintln!("  {} {}", "Editor failed:".red(), e),
+                }
+            }
+            None => eprintln!(
+                "  {}",
+                "y: apply this hunk, n: skip it, a: apply it and the rest, \
+                 d: skip it and the rest, e: edit the result in $EDITOR, \
+                 !: apply it and the rest and always allow edits to this file"
+                    .dimmed()
+            ),
+        }
+    }
+    let kept = accepted.iter().filter(|a| **a).count();
+    if kept < total {
+        eprintln!(
+            "  {}",
+            format!("Applying {} of {} hunk(s).", kept, total).dimmed()
+        );
+    }
+    HunkReview {
+        accepted,
+        edited: None,
+        always,
+    }
+}
+
+/// Open `content` in `$VISUAL`, `$EDITOR` or `vi` and return what the
+/// user saved. The temp file keeps `path`'s file name so the editor
+/// picks the right syntax; it sits in a fresh private directory so
+/// nothing planted in the shared temp dir can redirect the write.
+fn edit_in_editor(path: &str, content: &str) -> std::io::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let name = std::path::Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "edit".to_string());
+    let dir = tempfile::Builder::new().prefix("whet-edit-").tempdir()?;
+    let file = dir.path().join(name);
+    std::fs::write(&file, content)?;
+    // Through the shell so `EDITOR="code --wait"` works.
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&file)
+        .status();
+    let edited = std::fs::read_to_string(&file);
+    match status? {
+        s if s.success() => edited,
+        s => Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            editor, s
+        ))),
+    }
+}
+
 fn setup_agent(
     cfg: &Config,
     model: &str,
     skills: &[Skill],
     yolo: bool,
-    max_iterations_override: Option<usize>,
+    budget: BudgetFlags,
 ) -> Agent {
-    let provider = create_provider(cfg, model);
+    if let Some(path) = cfg.llm.recording_cassette() {
+        if let Err(e) = llm::cassette::start_recording(std::path::Path::new(path)) {
+            eprintln!("{} Cannot record to {}: {}", "Warning:".yellow(), path, e);
+        }
+    }
+    let (provider, cfg) = provider_for_model(cfg, model);
+    let cfg = &cfg;
     let mut registry = default_registry();
 
     // Register web tools if enabled
@@ -190,7 +624,19 @@ fn setup_agent(
         cfg.agent.permission_mode.clone()
     };
 
-    let max_iterations = resolve_max_iterations(cfg.agent.max_iterations, max_iterations_override);
+    let project_root = std::env::current_dir().unwrap_or_default();
+    let sandbox = Sandbox::new(&cfg.sandbox, &project_root);
+    if let Some(sandbox) = &sandbox {
+        registry.register(Box::new(tools::shell::ShellTool::sandboxed(
+            sandbox.clone(),
+        )));
+        if sandbox.backend().is_none() {
+            eprintln!("{} Sandbox: {}", "Warning:".yellow(), sandbox.describe());
+        }
+    }
+
+    let max_iterations = resolve_max_iterations(cfg.agent.max_iterations, budget.max_iterations);
+    let max_turn_seconds = budget.max_turn_seconds.or(cfg.agent.max_turn_seconds);
 
     let agent_config = AgentConfig {
         model: model.to_string(),
@@ -199,10 +645,95 @@ fn setup_agent(
         plan_mode: false,
         context_compression: cfg.agent.context_compression,
         compaction_token_threshold: resolve_compaction_threshold(cfg),
-        stats_jsonl_path: std::env::var_os("WHET_STATS_JSONL").map(std::path::PathBuf::from),
+        stats_jsonl_path: stats_jsonl_path(),
+        keep_reasoning: cfg.agent.keep_reasoning,
+        max_turn_tokens: budget.max_turn_tokens.or(cfg.agent.max_turn_tokens),
+        max_turn_duration: max_turn_seconds.map(std::time::Duration::from_secs),
+        loop_policies: cfg.agent.policies.clone(),
+        verify: cfg.agent.verify.clone(),
+        permissions: cfg.permissions.clone(),
+        workspace: cfg
+            .workspace
+            .confine
+            .then(|| Workspace::new(&project_root, &cfg.workspace.allowed_dirs)),
+        sandbox: sandbox.clone(),
     };
 
-    Agent::new(provider, registry, agent_config, skills)
+    let mut agent = Agent::new(provider, registry, agent_config, skills);
+    agent.set_token_counter(create_token_counter(cfg, model));
+    let checkpoint_dir = std::path::absolute(checkpoint::DEFAULT_DIR)
+        .unwrap_or_else(|_| checkpoint::DEFAULT_DIR.into());
+    agent.set_checkpoint_store(checkpoint::CheckpointStore::new(checkpoint_dir));
+    let rules_file = project_root.join(security::permissions::PROJECT_FILE);
+    match agent.permissions().load_project_file(&rules_file) {
+        Ok(0) => {}
+        Ok(ignored) => eprintln!(
+            "{} Ignoring {} allow rule(s) in {}; a project can only add deny and ask rules",
+            "Warning:".yellow(),
+            ignored,
+            rules_file.display()
+        ),
+        Err(e) => eprintln!("{} Ignoring permission rules in {}", "Warning:".yellow(), e),
+    }
+    if let Some(home) = config::config_home() {
+        let saved = home.join(".whet").join(security::permissions::SAVED_FILE);
+        if let Err(e) = agent.permissions().load_saved(&saved, &project_root) {
+            eprintln!("{} Ignoring permission rules in {}", "Warning:".yellow(), e);
+        }
+    }
+    if cfg.agent.show_reasoning {
+        agent.set_on_reasoning(agent::display::reasoning_printer());
+    }
+    // A line rewritten in place is only readable on a terminal.
+    if std::io::IsTerminal::is_terminal(&std::io::stderr()) {
+        agent.set_on_tool_progress(agent::display::tool_progress_printer());
+    }
+    for (role, model) in role_providers(cfg) {
+        agent.set_role_llm(role, model);
+    }
+    agent.set_vision(model_capabilities(cfg, model).vision != Some(false));
+    agent
+}
+
+/// The `[llm.roles]` entries other than `main`, as routed roles.
+fn routed_roles(cfg: &Config) -> Vec<(ModelRole, &config::LlmRoleConfig)> {
+    let roles = &cfg.llm.roles;
+    [
+        (ModelRole::Subagent, &roles.subagent),
+        (ModelRole::Compaction, &roles.compaction),
+        (ModelRole::Extraction, &roles.extraction),
+    ]
+    .into_iter()
+    .filter_map(|(role, entry)| entry.as_ref().map(|e| (role, e)))
+    .collect()
+}
+
+/// Build a provider for each routed role. Each goes through the same
+/// probing, retry and failover setup as the main model.
+fn role_providers(cfg: &Config) -> Vec<(ModelRole, Box<dyn LlmProvider>)> {
+    routed_roles(cfg)
+        .into_iter()
+        .map(|(role, entry)| {
+            let role_cfg = Config {
+                llm: cfg.llm.for_role(entry),
+                ..cfg.clone()
+            };
+            let (provider, _) = provider_for_model(&role_cfg, &role_cfg.llm.model);
+            (role, provider)
+        })
+        .collect()
+}
+
+/// `cfg` with `[llm.roles.main]` folded into `[llm]`, so everything that
+/// builds the main provider (startup, `/model`) sees one section.
+fn with_main_role(cfg: Config) -> Config {
+    match cfg.llm.roles.main {
+        Some(ref main) => Config {
+            llm: cfg.llm.for_role(main),
+            ..cfg
+        },
+        None => cfg,
+    }
 }
 
 /// Resolve the agent's `max_iterations` for this invocation. CLI flag
@@ -398,12 +929,28 @@ fn load_resumed_history_into_agent_by_id(
     if !has_tool_messages {
         agent.set_resumed(true);
     }
+    restore_todos(&handle.borrow(), id, agent);
     eprintln!(
         "{}",
         format!("Resumed {} ({} messages).", id, messages.len()).dimmed()
     );
 }
 
+/// Save the agent's todo list with the conversation.
+fn save_todos(store: &MemoryStore, conversation_id: &str, agent: &Agent) {
+    if let Ok(json) = serde_json::to_string(agent.todos()) {
+        let _ = store.save_todos(conversation_id, &json);
+    }
+}
+
+/// Restore the todo list saved with a conversation, if any.
+fn restore_todos(store: &MemoryStore, conversation_id: &str, agent: &mut Agent) {
+    let saved = store.load_todos(conversation_id).ok().flatten();
+    if let Some(todos) = saved.and_then(|json| serde_json::from_str(&json).ok()) {
+        agent.set_todos(todos);
+    }
+}
+
 fn pick_session(store: &MemoryStore, working_dir: &str) -> Option<String> {
     let convs = match store.list_conversations(working_dir) {
         Ok(c) => c,
@@ -489,6 +1036,24 @@ fn print_session_stats(stats: &SessionStats) {
     eprintln!("  Prompt tokens:      {}", stats.prompt_tokens);
     eprintln!("  Completion tokens:  {}", stats.completion_tokens);
     eprintln!("  Total tokens:       {}", stats.total_tokens());
+    if let Some(rate) = stats.prompt_cache_hit_rate() {
+        eprintln!(
+            "  Prompt cache:       {} reused ({:.0}%)",
+            stats.cached_prompt_tokens, rate
+        );
+    }
+    if let Some(rate) = stats.prefill_tokens_per_sec() {
+        eprintln!("  Prefill:            {:.1} tok/s", rate);
+    }
+    if let Some(rate) = stats.decode_tokens_per_sec() {
+        eprintln!("  Decode:             {:.1} tok/s", rate);
+    }
+    if stats.timings.load_ms > 0.0 {
+        eprintln!(
+            "  Model load:         {:.1}s",
+            stats.timings.load_ms / 1000.0
+        );
+    }
     let total_tools = stats.total_tool_calls();
     if total_tools > 0 || stats.tool_calls_skipped > 0 {
         if stats.tool_calls_skipped > 0 {
@@ -512,6 +1077,9 @@ fn print_session_stats(stats: &SessionStats) {
     if stats.reprompts > 0 {
         eprintln!("  Re-prompts:         {}", stats.reprompts);
     }
+    for (backend, calls) in &stats.backend_calls {
+        eprintln!("  Served by {}: {} calls", backend, calls);
+    }
     eprintln!("{}", "---------------------".dimmed());
 }
 
@@ -521,15 +1089,18 @@ fn run_chat(
     continue_conv: bool,
     message: Option<String>,
     yolo: bool,
-    max_iterations_override: Option<usize>,
+    budget: BudgetFlags,
 ) {
-    let cfg = Config::load();
+    let cfg = with_main_role(Config::load());
     let model = model.unwrap_or(cfg.llm.model.clone());
     let loaded_skills = skills::load_skills(&cfg.agent.skills_dir);
+    let cancel = cancel::CancelToken::new();
+    install_interrupt_handler(cancel.clone());
 
     // Single-shot mode
     if let Some(msg) = message.filter(|m| !m.trim().is_empty()) {
-        let mut agent = setup_agent(&cfg, &model, &loaded_skills, yolo, max_iterations_override);
+        let mut agent = setup_agent(&cfg, &model, &loaded_skills, yolo, budget);
+        agent.set_cancel_token(cancel.clone());
         // Single-shot still benefits from persistent memory: the model
         // can `remember` facts from a one-off invocation, and recalls
         // facts from past sessions on startup.
@@ -578,9 +1149,10 @@ fn run_chat(
         };
         let memory_before = agent.memory.len();
 
-        if cfg.llm.streaming {
+        let mut approve = unattended_approval(yolo, agent.config.workspace.clone());
+        let exit_reason = if cfg.llm.streaming {
             let mut spinner = Some(agent::display::Spinner::start());
-            agent.process_message_with_callbacks(
+            let (_, reason) = agent.process_message_full(
                 &msg,
                 &mut |token| {
                     if let Some(mut s) = spinner.take() {
@@ -588,17 +1160,18 @@ fn run_chat(
                     }
                     print!("{}", token);
                 },
-                &mut |_, _| yolo,
+                &mut approve,
             );
             if let Some(mut s) = spinner.take() {
                 s.stop();
             }
             println!();
+            reason
         } else {
-            let response =
-                agent.process_message_with_callbacks(&msg, &mut |_| {}, &mut |_, _| yolo);
+            let (response, reason) = agent.process_message_full(&msg, &mut |_| {}, &mut approve);
             println!("{}", response);
-        }
+            reason
+        };
         // Persist the new turn (user input + assistant response + any
         // tool messages) so the next `whet -c -p ...` can resume.
         if let Some(handle) = single_shot_memory.as_ref() {
@@ -618,8 +1191,13 @@ fn run_chat(
                     tc_json.as_deref(),
                 );
             }
+            save_todos(&store, &conversation_id, &agent);
         }
         print_session_stats(&agent.stats);
+        let code = exit_reason.exit_code();
+        if code != 0 {
+            std::process::exit(code);
+        }
         return;
     }
 
@@ -627,6 +1205,21 @@ fn run_chat(
     println!("{}", "whet v0.1.0".bold());
     println!("Terminal coding agent.\n");
     println!("Model: {}", model.green());
+    let roles = routed_roles(&cfg);
+    if !roles.is_empty() {
+        let list = roles
+            .iter()
+            .map(|(role, entry)| format!("{}={}", role.name(), cfg.llm.for_role(entry).model))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("Roles: {}", list.cyan());
+    }
+    if cfg.llm.provider == "replay" {
+        let cassette = cfg.llm.cassette.clone().unwrap_or_default();
+        println!("Replaying: {}", cassette.path.cyan());
+    } else if let Some(path) = cfg.llm.recording_cassette() {
+        println!("Recording: {}", path.cyan());
+    }
     println!(
         "Permission: {}",
         if yolo {
@@ -648,11 +1241,22 @@ fn run_chat(
     }
     println!("Type {} to exit.\n", "Ctrl+D".dimmed());
 
-    let mut agent = setup_agent(&cfg, &model, &loaded_skills, yolo, max_iterations_override);
+    let mut agent = setup_agent(&cfg, &model, &loaded_skills, yolo, budget);
+    agent.set_on_budget(Box::new(ask_budget_extension));
+    agent.set_on_review(Box::new(review_hunks));
+    agent.set_cancel_token(cancel.clone());
 
     if cfg.agent.web_enabled {
         println!("Web tools: {}", "enabled".green());
     }
+    if let Some(sandbox) = agent
+        .config
+        .sandbox
+        .as_ref()
+        .filter(|s| s.backend().is_some())
+    {
+        println!("Sandbox: {}", sandbox.describe().green());
+    }
 
     // Memory store
     let store = match MemoryStore::new(&cfg.memory.database_path) {
@@ -763,6 +1367,8 @@ fn run_chat(
                     "\n{}\n",
                     format!("Restored {} messages.", messages.len()).dimmed()
                 );
+                restore_todos(store, &id, &mut agent);
+                agent::display::print_todo_list(agent.todos());
                 // Backward compat: if no tool messages were restored
                 // (old DB without tool data), skip read-before-edit check
                 if !has_tool_messages {
@@ -801,6 +1407,9 @@ fn run_chat(
     }
 
     loop {
+        // Any interrupt from the previous turn has been handled by now;
+        // re-arm so the next Ctrl+C cancels instead of quitting.
+        cancel.reset();
         let readline = rl.readline(&format!("{} ", "you>".blue().bold()));
         match readline {
             Ok(line) => {
@@ -824,11 +1433,32 @@ fn run_chat(
                         SlashResult::Handled => continue,
                         SlashResult::NewProvider(provider) => {
                             agent.llm = provider;
+                            agent.set_vision(
+                                model_capabilities(&cfg, &current_model).vision != Some(false),
+                            );
                             continue;
                         }
                     }
                 }
 
+                let (attachments, problems) = image_mentions(input);
+                for problem in problems {
+                    eprintln!("{} {}", "Warning:".yellow(), problem);
+                }
+                if !attachments.is_empty() {
+                    for image in &attachments {
+                        eprintln!("{}", format!("Attached {}", image.describe()).dimmed());
+                    }
+                    if !agent.supports_vision() {
+                        eprintln!(
+                            "{} {} does not accept images; they will likely be ignored or rejected.",
+                            "Warning:".yellow(),
+                            current_model
+                        );
+                    }
+                    agent.queue_attachments(attachments);
+                }
+
                 // Create conversation lazily on first message
                 if !conversation_created {
                     if let Some(ref store) = store {
@@ -870,9 +1500,10 @@ fn run_chat(
                 // render, which prevents the spinner from racing with
                 // tool-call output on the same row.
                 let streaming = cfg.llm.streaming;
+                let permissions = agent.permissions();
                 let mut spinner = Some(agent::display::Spinner::start());
                 let mut bot_prefix_printed = false;
-                let response = agent.process_message_with_callbacks(
+                let (response, exit_reason) = agent.process_message_full(
                     input,
                     &mut |token| {
                         if let Some(mut s) = spinner.take() {
@@ -892,12 +1523,17 @@ fn run_chat(
                             eprint!("{}", token);
                         }
                     },
-                    &mut |tool_name, args| ask_approval(tool_name, args),
+                    &mut |tool_name, args| ask_approval(tool_name, args, &permissions),
                 );
                 if let Some(mut s) = spinner.take() {
                     s.stop();
                 }
-                if streaming {
+                if exit_reason == agent::ExitReason::Cancelled {
+                    if bot_prefix_printed {
+                        eprintln!();
+                    }
+                    println!("{}", "Turn cancelled.".yellow());
+                } else if streaming {
                     if !bot_prefix_printed {
                         // Loop ended without any streamed text (e.g. all
                         // tool calls + final empty content) — print prefix
@@ -930,6 +1566,7 @@ fn run_chat(
                             tc_json.as_deref(),
                         );
                     }
+                    save_todos(store, &conversation_id, &agent);
                 }
             }
             Err(rustyline::error::ReadlineError::Interrupted) => {
@@ -953,6 +1590,31 @@ fn run_chat(
     }
 }
 
+/// Images referenced as `@path` in a REPL line, e.g. `why is this
+/// misaligned? @shot.png`. Only tokens with an image extension count,
+/// so `@user` mentions and e-mail addresses pass through untouched; the
+/// text itself is sent unchanged. Returns the loaded images and a
+/// message for each one that couldn't be read.
+fn image_mentions(input: &str) -> (Vec<Attachment>, Vec<String>) {
+    let mut images = Vec::new();
+    let mut problems = Vec::new();
+    for word in input.split_whitespace() {
+        let Some(path) = word.strip_prefix('@') else {
+            continue;
+        };
+        let path = path.trim_end_matches([',', '.', ';', ':', '!', '?', ')']);
+        let path = std::path::Path::new(path);
+        if attachment::image_mime_type(path).is_none() {
+            continue;
+        }
+        match Attachment::from_path(path) {
+            Ok(image) => images.push(image),
+            Err(e) => problems.push(e),
+        }
+    }
+    (images, problems)
+}
+
 enum SlashResult {
     Handled,
     NewProvider(Box<dyn LlmProvider>),
@@ -984,7 +1646,9 @@ fn handle_slash_command(
                     new_model.green()
                 );
                 *current_model = new_model.clone();
-                let provider = create_provider(cfg, &new_model);
+                let (provider, effective) = provider_for_model(cfg, &new_model);
+                agent.config.compaction_token_threshold = resolve_compaction_threshold(&effective);
+                agent.set_token_counter(create_token_counter(cfg, &new_model));
                 return SlashResult::NewProvider(provider);
             }
             SlashResult::Handled
@@ -1094,6 +1758,37 @@ fn handle_slash_command(
             agent.compact(instruction);
             SlashResult::Handled
         }
+        "/context" => {
+            let usage = agent.context_usage();
+            println!("{}", "Context usage:".bold());
+            let num_ctx = with_capabilities(cfg, &model_capabilities(cfg, current_model))
+                .llm
+                .options
+                .num_ctx;
+            for line in agent::display::format_context_usage(&usage, num_ctx) {
+                println!("  {}", line);
+            }
+            SlashResult::Handled
+        }
+        "/reasoning" => {
+            let show = match arg {
+                "on" => true,
+                "off" => false,
+                "" => agent.take_on_reasoning().is_none(),
+                _ => {
+                    eprintln!("{} usage: /reasoning [on|off]", "Error:".red());
+                    return SlashResult::Handled;
+                }
+            };
+            if show {
+                agent.set_on_reasoning(agent::display::reasoning_printer());
+            } else {
+                agent.take_on_reasoning();
+            }
+            let state = if show { "shown" } else { "hidden" };
+            println!("{}", format!("Reasoning {}.", state).dimmed());
+            SlashResult::Handled
+        }
         "/help" => {
             println!("{}", "Available commands:".bold());
             println!("  {} <name>  - Switch LLM model", "/model".cyan());
@@ -1138,12 +1833,33 @@ fn handle_slash_command(
                 "  {} [msg] - Compress conversation context",
                 "/compact".cyan()
             );
+            println!("  {}        - Show context token usage", "/context".cyan());
+            println!(
+                "  {} [on|off] - Show or hide the model's reasoning",
+                "/reasoning".cyan()
+            );
             println!("  {}         - List loaded skills", "/skills".cyan());
+            println!(
+                "  {}           - Revert the files changed in the last turn",
+                "/undo".cyan()
+            );
+            println!(
+                "  {}    - List file checkpoints (one per turn)",
+                "/checkpoints".cyan()
+            );
+            println!(
+                "  {} <id>   - Revert files to before checkpoint <id>",
+                "/restore".cyan()
+            );
             println!(
                 "  {}          - Clear conversation history",
                 "/clear".cyan()
             );
             println!("  {}           - Show this help", "/help".cyan());
+            println!(
+                "  {}    - Attach an image to the message (vision models)",
+                "@<file.png>".dimmed()
+            );
             println!("  {}           - Exit", "Ctrl+D".dimmed());
             SlashResult::Handled
         }
@@ -1156,6 +1872,22 @@ fn handle_slash_command(
             run_doctor_command(cfg, current_model);
             SlashResult::Handled
         }
+        "/undo" => {
+            run_undo(agent);
+            SlashResult::Handled
+        }
+        "/checkpoints" => {
+            run_checkpoints_list(agent);
+            SlashResult::Handled
+        }
+        "/restore" => {
+            if arg.is_empty() {
+                eprintln!("{} usage: /restore <id>", "Error:".red());
+                return SlashResult::Handled;
+            }
+            run_restore(agent, arg);
+            SlashResult::Handled
+        }
         "/agent" => {
             if arg.is_empty() {
                 eprintln!("{} usage: /agent <task>", "Error:".red());
@@ -1241,6 +1973,20 @@ fn run_doctor_command(cfg: &Config, active_model: &str) {
     }
 }
 
+/// Approval for runs nobody is watching: yes in yolo mode, no otherwise.
+/// Writes outside a confined workspace are always refused, since they
+/// must be approved by a person even in yolo mode.
+fn unattended_approval(
+    yolo: bool,
+    workspace: Option<Workspace>,
+) -> impl FnMut(&str, &serde_json::Value) -> bool {
+    move |tool, args| {
+        yolo && !workspace
+            .as_ref()
+            .is_some_and(|w| w.writes_outside_root(tool, args))
+    }
+}
+
 /// Dispatch a `/agent <task>` slash invocation. Spawns a subagent with
 /// isolated memory + read-paths, prints the result inline, and returns
 /// to the parent REPL with parent state untouched.
@@ -1252,6 +1998,7 @@ fn run_agent_subtask(agent: &mut Agent, task: &str, cfg: &Config) {
     println!("{} {}", "Subagent:".cyan().bold(), task.dimmed());
 
     let yolo = matches!(cfg.agent.permission_mode, config::PermissionMode::Yolo);
+    let mut approve = unattended_approval(yolo, agent.config.workspace.clone());
     let streaming = cfg.llm.streaming;
 
     let result = if streaming {
@@ -1264,7 +2011,7 @@ fn run_agent_subtask(agent: &mut Agent, task: &str, cfg: &Config) {
                 }
                 eprint!("{}", token);
             },
-            &mut |_, _| yolo,
+            &mut approve,
         );
         if let Some(mut s) = spinner.take() {
             s.stop();
@@ -1272,7 +2019,7 @@ fn run_agent_subtask(agent: &mut Agent, task: &str, cfg: &Config) {
         eprintln!();
         r
     } else {
-        agent.run_subagent(task, &mut |_| {}, &mut |_, _| yolo)
+        agent.run_subagent(task, &mut |_| {}, &mut approve)
     };
 
     match result {
@@ -1363,8 +2110,99 @@ fn run_memories_forget(
     }
 }
 
+fn run_undo(agent: &Agent) {
+    let Some(store) = agent.checkpoints() else {
+        eprintln!("{} checkpoints are not enabled", "Error:".red());
+        return;
+    };
+    match store.undo() {
+        Ok(Some(checkpoint)) => {
+            println!(
+                "{} checkpoint {} ({})",
+                "Undid".green().bold(),
+                checkpoint.id,
+                checkpoint.label
+            );
+            for file in &checkpoint.files {
+                let action = if file.existed { "restored" } else { "removed" };
+                println!("  {} {}", action.dimmed(), file.path.display());
+            }
+            print_restore_hint();
+        }
+        Ok(None) => println!("{}", "Nothing to undo.".dimmed()),
+        Err(e) => eprintln!("{} {}", "Error:".red(), e),
+    }
+}
+
+fn run_checkpoints_list(agent: &Agent) {
+    let Some(store) = agent.checkpoints() else {
+        eprintln!("{} checkpoints are not enabled", "Error:".red());
+        return;
+    };
+    let checkpoints = store.list();
+    if checkpoints.is_empty() {
+        println!("{}", "No checkpoints for this project.".dimmed());
+        return;
+    }
+    println!("{}", "Checkpoints (newest last):".bold());
+    for checkpoint in checkpoints {
+        let when = chrono::DateTime::parse_from_rfc3339(&checkpoint.created_at)
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or(checkpoint.created_at);
+        println!(
+            "  {} {} {} {}",
+            format!("[{}]", checkpoint.id).cyan(),
+            when.dimmed(),
+            checkpoint.label,
+            format!("({} files)", checkpoint.files.len()).dimmed()
+        );
+    }
+}
+
+fn run_restore(agent: &Agent, id_str: &str) {
+    let Some(store) = agent.checkpoints() else {
+        eprintln!("{} checkpoints are not enabled", "Error:".red());
+        return;
+    };
+    let id: u64 = match id_str.parse() {
+        Ok(n) => n,
+        Err(_) => {
+            eprintln!("{} id must be a number, got {:?}", "Error:".red(), id_str);
+            return;
+        }
+    };
+    match store.restore(id) {
+        Ok(paths) => {
+            println!(
+                "{} {} file(s) to before checkpoint {}",
+                "Restored".green().bold(),
+                paths.len(),
+                id
+            );
+            for path in &paths {
+                println!("  {}", path.display());
+            }
+            print_restore_hint();
+        }
+        Err(e) => eprintln!("{} {}", "Error:".red(), e),
+    }
+}
+
+/// Files change under the model; the conversation does not.
+fn print_restore_hint() {
+    println!(
+        "{}",
+        "The conversation is unchanged; the model still remembers the edits.".dimmed()
+    );
+}
+
+/// How long one `/test` run may take before it is killed.
+const TEST_TIMEOUT_SECS: u64 = 1800;
+
 fn run_test_fix_loop(agent: &mut Agent, test_cmd: &str, cfg: &Config) {
     let max_fix_iterations = 5;
+    // Ctrl+C stops the test command or the fix turn, and the loop with it.
+    let cancel = agent.cancel_token();
 
     for iteration in 1..=max_fix_iterations {
         println!(
@@ -1375,10 +2213,18 @@ fn run_test_fix_loop(agent: &mut Agent, test_cmd: &str, cfg: &Config) {
             test_cmd.bright_white()
         );
 
-        // Run the test command
-        let output = std::process::Command::new("sh")
-            .args(["-c", test_cmd])
-            .output();
+        // Run the test command, in the same sandbox as `shell`
+        let output = tools::shell::run_command(
+            test_cmd,
+            None,
+            std::time::Duration::from_secs(TEST_TIMEOUT_SECS),
+            agent.config.sandbox.as_ref(),
+            &cancel,
+        );
+        if cancel.is_cancelled() {
+            println!("{}", "Test run cancelled.".yellow());
+            return;
+        }
 
         let output = match output {
             Ok(o) => o,
@@ -1388,21 +2234,21 @@ fn run_test_fix_loop(agent: &mut Agent, test_cmd: &str, cfg: &Config) {
             }
         };
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let combined = format!("{}{}", stdout, stderr);
-
-        if output.status.success() {
+        if output.success() {
             println!("{} All tests passed!", ">>".green().bold());
             return;
         }
 
         // Tests failed — show summary and ask agent to fix
-        println!(
-            "{} Tests failed (exit code: {})",
-            ">>".red(),
-            output.status.code().unwrap_or(-1)
-        );
+        match output.exit_code {
+            Some(code) => println!("{} Tests failed (exit code: {})", ">>".red(), code),
+            None => println!(
+                "{} Tests timed out after {} seconds",
+                ">>".red(),
+                TEST_TIMEOUT_SECS
+            ),
+        }
+        let combined = output.text;
 
         // Truncate output if too long
         let max_output = 4000;
@@ -1424,6 +2270,7 @@ fn run_test_fix_loop(agent: &mut Agent, test_cmd: &str, cfg: &Config) {
         println!("{} Asking agent to fix...\n", ">>".cyan());
 
         let streaming = cfg.llm.streaming;
+        let permissions = agent.permissions();
         let mut spinner = Some(agent::display::Spinner::start());
         let mut bot_prefix_printed = false;
         let response = agent.process_message_with_callbacks(
@@ -1440,7 +2287,7 @@ fn run_test_fix_loop(agent: &mut Agent, test_cmd: &str, cfg: &Config) {
                     eprint!("{}", token);
                 }
             },
-            &mut |tool_name, args| ask_approval(tool_name, args),
+            &mut |tool_name, args| ask_approval(tool_name, args, &permissions),
         );
         if let Some(mut s) = spinner.take() {
             s.stop();
@@ -1453,6 +2300,10 @@ fn run_test_fix_loop(agent: &mut Agent, test_cmd: &str, cfg: &Config) {
         } else {
             println!("{} {}", "bot>".green().bold(), response);
         }
+        if cancel.is_cancelled() {
+            println!("{}", "Turn cancelled.".yellow());
+            return;
+        }
 
         let _ = response; // Agent's fix is already applied via tools
     }
@@ -1506,7 +2357,11 @@ fn main() {
                 cli.continue_conv,
                 message,
                 cli.yolo,
-                cli.max_iterations,
+                BudgetFlags {
+                    max_iterations: cli.max_iterations,
+                    max_turn_tokens: cli.max_turn_tokens,
+                    max_turn_seconds: cli.max_turn_seconds,
+                },
             );
         }
     }
@@ -1532,6 +2387,13 @@ mod tests {
                 api_key: None,
                 streaming: false,
                 options,
+                retry: Default::default(),
+                fallbacks: Vec::new(),
+                tool_mode: Default::default(),
+                tool_call_format: Default::default(),
+                models: Default::default(),
+                roles: Default::default(),
+                cassette: None,
             },
             agent: CfgAgent {
                 max_iterations: 10,
@@ -1541,15 +2403,68 @@ mod tests {
                 compaction_token_threshold: abs,
                 compaction_token_threshold_ratio: ratio,
                 skills_dir: "~/.whet/skills".to_string(),
+                show_reasoning: true,
+                keep_reasoning: false,
+                max_turn_tokens: None,
+                max_turn_seconds: None,
+                policies: Default::default(),
+                verify: None,
             },
             memory: MemoryConfig {
                 database_path: ":memory:".to_string(),
                 max_inject_memories: 50,
             },
             mcp: McpConfig::default(),
+            permissions: Default::default(),
+            workspace: Default::default(),
+            sandbox: Default::default(),
         }
     }
 
+    #[test]
+    fn budget_answer_defaults_to_another_round() {
+        assert_eq!(parse_budget_answer("\n", 10), Some(10));
+        assert_eq!(parse_budget_answer("Y\n", 10), Some(10));
+        assert_eq!(parse_budget_answer("25\n", 10), Some(25));
+        assert_eq!(parse_budget_answer("n\n", 10), None);
+        assert_eq!(parse_budget_answer("0", 10), None);
+        assert_eq!(parse_budget_answer("later", 10), None);
+    }
+
+    #[test]
+    fn hunk_answer_defaults_to_skip() {
+        assert_eq!(parse_hunk_answer("\n"), Some(HunkAnswer::Skip));
+        assert_eq!(parse_hunk_answer("Y\n"), Some(HunkAnswer::Apply));
+        assert_eq!(parse_hunk_answer("a"), Some(HunkAnswer::ApplyRest));
+        assert_eq!(parse_hunk_answer("q"), Some(HunkAnswer::SkipRest));
+        assert_eq!(parse_hunk_answer("e"), Some(HunkAnswer::Edit));
+        assert_eq!(parse_hunk_answer("!"), Some(HunkAnswer::Always));
+        assert_eq!(parse_hunk_answer("?"), None);
+    }
+
+    #[test]
+    fn unattended_approval_refuses_only_writes_outside_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let shared = dir.path().join("shared");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&shared).unwrap();
+        let workspace = Workspace::new(&root, &[shared.display().to_string()]);
+        let outside = serde_json::json!({"path": shared.join("a.md").display().to_string()});
+        let inside = serde_json::json!({"path": "a.md"});
+        let push = serde_json::json!({"command": "push"});
+
+        let mut yolo = unattended_approval(true, Some(workspace.clone()));
+        assert!(yolo("git", &push), "ask rules are still approved in yolo");
+        assert!(yolo("write_file", &inside));
+        assert!(!yolo("write_file", &outside));
+        assert!(yolo("read_file", &outside));
+
+        let mut default = unattended_approval(false, Some(workspace));
+        assert!(!default("git", &push));
+        assert!(unattended_approval(true, None)("write_file", &outside));
+    }
+
     #[test]
     fn resolve_max_iterations_cli_overrides_config() {
         // The CLI flag wins when present. Lets a single research
@@ -1646,4 +2561,91 @@ mod tests {
         );
         assert_eq!(resolved, 7782);
     }
+
+    #[test]
+    fn probed_context_fills_unset_num_ctx_for_ratio() {
+        // 40 K advertised → capped at 32 K, then the 0.6 ratio applies.
+        let caps = ModelCapabilities {
+            context_length: Some(40960),
+            ..ModelCapabilities::default()
+        };
+        let cfg = with_capabilities(&make_cfg(None, 0.6, 5000), &caps);
+        assert_eq!(cfg.llm.options.num_ctx, Some(AUTO_NUM_CTX_CAP));
+        assert_eq!(resolve_compaction_threshold(&cfg), 19660);
+
+        let small = ModelCapabilities {
+            context_length: Some(8192),
+            ..ModelCapabilities::default()
+        };
+        let cfg = with_capabilities(&make_cfg(None, 0.6, 5000), &small);
+        assert_eq!(cfg.llm.options.num_ctx, Some(8192));
+    }
+
+    #[test]
+    fn probed_num_ctx_is_not_passed_to_fallbacks() {
+        let mut base = make_cfg(None, 0.6, 5000);
+        base.llm.fallbacks.push(config::LlmBackendConfig {
+            provider: "ollama".to_string(),
+            base_url: "http://backup:11434".to_string(),
+            model: Some("qwen3:1.7b".to_string()),
+            api_key: None,
+            options: None,
+        });
+        let caps = ModelCapabilities {
+            context_length: Some(40960),
+            ..ModelCapabilities::default()
+        };
+        let cfg = with_capabilities(&base, &caps);
+        assert_eq!(cfg.llm.options.num_ctx, Some(AUTO_NUM_CTX_CAP));
+        let fallback = cfg.llm.fallbacks[0].options.as_ref().unwrap();
+        assert_eq!(fallback.num_ctx, None);
+    }
+
+    #[test]
+    fn image_mentions_load_only_image_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let shot = dir.path().join("shot.png");
+        std::fs::write(&shot, b"\x89PNG").unwrap();
+        let input = format!(
+            "why is @{}, misaligned? cc @alice see @{}/gone.jpg",
+            shot.display(),
+            dir.path().display()
+        );
+        let (images, problems) = image_mentions(&input);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].name, "shot.png");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("gone.jpg"));
+        assert!(image_mentions("email me@example.com").0.is_empty());
+    }
+
+    #[test]
+    fn explicit_num_ctx_is_not_overridden_by_probe() {
+        let caps = ModelCapabilities {
+            context_length: Some(131072),
+            ..ModelCapabilities::default()
+        };
+        let cfg = with_capabilities(&make_cfg(Some(4096), 0.6, 5000), &caps);
+        assert_eq!(cfg.llm.options.num_ctx, Some(4096));
+    }
+
+    #[test]
+    fn think_is_dropped_for_models_without_thinking() {
+        let mut base = make_cfg(None, 0.0, 5000);
+        base.llm.options.think = Some(false);
+        let no_thinking = ModelCapabilities {
+            thinking: Some(false),
+            ..ModelCapabilities::default()
+        };
+        assert_eq!(
+            with_capabilities(&base, &no_thinking).llm.options.think,
+            None
+        );
+        // Unknown support leaves the user's setting alone.
+        let unknown = ModelCapabilities::default();
+        assert_eq!(
+            with_capabilities(&base, &unknown).llm.options.think,
+            Some(false)
+        );
+    }
 }
@@ -38,11 +38,19 @@ impl std::fmt::Display for McpError {
 impl McpClient {
     /// Spawn an MCP server process and perform the initialize handshake.
     pub fn new(name: &str, command: &str, args: &[String]) -> Result<Self, McpError> {
-        let mut child = Command::new(command)
-            .args(args)
+        let mut cmd = Command::new(command);
+        cmd.args(args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
+            .stderr(Stdio::null());
+        // Own process group, so the terminal's SIGINT on Ctrl+C (which
+        // only cancels the current turn) doesn't kill the server too.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+        let mut child = cmd
             .spawn()
             .map_err(|e| McpError::SpawnFailed(format!("Failed to spawn '{}': {}", command, e)))?;
 
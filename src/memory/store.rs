@@ -103,6 +103,9 @@ impl MemoryStore {
         let _ = self
             .conn
             .execute("ALTER TABLE conversations ADD COLUMN title TEXT", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE conversations ADD COLUMN todos TEXT", []);
         Ok(())
     }
 
@@ -250,6 +253,27 @@ impl MemoryStore {
         Ok(())
     }
 
+    /// Store the conversation's todo list, as JSON.
+    pub fn save_todos(&self, id: &str, todos_json: &str) -> SqliteResult<()> {
+        self.conn.execute(
+            "UPDATE conversations SET todos = ?1 WHERE id = ?2",
+            params![todos_json, id],
+        )?;
+        Ok(())
+    }
+
+    /// The todo list JSON saved with the conversation, if any.
+    pub fn load_todos(&self, id: &str) -> SqliteResult<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT todos FROM conversations WHERE id = ?1")?;
+        let mut rows = stmt.query(params![id])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
     pub fn list_conversations(&self, working_dir: &str) -> SqliteResult<Vec<ConversationSummary>> {
         let mut stmt = self.conn.prepare(
             "SELECT c.id, c.title, c.updated_at, COUNT(m.id) as msg_count
@@ -582,6 +606,18 @@ mod tests {
         assert_eq!(convs[0].title, Some("fix the bug in login".to_string()));
     }
 
+    #[test]
+    fn test_todos_saved_with_conversation() {
+        let store = MemoryStore::in_memory().unwrap();
+        store.create_conversation("conv-t", "/tmp").unwrap();
+        assert_eq!(store.load_todos("conv-t").unwrap(), None);
+        assert_eq!(store.load_todos("missing").unwrap(), None);
+
+        store.save_todos("conv-t", "[1]").unwrap();
+        store.save_todos("conv-t", "[2]").unwrap();
+        assert_eq!(store.load_todos("conv-t").unwrap().as_deref(), Some("[2]"));
+    }
+
     #[test]
     fn test_backward_compat_null_working_dir() {
         let store = MemoryStore::in_memory().unwrap();
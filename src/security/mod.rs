@@ -1 +1,4 @@
 pub mod path;
+pub mod permissions;
+pub mod sandbox;
+pub mod workspace;
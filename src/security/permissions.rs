@@ -0,0 +1,528 @@
+//! Evaluates `[permissions]` rules against tool calls.
+//!
+//! Rules come from three places:
+//!
+//! - `[permissions]` in the user's config.
+//! - `.whet/permissions.toml` in the project, with the same `[[deny]]` /
+//!   `[[ask]]` tables minus the `permissions.` prefix. Anyone who can
+//!   commit to the repository can write this file, so only its `deny`
+//!   and `ask` rules are used; its `allow` rules are ignored.
+//! - `~/.whet/permissions.toml`, where "always allow" answers are saved
+//!   under the project's absolute path, as `[[projects."<path>".allow]]`.
+//!
+//! Shell commands are matched per segment: `cargo test && rm -rf src`
+//! is two commands. An `allow` prefix or glob must match every segment
+//! and never matches command substitution or output redirection, so
+//! allowing `cargo test` doesn't allow whatever is chained after it.
+//! `deny` and `ask` rules fire if any segment matches.
+
+use crate::config::{PermissionRule, PermissionsConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Project-local rules file, relative to the project root.
+pub const PROJECT_FILE: &str = ".whet/permissions.toml";
+
+/// Saved "always allow" rules, relative to `~/.whet`.
+pub const SAVED_FILE: &str = "permissions.toml";
+
+/// Layout of the saved-rules file: rules per project root.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedRules {
+    #[serde(default)]
+    projects: BTreeMap<String, PermissionsConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Ask,
+    Deny,
+}
+
+pub struct Permissions {
+    rules: RefCell<PermissionsConfig>,
+    /// Where `always_allow` saves rules, and under which project key;
+    /// set by `load_saved`.
+    saved_file: RefCell<Option<(PathBuf, String)>>,
+}
+
+impl Permissions {
+    pub fn new(rules: PermissionsConfig) -> Self {
+        Self {
+            rules: RefCell::new(rules),
+            saved_file: RefCell::new(None),
+        }
+    }
+
+    /// Add the `deny` and `ask` rules from the project's rules file at
+    /// `path`. Returns how many `allow` rules it held, which are ignored.
+    /// A missing file adds nothing.
+    pub fn load_project_file(&self, path: &Path) -> Result<usize, String> {
+        let project = read_rules_file(path)?;
+        let mut rules = self.rules.borrow_mut();
+        rules.deny.extend(project.deny);
+        rules.ask.extend(project.ask);
+        Ok(project.allow.len())
+    }
+
+    /// Add the rules saved in `file` for the project at `project`, and
+    /// save later "always allow" answers there.
+    pub fn load_saved(&self, file: &Path, project: &Path) -> Result<(), String> {
+        let key = project.display().to_string();
+        let saved = read_saved_file(file)?
+            .projects
+            .remove(&key)
+            .unwrap_or_default();
+        *self.saved_file.borrow_mut() = Some((file.to_path_buf(), key));
+        let mut rules = self.rules.borrow_mut();
+        rules.allow.extend(saved.allow);
+        rules.deny.extend(saved.deny);
+        rules.ask.extend(saved.ask);
+        Ok(())
+    }
+
+    /// What the rules say about calling `tool` with `args`: `deny` wins
+    /// over `ask`, which wins over `allow`. None when no rule matches,
+    /// leaving it to the permission mode.
+    pub fn decide(&self, tool: &str, args: &Value) -> Option<Decision> {
+        let rules = self.rules.borrow();
+        if rules.deny.iter().any(|r| matches(r, tool, args, false)) {
+            Some(Decision::Deny)
+        } else if rules.ask.iter().any(|r| matches(r, tool, args, false)) {
+            Some(Decision::Ask)
+        } else if rules.allow.iter().any(|r| matches(r, tool, args, true)) {
+            Some(Decision::Allow)
+        } else {
+            None
+        }
+    }
+
+    /// Allow this exact call from now on and save the rule for this
+    /// project, if `load_saved` set a file. Returns the file written.
+    pub fn always_allow(&self, tool: &str, args: &Value) -> Result<Option<PathBuf>, String> {
+        let rule = exact_rule(tool, args);
+        self.rules.borrow_mut().allow.push(rule.clone());
+        let Some((path, key)) = self.saved_file.borrow().clone() else {
+            return Ok(None);
+        };
+        let mut saved = read_saved_file(&path)?;
+        saved.projects.entry(key).or_default().allow.push(rule);
+        let text = toml::to_string_pretty(&saved).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        std::fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Some(path))
+    }
+}
+
+fn read_rules_file(path: &Path) -> Result<PermissionsConfig, String> {
+    read_toml(path)
+}
+
+fn read_saved_file(path: &Path) -> Result<SavedRules, String> {
+    read_toml(path)
+}
+
+fn read_toml<T: Default + serde::de::DeserializeOwned>(path: &Path) -> Result<T, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// The narrowest rule covering this call: the exact shell command, the
+/// git subcommand with its exact arguments, or the tool on this one file.
+pub fn exact_rule(tool: &str, args: &Value) -> PermissionRule {
+    let mut rule = PermissionRule {
+        tool: Some(tool.to_string()),
+        ..Default::default()
+    };
+    match tool {
+        "shell" => rule.command = args["command"].as_str().map(str::to_string),
+        "git" => {
+            rule.git = args["command"].as_str().map(str::to_string);
+            rule.git_args = Some(normalize_args(args["args"].as_str().unwrap_or("")));
+        }
+        _ => {
+            if let [path] = call_paths(tool, args).as_slice() {
+                rule.path = Some(escape_glob(path));
+            }
+        }
+    }
+    rule
+}
+
+/// Whether `rule` covers the call. `allow` rules (`all_segments`) must
+/// cover every shell segment and every file the call touches; the
+/// others need only one.
+fn matches(rule: &PermissionRule, tool: &str, args: &Value, all_segments: bool) -> bool {
+    if rule.tool.as_deref().is_some_and(|t| t != "*" && t != tool) {
+        return false;
+    }
+
+    let command = args["command"].as_str().unwrap_or("");
+    if let Some(exact) = &rule.command {
+        if tool != "shell" || command.trim() != exact.trim() {
+            return false;
+        }
+    }
+    if let Some(prefix) = &rule.command_prefix {
+        let prefix = prefix.trim();
+        let starts = |s: &str| {
+            s == prefix
+                || s.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with(' '))
+        };
+        if tool != "shell" || !command_matches(command, all_segments, starts) {
+            return false;
+        }
+    }
+    if let Some(glob) = &rule.command_glob {
+        let fits = |s: &str| glob_match(glob.trim(), s, false);
+        if tool != "shell" || !command_matches(command, all_segments, fits) {
+            return false;
+        }
+    }
+
+    if let Some(sub) = &rule.git {
+        if tool != "git" || command != sub {
+            return false;
+        }
+    }
+    if let Some(exact) = &rule.git_args {
+        let given = normalize_args(args["args"].as_str().unwrap_or(""));
+        if tool != "git" || given != normalize_args(exact) {
+            return false;
+        }
+    }
+
+    if let Some(pattern) = &rule.path {
+        let paths = call_paths(tool, args);
+        let hit = |p: &String| {
+            path_candidates(p)
+                .iter()
+                .any(|c| glob_match(pattern, c, true))
+        };
+        let ok = if all_segments {
+            !paths.is_empty() && paths.iter().all(hit)
+        } else {
+            paths.iter().any(hit)
+        };
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+fn normalize_args(args: &str) -> String {
+    args.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn command_matches(command: &str, all_segments: bool, matcher: impl Fn(&str) -> bool) -> bool {
+    let segments = shell_segments(command);
+    if all_segments {
+        !has_substitution_or_redirect(command)
+            && !segments.is_empty()
+            && segments.iter().all(|s| matcher(s))
+    } else {
+        segments.iter().any(|s| matcher(s))
+    }
+}
+
+/// Files a call reads or writes: every target of an `apply_diff`, else
+/// the `path` argument.
+fn call_paths(tool: &str, args: &Value) -> Vec<String> {
+    if tool == "apply_diff" {
+        return crate::tools::apply_diff::target_paths(args);
+    }
+    args["path"]
+        .as_str()
+        .map(|p| vec![p.to_string()])
+        .unwrap_or_default()
+}
+
+/// `path` as given, relative to the working directory, and absolute, so
+/// `src/**` and `/home/me/project/src/**` both match.
+fn path_candidates(path: &str) -> Vec<String> {
+    let given = path.strip_prefix("./").unwrap_or(path).to_string();
+    let mut out = vec![given.clone()];
+    if let Ok(cwd) = std::env::current_dir() {
+        let p = Path::new(&given);
+        if p.is_absolute() {
+            if let Ok(rel) = p.strip_prefix(&cwd) {
+                out.push(rel.to_string_lossy().into_owned());
+            }
+        } else {
+            out.push(cwd.join(p).to_string_lossy().into_owned());
+        }
+    }
+    out
+}
+
+/// The simple commands in a shell line, split at `;`, `&&`, `||`, `|`,
+/// `&` and newlines. Quotes are not parsed, which only ever splits more.
+fn shell_segments(command: &str) -> Vec<String> {
+    command
+        .replace("2>&1", "")
+        .split([';', '&', '|', '\n'])
+        .map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn has_substitution_or_redirect(command: &str) -> bool {
+    let command = command.replace("2>&1", "");
+    command.contains('`') || command.contains("$(") || command.contains('>')
+}
+
+fn escape_glob(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// `*` matches any run of characters, `?` one, `\` escapes the next.
+/// With `paths`, `*` and `?` stop at `/` and `**` crosses it (`**/`
+/// also matches no directories at all).
+fn glob_match(pattern: &str, text: &str, paths: bool) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    glob_at(&p, &t, paths)
+}
+
+fn glob_at(p: &[char], t: &[char], paths: bool) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        Some('*') => {
+            let double = paths && p.get(1) == Some(&'*');
+            let rest = if double { &p[2..] } else { &p[1..] };
+            if double && rest.first() == Some(&'/') && glob_at(&rest[1..], t, paths) {
+                return true;
+            }
+            for skip in 0..=t.len() {
+                if glob_at(rest, &t[skip..], paths) {
+                    return true;
+                }
+                if skip < t.len() && paths && !double && t[skip] == '/' {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => match t.first() {
+            Some('/') if paths => false,
+            Some(_) => glob_at(&p[1..], &t[1..], paths),
+            None => false,
+        },
+        Some('\\') if p.len() > 1 => t.first() == Some(&p[1]) && glob_at(&p[2..], &t[1..], paths),
+        Some(c) => t.first() == Some(c) && glob_at(&p[1..], &t[1..], paths),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(tool: Option<&str>) -> PermissionRule {
+        PermissionRule {
+            tool: tool.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn shell(command: &str) -> Value {
+        json!({ "command": command })
+    }
+
+    #[test]
+    fn test_glob_paths() {
+        assert!(glob_match("src/*.rs", "src/main.rs", true));
+        assert!(!glob_match("src/*.rs", "src/agent/mod.rs", true));
+        assert!(glob_match("src/**", "src/agent/mod.rs", true));
+        assert!(glob_match("**/.env", ".env", true));
+        assert!(glob_match("**/.env", "app/config/.env", true));
+        assert!(!glob_match("**/.env", "app/.envrc", true));
+        assert!(glob_match(&escape_glob("a*b?.txt"), "a*b?.txt", true));
+        assert!(!glob_match(&escape_glob("a*b.txt"), "axxb.txt", true));
+        assert!(glob_match("npm run *", "npm run test/unit", false));
+    }
+
+    #[test]
+    fn test_allow_prefix_covers_every_segment() {
+        let allow = PermissionRule {
+            command_prefix: Some("cargo test".to_string()),
+            ..rule(Some("shell"))
+        };
+        assert!(matches(&allow, "shell", &shell("cargo test"), true));
+        assert!(matches(
+            &allow,
+            "shell",
+            &shell("cargo test --lib 2>&1"),
+            true
+        ));
+        assert!(!matches(&allow, "shell", &shell("cargo tests"), true));
+        assert!(!matches(
+            &allow,
+            "shell",
+            &shell("cargo test && rm -rf /"),
+            true
+        ));
+        assert!(!matches(
+            &allow,
+            "shell",
+            &shell("cargo test $(rm x)"),
+            true
+        ));
+        assert!(!matches(
+            &allow,
+            "shell",
+            &shell("cargo test > src/main.rs"),
+            true
+        ));
+        assert!(!matches(
+            &allow,
+            "git",
+            &json!({"command": "cargo test"}),
+            true
+        ));
+
+        let deny = PermissionRule {
+            command_prefix: Some("rm".to_string()),
+            ..rule(None)
+        };
+        assert!(matches(&deny, "shell", &shell("ls; rm -rf build"), false));
+    }
+
+    #[test]
+    fn test_deny_beats_ask_beats_allow() {
+        let permissions = Permissions::new(PermissionsConfig {
+            allow: vec![rule(Some("*"))],
+            ask: vec![PermissionRule {
+                git: Some("push".to_string()),
+                ..rule(Some("git"))
+            }],
+            deny: vec![PermissionRule {
+                path: Some("**/.env".to_string()),
+                ..rule(None)
+            }],
+        });
+        let decide = |tool: &str, args: Value| permissions.decide(tool, &args);
+        assert_eq!(
+            decide("git", json!({"command": "status"})),
+            Some(Decision::Allow)
+        );
+        assert_eq!(
+            decide("git", json!({"command": "push"})),
+            Some(Decision::Ask)
+        );
+        assert_eq!(
+            decide("read_file", json!({"path": "./.env"})),
+            Some(Decision::Deny)
+        );
+        assert_eq!(
+            Permissions::new(PermissionsConfig::default()).decide("shell", &shell("ls")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_always_allow_git_keeps_args() {
+        let permissions = Permissions::new(PermissionsConfig::default());
+        let push = json!({"command": "push", "args": "origin  main"});
+        permissions.always_allow("git", &push).unwrap();
+        assert_eq!(permissions.decide("git", &push), Some(Decision::Allow));
+        assert_eq!(
+            permissions.decide("git", &json!({"command": "push", "args": "origin main"})),
+            Some(Decision::Allow)
+        );
+        assert_eq!(
+            permissions.decide("git", &json!({"command": "push", "args": "--force"})),
+            None
+        );
+        assert_eq!(
+            permissions.decide(
+                "git",
+                &json!({"command": "push", "args": "origin main --force"})
+            ),
+            None
+        );
+
+        permissions
+            .always_allow("git", &json!({"command": "push"}))
+            .unwrap();
+        assert_eq!(
+            permissions.decide("git", &json!({"command": "push", "args": ""})),
+            Some(Decision::Allow)
+        );
+        assert_eq!(
+            permissions.decide("git", &json!({"command": "push", "args": "--force"})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_project_file_cannot_allow() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(PROJECT_FILE);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(
+            &file,
+            "[[allow]]\ntool = \"*\"\n\n[[deny]]\ncommand_prefix = \"rm\"\n",
+        )
+        .unwrap();
+        let permissions = Permissions::new(PermissionsConfig::default());
+        assert_eq!(permissions.load_project_file(&file), Ok(1));
+        assert_eq!(permissions.decide("shell", &shell("ls")), None);
+        assert_eq!(
+            permissions.decide("shell", &shell("rm -rf build")),
+            Some(Decision::Deny)
+        );
+    }
+
+    #[test]
+    fn test_always_allow_saves_exact_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(SAVED_FILE);
+        let project = dir.path().join("project");
+        let permissions = Permissions::new(PermissionsConfig::default());
+        permissions.load_saved(&file, &project).unwrap();
+
+        let saved = permissions
+            .always_allow("shell", &shell("make check"))
+            .unwrap();
+        assert_eq!(saved.as_deref(), Some(file.as_path()));
+        assert_eq!(
+            permissions.decide("shell", &shell("make check")),
+            Some(Decision::Allow)
+        );
+        assert_eq!(permissions.decide("shell", &shell("make check; ls")), None);
+
+        let reloaded = Permissions::new(PermissionsConfig::default());
+        reloaded.load_saved(&file, &project).unwrap();
+        assert_eq!(
+            reloaded.decide("shell", &shell("make check")),
+            Some(Decision::Allow)
+        );
+        let elsewhere = Permissions::new(PermissionsConfig::default());
+        elsewhere
+            .load_saved(&file, &dir.path().join("other"))
+            .unwrap();
+        assert_eq!(elsewhere.decide("shell", &shell("make check")), None);
+        assert!(std::fs::read_to_string(&file)
+            .unwrap()
+            .contains("[[projects."));
+    }
+}
@@ -0,0 +1,327 @@
+//! Sandboxed command execution for `shell`, `/test` and the verify check.
+//!
+//! `check_command_safety` only rejects a list of known-bad patterns. With
+//! a `[sandbox]` profile the command itself runs in fresh user and mount
+//! namespaces (plus a network namespace for `offline`), where every mount
+//! is read-only except the project, the temp dir and `writable_dirs`.
+//!
+//! Two backends build the namespaces:
+//!
+//! - `bwrap` (bubblewrap) when it is installed: a read-only bind of `/`
+//!   with the writable directories bound on top.
+//! - `unshare` from util-linux otherwise: a small `sh` script binds the
+//!   writable directories onto themselves, then remounts every other
+//!   mount read-only before running the command.
+//!
+//! Resource limits go through `prlimit`. When a profile is set but
+//! neither backend is on `PATH`, commands are refused rather than run
+//! unconfined.
+
+use crate::config::{SandboxBackend, SandboxConfig, SandboxProfile};
+use crate::tools::ToolError;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Run by `sh -c` inside the new namespaces as
+/// `sh -c SCRIPT whet-sandbox <command> <writable dir>...`. If any step
+/// of the setup fails it exits with 125 instead of running the command.
+const UNSHARE_SCRIPT: &str = r#"cmd=$1
+shift
+for dir; do
+  mount --bind "$dir" "$dir" || exit 125
+done
+awk '{ print $5, $6 }' /proc/self/mountinfo | while read -r mp opts; do
+  mp=$(printf '%b' "$mp")
+  case "$mp" in /dev | /dev/* | /proc | /proc/*) continue ;; esac
+  case "$opts" in rw*) ;; *) continue ;; esac
+  for dir; do
+    case "$mp" in "$dir" | "$dir"/*) continue 2 ;; esac
+  done
+  mount -o remount,bind,ro "$mp" || {
+    echo "whet sandbox: cannot make $mp read-only" >&2
+    exit 125
+  }
+done || exit 125
+# Re-enter the working directory through the new mounts.
+cd "$PWD" || exit 125
+exec sh -c "$cmd""#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Bubblewrap,
+    Unshare,
+}
+
+impl Backend {
+    pub fn program(self) -> &'static str {
+        match self {
+            Backend::Bubblewrap => "bwrap",
+            Backend::Unshare => "unshare",
+        }
+    }
+}
+
+/// The backend `choice` resolves to, given which programs are on `PATH`.
+pub fn find_backend(choice: SandboxBackend, on_path: impl Fn(&str) -> bool) -> Option<Backend> {
+    let candidates: &[Backend] = match choice {
+        SandboxBackend::Auto => &[Backend::Bubblewrap, Backend::Unshare],
+        SandboxBackend::Bubblewrap => &[Backend::Bubblewrap],
+        SandboxBackend::Unshare => &[Backend::Unshare],
+    };
+    candidates
+        .iter()
+        .copied()
+        .find(|b| cfg!(target_os = "linux") && on_path(b.program()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sandbox {
+    profile: SandboxProfile,
+    /// None when no usable backend was found; every command is refused.
+    backend: Option<Backend>,
+    /// Canonical paths, the project first.
+    writable: Vec<PathBuf>,
+    /// `prlimit` options, empty when no limit is set.
+    limits: Vec<String>,
+}
+
+impl Sandbox {
+    /// The sandbox for `cfg`, or None when its profile is `off`.
+    /// `project` is the directory whet was started in.
+    pub fn new(cfg: &SandboxConfig, project: &Path) -> Option<Self> {
+        if cfg.profile == SandboxProfile::Off {
+            return None;
+        }
+        let backend = find_backend(cfg.backend, on_path);
+        Some(Self::with_backend(cfg, project, backend))
+    }
+
+    fn with_backend(cfg: &SandboxConfig, project: &Path, backend: Option<Backend>) -> Self {
+        let extra = cfg
+            .writable_dirs
+            .iter()
+            .map(|dir| match dir.strip_prefix('~') {
+                Some(rest) => dirs::home_dir()
+                    .map(|home| home.join(rest.trim_start_matches('/')))
+                    .unwrap_or_else(|| PathBuf::from(dir)),
+                None => PathBuf::from(dir),
+            });
+        let mut writable: Vec<PathBuf> = Vec::new();
+        // Directories that don't exist can't be bound; leave them out.
+        for dir in [project.to_path_buf(), std::env::temp_dir()]
+            .into_iter()
+            .chain(extra)
+            .filter_map(|dir| std::fs::canonicalize(dir).ok())
+        {
+            if !writable.contains(&dir) {
+                writable.push(dir);
+            }
+        }
+        let mut limits = Vec::new();
+        if let Some(mb) = cfg.memory_mb {
+            limits.push(format!("--as={}", mb.saturating_mul(1024 * 1024)));
+        }
+        if let Some(secs) = cfg.cpu_seconds {
+            limits.push(format!("--cpu={}", secs));
+        }
+        if let Some(n) = cfg.max_processes {
+            limits.push(format!("--nproc={}", n));
+        }
+        Self {
+            profile: cfg.profile,
+            backend,
+            writable,
+            limits,
+        }
+    }
+
+    pub fn backend(&self) -> Option<Backend> {
+        self.backend
+    }
+
+    /// One line for the startup banner and `/doctor`.
+    pub fn describe(&self) -> String {
+        match self.backend {
+            Some(backend) => format!("{} profile via {}", self.profile, backend.program()),
+            None => format!(
+                "{} profile, but neither bwrap nor unshare is available; \
+                 shell commands will be refused",
+                self.profile
+            ),
+        }
+    }
+
+    /// A `Command` that runs `command` with `sh -c` inside the sandbox.
+    /// The caller sets the working directory and stdio as usual.
+    pub fn command(&self, command: &str) -> Result<Command, ToolError> {
+        let argv = self.argv(command).ok_or_else(|| {
+            ToolError::PermissionDenied(format!(
+                "the [sandbox] {} profile needs bwrap or unshare on PATH; \
+                 the command was not run",
+                self.profile
+            ))
+        })?;
+        let mut cmd = Command::new(&argv[0]);
+        cmd.args(&argv[1..]);
+        Ok(cmd)
+    }
+
+    fn argv(&self, command: &str) -> Option<Vec<String>> {
+        let offline = self.profile == SandboxProfile::Offline;
+        let mut argv: Vec<String> = Vec::new();
+        if !self.limits.is_empty() {
+            argv.push("prlimit".into());
+            argv.extend(self.limits.iter().cloned());
+            argv.push("--".into());
+        }
+        match self.backend? {
+            Backend::Bubblewrap => {
+                argv.extend(
+                    [
+                        "bwrap",
+                        "--ro-bind",
+                        "/",
+                        "/",
+                        "--dev",
+                        "/dev",
+                        "--proc",
+                        "/proc",
+                    ]
+                    .map(String::from),
+                );
+                for dir in &self.writable {
+                    let dir = dir.display().to_string();
+                    argv.extend(["--bind".to_string(), dir.clone(), dir]);
+                }
+                argv.extend(["--unshare-pid", "--die-with-parent"].map(String::from));
+                if offline {
+                    argv.push("--unshare-net".into());
+                }
+                argv.extend(["--", "sh", "-c", command].map(String::from));
+            }
+            Backend::Unshare => {
+                argv.extend(
+                    [
+                        "unshare",
+                        "--user",
+                        "--map-root-user",
+                        "--mount",
+                        "--propagation",
+                        "private",
+                    ]
+                    .map(String::from),
+                );
+                if offline {
+                    argv.push("--net".into());
+                }
+                argv.extend(
+                    ["--", "sh", "-c", UNSHARE_SCRIPT, "whet-sandbox", command].map(String::from),
+                );
+                argv.extend(self.writable.iter().map(|d| d.display().to_string()));
+            }
+        }
+        Some(argv)
+    }
+}
+
+/// Whether `program` is somewhere on `$PATH`.
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(profile: SandboxProfile) -> SandboxConfig {
+        SandboxConfig {
+            profile,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_find_backend_prefers_bwrap() {
+        let both = |_: &str| true;
+        let unshare_only = |p: &str| p == "unshare";
+        assert_eq!(
+            find_backend(SandboxBackend::Auto, both),
+            Some(Backend::Bubblewrap)
+        );
+        assert_eq!(
+            find_backend(SandboxBackend::Auto, unshare_only),
+            Some(Backend::Unshare)
+        );
+        assert_eq!(find_backend(SandboxBackend::Bubblewrap, unshare_only), None);
+        assert!(Sandbox::new(&config(SandboxProfile::Off), Path::new(".")).is_none());
+    }
+
+    #[test]
+    fn test_argv_per_backend_and_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = std::fs::canonicalize(dir.path()).unwrap();
+        let mut cfg = config(SandboxProfile::Offline);
+        cfg.memory_mb = Some(1);
+        cfg.writable_dirs = vec!["/definitely/not/here".to_string()];
+
+        let bwrap = Sandbox::with_backend(&cfg, &project, Some(Backend::Bubblewrap));
+        let argv = bwrap.argv("make test").unwrap();
+        assert_eq!(argv[..3], ["prlimit", "--as=1048576", "--"]);
+        assert_eq!(argv[3], "bwrap");
+        let project = project.display().to_string();
+        assert!(argv
+            .windows(3)
+            .any(|w| w == ["--bind", project.as_str(), project.as_str()]));
+        assert!(argv.contains(&"--unshare-net".to_string()));
+        assert!(!argv.iter().any(|a| a.contains("definitely")));
+        assert_eq!(argv.last().unwrap(), "make test");
+
+        cfg.profile = SandboxProfile::Workspace;
+        cfg.memory_mb = None;
+        let unshare = Sandbox::with_backend(&cfg, Path::new(&project), Some(Backend::Unshare));
+        let argv = unshare.argv("make test").unwrap();
+        assert_eq!(argv[0], "unshare");
+        assert!(!argv.contains(&"--net".to_string()));
+        let command_at = argv.iter().position(|a| a == "make test").unwrap();
+        assert_eq!(argv[command_at + 1], project);
+
+        let missing = Sandbox::with_backend(&cfg, Path::new(&project), None);
+        assert!(missing.command("true").is_err());
+        assert!(missing.describe().contains("refused"));
+    }
+
+    #[test]
+    fn test_unshare_keeps_only_project_writable() {
+        let usable = on_path("unshare")
+            && Command::new("unshare")
+                .args(["--user", "--map-root-user", "--mount", "true"])
+                .output()
+                .is_ok_and(|o| o.status.success());
+        if !usable {
+            // No unprivileged user namespaces here; nothing to check.
+            return;
+        }
+        let project = tempfile::tempdir().unwrap();
+        let home = dirs::home_dir().unwrap();
+        let cfg = config(SandboxProfile::Workspace);
+        let sandbox = Sandbox::with_backend(&cfg, project.path(), Some(Backend::Unshare));
+        let probe = home.join(format!(".whet-sandbox-probe-{}", std::process::id()));
+        let script = format!(
+            "touch inside && echo wrote; touch '{}' 2>/dev/null || echo blocked",
+            probe.display()
+        );
+        let output = sandbox
+            .command(&script)
+            .unwrap()
+            .current_dir(project.path())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("wrote"), "{:?}", output);
+        assert!(stdout.contains("blocked"), "{:?}", output);
+        assert!(project.path().join("inside").exists());
+        assert!(!probe.exists());
+        assert_ne!(output.status.code(), Some(125));
+    }
+}
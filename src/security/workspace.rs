@@ -0,0 +1,200 @@
+//! Workspace confinement for the file tools.
+//!
+//! With `[workspace] confine = true`, `read_file`, `write_file`,
+//! `edit_file`, `apply_diff`, `list_dir`, `grep` and `repo_map` only
+//! reach paths inside the project root (the directory whet started in)
+//! or one of `allowed_dirs`. Paths are compared after resolving
+//! symlinks and `..`, so a link inside the project that points out of
+//! it counts as outside. Writes to an allowed directory outside the
+//! root always need approval, whatever the permission mode.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Tools whose `path` argument (or diff targets) is confined.
+pub const CONFINED_TOOLS: &[&str] = &[
+    "read_file",
+    "write_file",
+    "edit_file",
+    "apply_diff",
+    "list_dir",
+    "grep",
+    "repo_map",
+];
+
+const WRITE_TOOLS: &[&str] = &["write_file", "edit_file", "apply_diff"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Inside,
+    /// In one of `allowed_dirs`, outside the root.
+    Allowed,
+    Outside,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workspace {
+    root: PathBuf,
+    allowed: Vec<PathBuf>,
+}
+
+impl Workspace {
+    /// `root` and `allowed` are resolved once here; an allowed directory
+    /// may start with `~`.
+    pub fn new(root: &Path, allowed: &[String]) -> Self {
+        let allowed = allowed
+            .iter()
+            .map(|dir| match dir.strip_prefix('~') {
+                Some(rest) => dirs::home_dir()
+                    .map(|home| home.join(rest.trim_start_matches('/')))
+                    .unwrap_or_else(|| PathBuf::from(dir)),
+                None => PathBuf::from(dir),
+            })
+            .map(|dir| resolve(&dir, root))
+            .collect();
+        Self {
+            root: resolve(root, root),
+            allowed,
+        }
+    }
+
+    /// Where `path` lands. Relative paths are taken from the root, which
+    /// is also the working directory the tools resolve them against.
+    pub fn access(&self, path: &str) -> Access {
+        let resolved = resolve(Path::new(path), &self.root);
+        if resolved.starts_with(&self.root) {
+            Access::Inside
+        } else if self.allowed.iter().any(|dir| resolved.starts_with(dir)) {
+            Access::Allowed
+        } else {
+            Access::Outside
+        }
+    }
+
+    /// Why `tool` may not run with `args`, if a path it touches is
+    /// outside the workspace.
+    pub fn violation(&self, tool: &str, args: &serde_json::Value) -> Option<String> {
+        if !CONFINED_TOOLS.contains(&tool) {
+            return None;
+        }
+        let outside = confined_paths(tool, args)
+            .into_iter()
+            .find(|p| self.access(p) == Access::Outside)?;
+        Some(format!(
+            "Tool error: Permission denied: '{}' is outside the workspace ({}). \
+             Only files under the project root or [workspace] allowed_dirs can be used.",
+            outside,
+            self.root.display()
+        ))
+    }
+
+    /// Whether `tool` would write somewhere outside the root.
+    pub fn writes_outside_root(&self, tool: &str, args: &serde_json::Value) -> bool {
+        WRITE_TOOLS.contains(&tool)
+            && confined_paths(tool, args)
+                .iter()
+                .any(|p| self.access(p) != Access::Inside)
+    }
+}
+
+/// The paths a confined tool call touches. The directory tools default
+/// to the working directory when `path` is left out.
+fn confined_paths(tool: &str, args: &serde_json::Value) -> Vec<String> {
+    if tool == "apply_diff" {
+        return crate::tools::apply_diff::target_paths(args);
+    }
+    vec![args["path"].as_str().unwrap_or(".").to_string()]
+}
+
+/// `path` made absolute against `base`, with symlinks and `..` resolved.
+/// The part that doesn't exist yet (a file about to be created) is
+/// resolved lexically on top of the deepest existing ancestor.
+fn resolve(path: &Path, base: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    };
+    let mut resolved = PathBuf::new();
+    let mut components = absolute.components();
+    for component in components.by_ref() {
+        let next = resolved.join(component);
+        match std::fs::canonicalize(&next) {
+            Ok(canonical) => resolved = canonical,
+            Err(_) => {
+                push_lexical(&mut resolved, component);
+                break;
+            }
+        }
+    }
+    for component in components {
+        push_lexical(&mut resolved, component);
+    }
+    resolved
+}
+
+fn push_lexical(path: &mut PathBuf, component: Component) {
+    match component {
+        Component::CurDir => {}
+        Component::ParentDir => {
+            path.pop();
+        }
+        other => path.push(other.as_os_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_access_resolves_dots_and_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let outside = dir.path().join("elsewhere");
+        let shared = dir.path().join("shared");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&shared).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+        let ws = Workspace::new(&root, &[shared.display().to_string()]);
+        assert_eq!(ws.access("src/main.rs"), Access::Inside);
+        assert_eq!(ws.access("src/new/../new.rs"), Access::Inside);
+        assert_eq!(ws.access("../elsewhere/x"), Access::Outside);
+        assert_eq!(
+            ws.access("src/missing/../../../elsewhere/x"),
+            Access::Outside
+        );
+        assert_eq!(ws.access("link/secret.txt"), Access::Outside);
+        assert_eq!(
+            ws.access(&shared.join("notes.md").display().to_string()),
+            Access::Allowed
+        );
+        assert_eq!(ws.access("/etc/hosts"), Access::Outside);
+    }
+
+    #[test]
+    fn test_violation_and_outside_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        std::fs::create_dir_all(&shared).unwrap();
+        let root = dir.path().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let ws = Workspace::new(&root, &[shared.display().to_string()]);
+        let shared_file = shared.join("a.txt").display().to_string();
+
+        assert!(ws.violation("list_dir", &json!({})).is_none());
+        assert!(ws.violation("grep", &json!({"path": "/etc"})).is_some());
+        assert!(ws
+            .violation("shell", &json!({"command": "cat /etc/hosts"}))
+            .is_none());
+        assert!(ws
+            .violation("read_file", &json!({ "path": shared_file }))
+            .is_none());
+
+        assert!(ws.writes_outside_root("write_file", &json!({ "path": shared_file })));
+        assert!(!ws.writes_outside_root("read_file", &json!({ "path": shared_file })));
+        assert!(!ws.writes_outside_root("write_file", &json!({"path": "a.txt"})));
+    }
+}
@@ -68,37 +68,8 @@ impl Tool for ApplyDiffTool {
             if group.hunks.is_empty() {
                 continue;
             }
-            // Use the per-group `--- path` when it resolves to a real file; otherwise
-            // fall back to the JSON `path` argument. This preserves backwards-compat
-            // with diffs that use dummy `a/file.txt` markers while still letting
-            // multi-file diffs route hunks to their real files.
-            let target = match group.file_path.as_deref() {
-                Some(p) if std::fs::metadata(p).is_ok() => p,
-                _ => default_path,
-            };
-
-            if !is_path_safe(target) {
-                return Err(ToolError::PermissionDenied(format!(
-                    "Access to '{}' is blocked for security",
-                    target
-                )));
-            }
-
-            let file_size = std::fs::metadata(target).map_err(|e| {
-                ToolError::ExecutionFailed(format!("Failed to read '{}': {}", target, e))
-            })?;
-            if file_size.len() > MAX_FILE_SIZE {
-                return Err(ToolError::ExecutionFailed(format!(
-                    "File '{}' is too large ({} bytes, max {} bytes)",
-                    target,
-                    file_size.len(),
-                    MAX_FILE_SIZE
-                )));
-            }
-
-            let content = std::fs::read_to_string(target).map_err(|e| {
-                ToolError::ExecutionFailed(format!("Failed to read '{}': {}", target, e))
-            })?;
+            let target = resolve_target(group.file_path.as_deref(), default_path);
+            let content = read_target(target)?;
 
             let (outcomes, new_content) = apply_hunks(&content, &group.hunks);
 
@@ -203,6 +174,100 @@ enum HunkOutcome {
     Failed { old_start: usize, reason: String },
 }
 
+/// The file a group of hunks applies to. Use the per-group `--- path` when it
+/// resolves to a real file; otherwise fall back to the JSON `path` argument.
+/// This preserves backwards-compat with diffs that use dummy `a/file.txt`
+/// markers while still letting multi-file diffs route hunks to their real files.
+fn resolve_target<'a>(group_path: Option<&'a str>, default_path: &'a str) -> &'a str {
+    match group_path {
+        Some(p) if std::fs::metadata(p).is_ok() => p,
+        _ => default_path,
+    }
+}
+
+/// Safety and size checks, then the file's contents.
+fn read_target(target: &str) -> Result<String, ToolError> {
+    if !is_path_safe(target) {
+        return Err(ToolError::PermissionDenied(format!(
+            "Access to '{}' is blocked for security",
+            target
+        )));
+    }
+
+    let file_size = std::fs::metadata(target)
+        .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", target, e)))?;
+    if file_size.len() > MAX_FILE_SIZE {
+        return Err(ToolError::ExecutionFailed(format!(
+            "File '{}' is too large ({} bytes, max {} bytes)",
+            target,
+            file_size.len(),
+            MAX_FILE_SIZE
+        )));
+    }
+
+    std::fs::read_to_string(target)
+        .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", target, e)))
+}
+
+/// What each file would look like after the diff, as `(path, old, new)`
+/// in diff order, without writing anything. Fails if any hunk doesn't
+/// apply; `execute` gives the detailed report in that case.
+pub fn proposed_contents(
+    args: &serde_json::Value,
+) -> Result<Vec<(String, String, String)>, ToolError> {
+    let default_path = args["path"]
+        .as_str()
+        .ok_or_else(|| ToolError::InvalidArguments("missing 'path' argument".to_string()))?;
+    let diff = args["diff"]
+        .as_str()
+        .ok_or_else(|| ToolError::InvalidArguments("missing 'diff' argument".to_string()))?;
+
+    let mut files: Vec<(String, String, String)> = Vec::new();
+    for group in parse_unified_diff(diff)? {
+        if group.hunks.is_empty() {
+            continue;
+        }
+        let target = resolve_target(group.file_path.as_deref(), default_path);
+        // A second group for the same file applies on top of the first,
+        // as it does when `execute` writes between groups.
+        let existing = files.iter().position(|(path, _, _)| path == target);
+        let current = match existing {
+            Some(i) => files[i].2.clone(),
+            None => read_target(target)?,
+        };
+        let (_, new_content) = apply_hunks(&current, &group.hunks);
+        let new_content = new_content.ok_or_else(|| {
+            ToolError::ExecutionFailed(format!("Some hunks do not apply to '{}'", target))
+        })?;
+        match existing {
+            Some(i) => files[i].2 = new_content,
+            None => files.push((target.to_string(), current, new_content)),
+        }
+    }
+    if files.is_empty() {
+        return Err(ToolError::InvalidArguments(
+            "No valid hunks found in diff".to_string(),
+        ));
+    }
+    Ok(files)
+}
+
+/// Files an `apply_diff` call would write, for snapshotting them first.
+/// Empty when the arguments don't parse; the call itself will fail then.
+pub fn target_paths(args: &serde_json::Value) -> Vec<String> {
+    let (Some(default_path), Some(diff)) = (args["path"].as_str(), args["diff"].as_str()) else {
+        return Vec::new();
+    };
+    let mut targets: Vec<String> = Vec::new();
+    for group in parse_unified_diff(diff).unwrap_or_default() {
+        let target = resolve_target(group.file_path.as_deref(), default_path);
+        if !group.hunks.is_empty() && !targets.iter().any(|t| t == target) {
+            targets.push(target.to_string());
+        }
+    }
+    targets
+}
+
 /// Parse a unified diff into groups by file. Each `--- <path>` header starts a new
 /// group; hunks before the first header (or in diffs without headers) end up in a
 /// single group with `file_path = None`.
@@ -955,6 +1020,45 @@ mod tests {
         cleanup(path_b);
     }
 
+    #[test]
+    fn test_target_paths_follow_file_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        fs::write(&a, "x\n").unwrap();
+        let diff = format!(
+            "--- {a}\n+++ {a}\n@@ -1,1 +1,1 @@\n-x\n+y\n--- b/missing.txt\n+++ b/missing.txt\n@@ -1,1 +1,1 @@\n-p\n+q\n",
+            a = a.display()
+        );
+        let targets = target_paths(&json!({"path": "fallback.txt", "diff": diff}));
+        assert_eq!(
+            targets,
+            vec![a.display().to_string(), "fallback.txt".to_string()]
+        );
+        assert!(target_paths(&json!({"path": "x"})).is_empty());
+    }
+
+    #[test]
+    fn test_proposed_contents_leaves_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        fs::write(&a, "one\ntwo\n").unwrap();
+        let path = a.display().to_string();
+        let diff = "@@ -1,1 +1,1 @@\n-one\n+ONE\n@@ -2,1 +2,1 @@\n-two\n+TWO\n";
+        let files = proposed_contents(&json!({"path": path, "diff": diff})).unwrap();
+        assert_eq!(
+            files,
+            vec![(
+                path.clone(),
+                "one\ntwo\n".to_string(),
+                "ONE\nTWO\n".to_string()
+            )]
+        );
+        assert_eq!(fs::read_to_string(&a).unwrap(), "one\ntwo\n");
+
+        let bad = "@@ -1,1 +1,1 @@\n-nope\n+x\n";
+        assert!(proposed_contents(&json!({"path": path, "diff": bad})).is_err());
+    }
+
     #[test]
     fn test_apply_diff_multi_file_inconsistent_default() {
         // The default `path` argument and the diff's first file header may disagree.
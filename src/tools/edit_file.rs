@@ -54,91 +54,130 @@ impl Tool for EditFileTool {
     }
 
     fn execute(&self, args: serde_json::Value) -> Result<String, ToolError> {
-        let path = args["path"]
-            .as_str()
-            .ok_or_else(|| ToolError::InvalidArguments("missing 'path' argument".to_string()))?;
-        let old_text = args["old_text"].as_str().ok_or_else(|| {
-            ToolError::InvalidArguments("missing 'old_text' argument".to_string())
-        })?;
-        let new_text = args["new_text"].as_str().ok_or_else(|| {
-            ToolError::InvalidArguments("missing 'new_text' argument".to_string())
+        let plan = plan_edit(&args)?;
+        std::fs::write(&plan.path, &plan.new_content).map_err(|e| {
+            ToolError::ExecutionFailed(format!("Failed to write '{}': {}", plan.path, e))
         })?;
+        let context = get_context(&plan.new_content, plan.preview_pos, plan.preview_len);
+        Ok(format!(
+            "Successfully edited '{}'{}. Context around change:\n{}",
+            plan.path,
+            if plan.fuzzy {
+                " (fuzzy whitespace match)"
+            } else {
+                ""
+            },
+            context
+        ))
+    }
+}
 
-        if !is_path_safe(path) {
-            return Err(ToolError::PermissionDenied(format!(
-                "Access to '{}' is blocked for security",
-                path
-            )));
-        }
+/// An edit worked out but not yet written.
+struct EditPlan {
+    path: String,
+    old_content: String,
+    new_content: String,
+    /// Byte range of the replacement in `new_content`, for the preview.
+    preview_pos: usize,
+    preview_len: usize,
+    fuzzy: bool,
+}
 
-        let file_size = std::fs::metadata(path)
-            .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))?
-            .len();
-        if file_size > MAX_FILE_SIZE {
-            return Err(ToolError::ExecutionFailed(format!(
-                "File '{}' is too large ({} bytes, max {} bytes)",
-                path, file_size, MAX_FILE_SIZE
-            )));
+/// Read the target and locate `old_text` in it, without writing.
+fn plan_edit(args: &serde_json::Value) -> Result<EditPlan, ToolError> {
+    let path = args["path"]
+        .as_str()
+        .ok_or_else(|| ToolError::InvalidArguments("missing 'path' argument".to_string()))?;
+    let old_text = args["old_text"]
+        .as_str()
+        .ok_or_else(|| ToolError::InvalidArguments("missing 'old_text' argument".to_string()))?;
+    let new_text = args["new_text"]
+        .as_str()
+        .ok_or_else(|| ToolError::InvalidArguments("missing 'new_text' argument".to_string()))?;
+
+    if !is_path_safe(path) {
+        return Err(ToolError::PermissionDenied(format!(
+            "Access to '{}' is blocked for security",
+            path
+        )));
+    }
+
+    let file_size = std::fs::metadata(path)
+        .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))?
+        .len();
+    if file_size > MAX_FILE_SIZE {
+        return Err(ToolError::ExecutionFailed(format!(
+            "File '{}' is too large ({} bytes, max {} bytes)",
+            path, file_size, MAX_FILE_SIZE
+        )));
+    }
+
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))?;
+
+    let (new_content, preview_pos, preview_len, fuzzy) = match locate_match(&content, old_text) {
+        MatchResult::Exact { byte_start } => {
+            let mut new_content = String::with_capacity(content.len() + new_text.len());
+            new_content.push_str(&content[..byte_start]);
+            new_content.push_str(new_text);
+            new_content.push_str(&content[byte_start + old_text.len()..]);
+            (new_content, byte_start, new_text.len(), false)
         }
-
-        let content = std::fs::read_to_string(path)
-            .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))?;
-
-        match locate_match(&content, old_text) {
-            MatchResult::Exact { byte_start } => {
-                let mut new_content = String::with_capacity(content.len() + new_text.len());
-                new_content.push_str(&content[..byte_start]);
-                new_content.push_str(new_text);
-                new_content.push_str(&content[byte_start + old_text.len()..]);
-                std::fs::write(path, &new_content).map_err(|e| {
-                    ToolError::ExecutionFailed(format!("Failed to write '{}': {}", path, e))
-                })?;
-                let context = get_context(&new_content, byte_start, new_text.len());
-                Ok(format!(
-                    "Successfully edited '{}'. Context around change:\n{}",
-                    path, context
-                ))
-            }
-            MatchResult::Fuzzy {
-                line_start,
-                line_count,
-            } => {
-                let mut content_lines: Vec<&str> = content.split('\n').collect();
-                let new_text_lines: Vec<&str> = new_text.split('\n').collect();
-                content_lines.splice(
-                    line_start..line_start + line_count,
-                    new_text_lines.iter().copied(),
-                );
-                let new_content = content_lines.join("\n");
-                std::fs::write(path, &new_content).map_err(|e| {
-                    ToolError::ExecutionFailed(format!("Failed to write '{}': {}", path, e))
-                })?;
-                let preview_pos = new_content
-                    .split('\n')
-                    .take(line_start)
-                    .map(|l| l.len() + 1)
-                    .sum::<usize>()
-                    .min(new_content.len());
-                let preview_len = new_text_lines.iter().map(|l| l.len() + 1).sum::<usize>();
-                let context = get_context(&new_content, preview_pos, preview_len);
-                Ok(format!(
-                    "Successfully edited '{}' (fuzzy whitespace match). Context around change:\n{}",
-                    path, context
-                ))
-            }
-            MatchResult::AmbiguousExact(n) => Err(ToolError::ExecutionFailed(format!(
+        MatchResult::Fuzzy {
+            line_start,
+            line_count,
+        } => {
+            let mut content_lines: Vec<&str> = content.split('\n').collect();
+            let new_text_lines: Vec<&str> = new_text.split('\n').collect();
+            content_lines.splice(
+                line_start..line_start + line_count,
+                new_text_lines.iter().copied(),
+            );
+            let new_content = content_lines.join("\n");
+            let preview_pos = new_content
+                .split('\n')
+                .take(line_start)
+                .map(|l| l.len() + 1)
+                .sum::<usize>()
+                .min(new_content.len());
+            let preview_len = new_text_lines.iter().map(|l| l.len() + 1).sum::<usize>();
+            (new_content, preview_pos, preview_len, true)
+        }
+        MatchResult::AmbiguousExact(n) => {
+            return Err(ToolError::ExecutionFailed(format!(
                 "old_text appears {} times; provide more context to make it unique",
                 n
-            ))),
-            MatchResult::AmbiguousFuzzy(n) => Err(ToolError::ExecutionFailed(format!(
+            )))
+        }
+        MatchResult::AmbiguousFuzzy(n) => {
+            return Err(ToolError::ExecutionFailed(format!(
                 "old_text matched {} locations after whitespace normalization; provide more context to disambiguate",
                 n
-            ))),
-            MatchResult::NotFound => Err(ToolError::ExecutionFailed(
-                "old_text not found in file (tried exact and whitespace-normalized matching)".to_string(),
-            )),
+            )))
         }
-    }
+        MatchResult::NotFound => {
+            return Err(ToolError::ExecutionFailed(
+                "old_text not found in file (tried exact and whitespace-normalized matching)"
+                    .to_string(),
+            ))
+        }
+    };
+
+    Ok(EditPlan {
+        path: path.to_string(),
+        old_content: content,
+        new_content,
+        preview_pos,
+        preview_len,
+        fuzzy,
+    })
+}
+
+/// The file's current and edited contents, as `(path, old, new)`, for
+/// reviewing the change before `execute` writes it.
+pub fn proposed_content(args: &serde_json::Value) -> Result<(String, String, String), ToolError> {
+    let plan = plan_edit(args)?;
+    Ok((plan.path, plan.old_content, plan.new_content))
 }
 
 /// Locate `old_text` in `content`, falling back from exact to per-line trim match.
@@ -8,10 +8,12 @@ pub mod remember;
 pub mod repo_map;
 pub mod shell;
 pub mod subagent;
+pub mod todo;
 pub mod web_fetch;
 pub mod web_search;
 pub mod write_file;
 
+use crate::cancel::CancelToken;
 use crate::config::ToolRiskLevel;
 use crate::llm::ToolDefinition;
 use std::collections::HashMap;
@@ -42,6 +44,17 @@ pub trait Tool: Send + Sync {
     fn parameters_schema(&self) -> serde_json::Value;
     fn execute(&self, args: serde_json::Value) -> Result<String, ToolError>;
 
+    /// Execute with a cancellation token. Tools that can run for a long
+    /// time (shell commands) override this to stop early when the user
+    /// presses Ctrl+C; the default just runs `execute` to completion.
+    fn execute_cancellable(
+        &self,
+        args: serde_json::Value,
+        _cancel: &CancelToken,
+    ) -> Result<String, ToolError> {
+        self.execute(args)
+    }
+
     /// Risk level of this tool. Determines when user approval is required.
     /// Default: classify by tool name.
     fn risk_level(&self) -> ToolRiskLevel {
@@ -72,9 +85,13 @@ impl ToolRegistry {
         }
     }
 
+    /// Add `tool`, or replace the one already registered under its name
+    /// while keeping its place in the order.
     pub fn register(&mut self, tool: Box<dyn Tool>) {
         let name = tool.name().to_string();
-        self.order.push(name.clone());
+        if !self.tools.contains_key(&name) {
+            self.order.push(name.clone());
+        }
         self.tools.insert(name, tool);
         // Invalidate caches
         self.rebuild_caches();
@@ -85,6 +102,57 @@ impl ToolRegistry {
         self.tools.get(name).map(|t| t.as_ref())
     }
 
+    /// Run independent calls concurrently on at most `max_threads`
+    /// worker threads, returning the results in call order. Meant for
+    /// `Safe` tools, whose calls can't affect each other's outcome. A
+    /// tool that panics fails its own call instead of the whole batch.
+    pub fn execute_parallel(
+        &self,
+        calls: &[(&str, &serde_json::Value)],
+        max_threads: usize,
+        cancel: &CancelToken,
+    ) -> Vec<Result<String, ToolError>> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Mutex;
+
+        let next = AtomicUsize::new(0);
+        let results: Vec<Mutex<Option<Result<String, ToolError>>>> =
+            calls.iter().map(|_| Mutex::new(None)).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..max_threads.clamp(1, calls.len().max(1)) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(&(name, args)) = calls.get(i) else {
+                        break;
+                    };
+                    let result = match self.get(name) {
+                        Some(tool) => {
+                            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                tool.execute_cancellable(args.clone(), cancel)
+                            }))
+                            .unwrap_or_else(|_| {
+                                Err(ToolError::ExecutionFailed(format!("{} panicked", name)))
+                            })
+                        }
+                        None => Err(ToolError::ExecutionFailed(format!(
+                            "Unknown tool: {}",
+                            name
+                        ))),
+                    };
+                    *results[i].lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+                });
+            }
+        });
+        results
+            .into_iter()
+            .map(|slot| {
+                slot.into_inner()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .expect("every call is run by some worker")
+            })
+            .collect()
+    }
+
     pub fn list(&self) -> Vec<&dyn Tool> {
         self.order
             .iter()
@@ -140,7 +208,7 @@ pub fn default_registry() -> ToolRegistry {
     registry.register(Box::new(read_file::ReadFileTool));
     registry.register(Box::new(list_dir::ListDirTool));
     registry.register(Box::new(write_file::WriteFileTool));
-    registry.register(Box::new(shell::ShellTool));
+    registry.register(Box::new(shell::ShellTool::default()));
     registry.register(Box::new(grep::GrepTool));
     registry.register(Box::new(edit_file::EditFileTool));
     registry.register(Box::new(git::GitTool));
@@ -148,6 +216,7 @@ pub fn default_registry() -> ToolRegistry {
     registry.register(Box::new(apply_diff::ApplyDiffTool));
     registry.register(Box::new(subagent::SubagentTool));
     registry.register(Box::new(remember::RememberTool));
+    registry.register(Box::new(todo::TodoTool));
     registry
 }
 
@@ -166,7 +235,24 @@ mod tests {
     fn test_registry_register_and_list() {
         let registry = default_registry();
         let tools = registry.list();
-        assert_eq!(tools.len(), 11);
+        assert_eq!(tools.len(), 12);
+    }
+
+    #[test]
+    fn test_registry_register_replaces_in_place() {
+        let mut registry = default_registry();
+        let before: Vec<String> = registry
+            .list()
+            .iter()
+            .map(|t| t.name().to_string())
+            .collect();
+        registry.register(Box::new(shell::ShellTool::default()));
+        let after: Vec<String> = registry
+            .list()
+            .iter()
+            .map(|t| t.name().to_string())
+            .collect();
+        assert_eq!(before, after);
     }
 
     #[test]
@@ -186,11 +272,95 @@ mod tests {
         assert!(registry.get("nonexistent").is_none());
     }
 
+    /// Sleeps, then echoes its `n` argument; tracks peak concurrency.
+    #[derive(Default)]
+    struct SlowEcho {
+        running: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        peak: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Tool for SlowEcho {
+        fn name(&self) -> &str {
+            "slow_echo"
+        }
+        fn description(&self) -> &str {
+            "test"
+        }
+        fn parameters_schema(&self) -> serde_json::Value {
+            json!({"type": "object"})
+        }
+        fn execute(&self, args: serde_json::Value) -> Result<String, ToolError> {
+            use std::sync::atomic::Ordering;
+            let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(args["n"].to_string())
+        }
+    }
+
+    /// Panics when called with `{"boom": true}`.
+    struct Panicky;
+
+    impl Tool for Panicky {
+        fn name(&self) -> &str {
+            "panicky"
+        }
+        fn description(&self) -> &str {
+            "test"
+        }
+        fn parameters_schema(&self) -> serde_json::Value {
+            json!({"type": "object"})
+        }
+        fn execute(&self, args: serde_json::Value) -> Result<String, ToolError> {
+            assert!(args["boom"] != json!(true), "boom");
+            Ok("fine".to_string())
+        }
+    }
+
+    #[test]
+    fn test_execute_parallel_contains_panics() {
+        let mut registry = ToolRegistry::new();
+        registry.register(Box::new(Panicky));
+        let (boom, calm) = (json!({"boom": true}), json!({}));
+        let calls = [("panicky", &calm), ("panicky", &boom), ("panicky", &calm)];
+        let results = registry.execute_parallel(&calls, 2, &CancelToken::new());
+        assert_eq!(results[0].as_deref().unwrap(), "fine");
+        assert!(results[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("panicked"));
+        assert_eq!(results[2].as_deref().unwrap(), "fine");
+    }
+
+    #[test]
+    fn test_execute_parallel_keeps_call_order() {
+        let echo = SlowEcho::default();
+        let peak = std::sync::Arc::clone(&echo.peak);
+        let mut registry = ToolRegistry::new();
+        registry.register(Box::new(echo));
+        let args: Vec<serde_json::Value> = (0..6).map(|n| json!({"n": n})).collect();
+        let mut calls: Vec<(&str, &serde_json::Value)> =
+            args.iter().map(|a| ("slow_echo", a)).collect();
+        calls.push(("missing", &args[0]));
+        let results = registry.execute_parallel(&calls, 3, &CancelToken::new());
+        let texts: Vec<&str> = results[..6]
+            .iter()
+            .map(|r| r.as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(texts, vec!["0", "1", "2", "3", "4", "5"]);
+        assert!(results[6].is_err());
+        // Never more workers than asked for.
+        let peak = peak.load(std::sync::atomic::Ordering::SeqCst);
+        assert!((2..=3).contains(&peak), "peak concurrency {}", peak);
+    }
+
     #[test]
     fn test_registry_definitions() {
         let registry = default_registry();
         let defs = registry.definitions();
-        assert_eq!(defs.len(), 11);
+        assert_eq!(defs.len(), 12);
         for def in defs {
             assert!(!def.name.is_empty());
             assert!(!def.description.is_empty());
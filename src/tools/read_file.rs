@@ -4,6 +4,29 @@ use serde_json::json;
 
 const MAX_FILE_SIZE: u64 = 10_000_000; // 10MB
 
+/// Whether `path` may be read: it must not be a sensitive path and must
+/// be at most `max_bytes` long. Reading an image through `read_file`
+/// checks this too, with the attachment size limit.
+pub fn check_read(path: &str, max_bytes: u64) -> Result<(), ToolError> {
+    if !is_path_safe(path) {
+        return Err(ToolError::PermissionDenied(format!(
+            "Access to '{}' is blocked for security",
+            path
+        )));
+    }
+
+    let file_size = std::fs::metadata(path)
+        .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))?
+        .len();
+    if file_size > max_bytes {
+        return Err(ToolError::ExecutionFailed(format!(
+            "File '{}' is too large ({} bytes, max {} bytes)",
+            path, file_size, max_bytes
+        )));
+    }
+    Ok(())
+}
+
 pub struct ReadFileTool;
 
 impl Tool for ReadFileTool {
@@ -33,22 +56,7 @@ impl Tool for ReadFileTool {
             .as_str()
             .ok_or_else(|| ToolError::InvalidArguments("missing 'path' argument".to_string()))?;
 
-        if !is_path_safe(path) {
-            return Err(ToolError::PermissionDenied(format!(
-                "Access to '{}' is blocked for security",
-                path
-            )));
-        }
-
-        let file_size = std::fs::metadata(path)
-            .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))?
-            .len();
-        if file_size > MAX_FILE_SIZE {
-            return Err(ToolError::ExecutionFailed(format!(
-                "File '{}' is too large ({} bytes, max {} bytes)",
-                path, file_size, MAX_FILE_SIZE
-            )));
-        }
+        check_read(path, MAX_FILE_SIZE)?;
 
         std::fs::read_to_string(path)
             .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read '{}': {}", path, e)))
@@ -0,0 +1,148 @@
+//! `todo` tool — the model's running plan for a multi-step task.
+//!
+//! Each call replaces the whole list. The list lives on `Agent`, not in
+//! the tool, so the agent loop intercepts `tool_call.name == "todo"`
+//! (same route as `remember`); `execute()` here only reports a bug if
+//! that interception is ever bypassed. Keeping it on the agent lets the
+//! list survive compaction verbatim and be saved with the session.
+
+use super::{Tool, ToolError};
+use crate::config::ToolRiskLevel;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoStatus {
+    Pending,
+    InProgress,
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TodoItem {
+    pub content: String,
+    pub status: TodoStatus,
+}
+
+/// Read the `todos` argument. Items need non-empty text and one of the
+/// three statuses; anything else is rejected whole so the model can
+/// resend a corrected list.
+pub fn parse_todos(args: &serde_json::Value) -> Result<Vec<TodoItem>, String> {
+    let items = args
+        .get("todos")
+        .cloned()
+        .ok_or("todo requires a 'todos' array")?;
+    let items: Vec<TodoItem> = serde_json::from_value(items).map_err(|e| {
+        format!(
+            "invalid 'todos' ({}); each item needs 'content' and a 'status' of \
+             pending, in_progress or done",
+            e
+        )
+    })?;
+    if let Some(i) = items.iter().position(|t| t.content.trim().is_empty()) {
+        return Err(format!("todo item {} has empty 'content'", i + 1));
+    }
+    Ok(items)
+}
+
+/// The list as the model sees it, one `[status] text` line per item.
+pub fn render_for_model(items: &[TodoItem]) -> String {
+    items
+        .iter()
+        .map(|t| {
+            let mark = match t.status {
+                TodoStatus::Pending => "[ ]",
+                TodoStatus::InProgress => "[~]",
+                TodoStatus::Done => "[x]",
+            };
+            format!("{} {}", mark, t.content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub struct TodoTool;
+
+impl Tool for TodoTool {
+    fn name(&self) -> &str {
+        "todo"
+    }
+
+    fn description(&self) -> &str {
+        "Keep a checklist for multi-step tasks. Send the FULL list every \
+         time; it replaces the previous one. Mark exactly one item \
+         in_progress while working on it and mark items done as soon as \
+         they are finished. Skip this tool for one-step requests."
+    }
+
+    fn parameters_schema(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "todos": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "content": {"type": "string", "description": "What to do, in a few words"},
+                            "status": {"type": "string", "enum": ["pending", "in_progress", "done"]}
+                        },
+                        "required": ["content", "status"]
+                    }
+                }
+            },
+            "required": ["todos"]
+        })
+    }
+
+    fn execute(&self, _args: serde_json::Value) -> Result<String, ToolError> {
+        Err(ToolError::ExecutionFailed(
+            "todo tool reached the generic dispatch path; \
+             this should be intercepted by the agent loop. \
+             Please report this as a bug."
+                .to_string(),
+        ))
+    }
+
+    fn risk_level(&self) -> ToolRiskLevel {
+        // Only touches the agent's in-memory list.
+        ToolRiskLevel::Safe
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_render() {
+        let args = json!({"todos": [
+            {"content": "Read config", "status": "done"},
+            {"content": "Add flag", "status": "in_progress"},
+            {"content": "Update docs", "status": "pending"}
+        ]});
+        let items = parse_todos(&args).unwrap();
+        assert_eq!(items[1].status, TodoStatus::InProgress);
+        assert_eq!(
+            render_for_model(&items),
+            "[x] Read config\n[~] Add flag\n[ ] Update docs"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_items() {
+        assert!(parse_todos(&json!({})).unwrap_err().contains("'todos'"));
+        assert!(
+            parse_todos(&json!({"todos": [{"content": "x", "status": "started"}]}))
+                .unwrap_err()
+                .contains("in_progress")
+        );
+        assert!(
+            parse_todos(&json!({"todos": [{"content": " ", "status": "pending"}]}))
+                .unwrap_err()
+                .contains("item 1")
+        );
+        assert_eq!(parse_todos(&json!({"todos": []})).unwrap(), vec![]);
+    }
+}
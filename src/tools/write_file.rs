@@ -2,6 +2,30 @@ use super::{Tool, ToolError};
 use crate::security::path::is_path_safe;
 use serde_json::json;
 
+/// Whether `content` may be written to `path`: the path must not be a
+/// sensitive one, and an existing non-empty file is never emptied. The
+/// hunk review checks this too before writing on the tool's behalf.
+pub fn check_write(path: &str, content: &str) -> Result<(), ToolError> {
+    if !is_path_safe(path) {
+        return Err(ToolError::PermissionDenied(format!(
+            "Access to '{}' is blocked for security",
+            path
+        )));
+    }
+
+    // Protect against emptying existing files
+    if std::path::Path::new(path).exists() {
+        let existing_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if content.is_empty() && existing_size > 0 {
+            return Err(ToolError::PermissionDenied(format!(
+                "Refusing to overwrite '{}' ({} bytes) with empty content",
+                path, existing_size
+            )));
+        }
+    }
+    Ok(())
+}
+
 pub struct WriteFileTool;
 
 impl Tool for WriteFileTool {
@@ -38,23 +62,7 @@ impl Tool for WriteFileTool {
             .as_str()
             .ok_or_else(|| ToolError::InvalidArguments("missing 'content' argument".to_string()))?;
 
-        if !is_path_safe(path) {
-            return Err(ToolError::PermissionDenied(format!(
-                "Access to '{}' is blocked for security",
-                path
-            )));
-        }
-
-        // Protect against emptying existing files
-        if std::path::Path::new(path).exists() {
-            let existing_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
-            if content.is_empty() && existing_size > 0 {
-                return Err(ToolError::PermissionDenied(format!(
-                    "Refusing to overwrite '{}' ({} bytes) with empty content",
-                    path, existing_size
-                )));
-            }
-        }
+        check_write(path, content)?;
 
         std::fs::write(path, content).map_err(|e| {
             ToolError::ExecutionFailed(format!("Failed to write '{}': {}", path, e))